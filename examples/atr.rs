@@ -0,0 +1,71 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: CC0-1.0
+
+//! Builds or explains an ATR.
+//!
+//! ```text
+//! cargo run --example atr -- explain 3b9513810180 73ff01000b
+//! cargo run --example atr -- build --protocol 1 --protocol 1 --ta1 13 --historical 8073ff0100
+//! ```
+
+use std::{
+    env,
+    io::{Error, ErrorKind, Result},
+};
+
+use vpicc::atr::Atr;
+
+const USAGE: &str = "usage: atr explain <hex> | atr build [--protocol <n>]... [--ta1 <hex>] \
+                     [--historical <hex>]";
+
+fn main() -> Result<()> {
+    let mut args = env::args().skip(1);
+    let atr = match args.next().as_deref() {
+        Some("explain") => Atr::parse(&decode(&args.collect::<String>())?)?,
+        Some("build") => build(args)?,
+        _ => return Err(Error::new(ErrorKind::InvalidInput, USAGE)),
+    };
+    println!("{}", atr);
+    Ok(())
+}
+
+fn build(mut args: impl Iterator<Item = String>) -> Result<Atr> {
+    let mut protocols = Vec::new();
+    let mut ta1 = None;
+    let mut historical = Vec::new();
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, USAGE))?;
+        match arg.as_str() {
+            "--protocol" => protocols.push(
+                value
+                    .parse()
+                    .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?,
+            ),
+            "--ta1" => ta1 = decode(&value)?.first().copied(),
+            "--historical" => historical = decode(&value)?,
+            _ => return Err(Error::new(ErrorKind::InvalidInput, USAGE)),
+        }
+    }
+    let mut atr = Atr::new(&protocols, &historical);
+    atr.set_ta1(ta1);
+    Ok(atr)
+}
+
+fn decode(s: &str) -> Result<Vec<u8>> {
+    let s: String = s
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != ':')
+        .collect();
+    if !s.len().is_multiple_of(2) {
+        return Err(Error::new(ErrorKind::InvalidInput, "odd number of digits"));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&s[i..i + 2], 16)
+                .map_err(|err| Error::new(ErrorKind::InvalidInput, err))
+        })
+        .collect()
+}
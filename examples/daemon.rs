@@ -0,0 +1,33 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: CC0-1.0
+
+//! Runs a dummy card as a Unix daemon.
+//!
+//! ```text
+//! cargo run --example daemon --features daemon -- <pidfile> [<log file>]
+//! ```
+//!
+//! The process forks into the background, writes its process ID to the pidfile and its log to
+//! the log file, if given.  The pidfile is removed when the connection to vpcd ends.
+
+#[cfg(unix)]
+fn main() -> vpicc::Result<()> {
+    let mut args = std::env::args_os().skip(1);
+    let Some(pidfile) = args.next() else {
+        eprintln!("usage: daemon <pidfile> [<log file>]");
+        std::process::exit(2);
+    };
+    let mut daemon = vpicc::daemon::Daemon::new();
+    daemon.set_pidfile(pidfile);
+    if let Some(log) = args.next() {
+        daemon.set_log_file(log);
+    }
+    let _pidfile = daemon.start()?;
+    env_logger::init();
+    vpicc::connect()?.run(&mut vpicc::DummySmartCard)
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("daemon mode is only supported on Unix");
+}
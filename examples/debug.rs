@@ -0,0 +1,31 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: CC0-1.0
+
+//! Runs a synthetic PIV card in the interactive debugger.
+//!
+//! ```text
+//! cargo run --example debug
+//! ```
+//!
+//! The debugger pauses before and after every command.  Type `help` at the prompt to list the
+//! available commands.  In addition to the default commands, `retries` shows the PIN retry
+//! counter and `pin <ascii>` changes the PIN.
+
+use vpicc::{
+    debugger::{Console, Debugger},
+    profiles::piv::PivCard,
+};
+
+fn main() -> vpicc::Result<()> {
+    env_logger::init();
+    let mut console = Console::stdio();
+    console.add_command("retries", |card: &mut PivCard, _| {
+        card.pin_retries().to_string()
+    });
+    console.add_command("pin", |card: &mut PivCard, pin| {
+        card.set_pin(pin.as_bytes());
+        "PIN changed".to_owned()
+    });
+    let mut card = Debugger::new(PivCard::synthetic(0), console);
+    vpicc::connect()?.run(&mut card)
+}
@@ -0,0 +1,133 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: CC0-1.0
+
+//! Compares two recorded traces.
+//!
+//! ```text
+//! cargo run --example diff -- [--ignore-command-data <ins>]... [--ignore-response-data <ins>]...
+//!     [--duration-tolerance <ms>] a.trace b.trace
+//! ```
+//!
+//! Like diff(1), the exit code is 0 if the traces are equal, 1 if they differ and 2 if an error
+//! occurred.  The output is colorized if stdout is a terminal and `NO_COLOR` is not set.
+
+use std::{
+    env,
+    io::{self, Error, ErrorKind, IsTerminal, Result},
+    process::ExitCode,
+    time::Duration,
+};
+
+use vpicc::{
+    diff::{diff, DiffOptions, Difference},
+    trace::Trace,
+};
+
+const USAGE: &str = "usage: diff [--ignore-command-data <ins>]... \
+                     [--ignore-response-data <ins>]... [--duration-tolerance <ms>] \
+                     <left> <right>";
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::from(1),
+        Err(err) => {
+            eprintln!("diff: {}", err);
+            ExitCode::from(2)
+        }
+    }
+}
+
+/// Returns true if the traces are equal.
+fn run() -> Result<bool> {
+    let mut options = DiffOptions::default();
+    let mut paths = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| Error::new(ErrorKind::InvalidInput, USAGE))
+        };
+        match arg.as_str() {
+            "--ignore-command-data" => {
+                options.ignore_command_data.insert(parse_ins(&value()?)?);
+            }
+            "--ignore-response-data" => {
+                options.ignore_response_data.insert(parse_ins(&value()?)?);
+            }
+            "--duration-tolerance" => {
+                let ms = value()?
+                    .parse()
+                    .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+                options.duration_tolerance = Some(Duration::from_millis(ms));
+            }
+            _ if arg.starts_with("--") => return Err(Error::new(ErrorKind::InvalidInput, USAGE)),
+            _ => paths.push(arg),
+        }
+    }
+    let [left, right] = paths.as_slice() else {
+        return Err(Error::new(ErrorKind::InvalidInput, USAGE));
+    };
+    let open = |path: &str| {
+        Trace::open(path).map_err(|err| Error::new(err.kind(), format!("{}: {}", path, err)))
+    };
+    let differences = diff(&open(left)?, &open(right)?, &options);
+
+    let color = io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none();
+    let paint = |style: &str, text: String| {
+        if color {
+            format!("{}{}{}", style, text, RESET)
+        } else {
+            text
+        }
+    };
+    println!("{}", paint(BOLD, format!("--- {}", left)));
+    println!("{}", paint(BOLD, format!("+++ {}", right)));
+    for difference in &differences {
+        let (index, what, l, r) = match difference {
+            Difference::Command { index, left, right } => {
+                (index, "command", Some(left), Some(right))
+            }
+            Difference::Response {
+                index, left, right, ..
+            } => (index, "response", Some(left), Some(right)),
+            Difference::Duration { index, left, right } => {
+                println!("{}", paint(BOLD, format!("@@ #{} duration", index)));
+                println!("{}", paint(RED, format!("-{:?}", left)));
+                println!("{}", paint(GREEN, format!("+{:?}", right)));
+                continue;
+            }
+            Difference::OnlyLeft { index, exchange } => {
+                (index, "exchange", Some(&exchange.command), None)
+            }
+            Difference::OnlyRight { index, exchange } => {
+                (index, "exchange", None, Some(&exchange.command))
+            }
+        };
+        println!("{}", paint(BOLD, format!("@@ #{} {}", index, what)));
+        if let Some(l) = l {
+            println!("{}", paint(RED, format!("-{}", hex(l))));
+        }
+        if let Some(r) = r {
+            println!("{}", paint(GREEN, format!("+{}", hex(r))));
+        }
+    }
+    match differences.len() {
+        0 => println!("traces are equal"),
+        n => println!("{} difference(s)", n),
+    }
+    Ok(differences.is_empty())
+}
+
+fn parse_ins(s: &str) -> Result<u8> {
+    u8::from_str_radix(s, 16).map_err(|err| Error::new(ErrorKind::InvalidInput, err))
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,35 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: CC0-1.0
+
+//! Shows the ATR and the capabilities of one of the built-in card profiles.
+//!
+//! ```text
+//! cargo run --example info -- piv
+//! ```
+
+use std::{
+    env,
+    io::{Error, ErrorKind, Result},
+};
+
+use vpicc::{
+    atr::Atr,
+    profiles::{calypso, egk, euicc, mdl, piv},
+    VSmartCard,
+};
+
+const USAGE: &str = "usage: info piv|calypso|egk|euicc|mdl";
+
+fn main() -> Result<()> {
+    let card: Box<dyn VSmartCard> = match env::args().nth(1).as_deref() {
+        Some("piv") => Box::new(piv::PivCard::synthetic(0)),
+        Some("calypso") => Box::new(calypso::CalypsoCard::new()),
+        Some("egk") => Box::new(egk::EgkCard::default()),
+        Some("euicc") => Box::new(euicc::Isdr::new()),
+        Some("mdl") => Box::new(mdl::MdlEngagement::new()),
+        _ => return Err(Error::new(ErrorKind::InvalidInput, USAGE)),
+    };
+    println!("{}", Atr::parse(card.atr())?);
+    println!("{}", card.capabilities());
+    Ok(())
+}
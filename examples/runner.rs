@@ -1,7 +1,9 @@
 // Copyright (C) 2022 Nitrokey GmbH
 // SPDX-License-Identifier: CC0-1.0
 
-fn main() -> std::io::Result<()> {
+//! Runs a dummy card.
+
+fn main() -> vpicc::Result<()> {
     env_logger::init();
     vpicc::connect()?.run(&mut vpicc::DummySmartCard)
 }
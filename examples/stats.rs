@@ -0,0 +1,23 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: CC0-1.0
+
+//! Runs a dummy card and exports its session statistics.
+//!
+//! ```text
+//! VPICC_STATS=stats.json cargo run --example stats
+//! ```
+//!
+//! If `VPICC_STATS` is set, the statistics of the session are written as JSON to the file with
+//! that name when the connection ends, so that CI jobs can archive them.
+
+use vpicc::stats::SessionStats;
+
+fn main() -> vpicc::Result<()> {
+    env_logger::init();
+    let mut card = SessionStats::new(vpicc::DummySmartCard);
+    let result = vpicc::connect()?.run(&mut card);
+    if let Some(path) = std::env::var_os("VPICC_STATS") {
+        card.save_json(path)?;
+    }
+    result
+}
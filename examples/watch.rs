@@ -0,0 +1,121 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: CC0-1.0
+
+//! Prints the traffic of a synthetic PIV card while it is connected to vpcd.
+//!
+//! ```text
+//! cargo run --example watch
+//! ```
+//!
+//! Every APDU is printed with its description, status word and response time as it is handled.
+//! The state of the connection to vpcd is printed as it changes, and the connection is
+//! reestablished if vpcd is restarted.  When the card is powered off, the statistics of the
+//! session and the number of commands and errors per instruction are printed.
+
+use std::{collections::BTreeMap, time::Instant};
+
+use vpicc::{
+    apdu, names, observer::Event, profiles::piv::PivCard, stats::SessionStats, Backoff, Supervisor,
+    VSmartCard,
+};
+
+/// The number of commands and of error responses for an instruction.
+#[derive(Default)]
+struct Counts {
+    commands: u64,
+    errors: u64,
+}
+
+struct Watch<C> {
+    card: SessionStats<C>,
+    instructions: BTreeMap<u8, Counts>,
+}
+
+impl<C: VSmartCard> Watch<C> {
+    fn new(card: C) -> Self {
+        Self {
+            card: SessionStats::new(card),
+            instructions: BTreeMap::new(),
+        }
+    }
+
+    fn print_summary(&self) {
+        print!(
+            "session: {} APDUs, {} errors",
+            self.card.apdus(),
+            self.card.errors()
+        );
+        match self.card.response_times() {
+            Some(times) => println!(", mean {:?}, max {:?}", times.mean, times.max),
+            None => println!(),
+        }
+        for (ins, counts) in &self.instructions {
+            println!(
+                "  {:02x} {:<24} {:>6} commands {:>6} errors",
+                ins,
+                names::instruction(*ins).unwrap_or("unknown"),
+                counts.commands,
+                counts.errors
+            );
+        }
+    }
+}
+
+impl<C: VSmartCard> VSmartCard for Watch<C> {
+    fn atr(&self) -> &[u8] {
+        self.card.atr()
+    }
+
+    fn power_on(&mut self) {
+        println!("power on");
+        self.card.power_on()
+    }
+
+    fn power_off(&mut self) {
+        println!("power off");
+        self.card.power_off();
+        self.print_summary();
+    }
+
+    fn reset(&mut self) {
+        println!("reset");
+        self.card.reset()
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        let start = Instant::now();
+        let response = self.card.execute(msg);
+        let status = apdu::status(&response);
+        println!(
+            "{:<48} -> {} ({} bytes, {:?})",
+            names::describe(msg),
+            status.map_or_else(|| "no status".to_owned(), |sw| format!("{:04x}", sw)),
+            response.len(),
+            start.elapsed()
+        );
+        if let Some(&ins) = msg.get(1) {
+            let counts = self.instructions.entry(ins).or_default();
+            counts.commands += 1;
+            if !status.is_some_and(apdu::is_success) {
+                counts.errors += 1;
+            }
+        }
+        response
+    }
+}
+
+fn main() -> vpicc::Result<()> {
+    env_logger::init();
+    let mut supervisor = Supervisor::default();
+    supervisor.set_backoff(Backoff::default());
+    supervisor.set_observer(|event: &Event<'_>| match event {
+        Event::Connected { addr } => println!("connected to vpcd on {}", addr),
+        Event::Disconnected { reason } => println!("disconnected: {}", reason),
+        Event::ReconnectAttempt { attempt } => println!("reconnecting (attempt {})", attempt),
+        Event::ReconnectFailed { attempt, reason } => {
+            println!("reconnect attempt {} failed: {}", attempt, reason)
+        }
+        _ => {}
+    });
+    supervisor.run(&mut Watch::new(PivCard::synthetic(0)))
+}
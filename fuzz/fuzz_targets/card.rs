@@ -0,0 +1,11 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: CC0-1.0
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // replace with your card implementation
+    vpicc::fuzz::card(&mut vpicc::DummySmartCard, data);
+});
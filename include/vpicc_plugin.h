@@ -0,0 +1,49 @@
+/*
+ * Copyright (C) 2022 Nitrokey GmbH
+ * SPDX-License-Identifier: MIT
+ *
+ * The C interface for vpicc card plugins, see the vpicc::plugin module.
+ *
+ * A plugin is a shared library exporting the function vpicc_plugin.  The functions of the table
+ * may be called from any thread, but never concurrently for the same card.  The optional
+ * functions may be NULL.
+ */
+
+#ifndef VPICC_PLUGIN_H
+#define VPICC_PLUGIN_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define VPICC_PLUGIN_ABI_VERSION 1
+
+/* The maximum length of a response, the size of the response buffer passed to execute. */
+#define VPICC_MAX_MESSAGE_LEN 65535
+
+typedef struct vpicc_plugin {
+	/* Must be VPICC_PLUGIN_ABI_VERSION. */
+	uint32_t abi_version;
+	/* Creates a card and returns a pointer to it, or NULL on failure. */
+	void *(*create)(void);
+	/* Destroys a card created with create. */
+	void (*destroy)(void *card);
+	/* Copies at most len bytes of the ATR into buf and returns the length of the ATR.
+	 * Optional, the default ATR of vpicc is used if NULL. */
+	size_t (*atr)(void *card, uint8_t *buf, size_t len);
+	/* Optional handlers for Power On, Power Off and Reset commands and for cold and warm
+	 * resets. */
+	void (*power_on)(void *card);
+	void (*power_off)(void *card);
+	void (*reset)(void *card);
+	void (*cold_reset)(void *card);
+	void (*warm_reset)(void *card);
+	/* Executes the command APDU in cmd, writes the response APDU to resp and returns its
+	 * length.  resp has resp_len bytes, at least VPICC_MAX_MESSAGE_LEN. */
+	size_t (*execute)(void *card, const uint8_t *cmd, size_t cmd_len, uint8_t *resp,
+			  size_t resp_len);
+} vpicc_plugin_t;
+
+/* Returns the table of the plugin.  The table must stay valid while the library is loaded. */
+const vpicc_plugin_t *vpicc_plugin(void);
+
+#endif /* VPICC_PLUGIN_H */
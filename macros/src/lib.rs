@@ -0,0 +1,126 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Procedural macros for [`vpicc`][].  Use them through the `derive` feature of `vpicc`.
+//!
+//! [`vpicc`]: https://docs.rs/vpicc
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, spanned::Spanned, Error, ImplItem, ItemImpl, LitInt};
+
+/// Implements `vpicc::apdu::Applet` for a type by routing commands to the methods of an impl
+/// block that are marked with `#[apdu(...)]`.  A method marked with `#[reset_security]` is used
+/// as `Applet::reset_security`.
+///
+/// See the documentation of `vpicc::applet` for details.
+#[proc_macro_attribute]
+pub fn applet(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return Error::new(
+            TokenStream2::from(attr).span(),
+            "#[applet] does not take arguments",
+        )
+        .into_compile_error()
+        .into();
+    }
+    let item = parse_macro_input!(item as ItemImpl);
+    expand(item)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+struct Route {
+    cla: Option<u8>,
+    ins: u8,
+    method: syn::Ident,
+}
+
+fn expand(mut item: ItemImpl) -> syn::Result<TokenStream2> {
+    let mut routes = Vec::new();
+    let mut reset = None;
+    for impl_item in &mut item.items {
+        let ImplItem::Fn(method) = impl_item else {
+            continue;
+        };
+        let mut attrs = Vec::new();
+        for attr in method.attrs.drain(..) {
+            if attr.path().is_ident("reset_security") {
+                attr.meta.require_path_only()?;
+                if reset.is_some() {
+                    return Err(Error::new(attr.span(), "duplicate `#[reset_security]`"));
+                }
+                reset = Some(method.sig.ident.clone());
+                continue;
+            }
+            if !attr.path().is_ident("apdu") {
+                attrs.push(attr);
+                continue;
+            }
+            let mut cla = None;
+            let mut ins = None;
+            attr.parse_nested_meta(|meta| {
+                let value: LitInt = meta.value()?.parse()?;
+                if meta.path.is_ident("cla") {
+                    cla = Some(value.base10_parse()?);
+                } else if meta.path.is_ident("ins") {
+                    ins = Some(value.base10_parse()?);
+                } else {
+                    return Err(meta.error("expected `cla` or `ins`"));
+                }
+                Ok(())
+            })?;
+            let ins = ins.ok_or_else(|| Error::new(attr.span(), "missing `ins`"))?;
+            routes.push(Route {
+                cla,
+                ins,
+                method: method.sig.ident.clone(),
+            });
+        }
+        method.attrs = attrs;
+    }
+
+    let arms = routes.iter().map(|route| {
+        let ins = route.ins;
+        let method = &route.method;
+        let cla = match route.cla {
+            Some(cla) => quote!(command.cla == #cla &&),
+            None => quote!(),
+        };
+        quote! {
+            if #cla command.ins == #ins {
+                return self.#method(command);
+            }
+        }
+    });
+    let instructions = routes.iter().map(|route| route.ins);
+    let reset = reset.map(|method| {
+        quote! {
+            fn reset_security(&mut self) {
+                self.#method()
+            }
+        }
+    });
+    let self_ty = &item.self_ty;
+    let (impl_generics, _, where_clause) = item.generics.split_for_impl();
+    Ok(quote! {
+        #item
+
+        impl #impl_generics ::vpicc::apdu::Applet for #self_ty #where_clause {
+            fn dispatch(
+                &mut self,
+                command: &::vpicc::apdu::Command<'_>,
+            ) -> ::core::result::Result<::std::vec::Vec<u8>, u16> {
+                #(#arms)*
+                if [#(#instructions),*].contains(&command.ins) {
+                    ::core::result::Result::Err(::vpicc::apdu::SW_CLA_NOT_SUPPORTED)
+                } else {
+                    ::core::result::Result::Err(::vpicc::apdu::SW_INS_NOT_SUPPORTED)
+                }
+            }
+
+            #reset
+        }
+    })
+}
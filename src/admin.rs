@@ -0,0 +1,318 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! An emulation of the Nitrokey 3 admin applet.
+//!
+//! [`AdminApplet`][] implements the administrative command set of the Nitrokey 3 so that
+//! companion applications like `pynitrokey` or `nitrokey-app2` can be tested against a virtual
+//! device.  The applet has to be selected using [`AID`][] before any other command is accepted.
+//! Commands that would affect the hardware, like firmware updates and reboots, are acknowledged
+//! without any further effect.
+//!
+//! # Example
+//!
+//! ```
+//! use vpicc::{admin::{AdminApplet, AID}, VSmartCard};
+//!
+//! let mut card = AdminApplet::new();
+//! card.set_version(1, 7, 2);
+//!
+//! let select = [&[0x00, 0xa4, 0x04, 0x00, AID.len() as u8][..], AID].concat();
+//! assert_eq!(card.execute(&select), [0x90, 0x00]);
+//! assert_eq!(card.execute(&[0x00, 0x61, 0x00, 0x00]), [0x00, 0x40, 0x01, 0xc2, 0x90, 0x00]);
+//! ```
+
+use std::collections::BTreeMap;
+
+use log::debug;
+
+use crate::{
+    apdu::{self, Command},
+    rng::Rng,
+    VSmartCard,
+};
+
+/// The AID of the admin applet.
+pub const AID: &[u8] = &[0xa0, 0x00, 0x00, 0x08, 0x47, 0x00, 0x00, 0x00, 0x01];
+/// The ATR of a Nitrokey 3.
+pub const ATR: &[u8] = &[
+    0x3b, 0x8f, 0x01, 0x80, 0x5d, 0x4e, 0x69, 0x74, 0x72, 0x6f, 0x6b, 0x65, 0x79, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x6a,
+];
+
+const INS_SELECT: u8 = 0xa4;
+const INS_WINK: u8 = 0x08;
+const INS_UPDATE: u8 = 0x51;
+const INS_REBOOT: u8 = 0x53;
+const INS_RNG: u8 = 0x60;
+const INS_VERSION: u8 = 0x61;
+const INS_UUID: u8 = 0x62;
+const INS_LOCKED: u8 = 0x63;
+const INS_STATUS: u8 = 0x80;
+const INS_GET_CONFIG: u8 = 0x82;
+const INS_SET_CONFIG: u8 = 0x83;
+const INS_FACTORY_RESET: u8 = 0x84;
+
+const RNG_LEN: usize = 57;
+
+const DEFAULT_CONFIG: &[(&str, &str)] = &[
+    ("fido.disable_skip_up_timeout", "false"),
+    ("opcard.use_se050_backend", "false"),
+    ("opcard.disabled", "false"),
+    ("piv.disabled", "true"),
+];
+
+/// The result of a config command, sent as the first byte of the response data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ConfigStatus {
+    /// The command was successful.
+    Ok = 0,
+    /// The request could not be decoded.
+    DeserializationFailed = 3,
+    /// The key does not exist.
+    InvalidKey = 5,
+    /// The value is not valid for the key.
+    InvalidValue = 6,
+}
+
+/// The device status reported by the status command.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Status {
+    /// The initialization status flags, zero if the device was initialized without errors.
+    pub init_status: u8,
+    /// The number of free blocks in the internal filesystem.
+    pub ifs_blocks: u8,
+    /// The number of free blocks in the external filesystem.
+    pub efs_blocks: u16,
+    /// The hardware variant.
+    pub variant: u8,
+}
+
+impl Status {
+    fn to_bytes(self) -> Vec<u8> {
+        let efs = self.efs_blocks.to_be_bytes();
+        vec![
+            self.init_status,
+            self.ifs_blocks,
+            efs[0],
+            efs[1],
+            self.variant,
+        ]
+    }
+}
+
+/// An emulated Nitrokey 3 admin applet, see the [module documentation][`self`].
+#[derive(Clone, Debug)]
+pub struct AdminApplet {
+    selected: bool,
+    version: u32,
+    uuid: [u8; 16],
+    locked: bool,
+    status: Status,
+    config: BTreeMap<String, String>,
+    rng: Rng,
+}
+
+impl AdminApplet {
+    /// Creates an admin applet with version 0.0.0, an all-zero UUID and the default
+    /// configuration.
+    pub fn new() -> Self {
+        Self {
+            selected: false,
+            version: 0,
+            uuid: [0; 16],
+            locked: false,
+            status: Status::default(),
+            config: default_config(),
+            rng: Rng::new(0),
+        }
+    }
+
+    /// Sets the firmware version reported by the version command.
+    pub fn set_version(&mut self, major: u16, minor: u16, patch: u8) {
+        self.version = (u32::from(major) << 22)
+            | ((u32::from(minor) & 0xffff) << 6)
+            | (u32::from(patch) & 0x3f);
+    }
+
+    /// Sets the UUID reported by the UUID command.
+    pub fn set_uuid(&mut self, uuid: [u8; 16]) {
+        self.uuid = uuid;
+    }
+
+    /// Sets whether the device reports that it is locked.
+    pub fn set_locked(&mut self, locked: bool) {
+        self.locked = locked;
+    }
+
+    /// Sets the device status reported by the status command.
+    pub fn set_status(&mut self, status: Status) {
+        self.status = status;
+    }
+
+    /// Sets the seed for the data returned by the RNG command, defaulting to zero.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    /// Returns the current configuration.
+    pub fn config(&self) -> &BTreeMap<String, String> {
+        &self.config
+    }
+
+    fn handle(&mut self, command: &Command<'_>) -> Result<Vec<u8>, u16> {
+        if command.ins == INS_SELECT && command.p1 == 0x04 {
+            self.selected = command.data == AID;
+            return if self.selected {
+                Ok(Vec::new())
+            } else {
+                Err(apdu::SW_FILE_NOT_FOUND)
+            };
+        }
+        if !self.selected {
+            return Err(apdu::SW_CONDITIONS_NOT_SATISFIED);
+        }
+        debug!("Executing admin command {:02x}", command.ins);
+        match command.ins {
+            INS_WINK | INS_UPDATE => Ok(Vec::new()),
+            INS_REBOOT => {
+                self.selected = false;
+                Ok(Vec::new())
+            }
+            INS_RNG => Ok((0..RNG_LEN).map(|_| self.rng.next_u64() as u8).collect()),
+            INS_VERSION => Ok(self.version.to_be_bytes().to_vec()),
+            INS_UUID => Ok(self.uuid.to_vec()),
+            INS_LOCKED => Ok(vec![self.locked.into()]),
+            INS_STATUS => Ok(self.status.to_bytes()),
+            INS_GET_CONFIG => {
+                let value = std::str::from_utf8(command.data)
+                    .ok()
+                    .and_then(|key| self.config.get(key));
+                Ok(match value {
+                    Some(value) => [&[ConfigStatus::Ok as u8], value.as_bytes()].concat(),
+                    None => vec![ConfigStatus::InvalidKey as u8],
+                })
+            }
+            INS_SET_CONFIG => Ok(vec![self.set_config(command.data) as u8]),
+            INS_FACTORY_RESET => {
+                self.config = default_config();
+                Ok(vec![0x00])
+            }
+            _ => Err(apdu::SW_INS_NOT_SUPPORTED),
+        }
+    }
+
+    /// Sets a config value from a CBOR map with the text fields `key` and `value`.
+    fn set_config(&mut self, data: &[u8]) -> ConfigStatus {
+        let Some(fields) = cbor::decode_map(data) else {
+            return ConfigStatus::DeserializationFailed;
+        };
+        let (Some(key), Some(value)) = (fields.get("key"), fields.get("value")) else {
+            return ConfigStatus::DeserializationFailed;
+        };
+        let Some(current) = self.config.get_mut(key) else {
+            return ConfigStatus::InvalidKey;
+        };
+        if !matches!(value.as_str(), "true" | "false") {
+            return ConfigStatus::InvalidValue;
+        }
+        current.clone_from(value);
+        ConfigStatus::Ok
+    }
+}
+
+impl Default for AdminApplet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VSmartCard for AdminApplet {
+    fn atr(&self) -> &[u8] {
+        ATR
+    }
+
+    fn power_on(&mut self) {
+        self.selected = false;
+    }
+
+    fn power_off(&mut self) {
+        self.selected = false;
+    }
+
+    fn reset(&mut self) {
+        self.selected = false;
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        let result = Command::parse(msg)
+            .ok_or(apdu::SW_WRONG_LENGTH)
+            .and_then(|command| self.handle(&command));
+        match result {
+            Ok(data) => apdu::response(&data, apdu::SW_SUCCESS),
+            Err(status) => apdu::response(&[], status),
+        }
+    }
+}
+
+fn default_config() -> BTreeMap<String, String> {
+    DEFAULT_CONFIG
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Just enough CBOR to decode the set config request:  a map with text keys and text or boolean
+/// values.
+mod cbor {
+    use std::collections::BTreeMap;
+
+    pub fn decode_map(data: &[u8]) -> Option<BTreeMap<String, String>> {
+        let mut data = data;
+        let (major, len) = header(&mut data)?;
+        if major != 5 {
+            return None;
+        }
+        let mut map = BTreeMap::new();
+        for _ in 0..len {
+            let key = text(&mut data)?;
+            let value = match data.first()? {
+                0xf4 => "false".to_owned(),
+                0xf5 => "true".to_owned(),
+                _ => {
+                    map.insert(key, text(&mut data)?);
+                    continue;
+                }
+            };
+            data = &data[1..];
+            map.insert(key, value);
+        }
+        data.is_empty().then_some(map)
+    }
+
+    fn text(data: &mut &[u8]) -> Option<String> {
+        let (major, len) = header(data)?;
+        if major != 3 || data.len() < len {
+            return None;
+        }
+        let (text, rest) = data.split_at(len);
+        *data = rest;
+        String::from_utf8(text.to_vec()).ok()
+    }
+
+    fn header(data: &mut &[u8]) -> Option<(u8, usize)> {
+        let (&initial, rest) = data.split_first()?;
+        let (len, rest) = match initial & 0x1f {
+            len @ 0..=23 => (usize::from(len), rest),
+            24 => (usize::from(*rest.first()?), &rest[1..]),
+            25 => {
+                let (len, rest) = rest.split_first_chunk::<2>()?;
+                (usize::from(u16::from_be_bytes(*len)), rest)
+            }
+            _ => return None,
+        };
+        *data = rest;
+        Some((initial >> 5, len))
+    }
+}
@@ -0,0 +1,498 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Async connections to vpcd using tokio.
+//!
+//! This module is only available if the `tokio` feature is enabled.  It mirrors the blocking API
+//! of the crate root:  [`connect`][] returns an [`AsyncConnection`][] that handles the requests
+//! from vpcd using an [`AsyncVSmartCard`][], so that a card can be embedded into a tokio-based
+//! service without dedicating a thread to it.  A blocking [`VSmartCard`][] can be used with
+//! [`SyncCard`][] if its commands complete quickly.
+//!
+//! To compose a connection with other futures and streams instead of running it with an
+//! [`AsyncVSmartCard`][], it can be split into a [`Stream`][] of requests and a [`Sink`][] for
+//! the responses using [`AsyncConnection::into_split`][].
+//!
+//! # Example
+//!
+//! ```no_run
+//! use vpicc::aio::AsyncVSmartCard;
+//!
+//! struct Card;
+//!
+//! impl AsyncVSmartCard for Card {
+//!     async fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+//!         log::info!("Received APDU: {:x?}", msg);
+//!         vec![0x90, 0x00]
+//!     }
+//! }
+//!
+//! #[tokio::main(flavor = "current_thread")]
+//! async fn main() -> vpicc::Result<()> {
+//!     vpicc::aio::connect().await?.run(&mut Card).await
+//! }
+//! ```
+
+use std::{
+    fmt::Display,
+    future::{self, Future},
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use futures_core::Stream;
+use futures_sink::Sink;
+use log::{debug, info, trace};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _, ReadBuf},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream, ToSocketAddrs,
+    },
+    sync::mpsc,
+};
+
+use crate::{
+    frame, names, power, Capabilities, Error, PowerState, Request, Result, VSmartCard, DEFAULT_ATR,
+    DEFAULT_HOST, DEFAULT_PORT,
+};
+
+/// A virtual smartcard implementation with async command execution.
+///
+/// This is the async equivalent of [`VSmartCard`][].  The power handlers and the ATR are
+/// synchronous as they are not expected to block.
+pub trait AsyncVSmartCard {
+    /// The ATR of this smartcard, defaulting to [`DEFAULT_ATR`].
+    fn atr(&self) -> &[u8] {
+        DEFAULT_ATR
+    }
+
+    /// Handles a Power On command.
+    fn power_on(&mut self) {}
+
+    /// Handles a Power Off command.
+    fn power_off(&mut self) {}
+
+    /// Handles a Reset command.
+    fn reset(&mut self) {}
+
+    /// Handles a cold reset, see [`VSmartCard::cold_reset`][].
+    fn cold_reset(&mut self) {}
+
+    /// Handles a warm reset, see [`VSmartCard::warm_reset`][].
+    fn warm_reset(&mut self) {}
+
+    /// Executes the given APDU command and returns the response APDU.
+    fn execute(&mut self, msg: &[u8]) -> impl Future<Output = Vec<u8>> + Send;
+
+    /// Returns a description of the features supported by this card, see
+    /// [`VSmartCard::capabilities`][].
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+}
+
+/// Uses a blocking [`VSmartCard`][] as an [`AsyncVSmartCard`][].
+///
+/// The commands are executed on the task that runs the connection, so the card should not block
+/// for a long time.
+#[derive(Clone, Debug, Default)]
+pub struct SyncCard<C> {
+    card: C,
+}
+
+impl<C> SyncCard<C> {
+    /// Wraps the given card.
+    pub fn new(card: C) -> Self {
+        Self { card }
+    }
+
+    /// Returns a reference to the wrapped card.
+    pub fn get_ref(&self) -> &C {
+        &self.card
+    }
+
+    /// Returns a mutable reference to the wrapped card.
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.card
+    }
+
+    /// Returns the wrapped card.
+    pub fn into_inner(self) -> C {
+        self.card
+    }
+}
+
+impl<C: VSmartCard + Send> AsyncVSmartCard for SyncCard<C> {
+    fn atr(&self) -> &[u8] {
+        self.card.atr()
+    }
+
+    fn power_on(&mut self) {
+        self.card.power_on()
+    }
+
+    fn power_off(&mut self) {
+        self.card.power_off()
+    }
+
+    fn reset(&mut self) {
+        self.card.reset()
+    }
+
+    fn cold_reset(&mut self) {
+        self.card.cold_reset()
+    }
+
+    fn warm_reset(&mut self) {
+        self.card.warm_reset()
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> impl Future<Output = Vec<u8>> + Send {
+        let response = self.card.execute(msg);
+        async move { response }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.card.capabilities()
+    }
+}
+
+/// Connects to the vpcd dameon using [`DEFAULT_HOST`][] and [`DEFAULT_PORT`][].
+pub async fn connect() -> Result<AsyncConnection> {
+    connect_socket(SocketAddr::new(DEFAULT_HOST.into(), DEFAULT_PORT)).await
+}
+
+/// Connects to the vpcd daemon at the given address.
+pub async fn connect_socket<A: ToSocketAddrs + Display>(addr: A) -> Result<AsyncConnection> {
+    info!("Connecting to vpcd on {}", addr);
+    Ok(TcpStream::connect(addr).await.map(AsyncConnection::from)?)
+}
+
+/// An async connection to the vpcd daemon.
+///
+/// The futures returned by the methods of this type are not cancellation safe:  if a future is
+/// dropped while a command is being executed or a response is being sent, the connection is in
+/// an undefined state and should be dropped.  A partially received request is kept and
+/// completed by the next call.
+#[derive(Debug)]
+pub struct AsyncConnection {
+    stream: TcpStream,
+    rx: Vec<u8>,
+    power: PowerState,
+}
+
+impl AsyncConnection {
+    /// Handles all commands from this connection using the given card.
+    ///
+    /// This is equivalent to calling [`poll`][`AsyncConnection::poll`] until a call fails.
+    pub async fn run<V: AsyncVSmartCard>(mut self, card: &mut V) -> Result<()> {
+        loop {
+            self.poll(card).await?;
+        }
+    }
+
+    /// Handles a single command from this connection using the given card.
+    pub async fn poll<V: AsyncVSmartCard>(&mut self, card: &mut V) -> Result<()> {
+        let request = Request::try_from(self.receive().await?)?;
+        if let Some(response) = handle(&request, card, &mut self.power).await {
+            self.send(&response).await?;
+        }
+        Ok(())
+    }
+
+    /// Handles all commands from this connection using the given card, receiving up to
+    /// `capacity` requests while the card is executing a command.
+    ///
+    /// vpcd may send the next requests before it has received a response.  With
+    /// [`run`][`AsyncConnection::run`], they are buffered by the socket.  This function reads
+    /// them into a bounded queue instead, so that they can be decoded while the card is busy.  If
+    /// the queue is full, no data is read from the socket until the card has handled a request,
+    /// so a slow card cannot cause unbounded memory growth.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use vpicc::aio::SyncCard;
+    /// #[tokio::main(flavor = "current_thread")]
+    /// async fn main() -> vpicc::Result<()> {
+    ///     let mut card = SyncCard::new(vpicc::DummySmartCard);
+    ///     vpicc::aio::connect().await?.run_pipelined(&mut card, 16).await
+    /// }
+    /// ```
+    pub async fn run_pipelined<V: AsyncVSmartCard>(
+        self,
+        card: &mut V,
+        capacity: usize,
+    ) -> Result<()> {
+        let mut power = self.power;
+        let (mut requests, mut responses) = self.into_split();
+        let (sender, mut receiver) = mpsc::channel(capacity);
+        let receive = async move {
+            loop {
+                let request = requests.receive().await?;
+                if sender.send(request).await.is_err() {
+                    return Ok(());
+                }
+            }
+        };
+        let execute = async {
+            while let Some(request) = receiver.recv().await {
+                if let Some(response) = handle(&request, card, &mut power).await {
+                    responses.send(&response).await?;
+                }
+            }
+            Ok(())
+        };
+        tokio::try_join!(receive, execute).map(|_| ())
+    }
+
+    async fn receive(&mut self) -> Result<Vec<u8>> {
+        loop {
+            if let Some(msg) = frame::decode(&mut self.rx) {
+                return Ok(msg);
+            }
+            if self.stream.read_buf(&mut self.rx).await? == 0 {
+                return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+            }
+        }
+    }
+
+    async fn send(&mut self, data: &[u8]) -> Result<()> {
+        let frame = frame::try_encode(data)?;
+        trace!("sending message: {:x?}", data);
+        self.stream.write_all(&frame).await?;
+        Ok(())
+    }
+
+    /// Splits this connection into a stream of requests and a sink for the responses.
+    ///
+    /// The requests are not passed to a card, so the power state of the connection is not
+    /// updated.  Use [`Request::handle_with_state`][] to handle them with a [`VSmartCard`][].
+    /// vpcd expects exactly one response for every [`Request::GetAtr`][] and
+    /// [`Request::Apdu`][], in the order of the requests.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # async fn run(mut shutdown: tokio::sync::oneshot::Receiver<()>) -> vpicc::Result<()> {
+    /// let (mut requests, mut responses) = vpicc::aio::connect().await?.into_split();
+    /// let mut card = vpicc::DummySmartCard;
+    /// let mut power = vpicc::PowerState::default();
+    /// loop {
+    ///     let request = tokio::select! {
+    ///         request = requests.receive() => request?,
+    ///         _ = &mut shutdown => return Ok(()),
+    ///     };
+    ///     if let Some(response) = request.handle_with_state(&mut card, &mut power) {
+    ///         responses.send(&response).await?;
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    pub fn into_split(self) -> (ReadHalf, WriteHalf) {
+        let (reader, writer) = self.stream.into_split();
+        (
+            ReadHalf {
+                stream: reader,
+                rx: self.rx,
+            },
+            WriteHalf {
+                stream: writer,
+                tx: Vec::new(),
+            },
+        )
+    }
+
+    /// Returns the address of vpcd.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    /// Returns the local address of this connection.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+
+    /// Returns the power state of the card as requested by vpcd on this connection.
+    pub fn power_state(&self) -> PowerState {
+        self.power
+    }
+}
+
+impl From<TcpStream> for AsyncConnection {
+    fn from(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            rx: Vec::new(),
+            power: PowerState::default(),
+        }
+    }
+}
+
+/// Passes the given request to the card, updating the given power state, and returns the
+/// response that has to be sent to vpcd, if any.
+async fn handle<V: AsyncVSmartCard>(
+    request: &Request,
+    card: &mut V,
+    power: &mut PowerState,
+) -> Option<Vec<u8>> {
+    match request {
+        Request::GetAtr => {
+            debug!("Sending ATR");
+            Some(card.atr().to_vec())
+        }
+        Request::Apdu(apdu) => {
+            debug!("APDU received: {}", names::describe(apdu));
+            Some(card.execute(apdu).await)
+        }
+        _ => {
+            power::handle(request, &mut Handlers(card), power);
+            None
+        }
+    }
+}
+
+/// The receiving half of an [`AsyncConnection`][], see [`AsyncConnection::into_split`][].
+///
+/// This is a [`Stream`][] of the requests from vpcd that ends when vpcd closes the connection.
+/// Receiving a request is cancellation safe:  a partially received request is kept and
+/// completed by the next call.
+#[derive(Debug)]
+pub struct ReadHalf {
+    stream: OwnedReadHalf,
+    rx: Vec<u8>,
+}
+
+impl ReadHalf {
+    /// Receives the next request from vpcd.
+    ///
+    /// Returns an error with the kind [`UnexpectedEof`][`ErrorKind::UnexpectedEof`] if vpcd has
+    /// closed the connection.
+    pub async fn receive(&mut self) -> Result<Request> {
+        future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx))
+            .await
+            .unwrap_or_else(|| Err(io::Error::from(ErrorKind::UnexpectedEof).into()))
+    }
+}
+
+impl Stream for ReadHalf {
+    type Item = Result<Request>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(msg) = frame::decode(&mut this.rx) {
+                return Poll::Ready(Some(Request::try_from(msg)));
+            }
+            let mut buf = [0; 1024];
+            let mut buf = ReadBuf::new(&mut buf);
+            ready!(Pin::new(&mut this.stream).poll_read(cx, &mut buf))?;
+            if buf.filled().is_empty() {
+                // a partial request is an error, otherwise the stream ends
+                return Poll::Ready(
+                    (!this.rx.is_empty())
+                        .then(|| Err(io::Error::from(ErrorKind::UnexpectedEof).into())),
+                );
+            }
+            this.rx.extend_from_slice(buf.filled());
+        }
+    }
+}
+
+/// The sending half of an [`AsyncConnection`][], see [`AsyncConnection::into_split`][].
+///
+/// This is a [`Sink`][] for the responses to vpcd.  Responses passed to the sink are buffered
+/// until the sink is flushed or the next response is sent.
+#[derive(Debug)]
+pub struct WriteHalf {
+    stream: OwnedWriteHalf,
+    tx: Vec<u8>,
+}
+
+impl WriteHalf {
+    /// Sends a response to vpcd.
+    ///
+    /// Responses that have been passed to the [`Sink`][] implementation are sent first.
+    pub async fn send(&mut self, data: &[u8]) -> Result<()> {
+        let frame = frame::try_encode(data)?;
+        trace!("sending message: {:x?}", data);
+        future::poll_fn(|cx| self.poll_write_buffered(cx)).await?;
+        self.stream.write_all(&frame).await?;
+        Ok(())
+    }
+
+    /// Writes the buffered responses to the stream.
+    fn poll_write_buffered(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        while !self.tx.is_empty() {
+            let n = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.tx))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::Error::from(ErrorKind::WriteZero).into()));
+            }
+            self.tx.drain(..n);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_flush_buffered(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        ready!(self.poll_write_buffered(cx))?;
+        Poll::Ready(Ok(ready!(Pin::new(&mut self.stream).poll_flush(cx))?))
+    }
+}
+
+impl<T: AsRef<[u8]>> Sink<T> for WriteHalf {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_write_buffered(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<()> {
+        let data = item.as_ref();
+        let frame = frame::try_encode(data)?;
+        trace!("sending message: {:x?}", data);
+        self.get_mut().tx.extend_from_slice(&frame);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_flush_buffered(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_flush_buffered(cx))?;
+        Poll::Ready(Ok(ready!(Pin::new(&mut this.stream).poll_shutdown(cx))?))
+    }
+}
+
+/// Calls the power handlers of an [`AsyncVSmartCard`][] for [`power::handle`][].
+struct Handlers<'a, V>(&'a mut V);
+
+impl<V: AsyncVSmartCard> power::Handlers for Handlers<'_, V> {
+    fn power_on(&mut self) {
+        self.0.power_on()
+    }
+
+    fn power_off(&mut self) {
+        self.0.power_off()
+    }
+
+    fn reset(&mut self) {
+        self.0.reset()
+    }
+
+    fn cold_reset(&mut self) {
+        self.0.cold_reset()
+    }
+
+    fn warm_reset(&mut self) {
+        self.0.warm_reset()
+    }
+}
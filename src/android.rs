@@ -0,0 +1,70 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Support for the vsmartcard Android apps.
+//!
+//! The [Android Smart Card Emulator][] can relay the APDUs that it receives via NFC to a virtual
+//! smartcard running on a PC.  In contrast to vpcd, the app opens the connection to the card,
+//! like `vicc --reversed`, and it opens a new connection for every NFC session:  the connection
+//! is closed when the phone leaves the field of the reader and reopened for the next tap.  The
+//! app also does not necessarily power on the card before sending the first APDU.
+//!
+//! [`serve`][] listens for connections from the app and handles them one at a time using the
+//! same card.  Every connection is treated like inserting the card into a reader:  the card is
+//! powered on when the connection is accepted and powered off when it is closed, so that no
+//! state from one session leaks into the next one.
+//!
+//! # Example
+//!
+//! ```no_run
+//! fn main() -> vpicc::Result<()> {
+//!     vpicc::android::serve(("0.0.0.0", vpicc::DEFAULT_PORT), &mut vpicc::DummySmartCard)
+//! }
+//! ```
+//!
+//! [Android Smart Card Emulator]: https://frankmorgner.github.io/vsmartcard/ACardEmulator/README.html
+
+use std::{
+    io::ErrorKind,
+    net::{TcpListener, ToSocketAddrs},
+};
+
+use log::{info, warn};
+
+use crate::{Connection, PowerState, Result, VSmartCard};
+
+/// Listens on the given address and handles all connections from the app using the given card.
+///
+/// This function only returns if the address cannot be bound or accepting a connection fails.
+/// Errors on a single connection are logged and end that session.
+pub fn serve<A: ToSocketAddrs, V: VSmartCard>(addr: A, card: &mut V) -> Result<()> {
+    serve_listener(&TcpListener::bind(addr)?, card)
+}
+
+/// Handles all connections from the app on the given listener using the given card, see
+/// [`serve`][].
+pub fn serve_listener<V: VSmartCard>(listener: &TcpListener, card: &mut V) -> Result<()> {
+    info!("Waiting for the app on {}", listener.local_addr()?);
+    loop {
+        let (stream, addr) = listener.accept()?;
+        info!("Accepted connection from {}", addr);
+        let mut connection = Connection::from(stream);
+        card.power_on();
+        card.cold_reset();
+        connection.power = PowerState::On;
+        let err = loop {
+            if let Err(err) = connection.poll(card) {
+                break err;
+            }
+        };
+        match err.kind() {
+            ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::BrokenPipe => {
+                info!("Connection from {} closed", addr)
+            }
+            _ => warn!("Connection from {} failed: {}", addr, err),
+        }
+        if connection.power_state() == PowerState::On {
+            card.power_off();
+        }
+    }
+}
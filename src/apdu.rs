@@ -0,0 +1,378 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Parsing of command and response APDUs as defined in ISO 7816-4.
+
+use smallvec::SmallVec;
+
+/// The number of bytes of a [`Response`][] that are stored inline without a heap allocation.
+pub const INLINE_RESPONSE_LEN: usize = 32;
+
+/// A response APDU that stores short responses inline, see
+/// [`VSmartCard::execute_small`][`crate::VSmartCard::execute_small`].
+pub type Response = SmallVec<[u8; INLINE_RESPONSE_LEN]>;
+
+/// The status word for a successfully executed command, 9000.
+pub const SW_SUCCESS: u16 = 0x9000;
+/// The status word for an execution error without further information, 6400.
+pub const SW_EXECUTION_ERROR: u16 = 0x6400;
+/// The status word for a command with a wrong length, 6700.
+pub const SW_WRONG_LENGTH: u16 = 0x6700;
+/// The status word for a memory failure, 6581.
+pub const SW_MEMORY_FAILURE: u16 = 0x6581;
+/// The status word for an unsatisfied security status, 6982.
+pub const SW_SECURITY_STATUS_NOT_SATISFIED: u16 = 0x6982;
+/// The status word for unsatisfied conditions of use, 6985.
+pub const SW_CONDITIONS_NOT_SATISFIED: u16 = 0x6985;
+/// The status word for incorrect command data, 6A80.
+pub const SW_WRONG_DATA: u16 = 0x6a80;
+/// The status word for an unsupported function, 6A81.
+pub const SW_FUNCTION_NOT_SUPPORTED: u16 = 0x6a81;
+/// The status word for a file or application that was not found, 6A82.
+pub const SW_FILE_NOT_FOUND: u16 = 0x6a82;
+/// The status word for a record that was not found, 6A83.
+pub const SW_RECORD_NOT_FOUND: u16 = 0x6a83;
+/// The status word for incorrect parameters P1 and P2, 6A86.
+pub const SW_WRONG_P1P2: u16 = 0x6a86;
+/// The status word for referenced data that was not found, 6A88.
+pub const SW_DATA_NOT_FOUND: u16 = 0x6a88;
+/// The status word for an error without precise diagnosis, 6F00.
+pub const SW_NO_PRECISE_DIAGNOSIS: u16 = 0x6f00;
+/// The status word for an unsupported instruction, 6D00.
+pub const SW_INS_NOT_SUPPORTED: u16 = 0x6d00;
+/// The status word for an unsupported class, 6E00.
+pub const SW_CLA_NOT_SUPPORTED: u16 = 0x6e00;
+
+/// A parsed command APDU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Command<'a> {
+    /// The class byte.
+    pub cla: u8,
+    /// The instruction byte.
+    pub ins: u8,
+    /// The first parameter byte.
+    pub p1: u8,
+    /// The second parameter byte.
+    pub p2: u8,
+    /// The command data, empty if there is no Lc field.
+    pub data: &'a [u8],
+    /// The maximum number of expected response bytes, if there is an Le field.
+    pub le: Option<usize>,
+}
+
+impl<'a> Command<'a> {
+    /// Parses a command APDU with short or extended length fields.
+    ///
+    /// Returns `None` if the APDU is shorter than four bytes or if the length fields do not
+    /// match the length of the APDU.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use vpicc::apdu::Command;
+    ///
+    /// let command = Command::parse(&[0x00, 0xa4, 0x04, 0x00, 0x02, 0x3f, 0x00, 0x00]).unwrap();
+    /// assert_eq!(command.ins, 0xa4);
+    /// assert_eq!(command.data, [0x3f, 0x00]);
+    /// assert_eq!(command.le, Some(256));
+    /// ```
+    pub fn parse(apdu: &'a [u8]) -> Option<Self> {
+        let (header, body) = apdu.split_first_chunk::<4>()?;
+        let [cla, ins, p1, p2] = *header;
+        let (data, le) = match *body {
+            [] => (&body[..0], None),
+            [le] => (&body[..0], Some(short_le(le))),
+            [0, hi, lo] => (&body[..0], Some(extended_le(hi, lo))),
+            [0, hi, lo, ref rest @ ..]
+                if rest.len() >= usize::from(u16::from_be_bytes([hi, lo])) =>
+            {
+                let lc = usize::from(u16::from_be_bytes([hi, lo]));
+                match rest.len() - lc {
+                    0 => (&rest[..lc], None),
+                    2 => (&rest[..lc], Some(extended_le(rest[lc], rest[lc + 1]))),
+                    _ => return None,
+                }
+            }
+            [lc, ref rest @ ..] if lc != 0 && rest.len() >= usize::from(lc) => {
+                let lc = usize::from(lc);
+                match rest.len() - lc {
+                    0 => (&rest[..lc], None),
+                    1 => (&rest[..lc], Some(short_le(rest[lc]))),
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        };
+        Some(Self {
+            cla,
+            ins,
+            p1,
+            p2,
+            data,
+            le,
+        })
+    }
+}
+
+/// The secure messaging indication of an interindustry class byte.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SecureMessaging {
+    /// No secure messaging or no indication.
+    None,
+    /// Proprietary secure messaging, only available for channels 0 to 3.
+    Proprietary,
+    /// Secure messaging as defined in ISO 7816-4 without authentication of the command header.
+    Standard,
+    /// Secure messaging as defined in ISO 7816-4 with authentication of the command header,
+    /// only available for channels 0 to 3.
+    HeaderAuthenticated,
+}
+
+/// The fields of an interindustry class byte as defined in ISO 7816-4.
+///
+/// Channels 0 to 3 use the first interindustry encoding (CLA 0x to 1x), channels 4 to 19 use
+/// the further interindustry encoding (CLA 4x to 7x).
+///
+/// # Example
+///
+/// ```
+/// use vpicc::apdu::{Class, SecureMessaging};
+///
+/// let class = Class::parse(0x0d).unwrap();
+/// assert_eq!(class.channel, 1);
+/// assert_eq!(class.secure_messaging, SecureMessaging::HeaderAuthenticated);
+/// assert_eq!(Class { channel: 5, ..class }.to_byte(), None);
+///
+/// let class = Class::parse(0x61).unwrap();
+/// assert_eq!(class.channel, 5);
+/// assert_eq!(class.secure_messaging, SecureMessaging::Standard);
+/// assert_eq!(class.to_byte(), Some(0x61));
+///
+/// // proprietary class
+/// assert_eq!(Class::parse(0x80), None);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Class {
+    /// The logical channel number, 0 to 19.
+    pub channel: u8,
+    /// The secure messaging indication.
+    pub secure_messaging: SecureMessaging,
+    /// The command chaining bit.
+    pub chaining: bool,
+}
+
+impl Class {
+    /// Parses an interindustry class byte.
+    ///
+    /// Returns `None` for proprietary and reserved class bytes.
+    pub fn parse(cla: u8) -> Option<Self> {
+        let chaining = cla & 0x10 != 0;
+        match cla & 0xe0 {
+            0x00 => {
+                let secure_messaging = match (cla >> 2) & 0x03 {
+                    0 => SecureMessaging::None,
+                    1 => SecureMessaging::Proprietary,
+                    2 => SecureMessaging::Standard,
+                    _ => SecureMessaging::HeaderAuthenticated,
+                };
+                Some(Self {
+                    channel: cla & 0x03,
+                    secure_messaging,
+                    chaining,
+                })
+            }
+            0x40 | 0x60 => Some(Self {
+                channel: (cla & 0x0f) + 4,
+                secure_messaging: if cla & 0x20 != 0 {
+                    SecureMessaging::Standard
+                } else {
+                    SecureMessaging::None
+                },
+                chaining,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Encodes this class as a class byte.
+    ///
+    /// Returns `None` if the channel is larger than 19, or if the secure messaging indication
+    /// cannot be encoded for the channel.
+    pub fn to_byte(&self) -> Option<u8> {
+        let chaining = if self.chaining { 0x10 } else { 0x00 };
+        match self.channel {
+            0..=3 => {
+                let secure_messaging = match self.secure_messaging {
+                    SecureMessaging::None => 0x00,
+                    SecureMessaging::Proprietary => 0x04,
+                    SecureMessaging::Standard => 0x08,
+                    SecureMessaging::HeaderAuthenticated => 0x0c,
+                };
+                Some(chaining | secure_messaging | self.channel)
+            }
+            4..=19 => {
+                let secure_messaging = match self.secure_messaging {
+                    SecureMessaging::None => 0x00,
+                    SecureMessaging::Standard => 0x20,
+                    _ => return None,
+                };
+                Some(0x40 | secure_messaging | chaining | (self.channel - 4))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// An application that handles parsed command APDUs.
+///
+/// With the `derive` feature, this trait can be implemented using the
+/// `applet` attribute macro.
+pub trait Applet {
+    /// Handles the given command and returns the response data or an error status word.
+    fn dispatch(&mut self, command: &Command<'_>) -> Result<Vec<u8>, u16>;
+
+    /// Clears the security status of the applet, like verified PINs and secure messaging
+    /// sessions.
+    ///
+    /// This is called by [`execute_command`][`Applet::execute_command`] before a command that
+    /// resets the security status on a real card is dispatched, see [`resets_security`][].
+    /// Applets should also call it when the card is powered off or reset.  The default
+    /// implementation does nothing.
+    fn reset_security(&mut self) {}
+
+    /// Parses the given command APDU, handles it using [`dispatch`][`Applet::dispatch`] and
+    /// returns the response APDU.
+    ///
+    /// This can be used to implement [`VSmartCard::execute`][`crate::VSmartCard::execute`].
+    /// Malformed commands are rejected with [`SW_WRONG_LENGTH`][].
+    fn execute_command(&mut self, msg: &[u8]) -> Vec<u8> {
+        let result = Command::parse(msg)
+            .ok_or(SW_WRONG_LENGTH)
+            .and_then(|command| {
+                if resets_security(&command) {
+                    self.reset_security();
+                }
+                self.dispatch(&command)
+            });
+        match result {
+            Ok(data) => response(&data, SW_SUCCESS),
+            Err(status) => response(&[], status),
+        }
+    }
+}
+
+/// Returns true if the given command resets the security status of the selected application.
+///
+/// This is the case for a SELECT by DF name, which selects an application, and for a MANAGE
+/// CHANNEL command that closes a logical channel.
+///
+/// # Example
+///
+/// ```
+/// use vpicc::apdu::{self, Command};
+///
+/// let select = Command::parse(&[0x00, 0xa4, 0x04, 0x00, 0x01, 0xa0]).unwrap();
+/// assert!(apdu::resets_security(&select));
+/// let read = Command::parse(&[0x00, 0xb0, 0x00, 0x00]).unwrap();
+/// assert!(!apdu::resets_security(&read));
+/// ```
+pub fn resets_security(command: &Command<'_>) -> bool {
+    match command.ins {
+        0xa4 => command.p1 == 0x04,
+        0x70 => command.p1 == 0x80,
+        _ => false,
+    }
+}
+
+/// Returns true if the given command APDU uses extended length fields.
+///
+/// # Example
+///
+/// ```
+/// assert!(vpicc::apdu::is_extended(&[0x00, 0xb0, 0x00, 0x00, 0x00, 0x01, 0x00]));
+/// assert!(!vpicc::apdu::is_extended(&[0x00, 0xb0, 0x00, 0x00, 0x00]));
+/// ```
+pub fn is_extended(msg: &[u8]) -> bool {
+    // short Lc fields cannot be zero, so a zero byte after the header starts an extended field
+    msg.len() >= 7 && msg[4] == 0
+}
+
+/// Returns the status word of the given response APDU, or `None` if it is too short.
+pub fn status(response: &[u8]) -> Option<u16> {
+    let (_, sw) = response.split_last_chunk::<2>()?;
+    Some(u16::from_be_bytes(*sw))
+}
+
+/// Builds a response APDU from the given data and status word.
+///
+/// # Example
+///
+/// ```
+/// assert_eq!(vpicc::apdu::response(&[0x01], vpicc::apdu::SW_SUCCESS), [0x01, 0x90, 0x00]);
+/// ```
+pub fn response(data: &[u8], status: u16) -> Vec<u8> {
+    [data, &status.to_be_bytes()].concat()
+}
+
+/// Builds a [`Response`][] from the given data and status word, without a heap allocation if it
+/// fits into [`INLINE_RESPONSE_LEN`][] bytes.
+///
+/// # Example
+///
+/// ```
+/// let response = vpicc::apdu::small_response(&[], vpicc::apdu::SW_SUCCESS);
+/// assert_eq!(response.as_slice(), [0x90, 0x00]);
+/// assert!(!response.spilled());
+/// ```
+pub fn small_response(data: &[u8], status: u16) -> Response {
+    let mut response = Response::with_capacity(data.len() + 2);
+    response.extend_from_slice(data);
+    response.extend_from_slice(&status.to_be_bytes());
+    response
+}
+
+/// Returns a response with the next chunk of the given pending data, at most `le` or 256 bytes.
+///
+/// If more data is left, the status word is 61xx, where xx is the number of remaining bytes or
+/// 00 if there are more than 255, so that the host fetches the rest with GET RESPONSE.
+///
+/// # Example
+///
+/// ```
+/// let mut pending = vec![0xab; 300];
+/// let response = vpicc::apdu::response_chunk(&mut pending, None);
+/// assert_eq!(response.len(), 258);
+/// assert_eq!(response[256..], [0x61, 0x2c]);
+/// assert_eq!(vpicc::apdu::response_chunk(&mut pending, Some(0x2c))[0x2c..], [0x90, 0x00]);
+/// ```
+pub fn response_chunk(pending: &mut Vec<u8>, le: Option<usize>) -> Vec<u8> {
+    let len = le.unwrap_or(256).min(pending.len());
+    let mut response = Vec::with_capacity(len + 2);
+    response.extend(pending.drain(..len));
+    let status = match pending.len() {
+        0 => SW_SUCCESS,
+        n => 0x6100 | n.min(0xff) as u16,
+    };
+    response.extend_from_slice(&status.to_be_bytes());
+    response
+}
+
+/// Returns true if the given status word indicates success, i. e. 9000 or 61xx.
+pub fn is_success(status: u16) -> bool {
+    status == SW_SUCCESS || status >> 8 == 0x61
+}
+
+fn short_le(le: u8) -> usize {
+    if le == 0 {
+        256
+    } else {
+        le.into()
+    }
+}
+
+fn extended_le(hi: u8, lo: u8) -> usize {
+    match u16::from_be_bytes([hi, lo]) {
+        0 => 65536,
+        le => le.into(),
+    }
+}
@@ -0,0 +1,444 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Building and parsing of answers to reset (ATR) as defined in ISO 7816-3.
+//!
+//! An [`Atr`][] consists of the initial character TS, the format byte T0, groups of interface
+//! bytes that describe the transmission parameters and the supported protocols, the historical
+//! bytes and, if a protocol other than T=0 is indicated, the check byte TCK.  [`Atr::new`][]
+//! builds an ATR from the protocols and historical bytes, [`Atr::parse`][] parses an existing
+//! ATR, and the [`Display`][`fmt::Display`] implementation explains its contents.
+//!
+//! # Example
+//!
+//! ```
+//! use vpicc::atr::Atr;
+//!
+//! let atr = Atr::parse(vpicc::DEFAULT_ATR)?;
+//! assert_eq!(atr.protocols(), [1]);
+//! assert_eq!(atr.historical_bytes(), [0x80, 0x73, 0xff, 0x01, 0x00]);
+//! println!("{}", atr);
+//!
+//! // TD1 and TD2 both indicate T=1
+//! let mut atr = Atr::new(&[1, 1], &[0x80, 0x73, 0xff, 0x01, 0x00]);
+//! atr.set_ta1(Some(0x13));
+//! assert_eq!(atr.to_bytes(), vpicc::DEFAULT_ATR);
+//! # Ok::<_, std::io::Error>(())
+//! ```
+
+use std::{
+    fmt,
+    io::{Error, ErrorKind, Result},
+};
+
+use crate::hex;
+
+/// The initial character for the direct convention.
+pub const TS_DIRECT: u8 = 0x3b;
+/// The initial character for the inverse convention.
+pub const TS_INVERSE: u8 = 0x3f;
+/// The maximum number of historical bytes.
+pub const MAX_HISTORICAL_BYTES: usize = 15;
+
+/// Well-known ATRs that can be looked up with [`preset`][].
+pub const PRESETS: &[(&str, &[u8])] = &[
+    ("default", crate::DEFAULT_ATR),
+    ("nitrokey3", crate::admin::ATR),
+    (
+        "openpgp3",
+        &[
+            0x3b, 0xda, 0x18, 0xff, 0x81, 0xb1, 0xfe, 0x75, 0x1f, 0x03, 0x00, 0x31, 0xf5, 0x73,
+            0xc0, 0x01, 0x60, 0x00, 0x90, 0x00, 0x1c,
+        ],
+    ),
+    (
+        "yubikey5",
+        &[
+            0x3b, 0xfd, 0x13, 0x00, 0x00, 0x81, 0x31, 0xfe, 0x15, 0x80, 0x73, 0xc0, 0x21, 0xc0,
+            0x57, 0x59, 0x75, 0x62, 0x69, 0x4b, 0x65, 0x79, 0x40,
+        ],
+    ),
+];
+
+/// Returns the well-known ATR with the given name from [`PRESETS`][].
+///
+/// The names are `default` for [`DEFAULT_ATR`][`crate::DEFAULT_ATR`], `nitrokey3` for a
+/// Nitrokey 3, `openpgp3` for an OpenPGP card 3.4 and `yubikey5` for a YubiKey 5.
+pub fn preset(name: &str) -> Option<&'static [u8]> {
+    PRESETS
+        .iter()
+        .find(|(preset, _)| *preset == name)
+        .map(|(_, atr)| *atr)
+}
+
+/// The clock rate conversion factors Fi indexed by the high nibble of TA1, `None` if reserved.
+const FI: [Option<u16>; 16] = [
+    Some(372),
+    Some(372),
+    Some(558),
+    Some(744),
+    Some(1116),
+    Some(1488),
+    Some(1860),
+    None,
+    None,
+    Some(512),
+    Some(768),
+    Some(1024),
+    Some(1536),
+    Some(2048),
+    None,
+    None,
+];
+
+/// The baud rate adjustment factors Di indexed by the low nibble of TA1, `None` if reserved.
+const DI: [Option<u8>; 16] = [
+    None,
+    Some(1),
+    Some(2),
+    Some(4),
+    Some(8),
+    Some(16),
+    Some(32),
+    Some(64),
+    Some(12),
+    Some(20),
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+];
+
+/// A group of interface bytes TAi, TBi, TCi and TDi.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InterfaceBytes {
+    /// The interface byte TAi.
+    pub ta: Option<u8>,
+    /// The interface byte TBi.
+    pub tb: Option<u8>,
+    /// The interface byte TCi.
+    pub tc: Option<u8>,
+    /// The protocol indicated by TDi, if present.  The presence of the following interface
+    /// bytes is computed when encoding the ATR.
+    pub protocol: Option<u8>,
+}
+
+/// An answer to reset, see the [module documentation][`self`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Atr {
+    ts: u8,
+    interface: Vec<InterfaceBytes>,
+    historical: Vec<u8>,
+}
+
+impl Atr {
+    /// Creates an ATR using the direct convention that indicates the given protocols and
+    /// contains the given historical bytes.
+    ///
+    /// If no protocols are given, T=0 is implied.  At most [`MAX_HISTORICAL_BYTES`][] historical
+    /// bytes are used.
+    pub fn new(protocols: &[u8], historical: &[u8]) -> Self {
+        let mut interface = vec![InterfaceBytes::default()];
+        for (i, &protocol) in protocols.iter().enumerate() {
+            interface[i].protocol = Some(protocol & 0x0f);
+            interface.push(InterfaceBytes::default());
+        }
+        let historical = historical[..historical.len().min(MAX_HISTORICAL_BYTES)].to_vec();
+        Self {
+            ts: TS_DIRECT,
+            interface,
+            historical,
+        }
+    }
+
+    /// Parses an ATR.
+    ///
+    /// Returns an error if the ATR is truncated, contains trailing bytes or has an invalid
+    /// check byte.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let invalid =
+            |msg: &str| Error::new(ErrorKind::InvalidData, format!("invalid ATR: {}", msg));
+        let mut bytes = data.iter().copied();
+        let mut next = |name: &str| {
+            bytes
+                .next()
+                .ok_or_else(|| invalid(&format!("missing {}", name)))
+        };
+        let ts = next("TS")?;
+        if ts != TS_DIRECT && ts != TS_INVERSE {
+            return Err(invalid(&format!("unknown initial character {:02x}", ts)));
+        }
+        let t0 = next("T0")?;
+        let mut interface = Vec::new();
+        let mut y = t0 >> 4;
+        loop {
+            let i = interface.len() + 1;
+            let mut group = InterfaceBytes::default();
+            if y & 0x1 != 0 {
+                group.ta = Some(next(&format!("TA{}", i))?);
+            }
+            if y & 0x2 != 0 {
+                group.tb = Some(next(&format!("TB{}", i))?);
+            }
+            if y & 0x4 != 0 {
+                group.tc = Some(next(&format!("TC{}", i))?);
+            }
+            let td = if y & 0x8 != 0 {
+                Some(next(&format!("TD{}", i))?)
+            } else {
+                None
+            };
+            group.protocol = td.map(|td| td & 0x0f);
+            interface.push(group);
+            match td {
+                Some(td) => y = td >> 4,
+                None => break,
+            }
+        }
+        let historical = (0..t0 & 0x0f)
+            .map(|_| next("historical byte"))
+            .collect::<Result<Vec<_>>>()?;
+        let atr = Self {
+            ts,
+            interface,
+            historical,
+        };
+        if atr.has_tck() {
+            let tck = next("TCK")?;
+            let expected = checksum(&data[1..data.len() - 1 - bytes.len()]);
+            if tck != expected {
+                return Err(invalid(&format!(
+                    "wrong TCK {:02x}, expected {:02x}",
+                    tck, expected
+                )));
+            }
+        }
+        if bytes.len() > 0 {
+            return Err(invalid("trailing bytes"));
+        }
+        Ok(atr)
+    }
+
+    /// Returns the initial character TS.
+    pub fn ts(&self) -> u8 {
+        self.ts
+    }
+
+    /// Returns the groups of interface bytes, starting with TA1, TB1, TC1 and TD1.
+    pub fn interface_bytes(&self) -> &[InterfaceBytes] {
+        &self.interface
+    }
+
+    /// Returns a mutable reference to the groups of interface bytes.
+    pub fn interface_bytes_mut(&mut self) -> &mut Vec<InterfaceBytes> {
+        &mut self.interface
+    }
+
+    /// Returns the historical bytes.
+    pub fn historical_bytes(&self) -> &[u8] {
+        &self.historical
+    }
+
+    /// Sets the historical bytes, using at most [`MAX_HISTORICAL_BYTES`][] bytes.
+    pub fn set_historical_bytes(&mut self, historical: &[u8]) {
+        self.historical = historical[..historical.len().min(MAX_HISTORICAL_BYTES)].to_vec();
+    }
+
+    /// Sets the historical bytes to a card capabilities data object (ISO 7816-4 8.1.1.2.7) that
+    /// matches the given capabilities.
+    ///
+    /// Selection by full and partial DF name is indicated if the card declares AIDs, and extended
+    /// Lc and Le fields are indicated if the card supports extended length.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use vpicc::{atr::Atr, Capabilities};
+    ///
+    /// let capabilities = Capabilities {
+    ///     aids: vec![vec![0xa0, 0x00, 0x00, 0x03, 0x08]],
+    ///     extended_length: true,
+    ///     max_response_len: 65536,
+    /// };
+    /// let mut atr = Atr::default();
+    /// atr.set_capabilities(&capabilities);
+    /// assert_eq!(atr.historical_bytes(), [0x80, 0x73, 0xc0, 0x01, 0x40]);
+    /// ```
+    pub fn set_capabilities(&mut self, capabilities: &crate::Capabilities) {
+        let selection = if capabilities.aids.is_empty() {
+            0x00
+        } else {
+            0xc0
+        };
+        let extended = if capabilities.extended_length {
+            0x40
+        } else {
+            0x00
+        };
+        self.historical = vec![0x80, 0x73, selection, 0x01, extended];
+    }
+
+    /// Returns the interface byte TA1 that encodes the clock rate conversion factor Fi and the
+    /// baud rate adjustment factor Di.
+    pub fn ta1(&self) -> Option<u8> {
+        self.interface.first().and_then(|group| group.ta)
+    }
+
+    /// Sets the interface byte TA1.
+    pub fn set_ta1(&mut self, ta1: Option<u8>) {
+        if self.interface.is_empty() {
+            self.interface.push(InterfaceBytes::default());
+        }
+        self.interface[0].ta = ta1;
+    }
+
+    /// Returns the factors Fi and Di encoded in TA1, or the defaults 372 and 1 if TA1 is absent.
+    ///
+    /// Returns `None` for reserved values.
+    pub fn fi_di(&self) -> Option<(u16, u8)> {
+        match self.ta1() {
+            Some(ta1) => Some((FI[usize::from(ta1 >> 4)]?, DI[usize::from(ta1 & 0x0f)]?)),
+            None => Some((372, 1)),
+        }
+    }
+
+    /// Returns the protocols indicated in this ATR without duplicates, or T=0 if no protocol is
+    /// indicated.
+    pub fn protocols(&self) -> Vec<u8> {
+        let mut protocols = Vec::new();
+        for protocol in self.interface.iter().filter_map(|group| group.protocol) {
+            if protocol != 15 && !protocols.contains(&protocol) {
+                protocols.push(protocol);
+            }
+        }
+        if protocols.is_empty() {
+            protocols.push(0);
+        }
+        protocols
+    }
+
+    /// Encodes this ATR, computing T0, the TDi presence indicators and TCK.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut groups = self.interface.clone();
+        if groups.is_empty() {
+            groups.push(InterfaceBytes::default());
+        }
+        // Remove trailing empty groups that are not announced by a TDi.
+        while groups.len() > 1 && groups[groups.len() - 1] == InterfaceBytes::default() {
+            if groups[groups.len() - 2].protocol.is_some() {
+                break;
+            }
+            groups.pop();
+        }
+        let mut atr = vec![
+            self.ts,
+            (presence(&groups[0]) << 4) | self.historical.len() as u8,
+        ];
+        for (i, group) in groups.iter().enumerate() {
+            atr.extend(group.ta);
+            atr.extend(group.tb);
+            atr.extend(group.tc);
+            if let Some(protocol) = group.protocol {
+                let y = groups.get(i + 1).map(presence).unwrap_or_default();
+                atr.push((y << 4) | protocol);
+            }
+        }
+        atr.extend_from_slice(&self.historical);
+        if self.has_tck() {
+            atr.push(checksum(&atr[1..]));
+        }
+        atr
+    }
+
+    /// Returns true if the ATR contains the check byte TCK, i. e. if any protocol other than
+    /// T=0 is indicated.
+    fn has_tck(&self) -> bool {
+        self.interface
+            .iter()
+            .filter_map(|group| group.protocol)
+            .any(|protocol| protocol != 0)
+    }
+}
+
+impl Default for Atr {
+    fn default() -> Self {
+        Self::new(&[], &[])
+    }
+}
+
+impl fmt::Display for Atr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "ATR: {}", hex::encode(&self.to_bytes()))?;
+        let convention = if self.ts == TS_INVERSE {
+            "inverse"
+        } else {
+            "direct"
+        };
+        writeln!(f, "  TS  = {:02x}  {} convention", self.ts, convention)?;
+        for (i, group) in self.interface.iter().enumerate() {
+            let i = i + 1;
+            if let Some(ta) = group.ta {
+                write!(f, "  TA{} = {:02x}", i, ta)?;
+                if i == 1 {
+                    match self.fi_di() {
+                        Some((fi, di)) => write!(f, "  Fi = {}, Di = {}", fi, di)?,
+                        None => write!(f, "  reserved Fi/Di")?,
+                    }
+                }
+                writeln!(f)?;
+            }
+            if let Some(tb) = group.tb {
+                writeln!(f, "  TB{} = {:02x}", i, tb)?;
+            }
+            if let Some(tc) = group.tc {
+                write!(f, "  TC{} = {:02x}", i, tc)?;
+                if i == 1 {
+                    write!(f, "  extra guard time = {} etu", tc)?;
+                }
+                writeln!(f)?;
+            }
+            if let Some(protocol) = group.protocol {
+                match protocol {
+                    15 => writeln!(f, "  TD{}  global interface bytes follow", i)?,
+                    protocol => writeln!(f, "  TD{}  protocol T={}", i, protocol)?,
+                }
+            }
+        }
+        let protocols: Vec<String> = self
+            .protocols()
+            .iter()
+            .map(|protocol| format!("T={}", protocol))
+            .collect();
+        writeln!(f, "  protocols: {}", protocols.join(", "))?;
+        write!(f, "  historical bytes: {}", hex::encode(&self.historical))?;
+        if !self.historical.is_empty() && self.historical.iter().all(u8::is_ascii_graphic) {
+            write!(f, " ({:?})", String::from_utf8_lossy(&self.historical))?;
+        }
+        match self.historical.first() {
+            Some(0x80) => write!(f, ", compact-TLV objects follow")?,
+            Some(0x00) => write!(f, ", status indicator at the end")?,
+            Some(0x10) => write!(f, ", DIR data reference follows")?,
+            _ => {}
+        }
+        if self.has_tck() {
+            let atr = self.to_bytes();
+            write!(f, "\n  TCK = {:02x}", atr[atr.len() - 1])?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the presence indicators for the interface bytes of the given group.
+fn presence(group: &InterfaceBytes) -> u8 {
+    u8::from(group.ta.is_some())
+        | u8::from(group.tb.is_some()) << 1
+        | u8::from(group.tc.is_some()) << 2
+        | u8::from(group.protocol.is_some()) << 3
+}
+
+/// Computes TCK for the given bytes from T0 to the last historical byte.
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |tck, byte| tck ^ byte)
+}
@@ -0,0 +1,42 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! An in-memory transport for connections that are driven by another I/O loop.
+
+use std::io::{ErrorKind, Read, Result, Write};
+
+/// A transport that reads from and writes to memory buffers.
+///
+/// Loops that read and write the socket themselves, like the [`Scheduler`][`crate::Scheduler`]
+/// and the [`Multiplexer`][`crate::mux::Multiplexer`], append the received data to `input`,
+/// call [`Connection::poll`][`crate::Connection::poll`] until it fails with
+/// [`WouldBlock`][`ErrorKind::WouldBlock`] and then send the data from `output`.  A partial
+/// request is kept by the connection until the rest of it has been received.
+#[derive(Debug, Default)]
+pub struct Buffer {
+    pub input: Vec<u8>,
+    pub output: Vec<u8>,
+}
+
+impl Read for Buffer {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.input.is_empty() && !buf.is_empty() {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        let n = buf.len().min(self.input.len());
+        buf[..n].copy_from_slice(&self.input[..n]);
+        self.input.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.output.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
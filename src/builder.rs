@@ -0,0 +1,176 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+use std::{
+    fmt::Display,
+    io::{self, ErrorKind},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use log::info;
+
+use crate::{Connection, Result, DEFAULT_HOST, DEFAULT_PORT};
+
+/// Returns a [`ConnectionBuilder`][] with the default settings.
+pub fn builder() -> ConnectionBuilder {
+    ConnectionBuilder::default()
+}
+
+/// Configures the socket options of a [`Connection`][] before connecting to vpcd.
+///
+/// By default, the options of the operating system are used, which is equivalent to
+/// [`connect`][`crate::connect`] and [`connect_socket`][`crate::connect_socket`].
+///
+/// # Example
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// fn main() -> vpicc::Result<()> {
+///     let connection = vpicc::builder()
+///         .connect_timeout(Duration::from_secs(5))
+///         .nodelay(true)
+///         .keepalive(Duration::from_secs(30))
+///         .fallback_atr(vpicc::DEFAULT_ATR)
+///         .connect_socket("vpcd:35963")?;
+///     connection.run(&mut vpicc::DummySmartCard)
+/// }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionBuilder {
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    nodelay: Option<bool>,
+    keepalive: Option<Duration>,
+    fallback_atr: Option<Vec<u8>>,
+}
+
+impl ConnectionBuilder {
+    /// Creates a builder with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the timeout for establishing the connection to every address of vpcd.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the read timeout of the socket, see [`TcpStream::set_read_timeout`][].
+    ///
+    /// If no request is received in time, [`poll`][`Connection::poll`] returns an error with the
+    /// kind [`WouldBlock`][`ErrorKind::WouldBlock`] or [`TimedOut`][`ErrorKind::TimedOut`].  A
+    /// partially received request is kept and completed by the next call.
+    /// [`run`][`Connection::run`] keeps waiting while no request is pending, because vpcd does
+    /// not send requests while no application uses the card, and only returns the error if vpcd
+    /// stops sending in the middle of a request.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the write timeout of the socket, see [`TcpStream::set_write_timeout`][].
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Enables or disables Nagle's algorithm, see [`TcpStream::set_nodelay`][].
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = Some(nodelay);
+        self
+    }
+
+    /// Enables TCP keepalive probes after the connection has been idle for the given time.
+    ///
+    /// This is only supported on Linux.  On other platforms, connecting fails with an error
+    /// with the kind [`Unsupported`][`ErrorKind::Unsupported`].
+    pub fn keepalive(mut self, idle: Duration) -> Self {
+        self.keepalive = Some(idle);
+        self
+    }
+
+    /// Sets the ATR that is sent to vpcd if the card returns an empty ATR, see
+    /// [`Connection::set_fallback_atr`][].
+    pub fn fallback_atr(mut self, atr: impl Into<Vec<u8>>) -> Self {
+        self.fallback_atr = Some(atr.into());
+        self
+    }
+
+    /// Connects to the vpcd daemon using [`DEFAULT_HOST`][] and [`DEFAULT_PORT`][].
+    pub fn connect(self) -> Result<Connection> {
+        self.connect_socket(SocketAddr::new(DEFAULT_HOST.into(), DEFAULT_PORT))
+    }
+
+    /// Connects to the vpcd daemon at the given address.
+    pub fn connect_socket<A: ToSocketAddrs + Display>(self, addr: A) -> Result<Connection> {
+        info!("Connecting to vpcd on {}", addr);
+        let stream = match self.connect_timeout {
+            Some(timeout) => connect_timeout(&addr, timeout)?,
+            None => TcpStream::connect(&addr)?,
+        };
+        self.configure(&stream)?;
+        let mut connection = Connection::new(stream);
+        connection.fallback_atr = self.fallback_atr;
+        Ok(connection)
+    }
+
+    fn configure(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(self.read_timeout)?;
+        stream.set_write_timeout(self.write_timeout)?;
+        if let Some(nodelay) = self.nodelay {
+            stream.set_nodelay(nodelay)?;
+        }
+        if let Some(idle) = self.keepalive {
+            set_keepalive(stream, idle)?;
+        }
+        Ok(())
+    }
+}
+
+fn connect_timeout<A: ToSocketAddrs>(addr: &A, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(ErrorKind::NotFound, "address did not resolve");
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_error = err,
+        }
+    }
+    Err(last_error)
+}
+
+#[cfg(target_os = "linux")]
+fn set_keepalive(stream: &TcpStream, idle: Duration) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let set = |level, name, value: libc::c_int| {
+        let result = unsafe {
+            libc::setsockopt(
+                stream.as_raw_fd(),
+                level,
+                name,
+                (&value as *const libc::c_int).cast(),
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    };
+    let idle = idle.as_secs().clamp(1, libc::c_int::MAX as u64) as libc::c_int;
+    set(libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, idle)?;
+    set(libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_keepalive(_stream: &TcpStream, _idle: Duration) -> io::Result<()> {
+    Err(io::Error::new(
+        ErrorKind::Unsupported,
+        "TCP keepalive is only supported on Linux",
+    ))
+}
@@ -0,0 +1,229 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! A control interface for running [`Registry`][] instances.
+//!
+//! The [`ControlServer`][] accepts simple line-based commands, for example from `socat` or
+//! `nc -U`, and answers every command with a single line that starts with `ok` or `error`:
+//!
+//! | Command                | Description                                  |
+//! |------------------------|----------------------------------------------|
+//! | `list`                 | lists the names of all cards                 |
+//! | `profiles`             | lists the names of all registered profiles   |
+//! | `stats <card>`         | shows the status and statistics of a card    |
+//! | `reset <card>`         | resets a card                                |
+//! | `restart <card>`       | reconnects a card to vpcd                    |
+//! | `remove <card>`        | stops and removes a card                     |
+//! | `swap <card> <profile>`| replaces a card with a new card of a profile |
+//!
+//! The protocol has no quoting or escaping.  Commands and responses have this grammar:
+//!
+//! ```text
+//! command  = word *(" " word)
+//! response = "ok" [" " text] / "error " text
+//! word     = 1*<any character except whitespace and control characters>
+//! text     = *<any character except line breaks>
+//! ```
+//!
+//! Commands are split at any whitespace.  Card and profile names must be words, see
+//! [`is_valid_name`][].  [`ControlServer::add_profile`][] rejects other names, and `list`
+//! responds with an error if a card of the registry has a name that is not a word, because it
+//! could not be told apart from other names.  Such cards can be managed using the
+//! [`Registry`][] directly.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::sync::{Arc, Mutex};
+//! use vpicc::{control::ControlServer, Registry};
+//!
+//! fn main() -> std::io::Result<()> {
+//!     let registry = Arc::new(Mutex::new(Registry::new()));
+//!     let addr = "127.0.0.1:35963".parse().unwrap();
+//!     registry.lock().unwrap().add("card", addr, vpicc::DummySmartCard)?;
+//!
+//!     let mut server = ControlServer::new(registry);
+//!     server.add_profile("dummy", || Box::new(vpicc::DummySmartCard))?;
+//!     server.serve("/run/vpicc.sock")
+//! }
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{Error, ErrorKind, Result},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use log::{debug, info};
+
+use crate::{BoxedCard, Registry};
+
+type Profile = Box<dyn Fn() -> BoxedCard + Send + Sync>;
+
+/// Serves control commands for a [`Registry`][], see the [module documentation][`self`].
+pub struct ControlServer {
+    registry: Arc<Mutex<Registry>>,
+    pub(crate) profiles: BTreeMap<String, Profile>,
+}
+
+impl ControlServer {
+    /// Creates a control server for the given registry.
+    pub fn new(registry: Arc<Mutex<Registry>>) -> Self {
+        Self {
+            registry,
+            profiles: Default::default(),
+        }
+    }
+
+    /// Registers a profile that can be used with the `swap` command.
+    ///
+    /// Returns an error if the name is not a word, see [`is_valid_name`][].
+    pub fn add_profile<F>(&mut self, name: impl Into<String>, factory: F) -> Result<()>
+    where
+        F: Fn() -> BoxedCard + Send + Sync + 'static,
+    {
+        let name = name.into();
+        if !is_valid_name(&name) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid profile name {:?}", name),
+            ));
+        }
+        self.profiles.insert(name, Box::new(factory));
+        Ok(())
+    }
+
+    /// Executes a single command and returns the response line without a trailing newline.
+    pub fn handle(&self, command: &str) -> String {
+        debug!("Received control command {:?}", command);
+        let response = match self.execute(command) {
+            Ok(response) if response.is_empty() => "ok".to_owned(),
+            Ok(response) => format!("ok {}", response),
+            Err(err) => format!("error {}", err),
+        };
+        // error messages from cards could contain line breaks
+        response.replace(['\r', '\n'], " ")
+    }
+
+    /// Binds a Unix domain socket at the given path and serves control commands.
+    ///
+    /// Clients are served one after another.  This function only returns if accepting a client
+    /// fails.
+    #[cfg(unix)]
+    pub fn serve<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
+        use std::{
+            io::{BufRead, BufReader, Write},
+            os::unix::net::UnixListener,
+        };
+
+        let path = path.as_ref();
+        info!("Serving control commands on {}", path.display());
+        let listener = UnixListener::bind(path)?;
+        for stream in listener.incoming() {
+            let mut stream = stream?;
+            let reader = BufReader::new(stream.try_clone()?);
+            for line in reader.lines() {
+                let Ok(line) = line else { break };
+                let response = self.handle(line.trim());
+                if writeln!(stream, "{}", response).is_err() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn execute(&self, command: &str) -> Result<String> {
+        let args: Vec<&str> = command.split_whitespace().collect();
+        match args.as_slice() {
+            ["list"] => {
+                let registry = self.registry();
+                if let Some(name) = registry.names().find(|name| !is_valid_name(name)) {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("card name {:?} cannot be listed", name),
+                    ));
+                }
+                Ok(join(registry.names()))
+            }
+            ["profiles"] => Ok(join(self.profiles.keys().map(String::as_str))),
+            ["stats", name] => {
+                let registry = self.registry();
+                let stats = registry
+                    .stats(name)
+                    .ok_or_else(|| not_found("card", name))?;
+                let mut response = String::new();
+                write!(
+                    response,
+                    "running={} apdus={} atr_requests={} power_ons={} power_offs={} resets={}",
+                    registry.is_running(name).unwrap_or_default(),
+                    stats.apdus,
+                    stats.atr_requests,
+                    stats.power_ons,
+                    stats.power_offs,
+                    stats.resets
+                )
+                .ok();
+                Ok(response)
+            }
+            ["reset", name] => {
+                self.registry().reset(name)?;
+                Ok(String::new())
+            }
+            ["restart", name] => {
+                self.registry().restart(name)?;
+                Ok(String::new())
+            }
+            ["remove", name] => {
+                self.registry().remove(name)?;
+                Ok(String::new())
+            }
+            ["swap", name, profile] => {
+                let card = self.create(profile)?;
+                self.registry().replace(name, card)?;
+                Ok(String::new())
+            }
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("unsupported command {:?}", command),
+            )),
+        }
+    }
+
+    pub(crate) fn create(&self, profile: &str) -> Result<BoxedCard> {
+        self.profiles
+            .get(profile)
+            .map(|factory| factory())
+            .ok_or_else(|| not_found("profile", profile))
+    }
+
+    pub(crate) fn registry(&self) -> MutexGuard<'_, Registry> {
+        self.registry.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Returns true if the given card or profile name can be used with the control protocol, i. e.
+/// if it is a non-empty word without whitespace and control characters, see the
+/// [module documentation][`self`].
+///
+/// # Example
+///
+/// ```
+/// use vpicc::control::is_valid_name;
+///
+/// assert!(is_valid_name("piv-1"));
+/// assert!(!is_valid_name("piv 1"));
+/// assert!(!is_valid_name(""));
+/// ```
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(|c: char| c.is_whitespace() || c.is_control())
+}
+
+fn not_found(kind: &str, name: &str) -> Error {
+    Error::new(ErrorKind::NotFound, format!("{} {} not found", kind, name))
+}
+
+fn join<'a>(names: impl Iterator<Item = &'a str>) -> String {
+    names.collect::<Vec<_>>().join(" ")
+}
@@ -0,0 +1,227 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! APDU coverage tracking.
+//!
+//! [`Coverage`][] records which CLA/INS/P1/P2 combinations have been executed by a card and
+//! which status words were returned, and produces JSON or HTML reports.  Instructions that are
+//! expected to be covered can be declared using [`Coverage::expect`][] so that the reports
+//! include the instructions that remain untested.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+};
+
+use log::warn;
+
+use crate::{Capabilities, VSmartCard};
+
+/// The header of a command APDU used as the coverage key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Header {
+    /// The class byte.
+    pub cla: u8,
+    /// The instruction byte.
+    pub ins: u8,
+    /// The first parameter byte.
+    pub p1: u8,
+    /// The second parameter byte.
+    pub p2: u8,
+}
+
+/// The coverage of a single command header.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Entry {
+    /// The number of executed commands with this header.
+    pub count: u64,
+    /// The status words returned for commands with this header.
+    pub status_words: BTreeSet<u16>,
+}
+
+/// Records the APDU coverage of the wrapped card.
+///
+/// # Example
+///
+/// ```
+/// use vpicc::{coverage::Coverage, VSmartCard};
+///
+/// let mut card = Coverage::new(vpicc::DummySmartCard);
+/// card.expect(0x00, 0xa4);
+/// card.expect(0x00, 0xb0);
+/// card.execute(&[0x00, 0xa4, 0x04, 0x00]);
+/// assert_eq!(card.uncovered(), [(0x00, 0xb0)]);
+/// assert!(card.to_json().contains(r#""ins":"a4""#));
+/// ```
+#[derive(Debug)]
+pub struct Coverage<C> {
+    card: C,
+    entries: BTreeMap<Header, Entry>,
+    expected: BTreeSet<(u8, u8)>,
+}
+
+impl<C> Coverage<C> {
+    /// Wraps the given card.
+    pub fn new(card: C) -> Self {
+        Self {
+            card,
+            entries: Default::default(),
+            expected: Default::default(),
+        }
+    }
+
+    /// Declares that commands with the given class and instruction are expected to be covered.
+    pub fn expect(&mut self, cla: u8, ins: u8) {
+        self.expected.insert((cla, ins));
+    }
+
+    /// Returns the coverage of all executed command headers.
+    pub fn entries(&self) -> &BTreeMap<Header, Entry> {
+        &self.entries
+    }
+
+    /// Returns the expected class and instruction pairs that have not been executed.
+    pub fn uncovered(&self) -> Vec<(u8, u8)> {
+        self.expected
+            .iter()
+            .filter(|(cla, ins)| {
+                !self
+                    .entries
+                    .keys()
+                    .any(|header| header.cla == *cla && header.ins == *ins)
+            })
+            .copied()
+            .collect()
+    }
+
+    /// Returns a JSON report of the coverage.
+    pub fn to_json(&self) -> String {
+        let commands: Vec<String> = self
+            .entries
+            .iter()
+            .map(|(header, entry)| {
+                let status_words: Vec<String> = entry
+                    .status_words
+                    .iter()
+                    .map(|sw| format!(r#""{:04x}""#, sw))
+                    .collect();
+                format!(
+                    r#"{{"cla":"{:02x}","ins":"{:02x}","p1":"{:02x}","p2":"{:02x}","count":{},"status_words":[{}]}}"#,
+                    header.cla,
+                    header.ins,
+                    header.p1,
+                    header.p2,
+                    entry.count,
+                    status_words.join(",")
+                )
+            })
+            .collect();
+        let uncovered: Vec<String> = self
+            .uncovered()
+            .iter()
+            .map(|(cla, ins)| format!(r#"{{"cla":"{:02x}","ins":"{:02x}"}}"#, cla, ins))
+            .collect();
+        format!(
+            r#"{{"commands":[{}],"uncovered":[{}]}}"#,
+            commands.join(","),
+            uncovered.join(",")
+        )
+    }
+
+    /// Returns an HTML report of the coverage.
+    pub fn to_html(&self) -> String {
+        let mut html = String::from(
+            "<!DOCTYPE html>\n<html>\n<head><title>APDU coverage</title></head>\n<body>\n\
+             <h1>APDU coverage</h1>\n<table>\n\
+             <tr><th>CLA</th><th>INS</th><th>P1</th><th>P2</th><th>Count</th><th>Status words</th></tr>\n",
+        );
+        for (header, entry) in &self.entries {
+            let status_words: Vec<String> = entry
+                .status_words
+                .iter()
+                .map(|sw| format!("{:04X}", sw))
+                .collect();
+            writeln!(
+                html,
+                "<tr><td>{:02X}</td><td>{:02X}</td><td>{:02X}</td><td>{:02X}</td><td>{}</td><td>{}</td></tr>",
+                header.cla,
+                header.ins,
+                header.p1,
+                header.p2,
+                entry.count,
+                status_words.join(" ")
+            )
+            .ok();
+        }
+        html.push_str("</table>\n");
+        let uncovered = self.uncovered();
+        if !uncovered.is_empty() {
+            html.push_str("<h2>Uncovered instructions</h2>\n<ul>\n");
+            for (cla, ins) in uncovered {
+                writeln!(html, "<li>CLA {:02X} INS {:02X}</li>", cla, ins).ok();
+            }
+            html.push_str("</ul>\n");
+        }
+        html.push_str("</body>\n</html>\n");
+        html
+    }
+
+    /// Returns a reference to the wrapped card.
+    pub fn get_ref(&self) -> &C {
+        &self.card
+    }
+
+    /// Returns a mutable reference to the wrapped card.
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.card
+    }
+
+    /// Returns the wrapped card.
+    pub fn into_inner(self) -> C {
+        self.card
+    }
+}
+
+impl<C: VSmartCard> VSmartCard for Coverage<C> {
+    fn atr(&self) -> &[u8] {
+        self.card.atr()
+    }
+
+    fn power_on(&mut self) {
+        self.card.power_on()
+    }
+
+    fn power_off(&mut self) {
+        self.card.power_off()
+    }
+
+    fn reset(&mut self) {
+        self.card.reset()
+    }
+
+    fn cold_reset(&mut self) {
+        self.card.cold_reset()
+    }
+
+    fn warm_reset(&mut self) {
+        self.card.warm_reset()
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        let response = self.card.execute(msg);
+        if let [cla, ins, p1, p2, ..] = *msg {
+            let entry = self.entries.entry(Header { cla, ins, p1, p2 }).or_default();
+            entry.count += 1;
+            if let [.., sw1, sw2] = *response {
+                entry.status_words.insert(u16::from_be_bytes([sw1, sw2]));
+            }
+        } else {
+            warn!("Ignoring command without header for coverage: {:x?}", msg);
+        }
+        response
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.card.capabilities()
+    }
+}
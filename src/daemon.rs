@@ -0,0 +1,181 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Running as a classic Unix daemon.
+//!
+//! [`Daemon::start`][] forks the process into the background, detaches it from the controlling
+//! terminal, redirects the standard streams and writes a pidfile.  This is only required on
+//! systems without a service manager like systemd, which takes care of these steps itself.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use vpicc::daemon::Daemon;
+//!
+//! fn main() -> vpicc::Result<()> {
+//!     let mut daemon = Daemon::new();
+//!     daemon.set_pidfile("/run/vpicc.pid");
+//!     daemon.set_log_file("/var/log/vpicc.log");
+//!     let _pidfile = daemon.start()?;
+//!     // env_logger writes to stderr, which is now redirected to the log file
+//!     env_logger::init();
+//!     vpicc::connect()?.run(&mut vpicc::DummySmartCard)
+//! }
+//! ```
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Error, ErrorKind, Result},
+    os::unix::io::AsRawFd,
+    path::{self, Path, PathBuf},
+    process,
+};
+
+use log::{debug, warn};
+
+/// Settings for daemonizing the current process, see the [module documentation][`self`].
+#[derive(Clone, Debug)]
+pub struct Daemon {
+    pidfile: Option<PathBuf>,
+    log_file: Option<PathBuf>,
+    working_dir: PathBuf,
+}
+
+impl Daemon {
+    /// Creates settings without a pidfile and log file that use `/` as the working directory.
+    pub fn new() -> Self {
+        Self {
+            pidfile: None,
+            log_file: None,
+            working_dir: PathBuf::from("/"),
+        }
+    }
+
+    /// Sets the file that the process ID of the daemon is written to.
+    pub fn set_pidfile<P: Into<PathBuf>>(&mut self, path: P) {
+        self.pidfile = Some(path.into());
+    }
+
+    /// Sets the file that stdout and stderr are appended to.  Without a log file, they are
+    /// redirected to `/dev/null`.
+    pub fn set_log_file<P: Into<PathBuf>>(&mut self, path: P) {
+        self.log_file = Some(path.into());
+    }
+
+    /// Sets the working directory of the daemon, defaulting to `/`.
+    pub fn set_working_dir<P: Into<PathBuf>>(&mut self, path: P) {
+        self.working_dir = path.into();
+    }
+
+    /// Forks the process into the background and returns in the daemon process.  The original
+    /// process exits.
+    ///
+    /// The log file and the pidfile are checked before forking, so that errors are still
+    /// reported to the caller.  Returns an [`AlreadyExists`][`ErrorKind::AlreadyExists`] error
+    /// if the pidfile contains the ID of a running process.
+    ///
+    /// This must be called before spawning any threads, as only the calling thread survives the
+    /// fork.
+    pub fn start(self) -> Result<Pidfile> {
+        let dev_null = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/null")?;
+        let log = match &self.log_file {
+            Some(path) => OpenOptions::new().create(true).append(true).open(path)?,
+            None => dev_null.try_clone()?,
+        };
+        // resolve relative paths before changing the working directory
+        let pidfile = self.pidfile.as_deref().map(path::absolute).transpose()?;
+        if let Some(path) = &pidfile {
+            check_pidfile(path)?;
+        }
+
+        fork()?;
+        if unsafe { libc::setsid() } < 0 {
+            return Err(Error::last_os_error());
+        }
+        // fork again so that the daemon can never reacquire a controlling terminal
+        fork()?;
+        std::env::set_current_dir(&self.working_dir)?;
+        redirect(&dev_null, libc::STDIN_FILENO)?;
+        redirect(&log, libc::STDOUT_FILENO)?;
+        redirect(&log, libc::STDERR_FILENO)?;
+
+        if let Some(path) = &pidfile {
+            fs::write(path, format!("{}\n", process::id()))?;
+            debug!("Wrote pidfile {}", path.display());
+        }
+        Ok(Pidfile { path: pidfile })
+    }
+}
+
+impl Default for Daemon {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The pidfile written by [`Daemon::start`][].  It is removed when this value is dropped.
+#[derive(Debug)]
+pub struct Pidfile {
+    path: Option<PathBuf>,
+}
+
+impl Pidfile {
+    /// Returns the path of the pidfile, if one was configured.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            if let Err(err) = fs::remove_file(path) {
+                warn!("Failed to remove pidfile {}: {}", path.display(), err);
+            }
+        }
+    }
+}
+
+/// Forks and exits in the parent process.
+fn fork() -> Result<()> {
+    match unsafe { libc::fork() } {
+        pid if pid < 0 => Err(Error::last_os_error()),
+        0 => Ok(()),
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+fn redirect(file: &File, fd: libc::c_int) -> Result<()> {
+    if unsafe { libc::dup2(file.as_raw_fd(), fd) } < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns an error if the given pidfile belongs to a running process.  Stale pidfiles are
+/// ignored.
+fn check_pidfile(path: &Path) -> Result<()> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    match content.trim().parse::<libc::pid_t>() {
+        Ok(pid) if pid > 0 && unsafe { libc::kill(pid, 0) } == 0 => Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!(
+                "the daemon of {} is still running with PID {}",
+                path.display(),
+                pid
+            ),
+        )),
+        _ => {
+            debug!("Ignoring stale pidfile {}", path.display());
+            Ok(())
+        }
+    }
+}
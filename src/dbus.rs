@@ -0,0 +1,133 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! A D-Bus interface for running [`Registry`][`crate::Registry`] instances.
+//!
+//! This module is only available if the `dbus` feature is enabled.  [`DbusService`][] publishes
+//! the cards and profiles of a [`ControlServer`][] as the `org.nitrokey.Vpicc1` interface with
+//! these methods:
+//!
+//! | Method                                 | Description                                 |
+//! |----------------------------------------|---------------------------------------------|
+//! | `ListCards() -> as`                    | lists the names of all cards                |
+//! | `ListProfiles() -> as`                 | lists the names of all registered profiles  |
+//! | `CardStatus(s name) -> (btttt)`        | returns the running state, APDU, ATR, power on and reset counts |
+//! | `Insert(s name, s profile, s address)` | connects a new card of a profile to vpcd    |
+//! | `Remove(s name)`                       | stops and removes a card                    |
+//! | `Reset(s name)`                        | resets a card                               |
+//! | `SelectProfile(s name, s profile)`     | replaces a card with a new card of a profile |
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::sync::{Arc, Mutex};
+//! use vpicc::{control::ControlServer, dbus::DbusService, Registry};
+//!
+//! fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     let registry = Arc::new(Mutex::new(Registry::new()));
+//!     let mut server = ControlServer::new(registry);
+//!     server.add_profile("dummy", || Box::new(vpicc::DummySmartCard))?;
+//!
+//!     let _connection = DbusService::new(server).serve()?;
+//!     loop {
+//!         std::thread::park();
+//!     }
+//! }
+//! ```
+
+use std::{fmt::Display, net::SocketAddr};
+
+use log::info;
+use zbus::{blocking::connection, fdo};
+
+use crate::control::ControlServer;
+
+/// The well-known bus name requested by [`DbusService::serve`][].
+pub const BUS_NAME: &str = "org.nitrokey.Vpicc";
+/// The object path of the interface published by [`DbusService`][].
+pub const OBJECT_PATH: &str = "/org/nitrokey/Vpicc";
+
+/// Publishes a [`ControlServer`][] on D-Bus, see the [module documentation][`self`].
+pub struct DbusService {
+    server: ControlServer,
+}
+
+impl DbusService {
+    /// Creates a D-Bus service for the registry and profiles of the given control server.
+    pub fn new(server: ControlServer) -> Self {
+        Self { server }
+    }
+
+    /// Publishes this service on the session bus.
+    ///
+    /// Method calls are handled in the background as long as the returned connection is alive.
+    pub fn serve(self) -> zbus::Result<connection::Connection> {
+        info!("Publishing {} on the session bus", BUS_NAME);
+        connection::Builder::session()?
+            .name(BUS_NAME)?
+            .serve_at(OBJECT_PATH, self)?
+            .build()
+    }
+}
+
+#[zbus::interface(name = "org.nitrokey.Vpicc1")]
+impl DbusService {
+    fn list_cards(&self) -> Vec<String> {
+        self.server
+            .registry()
+            .names()
+            .map(ToOwned::to_owned)
+            .collect()
+    }
+
+    fn list_profiles(&self) -> Vec<String> {
+        self.server.profiles.keys().cloned().collect()
+    }
+
+    fn card_status(&self, name: &str) -> fdo::Result<(bool, u64, u64, u64, u64)> {
+        let registry = self.server.registry();
+        let stats = registry
+            .stats(name)
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("card {} not found", name)))?;
+        Ok((
+            registry.is_running(name).unwrap_or_default(),
+            stats.apdus,
+            stats.atr_requests,
+            stats.power_ons,
+            stats.resets,
+        ))
+    }
+
+    fn insert(&self, name: &str, profile: &str, address: &str) -> fdo::Result<()> {
+        let addr: SocketAddr = address
+            .parse()
+            .map_err(|err| fdo::Error::InvalidArgs(format!("invalid address: {}", err)))?;
+        let card = self.server.create(profile).map_err(failed)?;
+        self.server.registry().add(name, addr, card).map_err(failed)
+    }
+
+    fn remove(&self, name: &str) -> fdo::Result<()> {
+        self.server
+            .registry()
+            .remove(name)
+            .map(drop)
+            .map_err(failed)
+    }
+
+    fn reset(&self, name: &str) -> fdo::Result<()> {
+        self.server.registry().reset(name).map_err(failed)
+    }
+
+    fn select_profile(&self, name: &str, profile: &str) -> fdo::Result<()> {
+        let card = self.server.create(profile).map_err(failed)?;
+        self.server
+            .registry()
+            .replace(name, card)
+            .map(drop)
+            .map_err(failed)
+    }
+}
+
+fn failed(err: impl Display) -> fdo::Error {
+    fdo::Error::Failed(err.to_string())
+}
@@ -0,0 +1,523 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Interactive debugging of card exchanges.
+//!
+//! A [`Debugger`][] wraps a card and pauses before and after every command while it is stepping.
+//! When it pauses, its [`Controller`][] is called with the card and the current [`Stop`][], so
+//! that it can inspect and change the state of the card, the command or the response.  The
+//! controller then decides whether the debugger keeps stepping or continues without pausing.
+//! The debugger starts stepping and can be paused again using a [`PauseHandle`][] or a
+//! [`Breakpoint`][] that matches the next command.  Breakpoints can also call a function instead
+//! of pausing, for example to log the interesting commands of a long exchange.
+//!
+//! Controllers can be implemented as closures for programmatic use in tests.  [`Console`][] is a
+//! controller for interactive use on a terminal, see the `debug` example.
+//!
+//! # Example
+//!
+//! ```
+//! use vpicc::{debugger::{Debugger, Resume, Stop}, VSmartCard};
+//!
+//! let mut card = Debugger::new(vpicc::DummySmartCard, |_card: &mut _, stop: &mut Stop<'_>| {
+//!     match stop {
+//!         Stop::Before { .. } => Resume::Step,
+//!         Stop::After { response, .. } => {
+//!             // pretend that the card is locked
+//!             **response = vec![0x69, 0x83];
+//!             Resume::Continue
+//!         }
+//!     }
+//! });
+//! assert_eq!(card.execute(&[0x00, 0x20, 0x00, 0x81]), [0x69, 0x83]);
+//! // the controller returned Resume::Continue, so the next command is not paused
+//! assert_eq!(card.execute(&[0x00, 0x20, 0x00, 0x81]), [0x90, 0x00]);
+//! ```
+
+use std::{
+    fmt,
+    io::{self, BufRead, StdinLock, Stdout, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crate::{apdu::Command, hex, names, router::Match, Capabilities, VSmartCard};
+
+/// A point at which a [`Debugger`][] pauses.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Stop<'a> {
+    /// The command is about to be executed.  The command can be changed before it is passed to
+    /// the card.
+    Before {
+        /// The command APDU.
+        command: &'a mut Vec<u8>,
+    },
+    /// The command has been executed.  The response can be changed before it is returned.
+    After {
+        /// The command APDU.
+        command: &'a [u8],
+        /// The response APDU.
+        response: &'a mut Vec<u8>,
+    },
+}
+
+impl Stop<'_> {
+    /// Returns the command APDU.
+    pub fn command(&self) -> &[u8] {
+        match self {
+            Self::Before { command } => command,
+            Self::After { command, .. } => command,
+        }
+    }
+}
+
+/// The way a [`Debugger`][] resumes after a pause.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resume {
+    /// Pauses again at the next stop.
+    Step,
+    /// Runs without pausing until a [`Breakpoint`][] matches or the debugger is paused using a
+    /// [`PauseHandle`][].
+    Continue,
+}
+
+/// Controls a [`Debugger`][] when it pauses.
+///
+/// This trait is implemented for all closures with a matching signature.
+pub trait Controller<C> {
+    /// Handles a pause of the debugger and returns how it resumes.
+    fn on_stop(&mut self, card: &mut C, stop: &mut Stop<'_>) -> Resume;
+}
+
+impl<C, F: FnMut(&mut C, &mut Stop<'_>) -> Resume> Controller<C> for F {
+    fn on_stop(&mut self, card: &mut C, stop: &mut Stop<'_>) -> Resume {
+        self(card, stop)
+    }
+}
+
+/// A handle to pause a [`Debugger`][] from another thread.
+#[derive(Clone, Debug, Default)]
+pub struct PauseHandle {
+    paused: Arc<AtomicBool>,
+}
+
+impl PauseHandle {
+    /// Pauses the debugger before the next command.
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns true if the debugger is stepping.
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+}
+
+type Callback = Box<dyn FnMut(&[u8]) + Send>;
+
+/// A pattern for commands that pauses a [`Debugger`][] or calls a function.
+///
+/// All conditions of a breakpoint must match.  A breakpoint without conditions matches all
+/// commands.
+///
+/// # Example
+///
+/// ```
+/// use vpicc::{debugger::{Breakpoint, Debugger, Resume, Stop}, router::Match, VSmartCard};
+///
+/// let mut paused = Vec::new();
+/// let mut card = Debugger::new(vpicc::DummySmartCard, |_card: &mut _, stop: &mut Stop<'_>| {
+///     if let Stop::Before { command } = stop {
+///         paused.push(command.clone());
+///     }
+///     Resume::Continue
+/// });
+/// card.set_stepping(false);
+/// // pause at the SELECT command for the OpenPGP application
+/// card.add_breakpoint(Breakpoint::new().aid(&[0xd2, 0x76, 0x00, 0x01, 0x24, 0x01]));
+/// // count the VERIFY commands without pausing
+/// let mut verify = 0;
+/// card.add_breakpoint(Breakpoint::header(Match::ins(0x20)).callback(move |_| verify += 1));
+///
+/// card.execute(&[0x00, 0xca, 0x00, 0x6e, 0x00]);
+/// card.execute(&[0x00, 0xa4, 0x04, 0x00, 0x06, 0xd2, 0x76, 0x00, 0x01, 0x24, 0x01]);
+/// card.execute(&[0x00, 0x20, 0x00, 0x81, 0x02, 0x31, 0x32]);
+/// let (_, controller) = card.into_inner();
+/// drop(controller);
+/// assert_eq!(paused.len(), 1);
+/// assert_eq!(paused[0][1], 0xa4);
+/// ```
+pub struct Breakpoint {
+    header: Match,
+    aid: Option<Vec<u8>>,
+    #[cfg(feature = "regex")]
+    data: Option<regex::Regex>,
+    callback: Option<Callback>,
+}
+
+impl Breakpoint {
+    /// Creates a breakpoint that matches all commands.
+    pub fn new() -> Self {
+        Self::header(Match::any())
+    }
+
+    /// Creates a breakpoint for commands with a header matching the given pattern.
+    pub fn header(header: Match) -> Self {
+        Self {
+            header,
+            aid: None,
+            #[cfg(feature = "regex")]
+            data: None,
+            callback: None,
+        }
+    }
+
+    /// Additionally requires a SELECT command by DF name for an AID starting with the given
+    /// bytes.
+    pub fn aid(mut self, aid: &[u8]) -> Self {
+        self.aid = Some(aid.to_vec());
+        self
+    }
+
+    /// Additionally requires the command data to match the given regular expression.
+    ///
+    /// The expression is matched against the command data encoded as lower-case hex without
+    /// separators, for example `^5c035fc1` for the GET DATA command of a PIV data object.
+    ///
+    /// This method is only available if the `regex` feature is enabled.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use vpicc::{debugger::Breakpoint, router::Match};
+    ///
+    /// let breakpoint = Breakpoint::header(Match::ins(0xcb)).data("^5c035fc1")?;
+    /// assert!(breakpoint.matches(&[0x00, 0xcb, 0x3f, 0xff, 0x05, 0x5c, 0x03, 0x5f, 0xc1, 0x05]));
+    /// assert!(!breakpoint.matches(&[0x00, 0xcb, 0x3f, 0xff, 0x03, 0x5c, 0x01, 0x7e]));
+    /// # Ok::<_, std::io::Error>(())
+    /// ```
+    #[cfg(feature = "regex")]
+    pub fn data(mut self, pattern: &str) -> std::io::Result<Self> {
+        let regex = regex::Regex::new(pattern)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+        self.data = Some(regex);
+        Ok(self)
+    }
+
+    /// Calls the given function with the command APDU instead of pausing the debugger.
+    pub fn callback<F: FnMut(&[u8]) + Send + 'static>(mut self, callback: F) -> Self {
+        self.callback = Some(Box::new(callback));
+        self
+    }
+
+    /// Returns true if the given command APDU matches this breakpoint.
+    pub fn matches(&self, msg: &[u8]) -> bool {
+        let Some(command) = Command::parse(msg) else {
+            return false;
+        };
+        if !self.header.matches(&command) {
+            return false;
+        }
+        if let Some(aid) = &self.aid {
+            let select = command.ins == 0xa4 && command.p1 == 0x04;
+            if !select || !command.data.starts_with(aid) {
+                return false;
+            }
+        }
+        #[cfg(feature = "regex")]
+        if let Some(data) = &self.data {
+            if !data.is_match(&hex::encode(command.data)) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl Default for Breakpoint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("Breakpoint");
+        debug.field("header", &self.header).field("aid", &self.aid);
+        #[cfg(feature = "regex")]
+        debug.field("data", &self.data);
+        debug.field("callback", &self.callback.is_some()).finish()
+    }
+}
+
+/// A card that pauses before and after commands, see the [module documentation][`self`].
+#[derive(Debug)]
+pub struct Debugger<C, D> {
+    card: C,
+    controller: D,
+    handle: PauseHandle,
+    breakpoints: Vec<(usize, Breakpoint)>,
+    next_id: usize,
+}
+
+impl<C, D: Controller<C>> Debugger<C, D> {
+    /// Wraps the given card, pausing before the first command.
+    pub fn new(card: C, controller: D) -> Self {
+        let handle = PauseHandle::default();
+        handle.pause();
+        Self {
+            card,
+            controller,
+            handle,
+            breakpoints: Vec::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Adds a breakpoint and returns its ID.
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.push((id, breakpoint));
+        id
+    }
+
+    /// Removes the breakpoint with the given ID and returns it.
+    pub fn remove_breakpoint(&mut self, id: usize) -> Option<Breakpoint> {
+        let i = self.breakpoints.iter().position(|(i, _)| *i == id)?;
+        Some(self.breakpoints.remove(i).1)
+    }
+
+    /// Removes all breakpoints.
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// Returns a handle that can be used to pause the debugger from another thread.
+    pub fn handle(&self) -> PauseHandle {
+        self.handle.clone()
+    }
+
+    /// Sets whether the debugger pauses at the next stop.
+    pub fn set_stepping(&mut self, stepping: bool) {
+        self.handle.paused.store(stepping, Ordering::Relaxed);
+    }
+
+    /// Returns a reference to the controller.
+    pub fn controller(&self) -> &D {
+        &self.controller
+    }
+
+    /// Returns a reference to the wrapped card.
+    pub fn get_ref(&self) -> &C {
+        &self.card
+    }
+
+    /// Returns a mutable reference to the wrapped card.
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.card
+    }
+
+    /// Returns the wrapped card and the controller.
+    pub fn into_inner(self) -> (C, D) {
+        (self.card, self.controller)
+    }
+
+    fn check_breakpoints(&mut self, msg: &[u8]) {
+        for (_, breakpoint) in &mut self.breakpoints {
+            if breakpoint.matches(msg) {
+                match &mut breakpoint.callback {
+                    Some(callback) => callback(msg),
+                    None => self.handle.pause(),
+                }
+            }
+        }
+    }
+
+    fn stop(&mut self, mut stop: Stop<'_>) {
+        if self.handle.is_paused() {
+            let resume = self.controller.on_stop(&mut self.card, &mut stop);
+            self.set_stepping(resume == Resume::Step);
+        }
+    }
+}
+
+impl<C: VSmartCard, D: Controller<C>> VSmartCard for Debugger<C, D> {
+    fn atr(&self) -> &[u8] {
+        self.card.atr()
+    }
+
+    fn power_on(&mut self) {
+        self.card.power_on()
+    }
+
+    fn power_off(&mut self) {
+        self.card.power_off()
+    }
+
+    fn reset(&mut self) {
+        self.card.reset()
+    }
+
+    fn cold_reset(&mut self) {
+        self.card.cold_reset()
+    }
+
+    fn warm_reset(&mut self) {
+        self.card.warm_reset()
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        self.check_breakpoints(msg);
+        let mut command = msg.to_vec();
+        self.stop(Stop::Before {
+            command: &mut command,
+        });
+        let mut response = self.card.execute(&command);
+        self.stop(Stop::After {
+            command: &command,
+            response: &mut response,
+        });
+        response
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.card.capabilities()
+    }
+}
+
+type ConsoleCommand<C> = Box<dyn FnMut(&mut C, &str) -> String + Send>;
+
+/// A [`Controller`][] that reads debugger commands from an input, for example stdin.
+///
+/// At every pause, the console shows the command or the response and reads commands until the
+/// debugger is resumed:
+///
+/// | Command           | Description                                              |
+/// |-------------------|----------------------------------------------------------|
+/// | `step`, `s`       | resumes and pauses at the next stop (default)            |
+/// | `continue`, `c`   | resumes without pausing                                  |
+/// | `print`, `p`      | shows the state of the card using its `Debug` output     |
+/// | `set <hex>`       | replaces the command or the response                     |
+/// | `help`, `h`       | lists the available commands                             |
+///
+/// Further commands, for example to change the state of the card, can be added with
+/// [`Console::add_command`][].  If the input ends, the debugger continues without pausing.
+///
+/// # Example
+///
+/// ```
+/// use vpicc::{debugger::{Console, Debugger}, VSmartCard};
+///
+/// let input: &[u8] = b"s\nset 6a82\nc\n";
+/// let console = Console::new(input, Vec::new());
+/// let mut card = Debugger::new(vpicc::DummySmartCard, console);
+/// assert_eq!(card.execute(&[0x00, 0xa4, 0x04, 0x00]), [0x6a, 0x82]);
+/// # Ok::<_, std::io::Error>(())
+/// ```
+pub struct Console<R, W, C> {
+    input: R,
+    output: W,
+    commands: Vec<(String, ConsoleCommand<C>)>,
+}
+
+impl<C> Console<StdinLock<'static>, Stdout, C> {
+    /// Creates a console that reads commands from stdin and writes to stdout.
+    pub fn stdio() -> Self {
+        Self::new(io::stdin().lock(), io::stdout())
+    }
+}
+
+impl<R, W, C> Console<R, W, C> {
+    /// Creates a console that reads commands from the given input and writes to the given
+    /// output.
+    pub fn new(input: R, output: W) -> Self {
+        Self {
+            input,
+            output,
+            commands: Vec::new(),
+        }
+    }
+
+    /// Adds a command with the given name.
+    ///
+    /// The handler is called with the card and the arguments of the command, and the returned
+    /// string is written to the output.
+    pub fn add_command<F>(&mut self, name: &str, handler: F)
+    where
+        F: FnMut(&mut C, &str) -> String + Send + 'static,
+    {
+        self.commands.push((name.to_owned(), Box::new(handler)));
+    }
+
+    /// Returns a reference to the output.
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+}
+
+impl<R: BufRead, W: Write, C: fmt::Debug> Console<R, W, C> {
+    fn run(&mut self, card: &mut C, stop: &mut Stop<'_>) -> io::Result<Resume> {
+        match stop {
+            Stop::Before { command } => writeln!(
+                self.output,
+                "> {} ({})",
+                hex::encode(command),
+                names::describe(command)
+            )?,
+            Stop::After { response, .. } => writeln!(self.output, "< {}", hex::encode(response))?,
+        }
+        loop {
+            write!(self.output, "(vpicc) ")?;
+            self.output.flush()?;
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                return Ok(Resume::Continue);
+            }
+            let (name, args) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+            match name {
+                "" | "s" | "step" => return Ok(Resume::Step),
+                "c" | "continue" => return Ok(Resume::Continue),
+                "p" | "print" => writeln!(self.output, "{:#?}", card)?,
+                "set" => match (hex::decode(args), &mut *stop) {
+                    (Ok(data), Stop::Before { command }) => **command = data,
+                    (Ok(data), Stop::After { response, .. }) => **response = data,
+                    (Err(err), _) => writeln!(self.output, "{}", err)?,
+                },
+                "h" | "help" => {
+                    writeln!(self.output, "step, continue, print, set <hex>, help")?;
+                    for (name, _) in &self.commands {
+                        writeln!(self.output, "{}", name)?;
+                    }
+                }
+                _ => match self.commands.iter_mut().find(|(n, _)| n == name) {
+                    Some((_, handler)) => {
+                        let result = handler(card, args.trim());
+                        writeln!(self.output, "{}", result)?;
+                    }
+                    None => writeln!(self.output, "unknown command: {}", name)?,
+                },
+            }
+        }
+    }
+}
+
+impl<R: BufRead, W: Write, C: fmt::Debug> Controller<C> for Console<R, W, C> {
+    fn on_stop(&mut self, card: &mut C, stop: &mut Stop<'_>) -> Resume {
+        self.run(card, stop).unwrap_or_else(|err| {
+            log::warn!("Debugger console failed: {}", err);
+            Resume::Continue
+        })
+    }
+}
+
+impl<R, W, C> fmt::Debug for Console<R, W, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let commands: Vec<_> = self.commands.iter().map(|(name, _)| name).collect();
+        f.debug_struct("Console")
+            .field("commands", &commands)
+            .finish_non_exhaustive()
+    }
+}
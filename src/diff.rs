@@ -0,0 +1,185 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Comparison of recorded traces.
+//!
+//! [`diff`][] compares two [`Trace`][]s exchange by exchange and reports the differences.  Fields
+//! that are expected to differ between runs, like response times or random challenges, can be
+//! ignored using [`DiffOptions`][].
+
+use std::{collections::BTreeSet, fmt, time::Duration};
+
+use crate::{
+    apdu,
+    trace::{Exchange, Trace},
+};
+
+/// Configures which differences are reported by [`diff`][].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DiffOptions {
+    /// The maximum difference of response times, or `None` to ignore response times.
+    pub duration_tolerance: Option<Duration>,
+    /// Instructions whose command data is ignored, for example because it depends on a random
+    /// challenge.
+    pub ignore_command_data: BTreeSet<u8>,
+    /// Instructions whose response data is ignored so that only the status words are compared,
+    /// for example GET CHALLENGE (84).
+    pub ignore_response_data: BTreeSet<u8>,
+}
+
+/// A difference between two traces reported by [`diff`][].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Difference {
+    /// The command APDUs of an exchange differ.
+    Command {
+        /// The index of the exchange.
+        index: usize,
+        /// The command in the left trace.
+        left: Vec<u8>,
+        /// The command in the right trace.
+        right: Vec<u8>,
+    },
+    /// The response APDUs of an exchange differ.
+    Response {
+        /// The index of the exchange.
+        index: usize,
+        /// The command of the exchange in the left trace.
+        command: Vec<u8>,
+        /// The response in the left trace.
+        left: Vec<u8>,
+        /// The response in the right trace.
+        right: Vec<u8>,
+    },
+    /// The response times of an exchange differ by more than the tolerance.
+    Duration {
+        /// The index of the exchange.
+        index: usize,
+        /// The response time in the left trace.
+        left: Duration,
+        /// The response time in the right trace.
+        right: Duration,
+    },
+    /// An exchange is only present in the left trace.
+    OnlyLeft {
+        /// The index of the exchange.
+        index: usize,
+        /// The exchange.
+        exchange: Exchange,
+    },
+    /// An exchange is only present in the right trace.
+    OnlyRight {
+        /// The index of the exchange.
+        index: usize,
+        /// The exchange.
+        exchange: Exchange,
+    },
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Command { index, left, right } => {
+                write!(f, "#{}: command {:02x?} != {:02x?}", index, left, right)
+            }
+            Self::Response {
+                index,
+                command,
+                left,
+                right,
+            } => write!(
+                f,
+                "#{}: response to {:02x?}: {:02x?} != {:02x?}",
+                index, command, left, right
+            ),
+            Self::Duration { index, left, right } => {
+                write!(f, "#{}: duration {:?} != {:?}", index, left, right)
+            }
+            Self::OnlyLeft { index, exchange } => {
+                write!(f, "#{}: only in left: {:02x?}", index, exchange.command)
+            }
+            Self::OnlyRight { index, exchange } => {
+                write!(f, "#{}: only in right: {:02x?}", index, exchange.command)
+            }
+        }
+    }
+}
+
+/// Compares two traces exchange by exchange.
+///
+/// # Example
+///
+/// ```
+/// use vpicc::{diff::{diff, DiffOptions}, trace::Trace};
+///
+/// let left = Trace::parse_apdu4j("A>> 0084000008\nA<< 0102030405060708 9000\n")?;
+/// let right = Trace::parse_apdu4j("A>> 0084000008\nA<< 1112131415161718 9000\n")?;
+/// assert_eq!(diff(&left, &right, &DiffOptions::default()).len(), 1);
+///
+/// let mut options = DiffOptions::default();
+/// options.ignore_response_data.insert(0x84);
+/// assert!(diff(&left, &right, &options).is_empty());
+/// # Ok::<_, std::io::Error>(())
+/// ```
+pub fn diff(left: &Trace, right: &Trace, options: &DiffOptions) -> Vec<Difference> {
+    let mut differences = Vec::new();
+    let (left, right) = (left.exchanges(), right.exchanges());
+    for (index, (l, r)) in left.iter().zip(right).enumerate() {
+        let ins = l.command.get(1).copied();
+        let ignored = |set: &BTreeSet<u8>| ins.is_some_and(|ins| set.contains(&ins));
+
+        let commands_equal = if ignored(&options.ignore_command_data) {
+            l.command.get(..4) == r.command.get(..4)
+        } else {
+            l.command == r.command
+        };
+        if !commands_equal {
+            differences.push(Difference::Command {
+                index,
+                left: l.command.clone(),
+                right: r.command.clone(),
+            });
+            continue;
+        }
+
+        let responses_equal = if ignored(&options.ignore_response_data) {
+            apdu::status(&l.response) == apdu::status(&r.response)
+        } else {
+            l.response == r.response
+        };
+        if !responses_equal {
+            differences.push(Difference::Response {
+                index,
+                command: l.command.clone(),
+                left: l.response.clone(),
+                right: r.response.clone(),
+            });
+        }
+
+        if let (Some(tolerance), Some(ld), Some(rd)) =
+            (options.duration_tolerance, l.duration, r.duration)
+        {
+            if ld.abs_diff(rd) > tolerance {
+                differences.push(Difference::Duration {
+                    index,
+                    left: ld,
+                    right: rd,
+                });
+            }
+        }
+    }
+
+    let common = left.len().min(right.len());
+    for (index, exchange) in left.iter().enumerate().skip(common) {
+        differences.push(Difference::OnlyLeft {
+            index,
+            exchange: exchange.clone(),
+        });
+    }
+    for (index, exchange) in right.iter().enumerate().skip(common) {
+        differences.push(Difference::OnlyRight {
+            index,
+            exchange: exchange.clone(),
+        });
+    }
+    differences
+}
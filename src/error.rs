@@ -0,0 +1,109 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+use std::{
+    error, fmt,
+    io::{self, ErrorKind},
+};
+
+use crate::MAX_MESSAGE_LEN;
+
+/// A specialized [`Result`][`std::result::Result`] type for connections to vpcd.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// An error on a connection to vpcd.
+///
+/// This makes it possible to tell apart protocol violations of vpcd from failures of the
+/// transport, for example to decide whether to reconnect.  The error can be converted from and
+/// into an [`io::Error`][], so it can be used with the `?` operator in functions that return an
+/// [`io::Result`][].  Converting it into an [`io::Error`][] and back yields the original error.
+///
+/// # Example
+///
+/// ```no_run
+/// fn main() -> std::io::Result<()> {
+///     loop {
+///         match vpicc::connect()?.run(&mut vpicc::DummySmartCard) {
+///             Err(vpicc::Error::Io(err)) => eprintln!("lost connection: {}", err),
+///             result => return Ok(result?),
+///         }
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The transport failed, for example because vpcd closed the connection.
+    Io(io::Error),
+    /// vpcd sent a malformed message, for example an empty message.
+    Protocol(String),
+    /// vpcd sent a control command that is not supported.
+    UnsupportedCommand(u8),
+    /// A response or an ATR with the given length exceeds [`MAX_MESSAGE_LEN`][].
+    MessageTooLarge(usize),
+    /// A [`TryVSmartCard`][`crate::TryVSmartCard`] failed and the connection was closed, see
+    /// [`ErrorPolicy::Close`][`crate::ErrorPolicy::Close`].
+    Card(Box<dyn error::Error + Send + Sync>),
+}
+
+impl Error {
+    /// Returns the [`ErrorKind`][] of the corresponding [`io::Error`][].
+    ///
+    /// Protocol violations have the kind [`InvalidData`][`ErrorKind::InvalidData`] and messages
+    /// that are too large the kind [`InvalidInput`][`ErrorKind::InvalidInput`].
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Io(err) => err.kind(),
+            Self::Protocol(_) | Self::UnsupportedCommand(_) => ErrorKind::InvalidData,
+            Self::MessageTooLarge(_) => ErrorKind::InvalidInput,
+            Self::Card(_) => ErrorKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => err.fmt(f),
+            Self::Protocol(msg) => f.write_str(msg),
+            Self::UnsupportedCommand(command) => {
+                write!(f, "unsupported control command {}", command)
+            }
+            Self::MessageTooLarge(len) => write!(
+                f,
+                "message with {} bytes exceeds the maximum length of {} bytes",
+                len, MAX_MESSAGE_LEN
+            ),
+            Self::Card(err) => err.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            Self::Card(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        if err.get_ref().is_some_and(|inner| inner.is::<Self>()) {
+            let inner = err.into_inner().expect("checked inner error");
+            return *inner.downcast().expect("checked error type");
+        }
+        Self::Io(err)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Io(err) => err,
+            err => io::Error::new(err.kind(), err),
+        }
+    }
+}
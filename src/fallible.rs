@@ -0,0 +1,198 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+use std::error;
+
+use log::warn;
+
+use crate::{Capabilities, Error, Result, VSmartCard, DEFAULT_ATR};
+
+/// A virtual smartcard implementation with fallible command execution.
+///
+/// This is like [`VSmartCard`][], but [`try_execute`][`TryVSmartCard::try_execute`] can return
+/// an error if the card cannot process commands at all, for example because the backend that
+/// holds its keys is no longer available.  Errors that concern a single command should still be
+/// reported with a status word.
+///
+/// Use [`Connection::try_run`][`crate::Connection::try_run`] or
+/// [`Connection::try_poll`][`crate::Connection::try_poll`] to handle the commands from vpcd with
+/// such a card.  The [`ErrorPolicy`][] determines how errors are reported to vpcd.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::io;
+/// use vpicc::{ErrorPolicy, TryVSmartCard};
+///
+/// struct Card {
+///     backend: Option<Vec<u8>>,
+/// }
+///
+/// impl TryVSmartCard for Card {
+///     type Error = io::Error;
+///
+///     fn try_execute(&mut self, _msg: &[u8]) -> io::Result<Vec<u8>> {
+///         let backend = self.backend.as_ref().ok_or(io::ErrorKind::NotConnected)?;
+///         Ok(backend.clone())
+///     }
+/// }
+///
+/// fn main() -> vpicc::Result<()> {
+///     let mut card = Card { backend: None };
+///     vpicc::connect()?.try_run(&mut card, ErrorPolicy::Close)
+/// }
+/// ```
+pub trait TryVSmartCard {
+    /// The error returned if a command cannot be executed.
+    type Error: error::Error + Send + Sync + 'static;
+
+    /// The ATR of this smartcard, defaulting to [`DEFAULT_ATR`].
+    fn atr(&self) -> &[u8] {
+        DEFAULT_ATR
+    }
+
+    /// Handles a Power On command.
+    fn power_on(&mut self) {}
+
+    /// Handles a Power Off command.
+    fn power_off(&mut self) {}
+
+    /// Handles a Reset command.
+    fn reset(&mut self) {}
+
+    /// Handles a cold reset, see [`VSmartCard::cold_reset`][].
+    fn cold_reset(&mut self) {}
+
+    /// Handles a warm reset, see [`VSmartCard::warm_reset`][].
+    fn warm_reset(&mut self) {}
+
+    /// Executes the given APDU command and returns the response APDU or an error.
+    fn try_execute(&mut self, msg: &[u8]) -> Result<Vec<u8>, Self::Error>;
+
+    /// Returns a description of the features supported by this card, see
+    /// [`VSmartCard::capabilities`][].
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+}
+
+impl<T: TryVSmartCard + ?Sized> TryVSmartCard for Box<T> {
+    type Error = T::Error;
+
+    fn atr(&self) -> &[u8] {
+        (**self).atr()
+    }
+
+    fn power_on(&mut self) {
+        (**self).power_on()
+    }
+
+    fn power_off(&mut self) {
+        (**self).power_off()
+    }
+
+    fn reset(&mut self) {
+        (**self).reset()
+    }
+
+    fn cold_reset(&mut self) {
+        (**self).cold_reset()
+    }
+
+    fn warm_reset(&mut self) {
+        (**self).warm_reset()
+    }
+
+    fn try_execute(&mut self, msg: &[u8]) -> Result<Vec<u8>, Self::Error> {
+        (**self).try_execute(msg)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        (**self).capabilities()
+    }
+}
+
+/// Determines how errors of a [`TryVSmartCard`][] are reported to vpcd.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Responds with the given status word and keeps the connection open.
+    Respond(u16),
+    /// Closes the connection without responding and returns the error.
+    Close,
+}
+
+impl Default for ErrorPolicy {
+    /// Responds with 6F00 (no precise diagnosis).
+    fn default() -> Self {
+        Self::Respond(0x6f00)
+    }
+}
+
+/// Runs a [`TryVSmartCard`][] as a [`VSmartCard`][] and keeps the error that should close the
+/// connection.
+pub(crate) struct Checked<'a, C: ?Sized> {
+    card: &'a mut C,
+    policy: ErrorPolicy,
+    error: Option<Error>,
+}
+
+impl<'a, C: TryVSmartCard + ?Sized> Checked<'a, C> {
+    pub fn new(card: &'a mut C, policy: ErrorPolicy) -> Self {
+        Self {
+            card,
+            policy,
+            error: None,
+        }
+    }
+
+    pub fn check(&mut self) -> Result<()> {
+        self.error.take().map_or(Ok(()), Err)
+    }
+}
+
+impl<C: TryVSmartCard + ?Sized> VSmartCard for Checked<'_, C> {
+    fn atr(&self) -> &[u8] {
+        self.card.atr()
+    }
+
+    fn power_on(&mut self) {
+        self.card.power_on()
+    }
+
+    fn power_off(&mut self) {
+        self.card.power_off()
+    }
+
+    fn reset(&mut self) {
+        self.card.reset()
+    }
+
+    fn cold_reset(&mut self) {
+        self.card.cold_reset()
+    }
+
+    fn warm_reset(&mut self) {
+        self.card.warm_reset()
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        let err = match self.card.try_execute(msg) {
+            Ok(response) => return response,
+            Err(err) => err,
+        };
+        match self.policy {
+            ErrorPolicy::Respond(status) => {
+                warn!("Card failed to execute APDU {:x?}: {}", msg, err);
+                status.to_be_bytes().to_vec()
+            }
+            ErrorPolicy::Close => {
+                self.error = Some(Error::Card(Box::new(err)));
+                Vec::new()
+            }
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.card.capabilities()
+    }
+}
@@ -0,0 +1,206 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::BTreeMap,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+};
+
+use log::{info, warn};
+
+use crate::{CardStats, Registry, Result, VSmartCard};
+
+/// Aggregate statistics of the cards in a [`Farm`][].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FarmStats {
+    /// The number of cards.
+    pub cards: usize,
+    /// The number of cards with an active connection to vpcd.
+    pub running: usize,
+    /// The sum of the request counters of all cards.
+    pub requests: CardStats,
+}
+
+impl FarmStats {
+    /// Returns the number of cards whose connection to vpcd has been closed.
+    pub fn disconnected(&self) -> usize {
+        self.cards - self.running
+    }
+
+    fn add(&mut self, running: bool, stats: CardStats) {
+        self.cards += 1;
+        if running {
+            self.running += 1;
+        }
+        let requests = &mut self.requests;
+        requests.apdus += stats.apdus;
+        requests.atr_requests += stats.atr_requests;
+        requests.power_ons += stats.power_ons;
+        requests.power_offs += stats.power_offs;
+        requests.resets += stats.resets;
+    }
+}
+
+/// Runs groups of labeled cards for load tests of host software.
+///
+/// [`spawn`][`Farm::spawn`] creates a number of cards using a factory, names them after the
+/// label of the group and their index, for example `piv-0`, `piv-1` and so on, and connects them
+/// to the given vpcd endpoints in turn.  vpcd listens on one port per reader, starting at
+/// [`DEFAULT_PORT`][`crate::DEFAULT_PORT`], so a farm usually uses one endpoint per reader.  The
+/// cards are managed by a [`Registry`][], so they can also be controlled individually.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::net::SocketAddr;
+/// use vpicc::{profiles::piv::PivCard, Farm};
+///
+/// fn main() -> vpicc::Result<()> {
+///     let endpoints: Vec<SocketAddr> = (35963..35973)
+///         .map(|port| SocketAddr::from(([127, 0, 0, 1], port)))
+///         .collect();
+///     let mut farm = Farm::new();
+///     farm.spawn("piv", 5, &endpoints, |i| PivCard::synthetic(i as u64))?;
+///     farm.spawn("dummy", 5, &endpoints[5..], |_| vpicc::DummySmartCard)?;
+///     loop {
+///         std::thread::sleep(std::time::Duration::from_secs(10));
+///         let stats = farm.stats();
+///         println!("{}/{} cards running, {} APDUs", stats.running, stats.cards, stats.requests.apdus);
+///         farm.restart_disconnected()?;
+///     }
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct Farm {
+    registry: Registry,
+    groups: BTreeMap<String, Vec<String>>,
+}
+
+impl Farm {
+    /// Creates an empty farm.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates the given number of cards with the given factory and connects them to vpcd.
+    ///
+    /// The factory is called with the index of the card in the group.  Card `i` is connected to
+    /// the endpoint `i % endpoints.len()`.  If a card cannot be connected, the cards of this
+    /// group that have already been connected are stopped and the error is returned.  An error
+    /// is also returned if a group with the same label exists or if no endpoints are given.
+    pub fn spawn<F, V>(
+        &mut self,
+        label: &str,
+        count: usize,
+        endpoints: &[SocketAddr],
+        mut factory: F,
+    ) -> Result<()>
+    where
+        F: FnMut(usize) -> V,
+        V: VSmartCard + Send + 'static,
+    {
+        if self.groups.contains_key(label) {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("group {} already exists", label),
+            )
+            .into());
+        }
+        if endpoints.is_empty() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "no vpcd endpoints given").into());
+        }
+        info!("Spawning {} cards for group {}", count, label);
+        let mut names = Vec::with_capacity(count);
+        for i in 0..count {
+            let name = format!("{}-{}", label, i);
+            let addr = endpoints[i % endpoints.len()];
+            if let Err(err) = self.registry.add(name.clone(), addr, factory(i)) {
+                self.remove_all(&names);
+                return Err(err);
+            }
+            names.push(name);
+        }
+        self.groups.insert(label.to_owned(), names);
+        Ok(())
+    }
+
+    /// Stops all cards of the group with the given label.
+    pub fn stop(&mut self, label: &str) -> Result<()> {
+        let names = self.groups.remove(label).ok_or_else(|| {
+            io::Error::new(ErrorKind::NotFound, format!("group {} not found", label))
+        })?;
+        info!("Stopping group {}", label);
+        self.remove_all(&names);
+        Ok(())
+    }
+
+    /// Reconnects all cards whose connection to vpcd has been closed and returns their number.
+    pub fn restart_disconnected(&mut self) -> Result<usize> {
+        let disconnected: Vec<_> = self.disconnected().map(str::to_owned).collect();
+        for name in &disconnected {
+            self.registry.restart(name)?;
+        }
+        Ok(disconnected.len())
+    }
+
+    /// Returns the names of the cards whose connection to vpcd has been closed.
+    pub fn disconnected(&self) -> impl Iterator<Item = &str> {
+        self.groups
+            .values()
+            .flatten()
+            .filter(|name| self.registry.is_running(name) == Some(false))
+            .map(String::as_str)
+    }
+
+    /// Returns the aggregate statistics of all cards.
+    pub fn stats(&self) -> FarmStats {
+        let mut stats = FarmStats::default();
+        for label in self.groups.keys() {
+            self.add_stats(label, &mut stats);
+        }
+        stats
+    }
+
+    /// Returns the aggregate statistics of the group with the given label, or `None` if there is
+    /// no such group.
+    pub fn group_stats(&self, label: &str) -> Option<FarmStats> {
+        let mut stats = FarmStats::default();
+        self.add_stats(label, &mut stats).then_some(stats)
+    }
+
+    /// Returns the labels of all groups.
+    pub fn labels(&self) -> impl Iterator<Item = &str> {
+        self.groups.keys().map(String::as_str)
+    }
+
+    /// Returns the names of the cards in the group with the given label.
+    pub fn names(&self, label: &str) -> Option<&[String]> {
+        self.groups.get(label).map(Vec::as_slice)
+    }
+
+    /// Returns the registry that manages the cards of this farm.
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    fn add_stats(&self, label: &str, stats: &mut FarmStats) -> bool {
+        let Some(names) = self.groups.get(label) else {
+            return false;
+        };
+        for name in names {
+            let running = self.registry.is_running(name).unwrap_or_default();
+            let requests = self.registry.stats(name).unwrap_or_default();
+            stats.add(running, requests);
+        }
+        true
+    }
+
+    fn remove_all(&mut self, names: &[String]) {
+        for name in names {
+            if let Err(err) = self.registry.remove(name) {
+                warn!("Failed to stop card {}: {}", name, err);
+            }
+        }
+    }
+}
@@ -0,0 +1,356 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Fault simulation for card implementations.
+//!
+//! [`Glitch`][] corrupts the persistent state of a card between commands according to a seeded
+//! [`FaultPlan`][], so that the robustness of the card logic against memory faults can be
+//! explored reproducibly.  [`MisbehavingCard`][] corrupts the responses of a card instead, to
+//! test the robustness of the host software.
+
+use log::debug;
+
+use crate::{rng::Rng, Capabilities, VSmartCard};
+
+/// A card with persistent state that can be corrupted by [`Glitch`][].
+pub trait FaultTarget {
+    /// Returns the persistent state of the card.
+    fn persistent_state(&mut self) -> &mut [u8];
+}
+
+/// A fault injected by [`Glitch`][].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// A single bit of the state was flipped.
+    BitFlip {
+        /// The index of the flipped bit, counted from the least significant bit of the first byte.
+        bit: usize,
+    },
+    /// A region of the state was set to zero.
+    Zero {
+        /// The offset of the region.
+        offset: usize,
+        /// The length of the region.
+        len: usize,
+    },
+}
+
+impl Fault {
+    fn apply(&self, state: &mut [u8]) {
+        match *self {
+            Self::BitFlip { bit } => state[bit / 8] ^= 1 << (bit % 8),
+            Self::Zero { offset, len } => state[offset..offset + len].fill(0),
+        }
+    }
+}
+
+/// Describes which faults [`Glitch`][] injects.
+///
+/// Before every command, a bit flip and a zeroed region are injected with the configured
+/// probabilities.  The same seed always produces the same faults for the same state sizes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FaultPlan {
+    /// The seed for the fault generator.
+    pub seed: u64,
+    /// The probability of a bit flip before a command.
+    pub bit_flip_probability: f64,
+    /// The probability of zeroing a region before a command.
+    pub zero_probability: f64,
+    /// The maximum length of a zeroed region.
+    pub max_zero_len: usize,
+}
+
+impl FaultPlan {
+    /// Creates a plan with the given seed that injects a bit flip before 10 % of the commands.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            bit_flip_probability: 0.1,
+            zero_probability: 0.0,
+            max_zero_len: 16,
+        }
+    }
+}
+
+/// Injects faults into the persistent state of the wrapped card between commands.
+///
+/// # Example
+///
+/// ```
+/// use vpicc::{fault::{FaultPlan, FaultTarget, Glitch}, VSmartCard};
+///
+/// struct Counter([u8; 4]);
+///
+/// impl VSmartCard for Counter {
+///     fn execute(&mut self, _msg: &[u8]) -> Vec<u8> {
+///         let mut response = self.0.to_vec();
+///         response.extend_from_slice(&[0x90, 0x00]);
+///         response
+///     }
+/// }
+///
+/// impl FaultTarget for Counter {
+///     fn persistent_state(&mut self) -> &mut [u8] {
+///         &mut self.0
+///     }
+/// }
+///
+/// let plan = FaultPlan { bit_flip_probability: 1.0, ..FaultPlan::new(42) };
+/// let mut card = Glitch::new(Counter([0; 4]), plan);
+/// let response = card.execute(&[0x00, 0xca, 0x00, 0x00]);
+/// assert_eq!(response[..4].iter().map(|b| b.count_ones()).sum::<u32>(), 1);
+/// assert_eq!(card.faults().len(), 1);
+/// ```
+#[derive(Debug)]
+pub struct Glitch<C> {
+    card: C,
+    plan: FaultPlan,
+    rng: Rng,
+    faults: Vec<Fault>,
+}
+
+impl<C: FaultTarget> Glitch<C> {
+    /// Wraps the given card, injecting faults according to the given plan.
+    pub fn new(card: C, plan: FaultPlan) -> Self {
+        Self {
+            card,
+            rng: Rng::new(plan.seed),
+            plan,
+            faults: Vec::new(),
+        }
+    }
+
+    /// Returns all faults injected so far.
+    pub fn faults(&self) -> &[Fault] {
+        &self.faults
+    }
+
+    /// Returns a reference to the wrapped card.
+    pub fn get_ref(&self) -> &C {
+        &self.card
+    }
+
+    /// Returns a mutable reference to the wrapped card.
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.card
+    }
+
+    /// Returns the wrapped card.
+    pub fn into_inner(self) -> C {
+        self.card
+    }
+
+    fn inject(&mut self) {
+        let state = self.card.persistent_state();
+        if state.is_empty() {
+            return;
+        }
+        let mut faults = Vec::new();
+        if self.rng.chance(self.plan.bit_flip_probability) {
+            faults.push(Fault::BitFlip {
+                bit: self.rng.below(state.len() * 8),
+            });
+        }
+        if self.plan.max_zero_len > 0 && self.rng.chance(self.plan.zero_probability) {
+            let len = 1 + self.rng.below(self.plan.max_zero_len.min(state.len()));
+            let offset = self.rng.below(state.len() - len + 1);
+            faults.push(Fault::Zero { offset, len });
+        }
+        for fault in faults {
+            debug!("Injecting fault {:?}", fault);
+            fault.apply(state);
+            self.faults.push(fault);
+        }
+    }
+}
+
+impl<C: VSmartCard + FaultTarget> VSmartCard for Glitch<C> {
+    fn atr(&self) -> &[u8] {
+        self.card.atr()
+    }
+
+    fn power_on(&mut self) {
+        self.card.power_on()
+    }
+
+    fn power_off(&mut self) {
+        self.card.power_off()
+    }
+
+    fn reset(&mut self) {
+        self.card.reset()
+    }
+
+    fn cold_reset(&mut self) {
+        self.card.cold_reset()
+    }
+
+    fn warm_reset(&mut self) {
+        self.card.warm_reset()
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        self.inject();
+        self.card.execute(msg)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.card.capabilities()
+    }
+}
+
+/// A way in which [`MisbehavingCard`][] corrupts a response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Misbehavior {
+    /// Responds with an empty response.
+    Empty,
+    /// Responds with a single byte, which is too short for a status word.
+    TooShort,
+    /// Removes the status word from the response of the wrapped card.
+    MissingStatusWord,
+    /// Pads the response data of the wrapped card to the given length, keeping the status word.
+    ///
+    /// Lengths above [`MAX_MESSAGE_LEN`][`crate::MAX_MESSAGE_LEN`] cannot be framed and cause
+    /// the connection to fail.
+    Oversized(usize),
+    /// Responds with the given number of random bytes.
+    Garbage(usize),
+}
+
+/// Corrupts the responses of the wrapped card to test the robustness of the host software.
+///
+/// The card executes every command and the response is replaced according to the configured
+/// [`Misbehavior`][] with the configured probability, using a seeded generator so that test
+/// runs are reproducible.
+///
+/// # Example
+///
+/// ```
+/// use vpicc::{fault::{Misbehavior, MisbehavingCard}, VSmartCard};
+///
+/// let mut card = MisbehavingCard::new(vpicc::DummySmartCard, Misbehavior::MissingStatusWord);
+/// assert_eq!(card.execute(&[0x00, 0xa4, 0x04, 0x00]), []);
+///
+/// card.set_misbehavior(Misbehavior::Oversized(300));
+/// let response = card.execute(&[0x00, 0xa4, 0x04, 0x00]);
+/// assert_eq!(response.len(), 302);
+/// assert_eq!(response[300..], [0x90, 0x00]);
+/// assert_eq!(card.misbehaved(), 2);
+/// ```
+#[derive(Debug)]
+pub struct MisbehavingCard<C> {
+    card: C,
+    misbehavior: Misbehavior,
+    probability: f64,
+    rng: Rng,
+    misbehaved: u64,
+}
+
+impl<C: VSmartCard> MisbehavingCard<C> {
+    /// Wraps the given card, corrupting all responses in the given way.
+    pub fn new(card: C, misbehavior: Misbehavior) -> Self {
+        Self {
+            card,
+            misbehavior,
+            probability: 1.0,
+            rng: Rng::new(0),
+            misbehaved: 0,
+        }
+    }
+
+    /// Sets the way in which responses are corrupted.
+    pub fn set_misbehavior(&mut self, misbehavior: Misbehavior) {
+        self.misbehavior = misbehavior;
+    }
+
+    /// Sets the probability that a response is corrupted, defaulting to one.
+    pub fn set_probability(&mut self, probability: f64) {
+        self.probability = probability;
+    }
+
+    /// Sets the seed of the generator for the probability and for garbage, defaulting to zero.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    /// Returns the number of corrupted responses.
+    pub fn misbehaved(&self) -> u64 {
+        self.misbehaved
+    }
+
+    /// Returns a reference to the wrapped card.
+    pub fn get_ref(&self) -> &C {
+        &self.card
+    }
+
+    /// Returns a mutable reference to the wrapped card.
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.card
+    }
+
+    /// Returns the wrapped card.
+    pub fn into_inner(self) -> C {
+        self.card
+    }
+
+    fn corrupt(&mut self, mut response: Vec<u8>) -> Vec<u8> {
+        match self.misbehavior {
+            Misbehavior::Empty => Vec::new(),
+            Misbehavior::TooShort => response.into_iter().take(1).collect(),
+            Misbehavior::MissingStatusWord => {
+                response.truncate(response.len().saturating_sub(2));
+                response
+            }
+            Misbehavior::Oversized(len) => {
+                let status = response.split_off(response.len().saturating_sub(2));
+                response.resize(len.max(response.len()), 0xa5);
+                response.extend_from_slice(&status);
+                response
+            }
+            Misbehavior::Garbage(len) => (0..len).map(|_| self.rng.next_u64() as u8).collect(),
+        }
+    }
+}
+
+impl<C: VSmartCard> VSmartCard for MisbehavingCard<C> {
+    fn atr(&self) -> &[u8] {
+        self.card.atr()
+    }
+
+    fn power_on(&mut self) {
+        self.card.power_on()
+    }
+
+    fn power_off(&mut self) {
+        self.card.power_off()
+    }
+
+    fn reset(&mut self) {
+        self.card.reset()
+    }
+
+    fn cold_reset(&mut self) {
+        self.card.cold_reset()
+    }
+
+    fn warm_reset(&mut self) {
+        self.card.warm_reset()
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        let response = self.card.execute(msg);
+        if !self.rng.chance(self.probability) {
+            return response;
+        }
+        self.misbehaved += 1;
+        debug!(
+            "Corrupting response {:x?} with {:?}",
+            response, self.misbehavior
+        );
+        self.corrupt(response)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.card.capabilities()
+    }
+}
@@ -0,0 +1,191 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! The framing used by vpcd: every message is prefixed with its length as a big-endian `u16`.
+
+use std::{
+    io::{ErrorKind, IoSlice, Read, Result, Write},
+    net::TcpStream,
+    time::Instant,
+};
+
+use log::trace;
+
+use crate::MAX_MESSAGE_LEN;
+
+/// The size of the length prefix.
+pub const HEADER_LEN: usize = 2;
+
+/// Prefixes the given data with its length.
+///
+/// The length is truncated if the data is longer than [`MAX_MESSAGE_LEN`][], see
+/// [`try_encode`][].
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let size = (data.len() as u16).to_be_bytes();
+    [&size[..], data].concat()
+}
+
+/// Prefixes the given data with its length, or returns an error if the data is too long.
+pub fn try_encode(data: &[u8]) -> Result<Vec<u8>> {
+    check_len(data)?;
+    Ok(encode(data))
+}
+
+fn check_len(data: &[u8]) -> Result<()> {
+    if data.len() > MAX_MESSAGE_LEN {
+        Err(crate::Error::MessageTooLarge(data.len()).into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Removes the first complete message from the given buffer and returns it, or returns `None`
+/// if the buffer does not contain a complete message yet.
+pub fn decode(buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let size = complete(buffer)?;
+    let msg = buffer[HEADER_LEN..HEADER_LEN + size].to_vec();
+    buffer.drain(..HEADER_LEN + size);
+    trace!("received message: {:x?}", msg);
+    Some(msg)
+}
+
+/// Returns the size of the first message in the buffer if it is complete.
+fn complete(buffer: &[u8]) -> Option<usize> {
+    match *buffer {
+        [hi, lo, ref rest @ ..] => {
+            let size = usize::from(u16::from_be_bytes([hi, lo]));
+            (rest.len() >= size).then_some(size)
+        }
+        _ => None,
+    }
+}
+
+/// Reads a single message from the given reader.
+pub fn read<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut size = [0; HEADER_LEN];
+    reader.read_exact(&mut size)?;
+    let size = usize::from(u16::from_be_bytes(size));
+    let mut msg = vec![0u8; size];
+    reader.read_exact(&mut msg)?;
+    trace!("received message: {:x?}", msg);
+    Ok(msg)
+}
+
+/// Reads a single message from the given reader, completing a partial message from the buffer.
+///
+/// If reading fails, for example because the read timeout expired, the data read so far is kept
+/// in the buffer so that the message can be completed by the next call.
+pub fn read_buffered<R: Read>(reader: &mut R, buffer: &mut Vec<u8>) -> Result<Vec<u8>> {
+    fill(reader, buffer)?;
+    Ok(decode(buffer).expect("buffer contains a complete message"))
+}
+
+/// Reads a single message from the given reader into the given message buffer, completing a
+/// partial message from the buffer, see [`read_buffered`][].
+pub fn read_into<R: Read>(reader: &mut R, buffer: &mut Vec<u8>, msg: &mut Vec<u8>) -> Result<()> {
+    msg.clear();
+    let size = fill(reader, buffer)?;
+    msg.extend_from_slice(&buffer[HEADER_LEN..HEADER_LEN + size]);
+    buffer.drain(..HEADER_LEN + size);
+    trace!("received message: {:x?}", msg);
+    Ok(())
+}
+
+/// Reads from the given reader until the buffer contains a complete message and returns its
+/// size.
+fn fill<R: Read>(reader: &mut R, buffer: &mut Vec<u8>) -> Result<usize> {
+    loop {
+        if let Some(size) = complete(buffer) {
+            return Ok(size);
+        }
+        match read_some(reader, buffer) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Reads the missing part of the header or the message into the buffer, keeping the data read
+/// so far if reading fails.
+fn read_some<R: Read>(reader: &mut R, buffer: &mut Vec<u8>) -> Result<()> {
+    let start = buffer.len();
+    buffer.resize(start + missing(buffer), 0);
+    let result = reader.read(&mut buffer[start..]);
+    buffer.truncate(start + *result.as_ref().unwrap_or(&0));
+    match result? {
+        0 => Err(ErrorKind::UnexpectedEof.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Reads a single message from the given stream, waiting at most until the deadline.
+///
+/// Returns `None` if the deadline expires before the message is complete.  The data read so far
+/// is kept in the buffer so that the message can be completed by the next call.  The read
+/// timeout of the stream is restored before returning.
+pub fn read_until(
+    stream: &mut TcpStream,
+    buffer: &mut Vec<u8>,
+    deadline: Instant,
+) -> Result<Option<Vec<u8>>> {
+    let timeout = stream.read_timeout()?;
+    let result = fill_until(stream, buffer, deadline);
+    stream.set_read_timeout(timeout)?;
+    result
+}
+
+fn fill_until(
+    stream: &mut TcpStream,
+    buffer: &mut Vec<u8>,
+    deadline: Instant,
+) -> Result<Option<Vec<u8>>> {
+    loop {
+        if let Some(msg) = decode(buffer) {
+            return Ok(Some(msg));
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(None);
+        }
+        stream.set_read_timeout(Some(remaining))?;
+        match read_some(stream, buffer) {
+            Ok(()) => {}
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Ok(None)
+            }
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Returns the number of bytes that are missing to complete the header or the message.
+fn missing(buffer: &[u8]) -> usize {
+    match *buffer {
+        [hi, lo, ..] => HEADER_LEN + usize::from(u16::from_be_bytes([hi, lo])) - buffer.len(),
+        _ => HEADER_LEN - buffer.len(),
+    }
+}
+
+/// Writes a single message to the given writer.
+///
+/// The length prefix and the message are written with a vectored write, so that they are sent
+/// together without copying the message into a temporary buffer.
+/// Returns an error if the message is too long to be framed.
+pub fn write<W: Write>(writer: &mut W, data: &[u8]) -> Result<()> {
+    check_len(data)?;
+    trace!("sending message: {:x?}", data);
+    let size = (data.len() as u16).to_be_bytes();
+    let mut slices = [IoSlice::new(&size), IoSlice::new(data)];
+    let mut slices = &mut slices[..];
+    while !slices.is_empty() {
+        match writer.write_vectored(slices) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(n) => IoSlice::advance_slices(&mut slices, n),
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,117 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Ready-made targets for coverage-guided fuzzing.
+//!
+//! Every function in this module accepts arbitrary input data, passes it to a part of the card
+//! stack and panics if an invariant is violated.  They are meant to be called from the fuzz
+//! targets of a fuzzer like [`cargo-fuzz`](https://github.com/rust-fuzz/cargo-fuzz).  The
+//! `fuzz` directory of this repository contains a `cargo-fuzz` project with targets for all
+//! functions that can be used as a template.
+//!
+//! To fuzz a card implementation, add a target like this to `fuzz/fuzz_targets` in your project:
+//!
+//! ```ignore
+//! #![no_main]
+//!
+//! use libfuzzer_sys::fuzz_target;
+//!
+//! fuzz_target!(|data: &[u8]| {
+//!     vpicc::fuzz::card(&mut my_card::MyCard::new(), data);
+//! });
+//! ```
+//!
+//! The functions can also be used to replay inputs without a fuzzer:
+//!
+//! ```
+//! vpicc::fuzz::card(&mut vpicc::DummySmartCard, &[0x00, 0x01, 0x01, 0x00, 0x04, 0x00, 0xa4, 0x04, 0x00]);
+//! ```
+
+use std::io::Cursor;
+
+use crate::{apdu::Command, frame, tlv, PowerState, Request, VSmartCard};
+
+/// Decodes the input as a stream of vpcd frames.
+///
+/// Checks that the buffered and the streaming decoder yield the same messages and that the
+/// messages are encoded to the consumed input.
+pub fn frame(data: &[u8]) {
+    let mut buffer = data.to_vec();
+    let mut reader = Cursor::new(data);
+    let mut consumed = 0;
+    while let Some(msg) = frame::decode(&mut buffer) {
+        let encoded = frame::encode(&msg);
+        assert_eq!(encoded, data[consumed..consumed + encoded.len()]);
+        consumed += encoded.len();
+        assert_eq!(buffer, data[consumed..]);
+        let read = frame::read(&mut reader).expect("streaming decoder failed on complete frame");
+        assert_eq!(read, msg);
+    }
+    assert!(frame::read(&mut reader).is_err());
+}
+
+/// Parses the input as a vpcd message and, if it is a command APDU, as a [`Command`][].
+///
+/// Checks that the parsed fields are consistent with the input.
+pub fn apdu(data: &[u8]) {
+    if let Ok(Request::Apdu(msg)) = Request::try_from(data.to_vec()) {
+        assert_eq!(msg, data);
+    }
+    let Some(command) = Command::parse(data) else {
+        return;
+    };
+    assert_eq!(
+        [command.cla, command.ins, command.p1, command.p2],
+        data[..4]
+    );
+    let input = data[4..].as_ptr_range();
+    let value = command.data.as_ptr_range();
+    assert!(command.data.is_empty() || (input.start <= value.start && value.end <= input.end));
+    if let Some(le) = command.le {
+        assert!((1..=65536).contains(&le));
+    }
+}
+
+/// Parses the input as a sequence of BER-TLV data objects, descending into constructed data
+/// objects.
+///
+/// Checks that the values are within the input.
+pub fn tlv(data: &[u8]) {
+    fn visit(data: &[u8], depth: usize) {
+        for tlv in tlv::iter(data).flatten() {
+            assert!(tlv.value.len() <= data.len());
+            // limit the recursion for deeply nested inputs
+            if tlv.is_constructed() && depth < 32 {
+                visit(tlv.value, depth + 1);
+            }
+        }
+    }
+    visit(data, 0);
+}
+
+/// Decodes the input as a stream of vpcd frames and passes them to the given card like a
+/// [`Connection`][`crate::Connection`] would, tracking the power state.
+///
+/// Invalid control messages are skipped.  Checks that every command APDU is answered with at
+/// least a status word and that the ATR is not empty.
+pub fn card<V: VSmartCard + ?Sized>(card: &mut V, data: &[u8]) {
+    let mut buffer = data.to_vec();
+    let mut power = PowerState::Off;
+    while let Some(msg) = frame::decode(&mut buffer) {
+        let Ok(request) = Request::try_from(msg) else {
+            continue;
+        };
+        let response = request.handle_with_state(card, &mut power);
+        match request {
+            Request::Apdu(_) => {
+                let response = response.expect("missing response to command APDU");
+                assert!(response.len() >= 2, "response without status word");
+            }
+            Request::GetAtr => {
+                let atr = response.expect("missing ATR");
+                assert!(!atr.is_empty(), "empty ATR");
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,207 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! A protocol fuzzer for vpcd implementations.
+//!
+//! [`Fuzzer`][] connects to a vpcd server like a card would, sends malformed and boundary frames
+//! and checks after every case whether the server still accepts connections.  This makes it
+//! possible to test the server side of the vsmartcard protocol.
+//!
+//! # Example
+//!
+//! ```no_run
+//! fn main() -> std::io::Result<()> {
+//!     let mut fuzzer = vpicc::fuzzer::Fuzzer::new("127.0.0.1:35963".parse().unwrap());
+//!     fuzzer.set_iterations(1000);
+//!     let report = fuzzer.run()?;
+//!     for case in &report.crashes {
+//!         println!("server crashed after case {}: {:x?}", case.name, case.data);
+//!     }
+//!     Ok(())
+//! }
+//! ```
+
+use std::{
+    io::{Result, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+
+use crate::{frame, rng::Rng, DEFAULT_ATR};
+
+/// The default number of random cases generated by [`Fuzzer`][].
+pub const DEFAULT_ITERATIONS: usize = 100;
+/// The default time [`Fuzzer`][] waits for the server to accept a connection.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The raw data sent to the server in a single fuzzing case.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Case {
+    /// A short description of the case.
+    pub name: &'static str,
+    /// The bytes sent to the server, including malformed length prefixes.
+    pub data: Vec<u8>,
+}
+
+/// The result of a fuzzing run.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// The number of executed cases.
+    pub executed: usize,
+    /// The cases after which the server stopped accepting connections.
+    pub crashes: Vec<Case>,
+}
+
+/// Sends malformed frames to a vpcd server, see the [module documentation][`self`].
+#[derive(Clone, Debug)]
+pub struct Fuzzer {
+    addr: SocketAddr,
+    seed: u64,
+    iterations: usize,
+    timeout: Duration,
+}
+
+impl Fuzzer {
+    /// Creates a fuzzer for the vpcd server at the given address.
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            seed: 0,
+            iterations: DEFAULT_ITERATIONS,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Sets the seed for the random cases, defaulting to zero.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    /// Sets the number of random cases, defaulting to [`DEFAULT_ITERATIONS`][].
+    pub fn set_iterations(&mut self, iterations: usize) {
+        self.iterations = iterations;
+    }
+
+    /// Sets the time to wait for the server, defaulting to [`DEFAULT_TIMEOUT`][].
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Returns all cases in the order they are executed by [`run`][`Fuzzer::run`].
+    ///
+    /// The fixed boundary cases are followed by the random cases generated from the seed.
+    pub fn cases(&self) -> Vec<Case> {
+        let mut cases = vec![
+            Case {
+                name: "empty frame",
+                data: frame::encode(&[]),
+            },
+            Case {
+                name: "partial header",
+                data: vec![0x00],
+            },
+            Case {
+                name: "truncated frame",
+                data: [&[0x00, 0x10][..], &[0x90, 0x00]].concat(),
+            },
+            Case {
+                name: "maximum length frame",
+                data: frame::encode(&vec![0xff; usize::from(u16::MAX)]),
+            },
+            Case {
+                name: "maximum length header without data",
+                data: vec![0xff, 0xff],
+            },
+            Case {
+                name: "oversized ATR",
+                data: frame::encode(&[DEFAULT_ATR; 8].concat()),
+            },
+            Case {
+                name: "single byte response",
+                data: frame::encode(&[0x90]),
+            },
+            Case {
+                name: "many small frames",
+                data: frame::encode(&[0x90, 0x00]).repeat(1000),
+            },
+        ];
+        let mut rng = Rng::new(self.seed);
+        for _ in 0..self.iterations {
+            let len = rng.below(512);
+            let data: Vec<u8> = (0..len).map(|_| rng.next_u64() as u8).collect();
+            let case = match rng.below(3) {
+                0 => Case {
+                    name: "random frame",
+                    data: frame::encode(&data),
+                },
+                1 => {
+                    let claimed = rng.below(usize::from(u16::MAX) + 1) as u16;
+                    Case {
+                        name: "random frame with wrong length",
+                        data: [&claimed.to_be_bytes()[..], &data].concat(),
+                    }
+                }
+                _ => Case {
+                    name: "random bytes",
+                    data,
+                },
+            };
+            cases.push(case);
+        }
+        cases
+    }
+
+    /// Executes all cases and returns a report.
+    ///
+    /// The run stops at the first case after which the server no longer accepts connections.
+    /// An error is returned if the server cannot be reached before the first case.
+    pub fn run(&mut self) -> Result<Report> {
+        let mut report = Report::default();
+        let mut stream = self.connect()?;
+        for case in self.cases() {
+            debug!("Sending case {}: {} bytes", case.name, case.data.len());
+            self.send(&mut stream, &case);
+            report.executed += 1;
+            match self.connect() {
+                Ok(next) => stream = next,
+                Err(err) => {
+                    warn!("Server unreachable after case {}: {}", case.name, err);
+                    report.crashes.push(case);
+                    break;
+                }
+            }
+        }
+        info!(
+            "Executed {} cases, found {} crashes",
+            report.executed,
+            report.crashes.len()
+        );
+        Ok(report)
+    }
+
+    fn send(&self, stream: &mut TcpStream, case: &Case) {
+        // wait briefly for the first request so that the case is processed as a response
+        stream.set_read_timeout(Some(self.timeout / 10)).ok();
+        if let Ok(request) = frame::read(stream) {
+            debug!("Received request {:x?}", request);
+        }
+        if let Err(err) = stream.write_all(&case.data) {
+            debug!("Failed to send case {}: {}", case.name, err);
+        }
+        stream.shutdown(Shutdown::Both).ok();
+    }
+
+    fn connect(&self) -> Result<TcpStream> {
+        let start = Instant::now();
+        loop {
+            match TcpStream::connect_timeout(&self.addr, self.timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(err) if start.elapsed() >= self.timeout => return Err(err),
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        }
+    }
+}
@@ -0,0 +1,39 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Helpers for hex-encoded APDUs in scripts and traces.
+
+use std::io::{Error, ErrorKind, Result};
+
+/// Decodes a hex string, ignoring whitespace and colons between the bytes.
+pub fn decode(s: &str) -> Result<Vec<u8>> {
+    let digits: Vec<u8> = s
+        .bytes()
+        .filter(|b| !b.is_ascii_whitespace() && *b != b':')
+        .collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("odd number of hex digits in {:?}", s),
+        ));
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("invalid hex string {:?}", s),
+                    )
+                })
+        })
+        .collect()
+}
+
+/// Encodes bytes as a lowercase hex string without separators.
+pub fn encode(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
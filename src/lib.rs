@@ -12,7 +12,7 @@
 //! ## Running a dummy smartcard
 //!
 //! ```no_run
-//! fn main() -> std::io::Result<()> {
+//! fn main() -> vpicc::Result<()> {
 //!     vpicc::connect()?.run(&mut vpicc::DummySmartCard)
 //! }
 //! ```
@@ -29,7 +29,7 @@
 //!     }
 //! }
 //!
-//! fn main() -> std::io::Result<()> {
+//! fn main() -> vpicc::Result<()> {
 //!     vpicc::connect()?.run(&mut Card)
 //! }
 //! ```
@@ -38,12 +38,136 @@
 
 use std::{
     fmt::Display,
-    io::{Error, ErrorKind, Read, Result, Write},
-    net::{Ipv4Addr, SocketAddr, TcpStream, ToSocketAddrs},
+    io::{self, ErrorKind, Read, Write},
+    net::{Ipv4Addr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, PoisonError,
+    },
+    thread::{self, Scope, ScopedJoinHandle},
+    time::{Duration, Instant, SystemTime},
 };
 
 use log::{debug, info, trace};
 
+pub mod admin;
+#[cfg(feature = "tokio")]
+pub mod aio;
+pub mod android;
+pub mod apdu;
+pub mod atr;
+pub mod control;
+pub mod coverage;
+#[cfg(all(unix, feature = "daemon"))]
+pub mod daemon;
+#[cfg(feature = "dbus")]
+pub mod dbus;
+pub mod debugger;
+pub mod diff;
+pub mod fault;
+pub mod fuzz;
+pub mod fuzzer;
+pub mod middleware;
+pub mod mock;
+pub mod model;
+pub mod mse;
+pub mod mux;
+pub mod names;
+pub mod observer;
+#[cfg(feature = "plugin")]
+pub mod plugin;
+pub mod pool;
+pub mod process;
+pub mod profiles;
+pub mod pso;
+pub mod router;
+pub mod script;
+pub mod selftest;
+pub mod state;
+pub mod stats;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+pub mod timing;
+pub mod tlv;
+pub mod trace;
+pub mod vectors;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+mod buffer;
+mod builder;
+mod error;
+mod fallible;
+mod farm;
+mod frame;
+mod hex;
+mod listener;
+mod power;
+mod recording;
+mod registry;
+mod rng;
+mod scheduler;
+mod supervisor;
+
+/// Implements [`apdu::Applet`][] by routing commands to the methods of an impl block.
+///
+/// Methods marked with `#[apdu(ins = ...)]` handle all commands with the given instruction,
+/// methods marked with `#[apdu(cla = ..., ins = ...)]` only commands that also have the given
+/// class.  The methods take a [`apdu::Command`][] and return the response data or an error
+/// status word.  Commands with an unknown instruction are rejected with
+/// [`apdu::SW_INS_NOT_SUPPORTED`][], commands with a known instruction but a different class with
+/// [`apdu::SW_CLA_NOT_SUPPORTED`][].  A method marked with `#[reset_security]` implements
+/// [`apdu::Applet::reset_security`][].
+///
+/// This macro requires the `derive` feature.
+///
+/// # Example
+///
+/// ```
+/// use vpicc::{apdu::{self, Applet, Command}, VSmartCard};
+///
+/// struct Card {
+///     counter: u8,
+/// }
+///
+/// #[vpicc::applet]
+/// impl Card {
+///     #[apdu(ins = 0xa4)]
+///     fn select(&mut self, _command: &Command<'_>) -> Result<Vec<u8>, u16> {
+///         Ok(Vec::new())
+///     }
+///
+///     #[apdu(cla = 0x80, ins = 0x10)]
+///     fn increment(&mut self, _command: &Command<'_>) -> Result<Vec<u8>, u16> {
+///         self.counter = self.counter.checked_add(1).ok_or(apdu::SW_CONDITIONS_NOT_SATISFIED)?;
+///         Ok(vec![self.counter])
+///     }
+/// }
+///
+/// impl VSmartCard for Card {
+///     fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+///         self.execute_command(msg)
+///     }
+/// }
+///
+/// let mut card = Card { counter: 0 };
+/// assert_eq!(card.execute(&[0x80, 0x10, 0x00, 0x00]), [0x01, 0x90, 0x00]);
+/// assert_eq!(card.execute(&[0x00, 0x10, 0x00, 0x00]), [0x6e, 0x00]);
+/// assert_eq!(card.execute(&[0x00, 0xb0, 0x00, 0x00]), [0x6d, 0x00]);
+/// ```
+#[cfg(feature = "derive")]
+pub use vpicc_macros::applet;
+
+pub use builder::{builder, ConnectionBuilder};
+pub use error::{Error, Result};
+pub use fallible::{ErrorPolicy, TryVSmartCard};
+pub use farm::{Farm, FarmStats};
+pub use listener::{listen, CardFactory, Listener, Shared, SharedCard};
+pub use recording::{Call, RecordingCard};
+pub use registry::{BoxedCard, CardStats, Registry};
+pub use scheduler::Scheduler;
+pub use supervisor::{Backoff, LinkStats, Supervisor};
+
 /// The default host used in [`connect`][].
 pub const DEFAULT_HOST: Ipv4Addr = Ipv4Addr::LOCALHOST;
 /// The default port used in [`connect`][].
@@ -52,6 +176,11 @@ pub const DEFAULT_PORT: u16 = 35963;
 pub const DEFAULT_ATR: &[u8] = &[
     0x3b, 0x95, 0x13, 0x81, 0x01, 0x80, 0x73, 0xff, 0x01, 0x00, 0x0B,
 ];
+/// The maximum length of a response or an ATR that can be sent to vpcd.
+///
+/// Sending a longer message fails with an [`InvalidInput`][`ErrorKind::InvalidInput`] error
+/// instead of sending a corrupted frame.
+pub const MAX_MESSAGE_LEN: usize = u16::MAX as usize;
 
 /// Connects to the vpcd dameon using [`DEFAULT_HOST`][] and [`DEFAULT_PORT`][].
 pub fn connect() -> Result<Connection> {
@@ -61,7 +190,72 @@ pub fn connect() -> Result<Connection> {
 /// Connects to the vpcd daemon at the given address.
 pub fn connect_socket<A: ToSocketAddrs + Display>(addr: A) -> Result<Connection> {
     info!("Connecting to vpcd on {}", addr);
-    TcpStream::connect(addr).map(Connection::from)
+    Ok(TcpStream::connect(addr).map(Connection::from)?)
+}
+
+/// Waits until vpcd at the given address accepts connections and speaks the vpcd protocol.
+///
+/// This repeatedly tries to connect to vpcd until it accepts the connection and sends a valid
+/// request, for example when pcscd checks whether a card is present.  The returned connection is
+/// ready to use, and the request received during the probe is handled by the first call to
+/// [`poll`][`Connection::poll`].  If vpcd is not ready before the timeout expires, an error with
+/// the kind [`ErrorKind::TimedOut`][] is returned.
+///
+/// This is useful in container setups where vpcd is started concurrently with the card.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// fn main() -> vpicc::Result<()> {
+///     let connection = vpicc::wait_for_vpcd("vpcd:35963", Duration::from_secs(30))?;
+///     connection.run(&mut vpicc::DummySmartCard)
+/// }
+/// ```
+pub fn wait_for_vpcd<A: ToSocketAddrs + Display>(addr: A, timeout: Duration) -> Result<Connection> {
+    info!("Waiting for vpcd on {}", addr);
+    let deadline = Instant::now() + timeout;
+    loop {
+        match probe(&addr, deadline) {
+            Ok(connection) => return Ok(connection),
+            Err(err) => debug!("vpcd on {} is not ready: {}", addr, err),
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                format!("vpcd on {} did not become ready", addr),
+            )
+            .into());
+        }
+        thread::sleep(remaining.min(Duration::from_millis(100)));
+    }
+}
+
+fn probe<A: ToSocketAddrs>(addr: &A, deadline: Instant) -> Result<Connection> {
+    let mut last_error = io::Error::new(ErrorKind::NotFound, "address did not resolve");
+    for addr in addr.to_socket_addrs()? {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        let mut stream = match TcpStream::connect_timeout(&addr, remaining) {
+            Ok(stream) => stream,
+            Err(err) => {
+                last_error = err;
+                continue;
+            }
+        };
+        let mut rx = Vec::new();
+        let msg = frame::read_until(&mut stream, &mut rx, deadline)?
+            .ok_or_else(|| io::Error::new(ErrorKind::TimedOut, "no request received"))?;
+        Request::try_from(msg.clone())?;
+        let mut connection = Connection::from(stream);
+        connection.rx = frame::encode(&msg);
+        return Ok(connection);
+    }
+    Err(last_error.into())
 }
 
 /// A virtual smartcard implementation.
@@ -84,104 +278,943 @@ pub trait VSmartCard {
     /// Handles a Reset command.
     fn reset(&mut self) {}
 
+    /// Handles a cold reset, i. e. the card has just been powered up.
+    ///
+    /// This is called after [`power_on`][`VSmartCard::power_on`], or after
+    /// [`reset`][`VSmartCard::reset`] if the card was powered off, see [`PowerState`][].
+    fn cold_reset(&mut self) {}
+
+    /// Handles a warm reset of a card that stays powered.
+    ///
+    /// This is called after [`reset`][`VSmartCard::reset`] if the card was powered on, see
+    /// [`PowerState`][].
+    fn warm_reset(&mut self) {}
+
     /// Executes the given APDU command and returns the response APDU.
     fn execute(&mut self, msg: &[u8]) -> Vec<u8>;
+
+    /// Executes the given APDU command and returns the response APDU without a heap allocation
+    /// for short responses.
+    ///
+    /// This is used by [`Connection`][] to handle APDUs.  The default implementation calls
+    /// [`execute`][`VSmartCard::execute`].  Cards that mostly return short responses, like a
+    /// bare status word, can implement this method to avoid allocating a `Vec` per exchange.
+    fn execute_small(&mut self, msg: &[u8]) -> apdu::Response {
+        apdu::Response::from_vec(self.execute(msg))
+    }
+
+    /// Returns a description of the features supported by this card, defaulting to
+    /// [`Capabilities::default`][].
+    ///
+    /// The capabilities are informational.  They are used by the [`Router`][`router::Router`],
+    /// [`Atr::set_capabilities`][`atr::Atr::set_capabilities`] and the `info` example, so that
+    /// all of them describe the card consistently.
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+}
+
+impl<T: VSmartCard + ?Sized> VSmartCard for Box<T> {
+    fn atr(&self) -> &[u8] {
+        (**self).atr()
+    }
+
+    fn power_on(&mut self) {
+        (**self).power_on()
+    }
+
+    fn power_off(&mut self) {
+        (**self).power_off()
+    }
+
+    fn reset(&mut self) {
+        (**self).reset()
+    }
+
+    fn cold_reset(&mut self) {
+        (**self).cold_reset()
+    }
+
+    fn warm_reset(&mut self) {
+        (**self).warm_reset()
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        (**self).execute(msg)
+    }
+
+    fn execute_small(&mut self, msg: &[u8]) -> apdu::Response {
+        (**self).execute_small(msg)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        (**self).capabilities()
+    }
 }
 
 /// A connection to the vpcd daemon.
+///
+/// The connection is generic over its transport, which can be any byte stream implementing
+/// [`Read`][] and [`Write`][], for example a Unix domain socket, a serial bridge or an in-memory
+/// pipe in tests.  [`connect`][] and [`connect_socket`][] return a connection using a
+/// [`TcpStream`][] as used by vpcd.  Some methods, like
+/// [`run_scoped`][`Connection::run_scoped`], are only available for TCP connections.
+///
+/// # Example
+///
+/// ```
+/// # #[cfg(unix)] {
+/// use std::{io::{ErrorKind, Write}, net::Shutdown, os::unix::net::UnixStream};
+///
+/// let (mut vpcd, stream) = UnixStream::pair()?;
+/// let (session, _) = vpicc::vectors::session();
+/// vpcd.write_all(&session)?;
+/// vpcd.shutdown(Shutdown::Write)?;
+///
+/// let result = vpicc::Connection::new(stream).run(&mut vpicc::DummySmartCard);
+/// assert_eq!(result.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+/// # }
+/// # Ok::<_, std::io::Error>(())
+/// ```
 #[derive(Debug)]
-pub struct Connection {
-    stream: TcpStream,
+pub struct Connection<T = TcpStream> {
+    stream: T,
+    rx: Vec<u8>,
+    power: PowerState,
+    started_at: SystemTime,
+    pool: Option<pool::BufferPool>,
+    latency: Latency,
+    last_sent: Option<Instant>,
+    shutdown: Arc<AtomicBool>,
+    fallback_atr: Option<Vec<u8>>,
 }
 
-impl Connection {
+impl<T: Read + Write> Connection<T> {
+    /// Creates a connection using the given transport.
+    pub fn new(stream: T) -> Self {
+        Self {
+            stream,
+            rx: Vec::new(),
+            power: PowerState::default(),
+            started_at: SystemTime::now(),
+            pool: None,
+            latency: Latency::default(),
+            last_sent: None,
+            shutdown: Arc::default(),
+            fallback_atr: None,
+        }
+    }
+
     /// Handles all commands from this connection using the given card.
     ///
-    /// This is equivalent to calling [`poll`][`Connection::poll`] until a call fails.
+    /// This is equivalent to calling [`poll`][`Connection::poll`] until a call fails.  If the
+    /// connection is shut down using a [`ShutdownHandle`][], `Ok(())` is returned.
+    ///
+    /// If the transport has a read timeout, it is used as a liveness check:  While no request
+    /// is pending, a timeout is ignored and the connection keeps waiting for the next request.
+    /// If vpcd stops sending in the middle of a request, the timeout error is returned.
     pub fn run<V: VSmartCard>(mut self, card: &mut V) -> Result<()> {
-        loop {
-            self.poll(card)?;
+        self.serve(card)
+    }
+
+    fn serve<V: VSmartCard>(&mut self, card: &mut V) -> Result<()> {
+        while !self.is_shutdown() {
+            self.poll(card).or_else(|err| self.check_error(err))?;
         }
+        Ok(())
     }
 
     /// Handles a single command from this connection using the given card.
     pub fn poll<V: VSmartCard>(&mut self, card: &mut V) -> Result<()> {
-        let msg = self.read()?;
-        if msg.is_empty() {
-            return Err(Error::new(ErrorKind::Other, "received an empty message"));
-        }
-
-        if msg.len() == 1 {
-            match Command::try_from(msg[0])? {
-                Command::PowerOff => card.power_off(),
-                Command::PowerOn => card.power_on(),
-                Command::Reset => card.reset(),
-                Command::GetAtr => {
-                    debug!("Sending ATR");
-                    self.send(card.atr())?;
-                }
-            }
+        self.poll_checked(card, |_| Ok(()))
+    }
+
+    /// Handles all commands from this connection using the given fallible card.
+    ///
+    /// This is equivalent to calling [`try_poll`][`Connection::try_poll`] until a call fails.  If
+    /// the connection is shut down using a [`ShutdownHandle`][], `Ok(())` is returned.  A read
+    /// timeout is handled like in [`run`][`Connection::run`].
+    pub fn try_run<V: TryVSmartCard + ?Sized>(
+        mut self,
+        card: &mut V,
+        policy: ErrorPolicy,
+    ) -> Result<()> {
+        while !self.is_shutdown() {
+            self.try_poll(card, policy)
+                .or_else(|err| self.check_error(err))?;
+        }
+        Ok(())
+    }
+
+    fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::Acquire)
+    }
+
+    /// Moves the state of this connection to a connection using the given transport and returns
+    /// the previous transport.
+    fn with_transport<U>(self, transport: U) -> (T, Connection<U>) {
+        let connection = Connection {
+            stream: transport,
+            rx: self.rx,
+            power: self.power,
+            started_at: self.started_at,
+            pool: self.pool,
+            latency: self.latency,
+            last_sent: self.last_sent,
+            shutdown: self.shutdown,
+            fallback_atr: self.fallback_atr,
+        };
+        (self.stream, connection)
+    }
+
+    /// Checks whether the given error from a poll ends the connection.
+    fn check_error(&self, err: Error) -> Result<()> {
+        if self.is_shutdown() {
+            debug!("Connection shut down: {}", err);
+            Ok(())
+        } else if self.rx.is_empty() && is_timeout(&err) {
+            trace!("Connection idle: {}", err);
+            Ok(())
         } else {
-            debug!("APDU received");
-            let response = card.execute(&msg);
-            self.send(&response)?;
+            Err(err)
         }
+    }
 
+    /// Handles a single command from this connection using the given fallible card.
+    ///
+    /// If the card returns an error, it is handled according to the given policy:  either the
+    /// status word is sent to vpcd, or no response is sent and the error is returned, so that
+    /// the connection can be closed.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # #[cfg(unix)] {
+    /// use std::{io::{self, Read, Write}, os::unix::net::UnixStream};
+    /// use vpicc::{ErrorPolicy, TryVSmartCard};
+    ///
+    /// struct Card;
+    ///
+    /// impl TryVSmartCard for Card {
+    ///     type Error = io::Error;
+    ///
+    ///     fn try_execute(&mut self, _msg: &[u8]) -> io::Result<Vec<u8>> {
+    ///         Err(io::Error::other("backend disconnected"))
+    ///     }
+    /// }
+    ///
+    /// let (mut vpcd, stream) = UnixStream::pair()?;
+    /// let mut connection = vpicc::Connection::new(stream);
+    /// vpcd.write_all(&[0x00, 0x04, 0x00, 0xa4, 0x04, 0x00])?;
+    /// connection.try_poll(&mut Card, ErrorPolicy::Respond(0x6f00))?;
+    /// let mut response = [0; 4];
+    /// vpcd.read_exact(&mut response)?;
+    /// assert_eq!(response, [0x00, 0x02, 0x6f, 0x00]);
+    ///
+    /// vpcd.write_all(&[0x00, 0x04, 0x00, 0xa4, 0x04, 0x00])?;
+    /// let err = connection.try_poll(&mut Card, ErrorPolicy::Close).unwrap_err();
+    /// assert_eq!(err.to_string(), "backend disconnected");
+    /// # }
+    /// # Ok::<_, vpicc::Error>(())
+    /// ```
+    pub fn try_poll<V: TryVSmartCard + ?Sized>(
+        &mut self,
+        card: &mut V,
+        policy: ErrorPolicy,
+    ) -> Result<()> {
+        let mut card = fallible::Checked::new(card, policy);
+        self.poll_checked(&mut card, fallible::Checked::check)
+    }
+
+    fn poll_checked<V, F>(&mut self, card: &mut V, check: F) -> Result<()>
+    where
+        V: VSmartCard,
+        F: FnOnce(&mut V) -> Result<()>,
+    {
+        let msg = match &self.pool {
+            Some(pool) => {
+                let mut msg = pool.get();
+                frame::read_into(&mut self.stream, &mut self.rx, &mut msg)?;
+                msg
+            }
+            None => frame::read_buffered(&mut self.stream, &mut self.rx)?,
+        };
+        let request = self.handle(msg, card, check)?;
+        if let (Some(pool), Request::Apdu(apdu)) = (&self.pool, request) {
+            pool.put(apdu);
+        }
         Ok(())
     }
 
-    fn read(&mut self) -> Result<Vec<u8>> {
-        let mut size = [0, 0];
-        self.stream.read_exact(&mut size)?;
-        let size = usize::from(u16::from_be_bytes(size));
-        let mut msg = vec![0u8; size];
-        self.stream.read_exact(&mut msg)?;
-        trace!("received message: {:x?}", msg);
-        Ok(msg)
+    fn handle<V, F>(&mut self, msg: Vec<u8>, card: &mut V, check: F) -> Result<Request>
+    where
+        V: VSmartCard,
+        F: FnOnce(&mut V) -> Result<()>,
+    {
+        let received = Instant::now();
+        if let Some(sent) = self.last_sent.take() {
+            self.latency.add_turnaround(received - sent);
+        }
+        let request = match (&self.pool, msg.as_slice()) {
+            (Some(pool), &[command]) => {
+                pool.put(msg);
+                Request::from_control(command)?
+            }
+            _ => Request::try_from(msg)?,
+        };
+        if let Some(mut response) = request.handle_small(card, &mut self.power) {
+            check(card)?;
+            if let (Request::GetAtr, true, Some(atr)) =
+                (&request, response.is_empty(), &self.fallback_atr)
+            {
+                debug!("Card returned an empty ATR, sending the fallback ATR");
+                response = apdu::Response::from_slice(atr);
+            }
+            self.latency.execution += received.elapsed();
+            self.latency.exchanges += 1;
+            frame::write(&mut self.stream, &response)?;
+            self.last_sent = Some(Instant::now());
+            if let Some(pool) = &self.pool {
+                if response.spilled() {
+                    pool.put(response.into_vec());
+                }
+            }
+        }
+        Ok(request)
     }
 
-    fn send(&mut self, data: &[u8]) -> Result<()> {
-        trace!("sending message: {:x?}", data);
-        let size = (data.len() as u16).to_be_bytes();
-        let msg = &[&size[..], data].concat();
-        self.stream.write_all(msg)?;
-        Ok(())
+    /// Sets the buffer pool used for commands and responses on this connection.
+    ///
+    /// Commands received by [`poll`][`Connection::poll`] and [`run`][`Connection::run`] are read
+    /// into buffers from the pool, and the command and response buffers are returned to the
+    /// pool after the response has been sent, see the [`pool`][] module.
+    pub fn set_buffer_pool(&mut self, pool: pool::BufferPool) {
+        self.pool = Some(pool);
+    }
+
+    /// Sets the ATR that is sent to vpcd if the card returns an empty ATR.
+    pub fn set_fallback_atr(&mut self, atr: impl Into<Vec<u8>>) {
+        self.fallback_atr = Some(atr.into());
+    }
+
+    /// Returns the time at which this connection was established.
+    pub fn started_at(&self) -> SystemTime {
+        self.started_at
+    }
+
+    /// Returns the timing of the exchanges on this connection.
+    ///
+    /// This makes it possible to tell apart the time spent in the card from the time spent in
+    /// the network, vpcd and the PC/SC stack.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// fn main() -> vpicc::Result<()> {
+    ///     let mut connection = vpicc::connect()?;
+    ///     for _ in 0..100 {
+    ///         connection.poll(&mut vpicc::DummySmartCard)?;
+    ///     }
+    ///     let latency = connection.latency();
+    ///     println!("card: {:?}, round trip: {:?}", latency.mean_execution(), latency.min_turnaround);
+    ///     println!("TCP round trip: {:?}", connection.tcp_rtt()?);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn latency(&self) -> Latency {
+        self.latency
+    }
+
+    /// Returns the power state of the card as requested by vpcd on this connection.
+    pub fn power_state(&self) -> PowerState {
+        self.power
+    }
+
+    /// Returns a reference to the transport of this connection.
+    pub fn get_ref(&self) -> &T {
+        &self.stream
+    }
+
+    /// Returns a mutable reference to the transport of this connection.
+    ///
+    /// Reading from or writing to the transport directly can corrupt the framing.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.stream
+    }
+}
+
+impl Connection<TcpStream> {
+    /// Handles all commands from this connection using the given card while running background
+    /// threads that are joined before this function returns.
+    ///
+    /// The given function is called with a [`ConnectionScope`][] that can be used to spawn
+    /// threads, for example to change the state of the card from a controller.  The threads
+    /// can borrow local variables like with [`std::thread::scope`][].  Once the connection
+    /// ends, [`RunControl::is_stopped`][] returns true and the threads should return.  A thread
+    /// can also end the connection using [`RunControl::stop`][], in which case this function
+    /// returns `Ok(())`.  Otherwise, the error that ended the connection is returned after all
+    /// threads have been joined.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use std::time::Duration;
+    /// use vpicc::middleware::AdjustableAtr;
+    ///
+    /// fn main() -> vpicc::Result<()> {
+    ///     let mut card = AdjustableAtr::new(vpicc::DummySmartCard);
+    ///     let handle = card.handle();
+    ///     vpicc::connect()?.run_scoped(&mut card, |scope| {
+    ///         scope.spawn(|control| {
+    ///             // present a different ATR every minute until the connection ends
+    ///             while !control.wait(Duration::from_secs(60)) {
+    ///                 handle.set_next([0x3b, 0x80, 0x80, 0x01, 0x01]);
+    ///             }
+    ///         });
+    ///     })
+    /// }
+    /// ```
+    pub fn run_scoped<'env, V, F>(mut self, card: &mut V, spawn: F) -> Result<()>
+    where
+        V: VSmartCard,
+        F: for<'scope> FnOnce(&ConnectionScope<'scope, 'env>),
+    {
+        let control = Arc::new(RunControl {
+            stopped: Mutex::new(false),
+            condvar: Condvar::new(),
+            handle: self.shutdown_handle()?,
+        });
+        thread::scope(|scope| {
+            spawn(&ConnectionScope {
+                scope,
+                control: control.clone(),
+            });
+            let result = self.serve(card);
+            control.set_stopped();
+            result
+        })
+    }
+
+    /// Handles a single command from this connection using the given card if it is received
+    /// before the deadline.
+    ///
+    /// Returns the handled request, or `None` if no complete request was received before the
+    /// deadline.  A partially received request is buffered and completed by the next call to
+    /// this function or to [`poll`][`Connection::poll`].  The read timeout of the connection is
+    /// not affected.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use std::time::{Duration, Instant};
+    ///
+    /// fn main() -> vpicc::Result<()> {
+    ///     let mut connection = vpicc::connect()?;
+    ///     let mut card = vpicc::DummySmartCard;
+    ///     loop {
+    ///         let deadline = Instant::now() + Duration::from_millis(100);
+    ///         while connection.poll_deadline(&mut card, deadline)?.is_some() {}
+    ///         // periodic work
+    ///     }
+    /// }
+    /// ```
+    pub fn poll_deadline<V: VSmartCard>(
+        &mut self,
+        card: &mut V,
+        deadline: Instant,
+    ) -> Result<Option<Request>> {
+        match frame::read_until(&mut self.stream, &mut self.rx, deadline)? {
+            Some(msg) => self.handle(msg, card, |_| Ok(())).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the address of vpcd.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    /// Returns the local address of this connection.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+
+    /// Returns the transport used by this connection.
+    pub fn transport(&self) -> Transport {
+        Transport::Tcp
+    }
+
+    /// Returns a handle that can be used to shut down this connection from another thread.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use std::{thread, time::Duration};
+    ///
+    /// fn main() -> vpicc::Result<()> {
+    ///     let connection = vpicc::connect()?;
+    ///     let handle = connection.shutdown_handle()?;
+    ///     thread::spawn(move || {
+    ///         // for example after receiving SIGTERM
+    ///         thread::sleep(Duration::from_secs(60));
+    ///         handle.shutdown();
+    ///     });
+    ///     // returns Ok(()) once the handle has been used
+    ///     connection.run(&mut vpicc::DummySmartCard)
+    /// }
+    /// ```
+    pub fn shutdown_handle(&self) -> io::Result<ShutdownHandle> {
+        Ok(ShutdownHandle {
+            shutdown: self.shutdown.clone(),
+            stream: Arc::new(self.stream.try_clone()?),
+        })
+    }
+
+    /// Returns true if Nagle's algorithm is disabled for this connection, see
+    /// [`TcpStream::set_nodelay`][].
+    pub fn nodelay(&self) -> io::Result<bool> {
+        self.stream.nodelay()
+    }
+
+    /// Returns the smoothed round trip time of the TCP connection as estimated by the kernel.
+    ///
+    /// This is only supported on Linux and returns `None` on other platforms.
+    pub fn tcp_rtt(&self) -> io::Result<Option<Duration>> {
+        tcp_rtt(&self.stream)
+    }
+
+    /// Splits this connection into a read half and a write half.
+    ///
+    /// This makes it possible to receive requests on one thread while sending the responses
+    /// from another thread.  Note that vpcd expects exactly one response for every
+    /// [`Request::GetAtr`][] and [`Request::Apdu`][], in the order of the requests.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// fn main() -> vpicc::Result<()> {
+    ///     let (mut reader, mut writer) = vpicc::connect()?.into_split()?;
+    ///     let (sender, receiver) = std::sync::mpsc::channel::<Vec<u8>>();
+    ///     std::thread::spawn(move || {
+    ///         for response in receiver {
+    ///             writer.send(&response)?;
+    ///         }
+    ///         Ok::<_, vpicc::Error>(())
+    ///     });
+    ///     let mut card = vpicc::DummySmartCard;
+    ///     loop {
+    ///         if let Some(response) = reader.receive()?.handle(&mut card) {
+    ///             sender.send(response).unwrap();
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn into_split(self) -> io::Result<(ReadHalf, WriteHalf)> {
+        let writer = self.stream.try_clone()?;
+        Ok((
+            ReadHalf {
+                stream: self.stream,
+                rx: self.rx,
+            },
+            WriteHalf { stream: writer },
+        ))
     }
 }
 
+fn is_timeout(err: &Error) -> bool {
+    matches!(err, Error::Io(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut))
+}
+
 impl From<TcpStream> for Connection {
     fn from(stream: TcpStream) -> Self {
-        Self { stream }
+        Self::new(stream)
+    }
+}
+
+/// Spawns threads that are joined when [`Connection::run_scoped`][] returns.
+#[derive(Debug)]
+pub struct ConnectionScope<'scope, 'env: 'scope> {
+    scope: &'scope Scope<'scope, 'env>,
+    control: Arc<RunControl>,
+}
+
+impl<'scope, 'env> ConnectionScope<'scope, 'env> {
+    /// Spawns a thread that is called with the [`RunControl`][] of the connection.
+    pub fn spawn<F, T>(&self, f: F) -> ScopedJoinHandle<'scope, T>
+    where
+        F: FnOnce(&RunControl) -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        let control = self.control.clone();
+        self.scope.spawn(move || f(&control))
+    }
+
+    /// Returns the [`RunControl`][] of the connection.
+    pub fn control(&self) -> &RunControl {
+        &self.control
+    }
+}
+
+/// Coordinates the threads spawned by [`Connection::run_scoped`][] with the connection.
+#[derive(Debug)]
+pub struct RunControl {
+    stopped: Mutex<bool>,
+    condvar: Condvar,
+    handle: ShutdownHandle,
+}
+
+impl RunControl {
+    /// Returns true if the connection has ended or [`stop`][`RunControl::stop`] has been called.
+    pub fn is_stopped(&self) -> bool {
+        *self.stopped.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Waits until the connection ends or the timeout elapses and returns true if the
+    /// connection has ended.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let stopped = self.stopped.lock().unwrap_or_else(PoisonError::into_inner);
+        let (stopped, _) = self
+            .condvar
+            .wait_timeout_while(stopped, timeout, |stopped| !*stopped)
+            .unwrap_or_else(PoisonError::into_inner);
+        *stopped
+    }
+
+    /// Ends the connection like [`ShutdownHandle::shutdown`][].
+    pub fn stop(&self) {
+        if !self.set_stopped() {
+            self.handle.shutdown();
+        }
+    }
+
+    /// Marks the connection as stopped and returns true if it was already stopped.
+    fn set_stopped(&self) -> bool {
+        let mut stopped = self.stopped.lock().unwrap_or_else(PoisonError::into_inner);
+        let previous = std::mem::replace(&mut *stopped, true);
+        self.condvar.notify_all();
+        previous
+    }
+}
+
+/// Shuts down a [`Connection`][] from another thread, see [`Connection::shutdown_handle`][].
+///
+/// Clones of the handle refer to the same connection.
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    shutdown: Arc<AtomicBool>,
+    stream: Arc<TcpStream>,
+}
+
+impl ShutdownHandle {
+    /// Shuts down the connection.
+    ///
+    /// A blocking read of the connection is interrupted by shutting down the read side of the
+    /// socket, and [`run`][`Connection::run`] returns `Ok(())`.  A command that is being
+    /// executed by the card is completed first and its response is sent to vpcd, so vpcd never
+    /// sees a half-completed exchange.  The connection is closed once `run` returns.
+    pub fn shutdown(&self) {
+        if !self.shutdown.swap(true, Ordering::AcqRel) {
+            if let Err(err) = self.stream.shutdown(Shutdown::Read) {
+                debug!("Failed to shut down connection: {}", err);
+            }
+        }
+    }
+
+    /// Returns true if the connection has been shut down using this handle or a clone of it.
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::Acquire)
+    }
+}
+
+/// The transport of a [`Connection`][].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Transport {
+    /// A TCP connection as used by vpcd.
+    Tcp,
+}
+
+/// The timing of the exchanges on a [`Connection`][], see [`Connection::latency`][].
+///
+/// Only exchanges with a response, i. e. command APDUs and ATR requests, are measured.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Latency {
+    /// The number of measured exchanges.
+    pub exchanges: u64,
+    /// The total time spent in the card.
+    pub execution: Duration,
+    /// The number of measured turnarounds.
+    pub turnarounds: u64,
+    /// The total time between sending a response and receiving the next request.
+    ///
+    /// This includes the network round trip, the processing in vpcd and the PC/SC stack and
+    /// the time until the application sends its next command.
+    pub turnaround: Duration,
+    /// The shortest time between sending a response and receiving the next request.
+    ///
+    /// As vpcd waits for every response before sending the next request, this is an upper bound
+    /// for the round trip time of the transport.
+    pub min_turnaround: Option<Duration>,
+}
+
+impl Latency {
+    /// Returns the mean time spent in the card, or `None` if no exchange was measured.
+    pub fn mean_execution(&self) -> Option<Duration> {
+        mean(self.execution, self.exchanges)
+    }
+
+    /// Returns the mean turnaround, or `None` if no turnaround was measured.
+    pub fn mean_turnaround(&self) -> Option<Duration> {
+        mean(self.turnaround, self.turnarounds)
+    }
+
+    fn add_turnaround(&mut self, turnaround: Duration) {
+        self.turnarounds += 1;
+        self.turnaround += turnaround;
+        self.min_turnaround = Some(
+            self.min_turnaround
+                .map_or(turnaround, |min| min.min(turnaround)),
+        );
+    }
+}
+
+fn mean(total: Duration, count: u64) -> Option<Duration> {
+    // u32 is sufficient for any realistic session; saturate instead of failing
+    let count = u32::try_from(count).unwrap_or(u32::MAX);
+    (count > 0).then(|| total / count)
+}
+
+#[cfg(target_os = "linux")]
+fn tcp_rtt(stream: &TcpStream) -> io::Result<Option<Duration>> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut info = std::mem::MaybeUninit::<libc::tcp_info>::zeroed();
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            info.as_mut_ptr().cast(),
+            &mut len,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let info = unsafe { info.assume_init() };
+    Ok(Some(Duration::from_micros(info.tcpi_rtt.into())))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn tcp_rtt(_stream: &TcpStream) -> io::Result<Option<Duration>> {
+    Ok(None)
+}
+
+/// The receiving half of a [`Connection`][], see [`Connection::into_split`][].
+#[derive(Debug)]
+pub struct ReadHalf {
+    stream: TcpStream,
+    rx: Vec<u8>,
+}
+
+impl ReadHalf {
+    /// Receives the next request from vpcd.
+    pub fn receive(&mut self) -> Result<Request> {
+        Request::try_from(frame::read_buffered(&mut self.stream, &mut self.rx)?)
     }
 }
 
-enum Command {
+/// The sending half of a [`Connection`][], see [`Connection::into_split`][].
+#[derive(Debug)]
+pub struct WriteHalf {
+    stream: TcpStream,
+}
+
+impl WriteHalf {
+    /// Sends a response to vpcd.
+    pub fn send(&mut self, data: &[u8]) -> Result<()> {
+        Ok(frame::write(&mut self.stream, data)?)
+    }
+}
+
+/// A request received from vpcd.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Request {
+    /// A Power Off command.
     PowerOff,
+    /// A Power On command.
     PowerOn,
+    /// A Reset command.
     Reset,
+    /// A request for the ATR of the card.
     GetAtr,
+    /// A command APDU.
+    Apdu(Vec<u8>),
+}
+
+impl Request {
+    /// Passes this request to the given card and returns the response that has to be sent to
+    /// vpcd, if any.
+    ///
+    /// As the power state is not tracked, every Power On command is treated as a cold reset and
+    /// every Reset command as a warm reset.  Use [`handle_with_state`][`Request::handle_with_state`]
+    /// to distinguish them based on the previous commands.
+    pub fn handle<V: VSmartCard + ?Sized>(&self, card: &mut V) -> Option<Vec<u8>> {
+        let mut power = match self {
+            Self::PowerOn => PowerState::Off,
+            _ => PowerState::On,
+        };
+        self.handle_with_state(card, &mut power)
+    }
+
+    /// Passes this request to the given card, updating the given power state, and returns the
+    /// response that has to be sent to vpcd, if any.
+    ///
+    /// Power On and Reset commands for a card that is powered off cause a
+    /// [cold reset][`VSmartCard::cold_reset`], Reset commands for a powered card cause a
+    /// [warm reset][`VSmartCard::warm_reset`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use vpicc::{Call, DummySmartCard, PowerState, RecordingCard, Request};
+    ///
+    /// let mut card = RecordingCard::new(DummySmartCard);
+    /// let mut power = PowerState::default();
+    /// Request::Reset.handle_with_state(&mut card, &mut power);
+    /// Request::Reset.handle_with_state(&mut card, &mut power);
+    /// card.assert_calls(&[Call::Reset, Call::ColdReset, Call::Reset, Call::WarmReset]);
+    /// ```
+    pub fn handle_with_state<V: VSmartCard + ?Sized>(
+        &self,
+        card: &mut V,
+        power: &mut PowerState,
+    ) -> Option<Vec<u8>> {
+        self.handle_small(card, power).map(apdu::Response::into_vec)
+    }
+
+    /// Like [`handle_with_state`][`Request::handle_with_state`], but uses
+    /// [`VSmartCard::execute_small`][] to avoid heap allocations for short responses.
+    pub fn handle_small<V: VSmartCard + ?Sized>(
+        &self,
+        card: &mut V,
+        power: &mut PowerState,
+    ) -> Option<apdu::Response> {
+        match self {
+            Self::GetAtr => {
+                debug!("Sending ATR");
+                Some(apdu::Response::from_slice(card.atr()))
+            }
+            Self::Apdu(apdu) => {
+                debug!("APDU received: {}", names::describe(apdu));
+                Some(card.execute_small(apdu))
+            }
+            _ => {
+                power::handle(self, card, power);
+                None
+            }
+        }
+    }
 }
 
-impl TryFrom<u8> for Command {
+impl TryFrom<Vec<u8>> for Request {
     type Error = Error;
 
-    fn try_from(command: u8) -> Result<Self> {
-        // https://frankmorgner.github.io/vsmartcard/virtualsmartcard/api.html
+    fn try_from(msg: Vec<u8>) -> Result<Self> {
+        match msg.len() {
+            0 => Err(Error::Protocol("received an empty message".into())),
+            // https://frankmorgner.github.io/vsmartcard/virtualsmartcard/api.html
+            1 => Self::from_control(msg[0]),
+            _ => Ok(Self::Apdu(msg)),
+        }
+    }
+}
+
+impl Request {
+    fn from_control(command: u8) -> Result<Self> {
         match command {
             0 => Ok(Self::PowerOff),
             1 => Ok(Self::PowerOn),
             2 => Ok(Self::Reset),
             4 => Ok(Self::GetAtr),
-            _ => Err(Error::new(
-                ErrorKind::InvalidData,
-                format!("unsupported control command {}", command),
-            )),
+            command => Err(Error::UnsupportedCommand(command)),
+        }
+    }
+}
+
+/// The power state of a card, as requested by vpcd.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PowerState {
+    /// The card is powered off.  This is the initial state of a connection.
+    #[default]
+    Off,
+    /// The card is powered on.
+    On,
+}
+
+/// The features supported by a card, see [`VSmartCard::capabilities`][].
+///
+/// # Example
+///
+/// ```
+/// use vpicc::Capabilities;
+///
+/// let mut capabilities = Capabilities::default();
+/// capabilities.aids.push(vec![0xa0, 0x00, 0x00, 0x03, 0x08]);
+/// assert!(capabilities.selects(&[0xa0, 0x00, 0x00]));
+/// assert!(!capabilities.selects(&[0xd2, 0x76]));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The AIDs of the applications that can be selected, or an empty list if unknown.
+    pub aids: Vec<Vec<u8>>,
+    /// Whether command APDUs with extended length fields are supported.
+    pub extended_length: bool,
+    /// The maximum length of the response data in a single response APDU, excluding the status
+    /// word.
+    pub max_response_len: usize,
+}
+
+impl Capabilities {
+    /// Returns true if the given DF name selects one of the [`aids`][`Capabilities::aids`],
+    /// either completely or as a prefix for partial selection.
+    pub fn selects(&self, name: &[u8]) -> bool {
+        !name.is_empty() && self.aids.iter().any(|aid| aid.starts_with(name))
+    }
+}
+
+impl Default for Capabilities {
+    /// Returns the capabilities of a card with unknown AIDs that only supports short length
+    /// fields, i. e. at most 256 bytes of response data.
+    fn default() -> Self {
+        Self {
+            aids: Vec::new(),
+            extended_length: false,
+            max_response_len: 256,
+        }
+    }
+}
+
+impl std::fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.aids.is_empty() {
+            writeln!(f, "AIDs: unknown")?;
+        }
+        for aid in &self.aids {
+            writeln!(f, "AID: {}", hex::encode(aid))?;
         }
+        let extended = if self.extended_length { "yes" } else { "no" };
+        writeln!(f, "Extended length: {}", extended)?;
+        write!(f, "Maximum response length: {}", self.max_response_len)
     }
 }
 
 /// A dummy [`VSmartCard`][] implementation that prints to the log instead of performing any
 /// action.
+///
+/// See [`RecordingCard`][] for a card that also records all calls.
+#[derive(Clone, Copy, Debug, Default)]
 pub struct DummySmartCard;
 
 impl VSmartCard for DummySmartCard {
@@ -195,7 +1228,10 @@ impl VSmartCard for DummySmartCard {
         info!("Reset");
     }
     fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        self.execute_small(msg).into_vec()
+    }
+    fn execute_small(&mut self, msg: &[u8]) -> apdu::Response {
         info!("Received APDU Comand : {:?}", msg);
-        vec![0x90, 0x00]
+        apdu::small_response(&[], apdu::SW_SUCCESS)
     }
 }
@@ -0,0 +1,275 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+use std::{
+    fmt::Display,
+    io::{self, ErrorKind},
+    mem,
+    net::{SocketAddr, TcpListener, ToSocketAddrs},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    thread::{self, JoinHandle},
+};
+
+use log::{info, warn};
+
+use crate::{apdu, Capabilities, Connection, Error, Result, VSmartCard};
+
+/// Listens for connections from vpcd on the given address.
+///
+/// This is used for the reversed setup where vpcd connects to the card, like `vicc --reversed`,
+/// so that a card behind a firewall or in a container does not have to connect to vpcd.
+pub fn listen<A: ToSocketAddrs + Display>(addr: A) -> Result<Listener> {
+    info!("Listening for vpcd on {}", addr);
+    Ok(TcpListener::bind(addr).map(Listener::from)?)
+}
+
+/// Accepts connections from vpcd, see [`listen`][].
+///
+/// # Example
+///
+/// ```no_run
+/// fn main() -> vpicc::Result<()> {
+///     let listener = vpicc::listen("0.0.0.0:35963")?;
+///     // every connection from vpcd gets its own card
+///     listener.serve(|| vpicc::DummySmartCard)
+/// }
+/// ```
+///
+/// To use the same card for all connections, pass a [`Shared`][] card instead:
+///
+/// ```no_run
+/// fn main() -> vpicc::Result<()> {
+///     let listener = vpicc::listen("0.0.0.0:35963")?;
+///     listener.serve(vpicc::Shared::new(vpicc::DummySmartCard))
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Listener {
+    listener: TcpListener,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Listener {
+    /// Waits for the next connection from vpcd.
+    pub fn accept(&self) -> Result<Connection> {
+        let (stream, addr) = self.listener.accept()?;
+        info!("Accepted connection from {}", addr);
+        Ok(Connection::from(stream))
+    }
+
+    /// Returns the local address of this listener.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts all connections from vpcd and runs each of them on its own thread with a card
+    /// created by the given factory, see [`CardFactory`][].
+    ///
+    /// This function only returns if the listener fails.  Errors on a single connection,
+    /// including connections that are aborted before they are accepted, are logged and end that
+    /// connection.  Use [`join`][`Listener::join`] to wait for the connections that are still
+    /// running after this function returned.
+    pub fn serve<F: CardFactory>(&self, mut factory: F) -> Result<()> {
+        loop {
+            let connection = match self.accept() {
+                Ok(connection) => connection,
+                Err(err) if is_aborted(&err) => {
+                    warn!("Failed to accept connection: {}", err);
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            let addr = match connection.peer_addr() {
+                Ok(addr) => addr,
+                Err(err) => {
+                    warn!("Dropping connection without peer address: {}", err);
+                    continue;
+                }
+            };
+            let mut card = factory.card(addr);
+            let thread = thread::Builder::new()
+                .name(format!("vpicc-{}", addr))
+                .spawn(move || {
+                    let err = match connection.run(&mut card) {
+                        Ok(()) => return,
+                        Err(err) => err,
+                    };
+                    match err.kind() {
+                        ErrorKind::UnexpectedEof
+                        | ErrorKind::ConnectionReset
+                        | ErrorKind::BrokenPipe => info!("Connection from {} closed", addr),
+                        _ => warn!("Connection from {} failed: {}", addr, err),
+                    }
+                });
+            match thread {
+                Ok(thread) => {
+                    let mut threads = self.threads.lock().unwrap_or_else(PoisonError::into_inner);
+                    threads.retain(|thread| !thread.is_finished());
+                    threads.push(thread);
+                }
+                Err(err) => warn!("Failed to start thread for {}: {}", addr, err),
+            }
+        }
+    }
+
+    /// Waits until all connections started by [`serve`][`Listener::serve`] have ended.
+    ///
+    /// Connections that are accepted while this function is waiting are not waited for.
+    pub fn join(&self) {
+        let threads = mem::take(&mut *self.threads.lock().unwrap_or_else(PoisonError::into_inner));
+        for thread in threads {
+            // the connection threads do not panic unless the card panics
+            thread.join().ok();
+        }
+    }
+}
+
+/// Creates the card for a connection accepted by [`Listener::serve`][].
+///
+/// Closures returning a card create a fresh card for every connection, so that connections do
+/// not share any state.  [`Shared`][] hands out the same card to all connections.
+pub trait CardFactory {
+    /// The card used for a connection.
+    type Card: VSmartCard + Send + 'static;
+
+    /// Returns the card for a connection from vpcd on the given address.
+    fn card(&mut self, addr: SocketAddr) -> Self::Card;
+}
+
+impl<F, V> CardFactory for F
+where
+    F: FnMut() -> V,
+    V: VSmartCard + Send + 'static,
+{
+    type Card = V;
+
+    fn card(&mut self, _addr: SocketAddr) -> V {
+        self()
+    }
+}
+
+/// A card that is shared by all connections accepted by [`Listener::serve`][].
+///
+/// The card is protected by a lock, so commands from different connections are executed one
+/// after the other.  Power events are forwarded from all connections, so a connection that
+/// powers off the card also resets the state seen by the other connections.
+#[derive(Debug)]
+pub struct Shared<C> {
+    card: Arc<Mutex<C>>,
+}
+
+impl<C> Shared<C> {
+    /// Shares the given card.
+    pub fn new(card: C) -> Self {
+        Self {
+            card: Arc::new(Mutex::new(card)),
+        }
+    }
+
+    /// Locks the shared card, waiting for the command that is currently executed.
+    pub fn lock(&self) -> MutexGuard<'_, C> {
+        self.card.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<C> Clone for Shared<C> {
+    fn clone(&self) -> Self {
+        Self {
+            card: self.card.clone(),
+        }
+    }
+}
+
+impl<C: VSmartCard + Send + 'static> CardFactory for Shared<C> {
+    type Card = SharedCard<C>;
+
+    fn card(&mut self, _addr: SocketAddr) -> SharedCard<C> {
+        let atr = self.lock().atr().to_vec();
+        SharedCard {
+            card: self.card.clone(),
+            atr,
+        }
+    }
+}
+
+/// The handle of a [`Shared`][] card used by a single connection.
+///
+/// The ATR of the card is copied when the handle is created and after every power event, as it
+/// cannot be borrowed from the locked card.
+#[derive(Debug)]
+pub struct SharedCard<C> {
+    card: Arc<Mutex<C>>,
+    atr: Vec<u8>,
+}
+
+impl<C: VSmartCard> SharedCard<C> {
+    fn with_card<T>(&mut self, f: impl FnOnce(&mut C) -> T) -> T {
+        let mut card = self.card.lock().unwrap_or_else(PoisonError::into_inner);
+        f(&mut card)
+    }
+
+    fn power_event(&mut self, f: impl FnOnce(&mut C)) {
+        self.atr = self.with_card(|card| {
+            f(card);
+            card.atr().to_vec()
+        });
+    }
+}
+
+impl<C: VSmartCard> VSmartCard for SharedCard<C> {
+    fn atr(&self) -> &[u8] {
+        &self.atr
+    }
+
+    fn power_on(&mut self) {
+        self.power_event(C::power_on)
+    }
+
+    fn power_off(&mut self) {
+        self.power_event(C::power_off)
+    }
+
+    fn reset(&mut self) {
+        self.power_event(C::reset)
+    }
+
+    fn cold_reset(&mut self) {
+        self.power_event(C::cold_reset)
+    }
+
+    fn warm_reset(&mut self) {
+        self.power_event(C::warm_reset)
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        self.with_card(|card| card.execute(msg))
+    }
+
+    fn execute_small(&mut self, msg: &[u8]) -> apdu::Response {
+        self.with_card(|card| card.execute_small(msg))
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.card
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .capabilities()
+    }
+}
+
+/// Returns true if accepting a connection failed because of the connection, not the listener.
+fn is_aborted(err: &Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset | ErrorKind::Interrupted
+    )
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Self {
+            listener,
+            threads: Mutex::default(),
+        }
+    }
+}
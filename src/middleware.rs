@@ -0,0 +1,1140 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Wrappers that change the behavior of a [`VSmartCard`][] implementation.
+
+use std::{
+    cell::Cell,
+    collections::BTreeMap,
+    io::{Error, ErrorKind, Result},
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Mutex, MutexGuard, PoisonError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, warn};
+
+use crate::{
+    apdu::{self, Class, SecureMessaging},
+    atr::Atr,
+    rng::Rng,
+    Capabilities, VSmartCard,
+};
+
+/// The status word returned by [`CatchUnwind`][] if the card panics, 6F00 (no precise
+/// diagnosis).
+pub const DEFAULT_PANIC_STATUS: u16 = apdu::SW_NO_PRECISE_DIAGNOSIS;
+
+/// The status word returned by [`Timeout`][] if the card does not respond in time, 6F00 (no
+/// precise diagnosis).
+pub const DEFAULT_TIMEOUT_STATUS: u16 = apdu::SW_NO_PRECISE_DIAGNOSIS;
+/// Alias for [`apdu::SW_INS_NOT_SUPPORTED`][].
+pub const INS_NOT_SUPPORTED: u16 = apdu::SW_INS_NOT_SUPPORTED;
+/// Alias for [`apdu::SW_CLA_NOT_SUPPORTED`][].
+pub const CLA_NOT_SUPPORTED: u16 = apdu::SW_CLA_NOT_SUPPORTED;
+
+/// Catches panics in [`VSmartCard::execute`][] and responds with a status word instead.
+///
+/// This keeps the connection alive if a single command handler panics.  Note that the card
+/// might be in an inconsistent state after a panic.
+///
+/// # Example
+///
+/// ```
+/// use vpicc::{middleware::CatchUnwind, VSmartCard};
+///
+/// struct Card;
+///
+/// impl VSmartCard for Card {
+///     fn execute(&mut self, _msg: &[u8]) -> Vec<u8> {
+///         panic!("not implemented");
+///     }
+/// }
+///
+/// let mut card = CatchUnwind::new(Card);
+/// assert_eq!(card.execute(&[0x00, 0xa4, 0x04, 0x00]), [0x6f, 0x00]);
+/// ```
+#[derive(Debug)]
+pub struct CatchUnwind<C> {
+    card: C,
+    status: u16,
+}
+
+impl<C> CatchUnwind<C> {
+    /// Wraps the given card, responding with [`DEFAULT_PANIC_STATUS`][] if it panics.
+    pub fn new(card: C) -> Self {
+        Self::with_status(card, DEFAULT_PANIC_STATUS)
+    }
+
+    /// Wraps the given card, responding with the given status word if it panics.
+    pub fn with_status(card: C, status: u16) -> Self {
+        Self { card, status }
+    }
+
+    /// Returns a reference to the wrapped card.
+    pub fn get_ref(&self) -> &C {
+        &self.card
+    }
+
+    /// Returns a mutable reference to the wrapped card.
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.card
+    }
+
+    /// Returns the wrapped card.
+    pub fn into_inner(self) -> C {
+        self.card
+    }
+}
+
+impl<C: VSmartCard> CatchUnwind<C> {
+    fn respond<R: Respond>(&mut self, msg: &[u8]) -> R {
+        let card = &mut self.card;
+        panic::catch_unwind(AssertUnwindSafe(|| R::execute(card, msg))).unwrap_or_else(|_| {
+            error!("Card panicked while executing APDU {:x?}", msg);
+            R::status(self.status)
+        })
+    }
+}
+
+impl<C: VSmartCard> VSmartCard for CatchUnwind<C> {
+    fn atr(&self) -> &[u8] {
+        self.card.atr()
+    }
+
+    fn power_on(&mut self) {
+        self.card.power_on()
+    }
+
+    fn power_off(&mut self) {
+        self.card.power_off()
+    }
+
+    fn reset(&mut self) {
+        self.card.reset()
+    }
+
+    fn cold_reset(&mut self) {
+        self.card.cold_reset()
+    }
+
+    fn warm_reset(&mut self) {
+        self.card.warm_reset()
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        self.respond(msg)
+    }
+
+    fn execute_small(&mut self, msg: &[u8]) -> apdu::Response {
+        self.respond(msg)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.card.capabilities()
+    }
+}
+
+/// Responds with a default status word to commands that are not handled by the wrapped card.
+///
+/// The wrapped card signals that it does not handle a command by returning an empty response
+/// from [`VSmartCard::execute`][].  A valid response APDU always contains a status word, so an
+/// empty response is never sent to vpcd by this wrapper.
+///
+/// # Example
+///
+/// ```
+/// use vpicc::{middleware::{WithDefault, INS_NOT_SUPPORTED}, VSmartCard};
+///
+/// struct Card;
+///
+/// impl VSmartCard for Card {
+///     fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+///         match msg.get(1) {
+///             Some(0xa4) => vec![0x90, 0x00],
+///             _ => Vec::new(),
+///         }
+///     }
+/// }
+///
+/// let mut card = WithDefault::new(Card, INS_NOT_SUPPORTED);
+/// assert_eq!(card.execute(&[0x00, 0xa4, 0x04, 0x00]), [0x90, 0x00]);
+/// assert_eq!(card.execute(&[0x00, 0xca, 0x00, 0x6e]), [0x6d, 0x00]);
+/// ```
+#[derive(Debug)]
+pub struct WithDefault<C> {
+    card: C,
+    status: u16,
+}
+
+impl<C> WithDefault<C> {
+    /// Wraps the given card, responding with the given status word to unhandled commands.
+    pub fn new(card: C, status: u16) -> Self {
+        Self { card, status }
+    }
+
+    /// Returns a reference to the wrapped card.
+    pub fn get_ref(&self) -> &C {
+        &self.card
+    }
+
+    /// Returns a mutable reference to the wrapped card.
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.card
+    }
+
+    /// Returns the wrapped card.
+    pub fn into_inner(self) -> C {
+        self.card
+    }
+}
+
+impl<C: VSmartCard> WithDefault<C> {
+    fn respond<R: Respond>(&mut self, msg: &[u8]) -> R {
+        let response = R::execute(&mut self.card, msg);
+        if response.is_empty() {
+            debug!(
+                "Command not handled by card, responding with {:04x}",
+                self.status
+            );
+            R::status(self.status)
+        } else {
+            response
+        }
+    }
+}
+
+impl<C: VSmartCard> VSmartCard for WithDefault<C> {
+    fn atr(&self) -> &[u8] {
+        self.card.atr()
+    }
+
+    fn power_on(&mut self) {
+        self.card.power_on()
+    }
+
+    fn power_off(&mut self) {
+        self.card.power_off()
+    }
+
+    fn reset(&mut self) {
+        self.card.reset()
+    }
+
+    fn cold_reset(&mut self) {
+        self.card.cold_reset()
+    }
+
+    fn warm_reset(&mut self) {
+        self.card.warm_reset()
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        self.respond(msg)
+    }
+
+    fn execute_small(&mut self, msg: &[u8]) -> apdu::Response {
+        self.respond(msg)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.card.capabilities()
+    }
+}
+
+/// Responds with a fallback status word if the wrapped card does not execute a command in time.
+///
+/// Commands are executed on a worker thread that is started for the first command.  If the card
+/// does not respond before the timeout expires, the fallback status word is sent to vpcd and the
+/// late response is discarded.  Commands received while the card is still executing the
+/// overrunning command are not queued but answered immediately with the fallback status word.
+/// Once the overrunning command has finished, a new worker thread is started for the next
+/// command.  Power events wait until the card has finished.
+///
+/// The ATR and the capabilities of the card cannot be borrowed while the card is executing a
+/// command, so they are copied after every command and power event.
+///
+/// The overrunning command is not cancelled, so its side effects, for example a decremented
+/// retry counter, take effect after vpcd has received the fallback status word.
+///
+/// # Example
+///
+/// ```
+/// use std::{thread, time::Duration};
+/// use vpicc::{middleware::Timeout, VSmartCard};
+///
+/// struct SlowCard;
+///
+/// impl VSmartCard for SlowCard {
+///     fn execute(&mut self, _msg: &[u8]) -> Vec<u8> {
+///         thread::sleep(Duration::from_millis(100));
+///         vec![0x90, 0x00]
+///     }
+/// }
+///
+/// let mut card = Timeout::new(SlowCard, Duration::from_millis(10));
+/// assert_eq!(card.execute(&[0x00, 0x46, 0x00, 0x00]), [0x6f, 0x00]);
+/// // the card is still busy with the first command
+/// assert_eq!(card.execute(&[0x00, 0x46, 0x00, 0x00]), [0x6f, 0x00]);
+/// ```
+#[derive(Debug)]
+pub struct Timeout<C> {
+    card: Arc<Mutex<C>>,
+    atr: Vec<u8>,
+    capabilities: Capabilities,
+    timeout: Duration,
+    status: u16,
+    worker: Option<Worker>,
+    overrun: Option<Worker>,
+}
+
+impl<C: VSmartCard + Send + 'static> Timeout<C> {
+    /// Wraps the given card, responding with [`DEFAULT_TIMEOUT_STATUS`][] if it does not
+    /// respond within the given timeout.
+    pub fn new(card: C, timeout: Duration) -> Self {
+        Self::with_status(card, timeout, DEFAULT_TIMEOUT_STATUS)
+    }
+
+    /// Wraps the given card, responding with the given status word if it does not respond
+    /// within the given timeout.
+    pub fn with_status(card: C, timeout: Duration, status: u16) -> Self {
+        Self {
+            atr: card.atr().to_vec(),
+            capabilities: card.capabilities(),
+            card: Arc::new(Mutex::new(card)),
+            timeout,
+            status,
+            worker: None,
+            overrun: None,
+        }
+    }
+
+    fn update<F: FnOnce(&mut C)>(&mut self, f: F) {
+        let mut card = lock(&self.card);
+        // the overrunning command has finished once the lock is available
+        self.overrun = None;
+        f(&mut card);
+        self.atr = card.atr().to_vec();
+        self.capabilities = card.capabilities();
+    }
+
+    /// Updates the cached state of the card and returns the response of the reply.
+    fn refresh(&mut self, reply: Reply) -> apdu::Response {
+        if let Some(atr) = reply.atr {
+            self.atr = atr;
+        }
+        if let Some(capabilities) = reply.capabilities {
+            self.capabilities = capabilities;
+        }
+        reply.response
+    }
+
+    fn respond<R: Respond>(&mut self, msg: &[u8]) -> R {
+        if let Some(overrun) = &self.overrun {
+            match overrun.replies.try_recv() {
+                Ok(reply) => {
+                    self.refresh(reply);
+                }
+                Err(mpsc::TryRecvError::Empty) => {
+                    warn!(
+                        "Card is still executing a previous APDU, responding to APDU {:x?} with {:04x}",
+                        msg, self.status
+                    );
+                    return R::status(self.status);
+                }
+                Err(mpsc::TryRecvError::Disconnected) => {}
+            }
+            self.overrun = None;
+        }
+        let worker = match self.worker.take() {
+            Some(worker) => worker,
+            None => Worker::spawn(
+                self.card.clone(),
+                self.atr.clone(),
+                self.capabilities.clone(),
+            ),
+        };
+        // if the worker has panicked, this is reported by recv_timeout
+        worker.commands.send((msg.into(), R::SMALL)).ok();
+        match worker.replies.recv_timeout(self.timeout) {
+            Ok(reply) => {
+                self.worker = Some(worker);
+                R::from_small(self.refresh(reply))
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                warn!(
+                    "Card did not respond to APDU {:x?} within {:?}, responding with {:04x}",
+                    msg, self.timeout, self.status
+                );
+                self.overrun = Some(worker);
+                R::status(self.status)
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                error!("Card panicked while executing APDU {:x?}", msg);
+                R::status(self.status)
+            }
+        }
+    }
+}
+
+impl<C: VSmartCard + Send + 'static> VSmartCard for Timeout<C> {
+    fn atr(&self) -> &[u8] {
+        &self.atr
+    }
+
+    fn power_on(&mut self) {
+        self.update(C::power_on)
+    }
+
+    fn power_off(&mut self) {
+        self.update(C::power_off)
+    }
+
+    fn reset(&mut self) {
+        self.update(C::reset)
+    }
+
+    fn cold_reset(&mut self) {
+        self.update(C::cold_reset)
+    }
+
+    fn warm_reset(&mut self) {
+        self.update(C::warm_reset)
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        self.respond(msg)
+    }
+
+    fn execute_small(&mut self, msg: &[u8]) -> apdu::Response {
+        self.respond(msg)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.capabilities.clone()
+    }
+}
+
+/// The response of a [`Timeout`][] worker and the state of the card after the command if it has
+/// changed.
+#[derive(Debug)]
+struct Reply {
+    response: apdu::Response,
+    atr: Option<Vec<u8>>,
+    capabilities: Option<Capabilities>,
+}
+
+/// The thread that executes the commands of a [`Timeout`][].
+#[derive(Debug)]
+struct Worker {
+    // short commands are stored inline like responses, the flag selects execute_small
+    commands: mpsc::Sender<(apdu::Response, bool)>,
+    replies: mpsc::Receiver<Reply>,
+}
+
+impl Worker {
+    fn spawn<C: VSmartCard + Send + 'static>(
+        card: Arc<Mutex<C>>,
+        mut atr: Vec<u8>,
+        mut capabilities: Capabilities,
+    ) -> Self {
+        let (commands, command_receiver) = mpsc::channel::<(apdu::Response, bool)>();
+        let (reply_sender, replies) = mpsc::channel();
+        // the thread exits when the worker is dropped
+        thread::spawn(move || {
+            for (command, small) in command_receiver {
+                let reply = {
+                    let mut card = lock(&card);
+                    let response = if small {
+                        card.execute_small(&command)
+                    } else {
+                        apdu::Response::from_vec(card.execute(&command))
+                    };
+                    let new_atr = (card.atr() != atr).then(|| card.atr().to_vec());
+                    let new_capabilities = Some(card.capabilities())
+                        .filter(|new_capabilities| *new_capabilities != capabilities);
+                    if let Some(new_atr) = &new_atr {
+                        atr.clone_from(new_atr);
+                    }
+                    if let Some(new_capabilities) = &new_capabilities {
+                        capabilities.clone_from(new_capabilities);
+                    }
+                    Reply {
+                        response,
+                        atr: new_atr,
+                        capabilities: new_capabilities,
+                    }
+                };
+                if reply_sender.send(reply).is_err() {
+                    break;
+                }
+            }
+        });
+        Self { commands, replies }
+    }
+}
+
+/// Presents a fixed ATR instead of the ATR of the wrapped card.
+///
+/// This is useful when relaying a real card but presenting a different ATR to the host.  All
+/// other calls are passed to the wrapped card.
+/// See [`AdjustableAtr`][] for changing the ATR at runtime.
+///
+/// # Example
+///
+/// ```
+/// use vpicc::{atr::Atr, middleware::WithAtr, VSmartCard};
+///
+/// let card = WithAtr::new(vpicc::DummySmartCard, [0x3b, 0x80, 0x80, 0x01, 0x01]);
+/// assert_eq!(card.atr(), [0x3b, 0x80, 0x80, 0x01, 0x01]);
+///
+/// let card = WithAtr::preset(vpicc::DummySmartCard, "nitrokey3")?;
+/// assert_eq!(card.atr(), vpicc::admin::ATR);
+///
+/// let card = WithAtr::from_atr(vpicc::DummySmartCard, &Atr::new(&[1], b"test"));
+/// assert_eq!(Atr::parse(card.atr())?.historical_bytes(), b"test");
+/// # Ok::<_, std::io::Error>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct WithAtr<C> {
+    card: C,
+    atr: Vec<u8>,
+}
+
+impl<C> WithAtr<C> {
+    /// Wraps the given card, presenting the given ATR.
+    pub fn new(card: C, atr: impl Into<Vec<u8>>) -> Self {
+        Self {
+            card,
+            atr: atr.into(),
+        }
+    }
+
+    /// Wraps the given card, presenting the given ATR built with [`Atr`][].
+    pub fn from_atr(card: C, atr: &Atr) -> Self {
+        Self::new(card, atr.to_bytes())
+    }
+
+    /// Wraps the given card, presenting the well-known ATR with the given name, see
+    /// [`atr::preset`][`crate::atr::preset`].
+    ///
+    /// Returns an [`InvalidInput`][`ErrorKind::InvalidInput`] error if there is no ATR with
+    /// this name.
+    pub fn preset(card: C, name: &str) -> Result<Self> {
+        let atr = crate::atr::preset(name).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown ATR preset: {}", name),
+            )
+        })?;
+        Ok(Self::new(card, atr))
+    }
+
+    /// Returns a reference to the wrapped card.
+    pub fn get_ref(&self) -> &C {
+        &self.card
+    }
+
+    /// Returns a mutable reference to the wrapped card.
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.card
+    }
+
+    /// Returns the wrapped card.
+    pub fn into_inner(self) -> C {
+        self.card
+    }
+}
+
+impl<C: VSmartCard> VSmartCard for WithAtr<C> {
+    fn atr(&self) -> &[u8] {
+        &self.atr
+    }
+
+    fn power_on(&mut self) {
+        self.card.power_on()
+    }
+
+    fn power_off(&mut self) {
+        self.card.power_off()
+    }
+
+    fn reset(&mut self) {
+        self.card.reset()
+    }
+
+    fn cold_reset(&mut self) {
+        self.card.cold_reset()
+    }
+
+    fn warm_reset(&mut self) {
+        self.card.warm_reset()
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        self.card.execute(msg)
+    }
+
+    fn execute_small(&mut self, msg: &[u8]) -> apdu::Response {
+        self.card.execute_small(msg)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.card.capabilities()
+    }
+}
+
+/// Rewrites the logical channel and the secure messaging indication of the class byte of the
+/// commands before they are passed to the wrapped card.
+///
+/// The class byte is parsed as a [`Class`][], the channel and the secure messaging
+/// indication are replaced according to the mappings, and the class byte is encoded again.
+/// This makes it possible to test how a card reacts to unusual but legal class bytes, for
+/// example commands on channel 19 or with proprietary secure messaging.  Together with a card
+/// that relays the commands, it can also be used to test hosts.
+///
+/// Proprietary class bytes and class bytes that cannot be encoded after the rewrite are passed
+/// unchanged.
+///
+/// # Example
+///
+/// ```
+/// use vpicc::{apdu::SecureMessaging, middleware::ClaRewrite, VSmartCard};
+///
+/// struct Card;
+///
+/// impl VSmartCard for Card {
+///     fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+///         vec![msg[0], 0x90, 0x00]
+///     }
+/// }
+///
+/// let mut card = ClaRewrite::new(Card)
+///     .channel(0, 19)
+///     .secure_messaging(SecureMessaging::Standard, SecureMessaging::None);
+/// assert_eq!(card.execute(&[0x00, 0xa4, 0x04, 0x00]), [0x4f, 0x90, 0x00]);
+/// assert_eq!(card.execute(&[0x09, 0xa4, 0x04, 0x00]), [0x01, 0x90, 0x00]);
+/// assert_eq!(card.execute(&[0x80, 0xa4, 0x04, 0x00]), [0x80, 0x90, 0x00]);
+/// ```
+#[derive(Clone, Debug)]
+pub struct ClaRewrite<C> {
+    card: C,
+    channels: BTreeMap<u8, u8>,
+    secure_messaging: BTreeMap<SecureMessaging, SecureMessaging>,
+}
+
+impl<C> ClaRewrite<C> {
+    /// Wraps the given card without rewriting any class bytes.
+    pub fn new(card: C) -> Self {
+        Self {
+            card,
+            channels: BTreeMap::new(),
+            secure_messaging: BTreeMap::new(),
+        }
+    }
+
+    /// Rewrites commands on the logical channel `from` to the channel `to`.
+    pub fn channel(mut self, from: u8, to: u8) -> Self {
+        self.channels.insert(from, to);
+        self
+    }
+
+    /// Rewrites the secure messaging indication `from` to `to`.
+    pub fn secure_messaging(mut self, from: SecureMessaging, to: SecureMessaging) -> Self {
+        self.secure_messaging.insert(from, to);
+        self
+    }
+
+    /// Returns a reference to the wrapped card.
+    pub fn get_ref(&self) -> &C {
+        &self.card
+    }
+
+    /// Returns a mutable reference to the wrapped card.
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.card
+    }
+
+    /// Returns the wrapped card.
+    pub fn into_inner(self) -> C {
+        self.card
+    }
+
+    fn rewrite(&self, cla: u8) -> Option<u8> {
+        let mut class = Class::parse(cla)?;
+        if let Some(channel) = self.channels.get(&class.channel) {
+            class.channel = *channel;
+        }
+        if let Some(secure_messaging) = self.secure_messaging.get(&class.secure_messaging) {
+            class.secure_messaging = *secure_messaging;
+        }
+        let rewritten = class.to_byte();
+        if rewritten.is_none() {
+            warn!("Cannot encode rewritten class {:?}", class);
+        }
+        rewritten
+    }
+}
+
+impl<C: VSmartCard> ClaRewrite<C> {
+    fn respond<R: Respond>(&mut self, msg: &[u8]) -> R {
+        match msg.first().and_then(|cla| self.rewrite(*cla)) {
+            Some(cla) if cla != msg[0] => {
+                debug!("Rewriting CLA {:02x} to {:02x}", msg[0], cla);
+                let mut command = apdu::Response::from_slice(msg);
+                command[0] = cla;
+                R::execute(&mut self.card, &command)
+            }
+            _ => R::execute(&mut self.card, msg),
+        }
+    }
+}
+
+impl<C: VSmartCard> VSmartCard for ClaRewrite<C> {
+    fn atr(&self) -> &[u8] {
+        self.card.atr()
+    }
+
+    fn power_on(&mut self) {
+        self.card.power_on()
+    }
+
+    fn power_off(&mut self) {
+        self.card.power_off()
+    }
+
+    fn reset(&mut self) {
+        self.card.reset()
+    }
+
+    fn cold_reset(&mut self) {
+        self.card.cold_reset()
+    }
+
+    fn warm_reset(&mut self) {
+        self.card.warm_reset()
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        self.respond(msg)
+    }
+
+    fn execute_small(&mut self, msg: &[u8]) -> apdu::Response {
+        self.respond(msg)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.card.capabilities()
+    }
+}
+
+/// A handle to change the ATR of an [`AdjustableAtr`][] card, for example from a controller
+/// thread.
+#[derive(Clone, Debug, Default)]
+pub struct AtrHandle {
+    next: Arc<Mutex<Option<Vec<u8>>>>,
+}
+
+impl AtrHandle {
+    /// Sets the ATR that the card presents after the next cold reset.
+    pub fn set_next(&self, atr: impl Into<Vec<u8>>) {
+        *lock(&self.next) = Some(atr.into());
+    }
+
+    /// Discards an ATR that has been set with [`set_next`][`AtrHandle::set_next`] but not been
+    /// applied yet.
+    pub fn clear(&self) {
+        *lock(&self.next) = None;
+    }
+
+    fn take(&self) -> Option<Vec<u8>> {
+        lock(&self.next).take()
+    }
+}
+
+/// Changes the ATR of the wrapped card at runtime.
+///
+/// A new ATR is only applied on the next [cold reset][`VSmartCard::cold_reset`], so the host sees
+/// it as a different card inserted into the same reader.  Until a new ATR is
+/// applied, the ATR of the wrapped card is used.  Warm resets keep the current ATR.
+///
+/// # Example
+///
+/// ```
+/// use vpicc::{middleware::AdjustableAtr, VSmartCard};
+///
+/// let mut card = AdjustableAtr::new(vpicc::DummySmartCard);
+/// let handle = card.handle();
+/// handle.set_next([0x3b, 0x80, 0x80, 0x01, 0x01]);
+/// assert_eq!(card.atr(), vpicc::DEFAULT_ATR);
+///
+/// card.cold_reset();
+/// assert_eq!(card.atr(), [0x3b, 0x80, 0x80, 0x01, 0x01]);
+/// ```
+#[derive(Debug)]
+pub struct AdjustableAtr<C> {
+    card: C,
+    atr: Option<Vec<u8>>,
+    handle: AtrHandle,
+}
+
+impl<C> AdjustableAtr<C> {
+    /// Wraps the given card, initially presenting its own ATR.
+    pub fn new(card: C) -> Self {
+        Self {
+            card,
+            atr: None,
+            handle: AtrHandle::default(),
+        }
+    }
+
+    /// Returns a handle that can be used to change the ATR from another thread.
+    pub fn handle(&self) -> AtrHandle {
+        self.handle.clone()
+    }
+
+    /// Sets the ATR that the card presents after the next cold reset.
+    pub fn set_next_atr(&mut self, atr: impl Into<Vec<u8>>) {
+        self.handle.set_next(atr);
+    }
+
+    /// Returns a reference to the wrapped card.
+    pub fn get_ref(&self) -> &C {
+        &self.card
+    }
+
+    /// Returns a mutable reference to the wrapped card.
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.card
+    }
+
+    /// Returns the wrapped card.
+    pub fn into_inner(self) -> C {
+        self.card
+    }
+}
+
+impl<C: VSmartCard> VSmartCard for AdjustableAtr<C> {
+    fn atr(&self) -> &[u8] {
+        self.atr.as_deref().unwrap_or_else(|| self.card.atr())
+    }
+
+    fn power_on(&mut self) {
+        self.card.power_on()
+    }
+
+    fn power_off(&mut self) {
+        self.card.power_off()
+    }
+
+    fn reset(&mut self) {
+        self.card.reset()
+    }
+
+    fn cold_reset(&mut self) {
+        if let Some(atr) = self.handle.take() {
+            debug!("Applying new ATR {:x?}", atr);
+            self.atr = Some(atr);
+        }
+        self.card.cold_reset()
+    }
+
+    fn warm_reset(&mut self) {
+        self.card.warm_reset()
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        self.card.execute(msg)
+    }
+
+    fn execute_small(&mut self, msg: &[u8]) -> apdu::Response {
+        self.card.execute_small(msg)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.card.capabilities()
+    }
+}
+
+/// Randomizes the historical bytes of the ATR of the wrapped card on every cold reset.
+///
+/// The interface bytes and the number of historical bytes are kept, so the protocol
+/// parameters do not change.  The first historical byte, the category indicator, is also kept
+/// and all following bytes are replaced with random values.  The check byte is recomputed.
+/// This makes it possible to verify that host software does not identify a card by its exact
+/// ATR, for example when caching card data.
+///
+/// The random values are derived from the given seed, so test runs are reproducible.  If the
+/// ATR of the wrapped card cannot be parsed, it is used unchanged.
+///
+/// # Example
+///
+/// ```
+/// use vpicc::{middleware::RandomizedAtr, VSmartCard};
+///
+/// let mut card = RandomizedAtr::new(vpicc::DummySmartCard, 42);
+/// card.cold_reset();
+/// let first = card.atr().to_vec();
+/// card.cold_reset();
+/// assert_ne!(card.atr(), first);
+/// assert_eq!(card.atr().len(), vpicc::DEFAULT_ATR.len());
+/// assert!(vpicc::atr::Atr::parse(card.atr()).is_ok());
+/// ```
+#[derive(Debug)]
+pub struct RandomizedAtr<C> {
+    card: C,
+    rng: Rng,
+    atr: Option<Vec<u8>>,
+}
+
+impl<C: VSmartCard> RandomizedAtr<C> {
+    /// Wraps the given card, using the given seed for the random historical bytes.
+    ///
+    /// Until the first cold reset, the ATR of the wrapped card is used.
+    pub fn new(card: C, seed: u64) -> Self {
+        Self {
+            card,
+            rng: Rng::new(seed),
+            atr: None,
+        }
+    }
+
+    /// Returns a reference to the wrapped card.
+    pub fn get_ref(&self) -> &C {
+        &self.card
+    }
+
+    /// Returns a mutable reference to the wrapped card.
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.card
+    }
+
+    /// Returns the wrapped card.
+    pub fn into_inner(self) -> C {
+        self.card
+    }
+
+    fn randomize(&mut self) -> Option<Vec<u8>> {
+        let mut atr = match Atr::parse(self.card.atr()) {
+            Ok(atr) => atr,
+            Err(err) => {
+                warn!("Not randomizing ATR of the card: {}", err);
+                return None;
+            }
+        };
+        let mut historical = atr.historical_bytes().to_vec();
+        for byte in historical.iter_mut().skip(1) {
+            *byte = self.rng.next_u64() as u8;
+        }
+        atr.set_historical_bytes(&historical);
+        let atr = atr.to_bytes();
+        debug!("Using randomized ATR {:x?}", atr);
+        Some(atr)
+    }
+}
+
+impl<C: VSmartCard> VSmartCard for RandomizedAtr<C> {
+    fn atr(&self) -> &[u8] {
+        self.atr.as_deref().unwrap_or_else(|| self.card.atr())
+    }
+
+    fn power_on(&mut self) {
+        self.card.power_on()
+    }
+
+    fn power_off(&mut self) {
+        self.card.power_off()
+    }
+
+    fn reset(&mut self) {
+        self.card.reset()
+    }
+
+    fn cold_reset(&mut self) {
+        self.card.cold_reset();
+        self.atr = self.randomize();
+    }
+
+    fn warm_reset(&mut self) {
+        self.card.warm_reset()
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        self.card.execute(msg)
+    }
+
+    fn execute_small(&mut self, msg: &[u8]) -> apdu::Response {
+        self.card.execute_small(msg)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.card.capabilities()
+    }
+}
+
+/// Emulates a card that takes some time to boot after a cold reset.
+///
+/// After every [cold reset][`VSmartCard::cold_reset`], the ATR and the response to the first
+/// command are delayed until the boot delay has passed since the reset.  This makes it possible
+/// to test reader timeouts and retry logic on the host side.  Warm resets are not delayed.
+///
+/// # Example
+///
+/// ```
+/// use std::time::{Duration, Instant};
+/// use vpicc::{middleware::SlowBoot, VSmartCard};
+///
+/// let mut card = SlowBoot::new(vpicc::DummySmartCard, Duration::from_millis(50));
+/// let start = Instant::now();
+/// card.power_on();
+/// card.cold_reset();
+/// card.atr();
+/// assert!(start.elapsed() >= Duration::from_millis(50));
+/// ```
+#[derive(Debug)]
+pub struct SlowBoot<C> {
+    card: C,
+    delay: Duration,
+    booting_since: Cell<Option<Instant>>,
+}
+
+impl<C> SlowBoot<C> {
+    /// Wraps the given card, delaying the ATR by the given duration after every cold reset.
+    pub fn new(card: C, delay: Duration) -> Self {
+        Self {
+            card,
+            delay,
+            booting_since: Cell::new(None),
+        }
+    }
+
+    /// Sets the boot delay for the following cold resets.
+    pub fn set_delay(&mut self, delay: Duration) {
+        self.delay = delay;
+    }
+
+    /// Returns a reference to the wrapped card.
+    pub fn get_ref(&self) -> &C {
+        &self.card
+    }
+
+    /// Returns a mutable reference to the wrapped card.
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.card
+    }
+
+    /// Returns the wrapped card.
+    pub fn into_inner(self) -> C {
+        self.card
+    }
+
+    fn wait_for_boot(&self) {
+        if let Some(since) = self.booting_since.take() {
+            let remaining = self.delay.saturating_sub(since.elapsed());
+            if !remaining.is_zero() {
+                debug!("Delaying response by {:?} for card boot", remaining);
+                thread::sleep(remaining);
+            }
+        }
+    }
+}
+
+impl<C: VSmartCard> VSmartCard for SlowBoot<C> {
+    fn atr(&self) -> &[u8] {
+        self.wait_for_boot();
+        self.card.atr()
+    }
+
+    fn power_on(&mut self) {
+        self.card.power_on()
+    }
+
+    fn power_off(&mut self) {
+        self.booting_since.set(None);
+        self.card.power_off()
+    }
+
+    fn reset(&mut self) {
+        self.card.reset()
+    }
+
+    fn cold_reset(&mut self) {
+        self.booting_since.set(Some(Instant::now()));
+        self.card.cold_reset()
+    }
+
+    fn warm_reset(&mut self) {
+        self.card.warm_reset()
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        self.wait_for_boot();
+        self.card.execute(msg)
+    }
+
+    fn execute_small(&mut self, msg: &[u8]) -> apdu::Response {
+        self.wait_for_boot();
+        self.card.execute_small(msg)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.card.capabilities()
+    }
+}
+
+/// A response APDU type, so that the wrappers can implement [`VSmartCard::execute`][] and
+/// [`VSmartCard::execute_small`][] with the same code.
+trait Respond: AsRef<[u8]> + Sized {
+    /// True if this is the type returned by [`VSmartCard::execute_small`][].
+    const SMALL: bool;
+
+    /// Executes the command using the method of the card that returns this type.
+    fn execute<C: VSmartCard + ?Sized>(card: &mut C, msg: &[u8]) -> Self;
+
+    /// Returns a response consisting of the given status word.
+    fn status(status: u16) -> Self;
+
+    /// Converts a response of [`VSmartCard::execute_small`][] to this type.
+    fn from_small(response: apdu::Response) -> Self;
+
+    fn is_empty(&self) -> bool {
+        self.as_ref().is_empty()
+    }
+}
+
+impl Respond for Vec<u8> {
+    const SMALL: bool = false;
+
+    fn execute<C: VSmartCard + ?Sized>(card: &mut C, msg: &[u8]) -> Self {
+        card.execute(msg)
+    }
+
+    fn status(status: u16) -> Self {
+        status.to_be_bytes().to_vec()
+    }
+
+    fn from_small(response: apdu::Response) -> Self {
+        response.into_vec()
+    }
+}
+
+impl Respond for apdu::Response {
+    const SMALL: bool = true;
+
+    fn execute<C: VSmartCard + ?Sized>(card: &mut C, msg: &[u8]) -> Self {
+        card.execute_small(msg)
+    }
+
+    fn status(status: u16) -> Self {
+        apdu::Response::from_slice(&status.to_be_bytes())
+    }
+
+    fn from_small(response: apdu::Response) -> Self {
+        response
+    }
+}
+
+fn lock<C>(card: &Mutex<C>) -> MutexGuard<'_, C> {
+    card.lock().unwrap_or_else(PoisonError::into_inner)
+}
@@ -0,0 +1,352 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Mock cards for testing host applications.
+//!
+//! A [`MockCard`][] answers commands according to a list of [`Expectation`][]s and keeps track
+//! of how often each of them was met.  After the host application under test has talked to the
+//! card, [`MockCard::verify`][] panics if a command was not expected or if an expectation was not
+//! met as often as required, so that the test fails.
+//!
+//! Expectations match the command header using a [`Match`][] and optionally the command data, or
+//! the complete command APDU.  By default, every expectation must be met exactly once, which can
+//! be changed using [`Expectation::times`][] and [`Expectation::at_least`][].  If the card is
+//! ordered, the expectations must be met in the order in which they were added.  Otherwise, a
+//! command is answered by the first matching expectation that has not yet been met as often as
+//! allowed.  Unexpected commands are answered with
+//! [`SW_NO_PRECISE_DIAGNOSIS`][`apdu::SW_NO_PRECISE_DIAGNOSIS`].
+//!
+//! Clones of a mock card share their expectations, so a clone can be kept for verification when
+//! the card is moved into a connection or a test helper.
+//!
+//! # Example
+//!
+//! ```
+//! use vpicc::{
+//!     apdu,
+//!     mock::{Expectation, MockCard},
+//!     router::Match,
+//!     VSmartCard,
+//! };
+//!
+//! const AID: [u8; 6] = [0xd2, 0x76, 0x00, 0x01, 0x24, 0x01];
+//!
+//! let card = MockCard::new().ordered();
+//! card.expect(Expectation::header(Match::ins(0xa4).p1(0x04)).data(&AID));
+//! card.expect(
+//!     Expectation::apdu(&[0x00, 0xca, 0x00, 0x6e, 0x00])
+//!         .times(2)
+//!         .respond(&[0x6e, 0x00], apdu::SW_SUCCESS),
+//! );
+//!
+//! let mut host = card.clone();
+//! let select = [&[0x00, 0xa4, 0x04, 0x00, 0x06][..], &AID].concat();
+//! assert_eq!(host.execute(&select), [0x90, 0x00]);
+//! assert_eq!(host.execute(&[0x00, 0xca, 0x00, 0x6e, 0x00]), [0x6e, 0x00, 0x90, 0x00]);
+//! assert!(card.check().is_err());
+//! host.execute(&[0x00, 0xca, 0x00, 0x6e, 0x00]);
+//! card.verify();
+//! ```
+
+use std::{
+    fmt,
+    io::{Error, Result},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use crate::{
+    apdu::{self, Command},
+    hex,
+    router::Match,
+    VSmartCard, DEFAULT_ATR,
+};
+
+type Responder = Box<dyn FnMut(&[u8]) -> Vec<u8> + Send>;
+
+enum Pattern {
+    Header(Match, Option<Vec<u8>>),
+    Apdu(Vec<u8>),
+}
+
+/// An expected command of a [`MockCard`][], see the [module documentation][`self`].
+pub struct Expectation {
+    pattern: Pattern,
+    min: usize,
+    max: Option<usize>,
+    responder: Responder,
+    calls: usize,
+}
+
+impl Expectation {
+    /// Expects a command with a header matching the given pattern.
+    pub fn header(pattern: Match) -> Self {
+        Self::new(Pattern::Header(pattern, None))
+    }
+
+    /// Expects exactly the given command APDU.
+    pub fn apdu(apdu: &[u8]) -> Self {
+        Self::new(Pattern::Apdu(apdu.to_vec()))
+    }
+
+    fn new(pattern: Pattern) -> Self {
+        Self {
+            pattern,
+            min: 1,
+            max: Some(1),
+            responder: Box::new(|_| apdu::response(&[], apdu::SW_SUCCESS)),
+            calls: 0,
+        }
+    }
+
+    /// Additionally requires the given command data.
+    ///
+    /// This has no effect for expectations created with [`Expectation::apdu`][].
+    pub fn data(mut self, data: &[u8]) -> Self {
+        if let Pattern::Header(_, expected) = &mut self.pattern {
+            *expected = Some(data.to_vec());
+        }
+        self
+    }
+
+    /// Requires this expectation to be met exactly the given number of times.
+    pub fn times(mut self, n: usize) -> Self {
+        self.min = n;
+        self.max = Some(n);
+        self
+    }
+
+    /// Requires this expectation to be met at least the given number of times.
+    ///
+    /// `at_least(0)` allows any number of matching commands.
+    pub fn at_least(mut self, n: usize) -> Self {
+        self.min = n;
+        self.max = None;
+        self
+    }
+
+    /// Answers matching commands with the given data and status word.
+    ///
+    /// By default, matching commands are answered with
+    /// [`SW_SUCCESS`][`apdu::SW_SUCCESS`] without data.
+    pub fn respond(mut self, data: &[u8], sw: u16) -> Self {
+        let response = apdu::response(data, sw);
+        self.responder = Box::new(move |_| response.clone());
+        self
+    }
+
+    /// Answers matching commands using the given handler that returns the response data or an
+    /// error status word.
+    ///
+    /// Matching commands that cannot be parsed are answered with
+    /// [`SW_WRONG_LENGTH`][`apdu::SW_WRONG_LENGTH`].
+    pub fn respond_with<F>(mut self, mut handler: F) -> Self
+    where
+        F: FnMut(&Command<'_>) -> std::result::Result<Vec<u8>, u16> + Send + 'static,
+    {
+        self.responder = Box::new(move |msg| {
+            match Command::parse(msg)
+                .ok_or(apdu::SW_WRONG_LENGTH)
+                .and_then(|command| handler(&command))
+            {
+                Ok(data) => apdu::response(&data, apdu::SW_SUCCESS),
+                Err(sw) => apdu::response(&[], sw),
+            }
+        });
+        self
+    }
+
+    /// Returns the number of commands that matched this expectation.
+    pub fn calls(&self) -> usize {
+        self.calls
+    }
+
+    fn matches(&self, msg: &[u8], command: Option<&Command<'_>>) -> bool {
+        match &self.pattern {
+            Pattern::Header(pattern, data) => command.is_some_and(|command| {
+                pattern.matches(command) && data.as_ref().is_none_or(|data| data == command.data)
+            }),
+            Pattern::Apdu(apdu) => apdu == msg,
+        }
+    }
+
+    fn is_saturated(&self) -> bool {
+        self.max.is_some_and(|max| self.calls >= max)
+    }
+
+    fn is_satisfied(&self) -> bool {
+        self.calls >= self.min
+    }
+}
+
+impl fmt::Debug for Expectation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Expectation")
+            .field("pattern", &format_args!("{}", self))
+            .field("min", &self.min)
+            .field("max", &self.max)
+            .field("calls", &self.calls)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Display for Expectation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.pattern {
+            Pattern::Header(pattern, data) => {
+                let field = |value: Option<u8>| {
+                    value.map_or_else(|| "??".to_owned(), |value| format!("{:02x}", value))
+                };
+                write!(
+                    f,
+                    "{} {} {} {}",
+                    field(pattern.cla),
+                    field(pattern.ins),
+                    field(pattern.p1),
+                    field(pattern.p2)
+                )?;
+                if let Some(data) = data {
+                    write!(f, " with data {}", hex::encode(data))?;
+                }
+                Ok(())
+            }
+            Pattern::Apdu(apdu) => f.write_str(&hex::encode(apdu)),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    expectations: Vec<Expectation>,
+    position: usize,
+    unexpected: Vec<Vec<u8>>,
+}
+
+impl Inner {
+    fn find(&self, msg: &[u8], command: Option<&Command<'_>>, ordered: bool) -> Option<usize> {
+        if !ordered {
+            return self
+                .expectations
+                .iter()
+                .position(|e| !e.is_saturated() && e.matches(msg, command));
+        }
+        for (i, expectation) in self.expectations.iter().enumerate().skip(self.position) {
+            if !expectation.is_saturated() && expectation.matches(msg, command) {
+                return Some(i);
+            }
+            if !expectation.is_satisfied() {
+                break;
+            }
+        }
+        None
+    }
+}
+
+/// A card that answers commands according to expectations, see the
+/// [module documentation][`self`].
+#[derive(Clone, Debug)]
+pub struct MockCard {
+    atr: Vec<u8>,
+    ordered: bool,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl MockCard {
+    /// Creates an unordered mock card without expectations that uses [`DEFAULT_ATR`][].
+    pub fn new() -> Self {
+        Self {
+            atr: DEFAULT_ATR.to_vec(),
+            ordered: false,
+            inner: Default::default(),
+        }
+    }
+
+    /// Requires the expectations to be met in the order in which they are added.
+    pub fn ordered(mut self) -> Self {
+        self.ordered = true;
+        self
+    }
+
+    /// Sets the ATR of the card.
+    pub fn atr(mut self, atr: &[u8]) -> Self {
+        self.atr = atr.to_vec();
+        self
+    }
+
+    /// Adds an expectation.
+    pub fn expect(&self, expectation: Expectation) -> &Self {
+        self.lock().expectations.push(expectation);
+        self
+    }
+
+    /// Returns the unexpected commands received so far.
+    pub fn unexpected(&self) -> Vec<Vec<u8>> {
+        self.lock().unexpected.clone()
+    }
+
+    /// Checks that all expectations have been met and that no unexpected commands have been
+    /// received, returning an error describing all violations otherwise.
+    pub fn check(&self) -> Result<()> {
+        let inner = self.lock();
+        let mut violations = Vec::new();
+        for expectation in &inner.expectations {
+            if !expectation.is_satisfied() {
+                let expected = match expectation.max {
+                    Some(max) if max == expectation.min => format!("{}", max),
+                    _ => format!("at least {}", expectation.min),
+                };
+                violations.push(format!(
+                    "expected {} {} time(s), received {} time(s)",
+                    expectation, expected, expectation.calls
+                ));
+            }
+        }
+        for apdu in &inner.unexpected {
+            violations.push(format!("unexpected command {}", hex::encode(apdu)));
+        }
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::other(violations.join("\n")))
+        }
+    }
+
+    /// Panics if an expectation has not been met or if an unexpected command has been received,
+    /// see [`MockCard::check`][].
+    #[track_caller]
+    pub fn verify(&self) {
+        if let Err(err) = self.check() {
+            panic!("mock card expectations not met:\n{}", err);
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Default for MockCard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VSmartCard for MockCard {
+    fn atr(&self) -> &[u8] {
+        &self.atr
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        let command = Command::parse(msg);
+        let mut inner = self.lock();
+        let Some(i) = inner.find(msg, command.as_ref(), self.ordered) else {
+            inner.unexpected.push(msg.to_vec());
+            return apdu::response(&[], apdu::SW_NO_PRECISE_DIAGNOSIS);
+        };
+        if self.ordered {
+            inner.position = i;
+        }
+        let expectation = &mut inner.expectations[i];
+        expectation.calls += 1;
+        (expectation.responder)(msg)
+    }
+}
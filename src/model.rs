@@ -0,0 +1,323 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Model-based testing of card implementations.
+//!
+//! A [`Model`][] is a simplified description of the expected behavior of a card.  It generates
+//! random but protocol-valid [`Step`][]s based on its current state, for example a VERIFY
+//! command only after the application has been selected, and checks the response of the card
+//! after every step.  [`Harness`][] executes many random step sequences against fresh instances
+//! of the card and the model.  If a postcondition fails, the failing sequence is shrunk by
+//! removing steps that are not needed to reproduce the failure.
+//!
+//! # Example
+//!
+//! ```
+//! use vpicc::{apdu, model::{Gen, Harness, Model, Step}, VSmartCard};
+//!
+//! /// A card with a counter that is incremented by INS 10 and cleared on power cycles.
+//! #[derive(Default)]
+//! struct Counter(u8);
+//!
+//! impl VSmartCard for Counter {
+//!     fn power_off(&mut self) {
+//!         self.0 = 0;
+//!     }
+//!
+//!     fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+//!         match msg.get(1) {
+//!             Some(0x10) => {
+//!                 self.0 = self.0.wrapping_add(1);
+//!                 apdu::response(&[self.0], apdu::SW_SUCCESS)
+//!             }
+//!             _ => apdu::response(&[], apdu::SW_INS_NOT_SUPPORTED),
+//!         }
+//!     }
+//! }
+//!
+//! #[derive(Default)]
+//! struct CounterModel(u8);
+//!
+//! impl Model for CounterModel {
+//!     fn generate(&self, gen: &mut Gen) -> Step {
+//!         match gen.below(10) {
+//!             0 => Step::PowerCycle,
+//!             _ => Step::Command(vec![0x00, 0x10, 0x00, 0x00]),
+//!         }
+//!     }
+//!
+//!     fn check(&mut self, step: &Step, response: &[u8]) -> Result<(), String> {
+//!         match step {
+//!             Step::PowerCycle => self.0 = 0,
+//!             Step::Reset => {}
+//!             Step::Command(_) => {
+//!                 self.0 = self.0.wrapping_add(1);
+//!                 if response != [self.0, 0x90, 0x00] {
+//!                     return Err(format!("expected counter {}, got {:x?}", self.0, response));
+//!                 }
+//!             }
+//!         }
+//!         Ok(())
+//!     }
+//! }
+//!
+//! let report = Harness::new().run(Counter::default, CounterModel::default);
+//! assert!(report.is_success(), "{:?}", report.failure);
+//! ```
+
+use log::{debug, warn};
+
+use crate::{rng::Rng, VSmartCard};
+
+/// The default number of sequences executed by [`Harness::run`][].
+pub const DEFAULT_RUNS: usize = 100;
+/// The default number of steps per sequence.
+pub const DEFAULT_STEPS: usize = 50;
+
+/// A step of a test sequence.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Step {
+    /// Executes a command APDU.
+    Command(Vec<u8>),
+    /// Powers the card off and on again, causing a cold reset.
+    PowerCycle,
+    /// Resets the powered card, causing a warm reset.
+    Reset,
+}
+
+/// A source of randomness for [`Model::generate`][].
+///
+/// The values are derived from the seed of the [`Harness`][] so that failures can be
+/// reproduced.  They are not suitable for any cryptographic purpose.
+#[derive(Clone, Debug)]
+pub struct Gen {
+    rng: Rng,
+}
+
+impl Gen {
+    /// Creates a generator from the given seed.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: Rng::new(seed),
+        }
+    }
+
+    /// Returns a number in the range `0..n`, or zero if `n` is zero.
+    pub fn below(&mut self, n: usize) -> usize {
+        self.rng.below(n)
+    }
+
+    /// Returns true with the given probability.
+    pub fn chance(&mut self, probability: f64) -> bool {
+        self.rng.chance(probability)
+    }
+
+    /// Returns a random byte.
+    pub fn byte(&mut self) -> u8 {
+        self.rng.next_u64() as u8
+    }
+
+    /// Returns the given number of random bytes.
+    pub fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.byte()).collect()
+    }
+
+    /// Returns a random element of the given slice, or `None` if it is empty.
+    pub fn choose<'a, T>(&mut self, items: &'a [T]) -> Option<&'a T> {
+        if items.is_empty() {
+            None
+        } else {
+            Some(&items[self.below(items.len())])
+        }
+    }
+}
+
+/// A model of the expected behavior of a card, see the [module documentation][`self`].
+pub trait Model {
+    /// Generates the next step based on the current state of the model.
+    fn generate(&self, gen: &mut Gen) -> Step;
+
+    /// Checks the response of the card to the given step and updates the state of the model.
+    ///
+    /// For [`Step::PowerCycle`][] and [`Step::Reset`][], the response is empty.  Returns a
+    /// description of the problem if the postcondition does not hold.
+    fn check(&mut self, step: &Step, response: &[u8]) -> Result<(), String>;
+}
+
+/// A failed postcondition found by [`Harness::run`][].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Failure {
+    /// The seed of the failing sequence.
+    pub seed: u64,
+    /// The shrunk sequence of steps that reproduces the failure, ending with the failing step.
+    pub steps: Vec<Step>,
+    /// The description of the failure returned by [`Model::check`][].
+    pub message: String,
+}
+
+/// The result of [`Harness::run`][].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// The number of executed sequences.
+    pub runs: usize,
+    /// The number of executed steps, excluding shrinking.
+    pub steps: usize,
+    /// The first failure, if any.
+    pub failure: Option<Failure>,
+}
+
+impl Report {
+    /// Returns true if no postcondition failed.
+    pub fn is_success(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+/// Executes random step sequences against a card and a model.
+#[derive(Clone, Debug)]
+pub struct Harness {
+    seed: u64,
+    runs: usize,
+    steps: usize,
+}
+
+impl Harness {
+    /// Creates a harness with the seed zero, [`DEFAULT_RUNS`][] and [`DEFAULT_STEPS`][].
+    pub fn new() -> Self {
+        Self {
+            seed: 0,
+            runs: DEFAULT_RUNS,
+            steps: DEFAULT_STEPS,
+        }
+    }
+
+    /// Sets the seed of the first sequence, defaulting to zero.  Sequence `i` uses the seed
+    /// `seed + i`.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    /// Sets the number of sequences, defaulting to [`DEFAULT_RUNS`][].
+    pub fn set_runs(&mut self, runs: usize) {
+        self.runs = runs;
+    }
+
+    /// Sets the number of steps per sequence, defaulting to [`DEFAULT_STEPS`][].
+    pub fn set_steps(&mut self, steps: usize) {
+        self.steps = steps;
+    }
+
+    /// Executes the sequences, creating a fresh card and model for every sequence, and stops at
+    /// the first failure.
+    ///
+    /// Every sequence starts with a power cycle.
+    pub fn run<C, M, FC, FM>(&self, mut card: FC, mut model: FM) -> Report
+    where
+        C: VSmartCard,
+        M: Model,
+        FC: FnMut() -> C,
+        FM: FnMut() -> M,
+    {
+        let mut report = Report::default();
+        for run in 0..self.runs {
+            let seed = self.seed.wrapping_add(run as u64);
+            let mut gen = Gen::new(seed);
+            let mut card_instance = card();
+            let mut model_instance = model();
+            let mut steps = Vec::with_capacity(self.steps + 1);
+            report.runs += 1;
+            for i in 0..=self.steps {
+                let step = if i == 0 {
+                    Step::PowerCycle
+                } else {
+                    model_instance.generate(&mut gen)
+                };
+                report.steps += 1;
+                let response = execute(&mut card_instance, &step);
+                let result = model_instance.check(&step, &response);
+                steps.push(step);
+                if let Err(message) = result {
+                    warn!("Sequence {} failed at step {}: {}", seed, i, message);
+                    let (steps, message) = shrink(&mut card, &mut model, steps, message);
+                    report.failure = Some(Failure {
+                        seed,
+                        steps,
+                        message,
+                    });
+                    return report;
+                }
+            }
+            debug!("Sequence {} passed", seed);
+        }
+        report
+    }
+}
+
+impl Default for Harness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Removes steps from a failing sequence as long as the remaining sequence still fails.
+///
+/// The first step, the initial power cycle, is always kept.
+fn shrink<C, M>(
+    card: &mut impl FnMut() -> C,
+    model: &mut impl FnMut() -> M,
+    mut steps: Vec<Step>,
+    mut message: String,
+) -> (Vec<Step>, String)
+where
+    C: VSmartCard,
+    M: Model,
+{
+    let mut i = 1;
+    while i < steps.len() {
+        let mut candidate = steps.clone();
+        candidate.remove(i);
+        match replay(&mut card(), &mut model(), &candidate) {
+            Some((len, candidate_message)) => {
+                candidate.truncate(len);
+                steps = candidate;
+                message = candidate_message;
+            }
+            None => i += 1,
+        }
+    }
+    debug!("Shrunk failing sequence to {} steps", steps.len());
+    (steps, message)
+}
+
+/// Executes the given steps and returns the length of the sequence up to the first failing step
+/// and the failure message, or `None` if all steps pass.
+fn replay<C: VSmartCard, M: Model>(
+    card: &mut C,
+    model: &mut M,
+    steps: &[Step],
+) -> Option<(usize, String)> {
+    for (i, step) in steps.iter().enumerate() {
+        let response = execute(card, step);
+        if let Err(message) = model.check(step, &response) {
+            return Some((i + 1, message));
+        }
+    }
+    None
+}
+
+fn execute<C: VSmartCard>(card: &mut C, step: &Step) -> Vec<u8> {
+    match step {
+        Step::Command(command) => card.execute(command),
+        Step::PowerCycle => {
+            card.power_off();
+            card.power_on();
+            card.cold_reset();
+            Vec::new()
+        }
+        Step::Reset => {
+            card.reset();
+            card.warm_reset();
+            Vec::new()
+        }
+    }
+}
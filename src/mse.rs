@@ -0,0 +1,221 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! MANAGE SECURITY ENVIRONMENT commands as defined in ISO 7816-4.
+//!
+//! A [`SecurityEnvironment`][] consists of control reference [`Template`][]s that reference the
+//! keys and algorithms for the security operations.  [`Environments`][] holds the current
+//! security environment and the stored environments of a card and handles MSE SET, STORE,
+//! RESTORE and ERASE commands.  The key references of the current environment can be passed to
+//! [`pso::perform`][`crate::pso::perform`].
+//!
+//! MSE SET (P1 41 or 81) sets the algorithm reference (tag 80) and the key reference (tag 83 or
+//! 84) of the digital signature template (P2 B6), the confidentiality template (B8) or the
+//! authentication template (A4).
+//!
+//! # Example
+//!
+//! ```
+//! use vpicc::{apdu::Command, mse::{Environments, SecurityEnvironment}};
+//!
+//! let mut environments = Environments::new();
+//! // SE 1 selects the signature key 9C
+//! let mut se = SecurityEnvironment::default();
+//! se.signature.key = Some(vec![0x9c]);
+//! environments.insert(1, se);
+//!
+//! // MSE SET DST with the key 84 and the algorithm 54
+//! let msg = [0x00, 0x22, 0x41, 0xb6, 0x06, 0x80, 0x01, 0x54, 0x84, 0x01, 0x84];
+//! environments.manage(&Command::parse(&msg).unwrap())?;
+//! assert_eq!(environments.key_references().signature, Some(0x84));
+//! assert_eq!(environments.current().signature.algorithm, Some(vec![0x54]));
+//!
+//! // MSE RESTORE SE 1
+//! environments.manage(&Command::parse(&[0x00, 0x22, 0xf3, 0x01]).unwrap())?;
+//! assert_eq!(environments.key_references().signature, Some(0x9c));
+//! # Ok::<_, u16>(())
+//! ```
+
+use std::collections::BTreeMap;
+
+use crate::{apdu, pso::KeyReferences, tlv};
+
+/// The instruction byte of the MANAGE SECURITY ENVIRONMENT command.
+pub const INS_MSE: u8 = 0x22;
+
+const TAG_AT: u8 = 0xa4;
+const TAG_DST: u8 = 0xb6;
+const TAG_CT: u8 = 0xb8;
+
+/// A control reference template of a [`SecurityEnvironment`][].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Template {
+    /// The algorithm reference, tag 80.
+    pub algorithm: Option<Vec<u8>>,
+    /// The key reference, tag 83 or 84.
+    pub key: Option<Vec<u8>>,
+}
+
+impl Template {
+    /// Returns the key reference if it consists of a single byte.
+    pub fn key_reference(&self) -> Option<u8> {
+        match self.key.as_deref() {
+            Some(&[key]) => Some(key),
+            _ => None,
+        }
+    }
+
+    fn set(&mut self, data: &[u8]) -> Result<(), u16> {
+        let mut template = Self::default();
+        for object in tlv::iter(data) {
+            let object = object.ok_or(apdu::SW_WRONG_DATA)?;
+            match object.tag {
+                0x80 => template.algorithm = Some(object.value.to_vec()),
+                // private keys are usually referenced with 84, public and secret keys with 83
+                0x83 | 0x84 => template.key = Some(object.value.to_vec()),
+                // other data objects like file references and initial values are not used
+                _ => {}
+            }
+        }
+        *self = template;
+        Ok(())
+    }
+}
+
+/// A security environment that references the keys and algorithms for the security operations.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SecurityEnvironment {
+    /// The digital signature template for computing signatures.
+    pub signature: Template,
+    /// The digital signature template for verifying signatures and certificates.
+    pub verification: Template,
+    /// The confidentiality template for deciphering.
+    pub decipher: Template,
+    /// The authentication template.
+    pub authentication: Template,
+}
+
+impl SecurityEnvironment {
+    /// Returns the single-byte key references of this environment.
+    pub fn key_references(&self) -> KeyReferences {
+        KeyReferences {
+            signature: self.signature.key_reference(),
+            decipher: self.decipher.key_reference(),
+            verification: self.verification.key_reference(),
+        }
+    }
+
+    fn set(&mut self, p1: u8, p2: u8, data: &[u8]) -> Result<(), u16> {
+        // b7 of P1 indicates computation and decipherment, b8 verification and encipherment
+        let internal = p1 & 0x40 != 0;
+        let external = p1 & 0x80 != 0;
+        let templates = match p2 {
+            TAG_DST => [
+                internal.then_some(&mut self.signature),
+                external.then_some(&mut self.verification),
+            ],
+            TAG_CT if !external => [internal.then_some(&mut self.decipher), None],
+            TAG_AT => [
+                (internal || external).then_some(&mut self.authentication),
+                None,
+            ],
+            _ => return Err(apdu::SW_WRONG_P1P2),
+        };
+        let mut found = false;
+        for template in templates.into_iter().flatten() {
+            template.set(data)?;
+            found = true;
+        }
+        if found {
+            Ok(())
+        } else {
+            Err(apdu::SW_WRONG_P1P2)
+        }
+    }
+}
+
+/// The current and the stored security environments of a card, see the
+/// [module documentation][`self`].
+///
+/// The stored environments are identified by a security environment number (SEID).  SE 0 is
+/// restored when the card is [reset][`Environments::reset`] if it has been stored, otherwise the
+/// current environment is cleared.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Environments {
+    current: SecurityEnvironment,
+    stored: BTreeMap<u8, SecurityEnvironment>,
+}
+
+impl Environments {
+    /// Creates an empty current environment without stored environments.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores the given environment with the given number.
+    pub fn insert(&mut self, seid: u8, environment: SecurityEnvironment) {
+        self.stored.insert(seid, environment);
+    }
+
+    /// Returns the stored environment with the given number.
+    pub fn get(&self, seid: u8) -> Option<&SecurityEnvironment> {
+        self.stored.get(&seid)
+    }
+
+    /// Returns the current environment.
+    pub fn current(&self) -> &SecurityEnvironment {
+        &self.current
+    }
+
+    /// Returns a mutable reference to the current environment.
+    pub fn current_mut(&mut self) -> &mut SecurityEnvironment {
+        &mut self.current
+    }
+
+    /// Returns the single-byte key references of the current environment.
+    pub fn key_references(&self) -> KeyReferences {
+        self.current.key_references()
+    }
+
+    /// Restores SE 0 or clears the current environment, for example after a reset of the card.
+    pub fn reset(&mut self) {
+        self.current = self.stored.get(&0).cloned().unwrap_or_default();
+    }
+
+    /// Handles a MANAGE SECURITY ENVIRONMENT command and returns the response data or an error
+    /// status word.
+    ///
+    /// Commands with another instruction are rejected with
+    /// [`SW_INS_NOT_SUPPORTED`][`apdu::SW_INS_NOT_SUPPORTED`].  Restoring or erasing an
+    /// environment that has not been stored is rejected with
+    /// [`SW_DATA_NOT_FOUND`][`apdu::SW_DATA_NOT_FOUND`].
+    pub fn manage(&mut self, command: &apdu::Command<'_>) -> Result<Vec<u8>, u16> {
+        if command.ins != INS_MSE {
+            return Err(apdu::SW_INS_NOT_SUPPORTED);
+        }
+        match command.p1 & 0x0f {
+            // SET
+            0x01 => self.current.set(command.p1, command.p2, command.data)?,
+            // STORE
+            0x02 => {
+                self.stored.insert(command.p2, self.current.clone());
+            }
+            // RESTORE
+            0x03 => {
+                self.current = self
+                    .stored
+                    .get(&command.p2)
+                    .cloned()
+                    .ok_or(apdu::SW_DATA_NOT_FOUND)?;
+            }
+            // ERASE
+            0x04 => {
+                self.stored
+                    .remove(&command.p2)
+                    .ok_or(apdu::SW_DATA_NOT_FOUND)?;
+            }
+            _ => return Err(apdu::SW_WRONG_P1P2),
+        }
+        Ok(Vec::new())
+    }
+}
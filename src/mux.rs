@@ -0,0 +1,249 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Multiplexing several cards over one connection.
+//!
+//! vpcd expects one connection per card.  For large card farms behind a custom relay, this
+//! module defines an optional extension of the vpcd framing that multiplexes several logical
+//! cards over a single connection.  Every message is still prefixed with its length, but the
+//! payload starts with a big-endian `u16` channel number that identifies the card:
+//!
+//! ```text
+//! +--------+---------+------------------------------+
+//! | length | channel | vpcd message (control / APDU) |
+//! +--------+---------+------------------------------+
+//!   2 bytes  2 bytes   length - 2 bytes
+//! ```
+//!
+//! Requests from the relay and responses from the cards use the same format.  The channel
+//! [`CONTROL_CHANNEL`][] is reserved for control messages from the card side that announce
+//! cards:  [`ATTACH`][] followed by a channel number when a card is added and [`DETACH`][]
+//! followed by a channel number when it is removed.  If the relay sends a request for an unknown
+//! channel, the multiplexer answers with a [`DETACH`][] message for that channel.
+//!
+//! In [`Mode::Plain`][], the multiplexer talks plain vpcd without channel numbers and control
+//! messages so that the same code can be used with vpcd.  In this mode, only a single card can
+//! be added.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use vpicc::mux::{Mode, Multiplexer};
+//!
+//! fn main() -> vpicc::Result<()> {
+//!     let connection = vpicc::connect_socket("relay:35964")?;
+//!     let mut mux = Multiplexer::new(connection, Mode::Multiplexed);
+//!     for channel in 0..100 {
+//!         mux.add(channel, vpicc::DummySmartCard)?;
+//!     }
+//!     mux.run()
+//! }
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    io::{self, ErrorKind},
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use log::{debug, info, warn};
+
+use crate::{
+    buffer::Buffer, frame, pool::BufferPool, BoxedCard, Connection, Error, Result, VSmartCard,
+};
+
+/// The channel reserved for control messages in [`Mode::Multiplexed`][].
+pub const CONTROL_CHANNEL: u16 = 0xffff;
+/// The control message announcing a new card, followed by its channel.
+pub const ATTACH: u8 = 0x01;
+/// The control message announcing the removal of a card, followed by its channel.
+pub const DETACH: u8 = 0x00;
+
+/// The framing used by a [`Multiplexer`][].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Plain vpcd framing with a single card.
+    Plain,
+    /// The multiplexing extension with a channel number in every message.
+    Multiplexed,
+}
+
+/// A card and the state of its logical connection.
+struct Channel {
+    connection: Connection<Buffer>,
+    card: BoxedCard,
+}
+
+/// Serves several cards over a single connection, see the [module documentation][`self`].
+///
+/// Every card has its own logical [`Connection`][] with the fallback ATR and buffer pool of the
+/// connection passed to [`new`][`Multiplexer::new`].
+pub struct Multiplexer {
+    stream: TcpStream,
+    rx: Vec<u8>,
+    mode: Mode,
+    shutdown: Arc<AtomicBool>,
+    pool: Option<BufferPool>,
+    fallback_atr: Option<Vec<u8>>,
+    channels: BTreeMap<u16, Channel>,
+}
+
+impl Multiplexer {
+    /// Creates a multiplexer without cards for the given connection.
+    pub fn new(connection: Connection, mode: Mode) -> Self {
+        Self {
+            stream: connection.stream,
+            rx: connection.rx,
+            mode,
+            shutdown: connection.shutdown,
+            pool: connection.pool,
+            fallback_atr: connection.fallback_atr,
+            channels: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the framing used by this multiplexer.
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Returns the channels of all cards.
+    pub fn channels(&self) -> impl Iterator<Item = u16> + '_ {
+        self.channels.keys().copied()
+    }
+
+    /// Adds a card on the given channel and announces it to the relay.
+    ///
+    /// Returns an error if the channel is already in use or reserved, or if a card has already
+    /// been added in [`Mode::Plain`][].
+    pub fn add<V: VSmartCard + Send + 'static>(&mut self, channel: u16, card: V) -> Result<()> {
+        if channel == CONTROL_CHANNEL {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "the control channel cannot be used for a card",
+            )
+            .into());
+        }
+        if self.channels.contains_key(&channel) {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("channel {} is already in use", channel),
+            )
+            .into());
+        }
+        if self.mode == Mode::Plain && !self.channels.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "plain mode only supports a single card",
+            )
+            .into());
+        }
+        self.control(ATTACH, channel)?;
+        info!("Attached card on channel {}", channel);
+        let mut connection = Connection::new(Buffer::default());
+        connection.pool = self.pool.clone();
+        connection.fallback_atr = self.fallback_atr.clone();
+        self.channels.insert(
+            channel,
+            Channel {
+                connection,
+                card: Box::new(card),
+            },
+        );
+        Ok(())
+    }
+
+    /// Removes the card on the given channel and announces the removal to the relay.
+    pub fn remove(&mut self, channel: u16) -> Result<BoxedCard> {
+        let entry = self.channels.remove(&channel).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::NotFound,
+                format!("no card on channel {}", channel),
+            )
+        })?;
+        self.control(DETACH, channel)?;
+        info!("Detached card on channel {}", channel);
+        Ok(entry.card)
+    }
+
+    /// Handles all requests using the cards of this multiplexer.
+    ///
+    /// This is equivalent to calling [`poll`][`Multiplexer::poll`] until a call fails.  If the
+    /// connection is shut down using a [`ShutdownHandle`][`crate::ShutdownHandle`], `Ok(())` is
+    /// returned.
+    pub fn run(mut self) -> Result<()> {
+        while !self.is_shutdown() {
+            if let Err(err) = self.poll() {
+                if !self.is_shutdown() {
+                    return Err(err);
+                }
+                debug!("Multiplexer shut down: {}", err);
+            }
+        }
+        Ok(())
+    }
+
+    fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::Acquire)
+    }
+
+    /// Handles a single request using the card on the requested channel.
+    pub fn poll(&mut self) -> Result<()> {
+        let msg = frame::read_buffered(&mut self.stream, &mut self.rx)?;
+        let (channel, msg) = match self.mode {
+            Mode::Plain => {
+                let channel = self.channels.keys().next().copied().ok_or_else(|| {
+                    io::Error::new(ErrorKind::NotFound, "no card added to the multiplexer")
+                })?;
+                (channel, msg)
+            }
+            Mode::Multiplexed => match *msg.as_slice() {
+                [hi, lo, ref payload @ ..] => (u16::from_be_bytes([hi, lo]), payload.to_vec()),
+                _ => {
+                    return Err(Error::Protocol(
+                        "multiplexed message without channel".to_owned(),
+                    ))
+                }
+            },
+        };
+        let Some(entry) = self.channels.get_mut(&channel) else {
+            warn!("Received request for unknown channel {}", channel);
+            return self.control(DETACH, channel);
+        };
+        debug!("Channel {}: {:x?}", channel, msg);
+        entry
+            .connection
+            .get_mut()
+            .input
+            .extend(frame::try_encode(&msg)?);
+        entry.connection.poll(&mut entry.card)?;
+        let mut output = std::mem::take(&mut entry.connection.get_mut().output);
+        while let Some(response) = frame::decode(&mut output) {
+            self.send(channel, &response)?;
+        }
+        Ok(())
+    }
+
+    fn control(&mut self, op: u8, channel: u16) -> Result<()> {
+        if self.mode == Mode::Plain {
+            return Ok(());
+        }
+        let [hi, lo] = channel.to_be_bytes();
+        self.send(CONTROL_CHANNEL, &[op, hi, lo])
+    }
+
+    fn send(&mut self, channel: u16, data: &[u8]) -> Result<()> {
+        match self.mode {
+            Mode::Plain => frame::write(&mut self.stream, data)?,
+            Mode::Multiplexed => {
+                let msg = [&channel.to_be_bytes()[..], data].concat();
+                frame::write(&mut self.stream, &msg)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Human-readable names for classes, instructions and application identifiers.
+//!
+//! [`describe`][] combines these tables to turn a command APDU into a short description that can
+//! be used in log messages.
+
+use std::fmt::Write as _;
+
+use crate::{apdu::Command, hex};
+
+/// The interindustry instructions defined in ISO 7816-4.
+const INSTRUCTIONS: &[(u8, &str)] = &[
+    (0x04, "DEACTIVATE FILE"),
+    (0x0c, "ERASE RECORD"),
+    (0x0e, "ERASE BINARY"),
+    (0x0f, "ERASE BINARY"),
+    (0x10, "PERFORM SCQL OPERATION"),
+    (0x12, "PERFORM TRANSACTION OPERATION"),
+    (0x14, "PERFORM USER OPERATION"),
+    (0x20, "VERIFY"),
+    (0x21, "VERIFY"),
+    (0x22, "MANAGE SECURITY ENVIRONMENT"),
+    (0x24, "CHANGE REFERENCE DATA"),
+    (0x26, "DISABLE VERIFICATION REQUIREMENT"),
+    (0x28, "ENABLE VERIFICATION REQUIREMENT"),
+    (0x2a, "PERFORM SECURITY OPERATION"),
+    (0x2c, "RESET RETRY COUNTER"),
+    (0x44, "ACTIVATE FILE"),
+    (0x46, "GENERATE ASYMMETRIC KEY PAIR"),
+    (0x47, "GENERATE ASYMMETRIC KEY PAIR"),
+    (0x70, "MANAGE CHANNEL"),
+    (0x82, "EXTERNAL AUTHENTICATE"),
+    (0x84, "GET CHALLENGE"),
+    (0x86, "GENERAL AUTHENTICATE"),
+    (0x87, "GENERAL AUTHENTICATE"),
+    (0x88, "INTERNAL AUTHENTICATE"),
+    (0xa0, "SEARCH BINARY"),
+    (0xa1, "SEARCH BINARY"),
+    (0xa2, "SEARCH RECORD"),
+    (0xa4, "SELECT"),
+    (0xb0, "READ BINARY"),
+    (0xb1, "READ BINARY"),
+    (0xb2, "READ RECORD"),
+    (0xb3, "READ RECORD"),
+    (0xc0, "GET RESPONSE"),
+    (0xc2, "ENVELOPE"),
+    (0xc3, "ENVELOPE"),
+    (0xca, "GET DATA"),
+    (0xcb, "GET DATA"),
+    (0xd0, "WRITE BINARY"),
+    (0xd1, "WRITE BINARY"),
+    (0xd2, "WRITE RECORD"),
+    (0xd6, "UPDATE BINARY"),
+    (0xd7, "UPDATE BINARY"),
+    (0xda, "PUT DATA"),
+    (0xdb, "PUT DATA"),
+    (0xdc, "UPDATE RECORD"),
+    (0xdd, "UPDATE RECORD"),
+    (0xe0, "CREATE FILE"),
+    (0xe2, "APPEND RECORD"),
+    (0xe4, "DELETE FILE"),
+    (0xe6, "TERMINATE DF"),
+    (0xe8, "TERMINATE EF"),
+    (0xfe, "TERMINATE CARD USAGE"),
+];
+
+/// Well-known application identifiers.  An entry matches all AIDs that start with it.
+const APPLICATIONS: &[(&[u8], &str)] = &[
+    (&[0xd2, 0x76, 0x00, 0x01, 0x24, 0x01], "OpenPGP"),
+    (
+        &[0xa0, 0x00, 0x00, 0x03, 0x08, 0x00, 0x00, 0x10, 0x00],
+        "PIV",
+    ),
+    (&[0xa0, 0x00, 0x00, 0x06, 0x47, 0x2f, 0x00, 0x01], "FIDO"),
+    (&[0xa0, 0x00, 0x00, 0x05, 0x27, 0x21, 0x01], "OATH"),
+    (
+        &[0xa0, 0x00, 0x00, 0x08, 0x47, 0x00, 0x00, 0x00, 0x01],
+        "Nitrokey Admin",
+    ),
+    (
+        &[0xa0, 0x00, 0x00, 0x08, 0x47, 0x01, 0x00, 0x00, 0x01],
+        "Nitrokey Provisioner",
+    ),
+    (&[0xd2, 0x76, 0x00, 0x00, 0x85, 0x01, 0x01], "NDEF"),
+    (
+        &[0xa0, 0x00, 0x00, 0x01, 0x51, 0x00, 0x00, 0x00],
+        "GlobalPlatform ISD",
+    ),
+    (
+        &[
+            0xa0, 0x00, 0x00, 0x00, 0x63, 0x50, 0x4b, 0x43, 0x53, 0x2d, 0x31, 0x35,
+        ],
+        "PKCS#15",
+    ),
+    (&[0xa0, 0x00, 0x00, 0x02, 0x47, 0x10, 0x01], "eMRTD"),
+];
+
+/// Returns the name of an interindustry instruction defined in ISO 7816-4.
+///
+/// The instruction byte only has this meaning if the class byte is interindustry, see
+/// [`is_interindustry`][].
+pub fn instruction(ins: u8) -> Option<&'static str> {
+    INSTRUCTIONS
+        .iter()
+        .find(|(code, _)| *code == ins)
+        .map(|(_, name)| *name)
+}
+
+/// Returns the name of a well-known application with the given AID.
+///
+/// Partial AIDs that contain at least the five byte registered application provider identifier
+/// are matched too.
+pub fn application(aid: &[u8]) -> Option<&'static str> {
+    APPLICATIONS
+        .iter()
+        .find(|(prefix, _)| aid.starts_with(prefix) || (aid.len() >= 5 && prefix.starts_with(aid)))
+        .map(|(_, name)| *name)
+}
+
+/// Returns true if the class byte indicates an interindustry command.
+pub fn is_interindustry(cla: u8) -> bool {
+    matches!(cla, 0x00..=0x1f | 0x40..=0x7f)
+}
+
+/// Returns a short human-readable description of a command APDU.
+///
+/// # Example
+///
+/// ```
+/// use vpicc::names::describe;
+///
+/// let select = [0x00, 0xa4, 0x04, 0x00, 0x06, 0xd2, 0x76, 0x00, 0x01, 0x24, 0x01];
+/// assert_eq!(describe(&select), "SELECT (AID: OpenPGP)");
+/// assert_eq!(describe(&[0x00, 0x84, 0x00, 0x00, 0x08]), "GET CHALLENGE");
+/// assert_eq!(describe(&[0x80, 0x50, 0x00, 0x00]), "proprietary command 80 50");
+/// ```
+pub fn describe(apdu: &[u8]) -> String {
+    let Some(command) = Command::parse(apdu) else {
+        return format!("malformed command {:02x?}", apdu);
+    };
+    let name = if is_interindustry(command.cla) {
+        instruction(command.ins)
+    } else {
+        None
+    };
+    let Some(name) = name else {
+        let kind = if is_interindustry(command.cla) {
+            "unknown"
+        } else {
+            "proprietary"
+        };
+        return format!("{} command {:02x} {:02x}", kind, command.cla, command.ins);
+    };
+    let mut description = name.to_owned();
+    if command.ins == 0xa4 && command.p1 == 0x04 {
+        match application(command.data) {
+            Some(application) => write!(description, " (AID: {})", application),
+            None => write!(description, " (AID: {})", hex::encode(command.data)),
+        }
+        .ok();
+    }
+    if command.cla & 0x10 != 0 {
+        description.push_str(" (chained)");
+    }
+    description
+}
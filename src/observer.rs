@@ -0,0 +1,163 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Notifications about the traffic handled by a card.
+//!
+//! An [`Observer`][] receives [`Event`][]s, for example from a card wrapped with
+//! [`Observed`][], so that monitoring tools do not have to decode APDUs themselves.  A
+//! [`Supervisor`][`crate::Supervisor`] reports the state of its connection to vpcd, so that
+//! operators can alert on unstable links.
+
+use std::net::SocketAddr;
+
+use crate::{apdu, Capabilities, VSmartCard};
+
+/// An event reported to an [`Observer`][].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Event<'a> {
+    /// A SELECT command by AID was executed.
+    Select {
+        /// The selected AID.
+        aid: &'a [u8],
+        /// The status word returned by the card, if any.
+        status: Option<u16>,
+    },
+    /// The connection to vpcd was established.
+    Connected {
+        /// The address of vpcd.
+        addr: SocketAddr,
+    },
+    /// The connection to vpcd was closed because of an error.
+    Disconnected {
+        /// A description of the error.
+        reason: &'a str,
+    },
+    /// An attempt to reestablish the connection to vpcd is started.
+    ReconnectAttempt {
+        /// The number of the attempt since the connection was lost, starting at 1.
+        attempt: usize,
+    },
+    /// An attempt to reestablish the connection to vpcd failed.
+    ReconnectFailed {
+        /// The number of the attempt since the connection was lost, starting at 1.
+        attempt: usize,
+        /// A description of the error.
+        reason: &'a str,
+    },
+}
+
+impl Event<'_> {
+    /// Returns true if this event reports a successful SELECT command, i. e. if the AID matched
+    /// an application of the card.
+    pub fn is_selected(&self) -> bool {
+        match self {
+            Self::Select { status, .. } => status.is_some_and(apdu::is_success),
+            _ => false,
+        }
+    }
+}
+
+/// Receives [`Event`][]s.
+///
+/// This trait is implemented for all closures that accept an event.
+pub trait Observer {
+    /// Handles the given event.
+    fn on_event(&mut self, event: &Event<'_>);
+}
+
+impl<F: FnMut(&Event<'_>)> Observer for F {
+    fn on_event(&mut self, event: &Event<'_>) {
+        self(event)
+    }
+}
+
+/// Reports the events of the wrapped card to an observer.
+///
+/// # Example
+///
+/// ```
+/// use vpicc::{observer::{Event, Observed}, VSmartCard};
+///
+/// let mut card = Observed::new(vpicc::DummySmartCard, |event: &Event<'_>| {
+///     if let Event::Select { aid, .. } = event {
+///         println!("AID {:x?} selected: {}", aid, event.is_selected());
+///     }
+/// });
+/// card.execute(&[0x00, 0xa4, 0x04, 0x00, 0x06, 0xd2, 0x76, 0x00, 0x01, 0x24, 0x01]);
+/// ```
+#[derive(Debug)]
+pub struct Observed<C, O> {
+    card: C,
+    observer: O,
+}
+
+impl<C, O: Observer> Observed<C, O> {
+    /// Wraps the given card, reporting its events to the given observer.
+    pub fn new(card: C, observer: O) -> Self {
+        Self { card, observer }
+    }
+
+    /// Returns a reference to the observer.
+    pub fn observer(&self) -> &O {
+        &self.observer
+    }
+
+    /// Returns a reference to the wrapped card.
+    pub fn get_ref(&self) -> &C {
+        &self.card
+    }
+
+    /// Returns a mutable reference to the wrapped card.
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.card
+    }
+
+    /// Returns the wrapped card and the observer.
+    pub fn into_inner(self) -> (C, O) {
+        (self.card, self.observer)
+    }
+}
+
+impl<C: VSmartCard, O: Observer> VSmartCard for Observed<C, O> {
+    fn atr(&self) -> &[u8] {
+        self.card.atr()
+    }
+
+    fn power_on(&mut self) {
+        self.card.power_on()
+    }
+
+    fn power_off(&mut self) {
+        self.card.power_off()
+    }
+
+    fn reset(&mut self) {
+        self.card.reset()
+    }
+
+    fn cold_reset(&mut self) {
+        self.card.cold_reset()
+    }
+
+    fn warm_reset(&mut self) {
+        self.card.warm_reset()
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        let response = self.card.execute(msg);
+        if let Some(command) = apdu::Command::parse(msg) {
+            if command.ins == 0xa4 && command.p1 == 0x04 {
+                self.observer.on_event(&Event::Select {
+                    aid: command.data,
+                    status: apdu::status(&response),
+                });
+            }
+        }
+        response
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.card.capabilities()
+    }
+}
@@ -0,0 +1,357 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Card implementations loaded from shared libraries.
+//!
+//! A plugin is a shared library that exports a function `vpicc_plugin` returning a pointer to
+//! a [`VTable`][] with the current [`ABI_VERSION`][].  The table is a stable C interface, so
+//! plugins can be written in any language, see `include/vpicc_plugin.h`.  Plugins written in
+//! Rust can use the [`export_plugin`][`crate::export_plugin`] macro to export a [`VSmartCard`][]
+//! implementation.
+//!
+//! [`Plugin::open`][] loads a plugin and [`Plugin::create`][] creates a [`PluginCard`][] that
+//! can be used like any other card.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use vpicc::plugin::Plugin;
+//!
+//! fn main() -> vpicc::Result<()> {
+//!     // Safety: the plugin is trusted and implements the ABI correctly
+//!     let plugin = unsafe { Plugin::open("libmycard.so")? };
+//!     let mut card = plugin.create()?;
+//!     vpicc::connect()?.run(&mut card)
+//! }
+//! ```
+//!
+//! A plugin implemented in Rust is a `cdylib` crate containing:
+//!
+//! ```no_run
+//! vpicc::export_plugin!(vpicc::DummySmartCard, vpicc::DummySmartCard);
+//! ```
+
+use std::{
+    ffi::{c_void, OsStr},
+    fmt,
+    io::{Error, ErrorKind, Result},
+    panic::{self, AssertUnwindSafe},
+    ptr, slice,
+    sync::Arc,
+};
+
+use crate::{apdu, VSmartCard, DEFAULT_ATR, MAX_MESSAGE_LEN};
+
+/// The version of the plugin ABI described by [`VTable`][].
+pub const ABI_VERSION: u32 = 1;
+
+/// The name of the function exported by plugins.
+pub const SYMBOL: &[u8] = b"vpicc_plugin";
+
+/// The interface of a plugin.
+///
+/// All card pointers are created by `create` and passed to the other functions.  The functions
+/// may be called from any thread, but never concurrently for the same card.  The optional
+/// functions have the same meaning as the methods of [`VSmartCard`][].
+#[repr(C)]
+pub struct VTable {
+    /// The ABI version implemented by the plugin, [`ABI_VERSION`][].
+    pub abi_version: u32,
+    /// Creates a card and returns a pointer to it, or null if the card could not be created.
+    pub create: extern "C" fn() -> *mut c_void,
+    /// Destroys a card created with `create`.
+    pub destroy: extern "C" fn(card: *mut c_void),
+    /// Copies at most `len` bytes of the ATR into `buf` and returns the length of the ATR.  If
+    /// not set, [`DEFAULT_ATR`][] is used.
+    pub atr: Option<extern "C" fn(card: *mut c_void, buf: *mut u8, len: usize) -> usize>,
+    /// Handles a Power On command.
+    pub power_on: Option<extern "C" fn(card: *mut c_void)>,
+    /// Handles a Power Off command.
+    pub power_off: Option<extern "C" fn(card: *mut c_void)>,
+    /// Handles a Reset command.
+    pub reset: Option<extern "C" fn(card: *mut c_void)>,
+    /// Handles a cold reset.
+    pub cold_reset: Option<extern "C" fn(card: *mut c_void)>,
+    /// Handles a warm reset.
+    pub warm_reset: Option<extern "C" fn(card: *mut c_void)>,
+    /// Executes the command with `cmd_len` bytes at `cmd`, writes the response to `resp` and
+    /// returns its length.  The response buffer has `resp_len` bytes, which is at least
+    /// [`MAX_MESSAGE_LEN`][].  Returning a larger length is treated as an error.
+    pub execute: extern "C" fn(
+        card: *mut c_void,
+        cmd: *const u8,
+        cmd_len: usize,
+        resp: *mut u8,
+        resp_len: usize,
+    ) -> usize,
+}
+
+/// A loaded plugin, see the [module documentation][`self`].
+#[derive(Clone)]
+pub struct Plugin {
+    library: Arc<libloading::Library>,
+    vtable: &'static VTable,
+}
+
+impl Plugin {
+    /// Loads the plugin from the given shared library.
+    ///
+    /// Returns an [`InvalidData`][`ErrorKind::InvalidData`] error if the library does not
+    /// export a plugin or if it implements a different ABI version.
+    ///
+    /// # Safety
+    ///
+    /// Loading a library runs its initialization code, and the plugin must implement the
+    /// [`VTable`][] correctly.  Only trusted plugins must be loaded.
+    pub unsafe fn open<P: AsRef<OsStr>>(path: P) -> Result<Self> {
+        let library = libloading::Library::new(path.as_ref()).map_err(Error::other)?;
+        let vtable = {
+            let symbol = library
+                .get::<unsafe extern "C" fn() -> *const VTable>(SYMBOL)
+                .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+            symbol().as_ref()
+        };
+        let vtable = vtable
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "the plugin returned no table"))?;
+        if vtable.abi_version != ABI_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "the plugin implements ABI version {}, expected {}",
+                    vtable.abi_version, ABI_VERSION
+                ),
+            ));
+        }
+        // the table lives as long as the library, which is kept alive by every card
+        let vtable = &*(vtable as *const VTable);
+        Ok(Self {
+            library: Arc::new(library),
+            vtable,
+        })
+    }
+
+    /// Creates a card using the plugin.
+    pub fn create(&self) -> Result<PluginCard> {
+        let card = (self.vtable.create)();
+        if card.is_null() {
+            return Err(Error::other("the plugin failed to create a card"));
+        }
+        let mut card = PluginCard {
+            card,
+            vtable: self.vtable,
+            atr: Vec::new(),
+            buffer: vec![0; MAX_MESSAGE_LEN],
+            _library: self.library.clone(),
+        };
+        card.update_atr();
+        Ok(card)
+    }
+}
+
+impl fmt::Debug for Plugin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Plugin").finish_non_exhaustive()
+    }
+}
+
+/// A card created by a [`Plugin`][].
+pub struct PluginCard {
+    card: *mut c_void,
+    vtable: &'static VTable,
+    atr: Vec<u8>,
+    buffer: Vec<u8>,
+    _library: Arc<libloading::Library>,
+}
+
+// Safety: the ABI requires that the functions can be called from any thread
+unsafe impl Send for PluginCard {}
+
+impl PluginCard {
+    fn update_atr(&mut self) {
+        let Some(atr) = self.vtable.atr else {
+            self.atr = DEFAULT_ATR.to_vec();
+            return;
+        };
+        let len = atr(self.card, self.buffer.as_mut_ptr(), self.buffer.len());
+        let len = if len > self.buffer.len() {
+            self.atr.resize(len, 0);
+            atr(self.card, self.atr.as_mut_ptr(), len).min(len)
+        } else {
+            self.atr.clear();
+            self.atr.extend_from_slice(&self.buffer[..len]);
+            len
+        };
+        self.atr.truncate(len);
+    }
+
+    fn hook(&mut self, hook: Option<extern "C" fn(*mut c_void)>) {
+        if let Some(hook) = hook {
+            hook(self.card);
+        }
+    }
+}
+
+impl fmt::Debug for PluginCard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PluginCard")
+            .field("atr", &self.atr)
+            .finish_non_exhaustive()
+    }
+}
+
+impl VSmartCard for PluginCard {
+    fn atr(&self) -> &[u8] {
+        &self.atr
+    }
+
+    fn power_on(&mut self) {
+        self.hook(self.vtable.power_on);
+    }
+
+    fn power_off(&mut self) {
+        self.hook(self.vtable.power_off);
+    }
+
+    fn reset(&mut self) {
+        self.hook(self.vtable.reset);
+    }
+
+    fn cold_reset(&mut self) {
+        self.hook(self.vtable.cold_reset);
+        self.update_atr();
+    }
+
+    fn warm_reset(&mut self) {
+        self.hook(self.vtable.warm_reset);
+        self.update_atr();
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        let len = (self.vtable.execute)(
+            self.card,
+            msg.as_ptr(),
+            msg.len(),
+            self.buffer.as_mut_ptr(),
+            self.buffer.len(),
+        );
+        match self.buffer.get(..len) {
+            Some(response) => response.to_vec(),
+            None => {
+                log::warn!("Plugin returned a response with {} bytes", len);
+                apdu::response(&[], apdu::SW_NO_PRECISE_DIAGNOSIS)
+            }
+        }
+    }
+}
+
+impl Drop for PluginCard {
+    fn drop(&mut self) {
+        (self.vtable.destroy)(self.card);
+    }
+}
+
+/// Exports a [`VSmartCard`][] implementation as a plugin, see the
+/// [module documentation][`crate::plugin`].
+///
+/// The first argument is the type of the card, which must implement [`Send`][], and the
+/// second argument an expression creating a card.  Panics of the card are caught:  a panic in
+/// `execute` is answered with 6F00, and a panic while creating a card makes `create` fail.
+#[macro_export]
+macro_rules! export_plugin {
+    ($card:ty, $create:expr) => {
+        #[no_mangle]
+        pub extern "C" fn vpicc_plugin() -> *const $crate::plugin::VTable {
+            extern "C" fn create() -> *mut ::std::ffi::c_void {
+                $crate::plugin::__export::create::<$card>(|| $create)
+            }
+            static VTABLE: $crate::plugin::VTable =
+                $crate::plugin::__export::vtable::<$card>(create);
+            &VTABLE
+        }
+    };
+}
+
+#[doc(hidden)]
+pub mod __export {
+    use super::*;
+
+    fn catch<T>(f: impl FnOnce() -> T) -> Option<T> {
+        panic::catch_unwind(AssertUnwindSafe(f)).ok()
+    }
+
+    /// Safety: the pointer must have been created by `create::<V>`.
+    unsafe fn card<'a, V>(card: *mut c_void) -> &'a mut V {
+        &mut *card.cast::<V>()
+    }
+
+    pub fn create<V: VSmartCard + Send>(f: impl FnOnce() -> V) -> *mut c_void {
+        catch(|| Box::into_raw(Box::new(f())).cast()).unwrap_or(ptr::null_mut())
+    }
+
+    pub const fn vtable<V: VSmartCard + Send>(create: extern "C" fn() -> *mut c_void) -> VTable {
+        VTable {
+            abi_version: ABI_VERSION,
+            create,
+            destroy: destroy::<V>,
+            atr: Some(atr::<V>),
+            power_on: Some(power_on::<V>),
+            power_off: Some(power_off::<V>),
+            reset: Some(reset::<V>),
+            cold_reset: Some(cold_reset::<V>),
+            warm_reset: Some(warm_reset::<V>),
+            execute: execute::<V>,
+        }
+    }
+
+    extern "C" fn destroy<V>(card: *mut c_void) {
+        catch(|| drop(unsafe { Box::from_raw(card.cast::<V>()) }));
+    }
+
+    extern "C" fn atr<V: VSmartCard>(card: *mut c_void, buf: *mut u8, len: usize) -> usize {
+        catch(|| {
+            let atr = unsafe { self::card::<V>(card) }.atr();
+            let n = atr.len().min(len);
+            unsafe { ptr::copy_nonoverlapping(atr.as_ptr(), buf, n) };
+            atr.len()
+        })
+        .unwrap_or(0)
+    }
+
+    extern "C" fn power_on<V: VSmartCard>(card: *mut c_void) {
+        catch(|| unsafe { self::card::<V>(card) }.power_on());
+    }
+
+    extern "C" fn power_off<V: VSmartCard>(card: *mut c_void) {
+        catch(|| unsafe { self::card::<V>(card) }.power_off());
+    }
+
+    extern "C" fn reset<V: VSmartCard>(card: *mut c_void) {
+        catch(|| unsafe { self::card::<V>(card) }.reset());
+    }
+
+    extern "C" fn cold_reset<V: VSmartCard>(card: *mut c_void) {
+        catch(|| unsafe { self::card::<V>(card) }.cold_reset());
+    }
+
+    extern "C" fn warm_reset<V: VSmartCard>(card: *mut c_void) {
+        catch(|| unsafe { self::card::<V>(card) }.warm_reset());
+    }
+
+    extern "C" fn execute<V: VSmartCard>(
+        card: *mut c_void,
+        cmd: *const u8,
+        cmd_len: usize,
+        resp: *mut u8,
+        resp_len: usize,
+    ) -> usize {
+        let response = catch(|| {
+            let msg = unsafe { slice::from_raw_parts(cmd, cmd_len) };
+            unsafe { self::card::<V>(card) }.execute_small(msg)
+        })
+        .unwrap_or_else(|| apdu::small_response(&[], apdu::SW_NO_PRECISE_DIAGNOSIS));
+        if response.len() <= resp_len {
+            unsafe { ptr::copy_nonoverlapping(response.as_ptr(), resp, response.len()) };
+        }
+        response.len()
+    }
+}
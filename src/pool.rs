@@ -0,0 +1,116 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Reusable buffers for APDU processing.
+//!
+//! By default, every exchange allocates a buffer for the received command and for the response.
+//! A [`BufferPool`][] keeps buffers that are no longer used so that they can be reused for the
+//! next exchange.  If a pool is set for a [`Connection`][`crate::Connection`], see
+//! [`Connection::set_buffer_pool`][`crate::Connection::set_buffer_pool`], commands are read into
+//! buffers from the pool and the command and response buffers are returned to the pool after
+//! the response has been sent.  Cards and middleware layers can take their response buffers from
+//! the same pool, so that steady-state operation does not allocate, also with extended-length
+//! APDUs.  Responses are framed without copying them into a temporary buffer.
+//!
+//! The buffers kept by a pool are not freed until the pool is dropped.  With the defaults,
+//! [`DEFAULT_MAX_BUFFERS`][] buffers of [`DEFAULT_BUFFER_CAPACITY`][] bytes, a pool holds up to
+//! 16 × 65535 bytes, about 1 MiB.  Use [`BufferPool::new`][] with a smaller number of buffers or
+//! a smaller capacity if the cards only exchange short APDUs.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use vpicc::{pool::BufferPool, VSmartCard};
+//!
+//! struct Card {
+//!     pool: BufferPool,
+//! }
+//!
+//! impl VSmartCard for Card {
+//!     fn execute(&mut self, _msg: &[u8]) -> Vec<u8> {
+//!         let mut response = self.pool.get();
+//!         response.resize(1024, 0x00);
+//!         response.extend_from_slice(&[0x90, 0x00]);
+//!         response
+//!     }
+//! }
+//!
+//! fn main() -> vpicc::Result<()> {
+//!     let pool = BufferPool::default();
+//!     let mut connection = vpicc::connect()?;
+//!     connection.set_buffer_pool(pool.clone());
+//!     connection.run(&mut Card { pool })
+//! }
+//! ```
+
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// The default capacity of the buffers allocated by a [`BufferPool`][], enough for any message
+/// sent by vpcd.
+pub const DEFAULT_BUFFER_CAPACITY: usize = u16::MAX as usize;
+/// The default maximum number of buffers kept by a [`BufferPool`][].
+pub const DEFAULT_MAX_BUFFERS: usize = 16;
+
+/// A shared pool of reusable buffers, see the [module documentation][`self`].
+///
+/// Cloning a pool returns a handle to the same pool.
+#[derive(Clone, Debug)]
+pub struct BufferPool {
+    buffers: Arc<Mutex<Vec<Vec<u8>>>>,
+    buffer_capacity: usize,
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    /// Creates an empty pool that allocates buffers with the given capacity and keeps at most
+    /// the given number of unused buffers.
+    pub fn new(buffer_capacity: usize, max_buffers: usize) -> Self {
+        Self {
+            buffers: Default::default(),
+            buffer_capacity,
+            max_buffers,
+        }
+    }
+
+    /// Returns an empty buffer from the pool, or a newly allocated buffer if the pool is empty.
+    pub fn get(&self) -> Vec<u8> {
+        self.buffers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pop()
+            .unwrap_or_else(|| Vec::with_capacity(self.buffer_capacity))
+    }
+
+    /// Returns a buffer to the pool.
+    ///
+    /// The buffer is cleared.  If the pool is full or the buffer has no capacity, it is dropped.
+    pub fn put(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() == 0 {
+            return;
+        }
+        buffer.clear();
+        let mut buffers = self.buffers.lock().unwrap_or_else(PoisonError::into_inner);
+        if buffers.len() < self.max_buffers {
+            buffers.push(buffer);
+        }
+    }
+
+    /// Returns the number of unused buffers in the pool.
+    pub fn len(&self) -> usize {
+        self.buffers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    /// Returns true if the pool does not contain any unused buffers.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_BUFFER_CAPACITY, DEFAULT_MAX_BUFFERS)
+    }
+}
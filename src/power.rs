@@ -0,0 +1,68 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! The power state machine shared by the blocking and the async connections.
+
+use crate::{PowerState, Request, VSmartCard};
+
+/// The power handlers of a card.
+pub trait Handlers {
+    fn power_on(&mut self);
+    fn power_off(&mut self);
+    fn reset(&mut self);
+    fn cold_reset(&mut self);
+    fn warm_reset(&mut self);
+}
+
+impl<V: VSmartCard + ?Sized> Handlers for V {
+    fn power_on(&mut self) {
+        VSmartCard::power_on(self)
+    }
+
+    fn power_off(&mut self) {
+        VSmartCard::power_off(self)
+    }
+
+    fn reset(&mut self) {
+        VSmartCard::reset(self)
+    }
+
+    fn cold_reset(&mut self) {
+        VSmartCard::cold_reset(self)
+    }
+
+    fn warm_reset(&mut self) {
+        VSmartCard::warm_reset(self)
+    }
+}
+
+/// Updates the power state for the given request and calls the power handlers of the card.
+///
+/// Power On and Reset commands for a card that is powered off cause a cold reset, Reset commands
+/// for a powered card cause a warm reset.  Get ATR requests and APDUs do not change the power
+/// state and have to be handled by the caller.
+pub fn handle<H: Handlers + ?Sized>(request: &Request, card: &mut H, power: &mut PowerState) {
+    let previous = *power;
+    match request {
+        Request::PowerOff => {
+            *power = PowerState::Off;
+            card.power_off();
+        }
+        Request::PowerOn => {
+            *power = PowerState::On;
+            card.power_on();
+            if previous == PowerState::Off {
+                card.cold_reset();
+            }
+        }
+        Request::Reset => {
+            *power = PowerState::On;
+            card.reset();
+            match previous {
+                PowerState::Off => card.cold_reset(),
+                PowerState::On => card.warm_reset(),
+            }
+        }
+        Request::GetAtr | Request::Apdu(_) => {}
+    }
+}
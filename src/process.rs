@@ -0,0 +1,295 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Running cards in a child process.
+//!
+//! [`ProcessCard`][] forwards all requests to a card running in a child process, so that a
+//! crashing or memory-hungry card cannot take down the process serving other cards.  The child
+//! process calls [`serve`][] with the card implementation.  Requests and responses are exchanged
+//! over the standard input and output of the child using the same framing as vpcd, so the child
+//! must not write anything else to stdout.  Logs can be written to stderr.
+//!
+//! If the child process crashes, the card responds with 6F00 until the next Power On or Reset
+//! command, which starts a new child process.  Resource limits for the child can be set on the
+//! [`Command`][], for example using `prlimit`.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::process::Command;
+//! use vpicc::process::{self, ProcessCard};
+//!
+//! fn main() -> vpicc::Result<()> {
+//!     if std::env::args().any(|arg| arg == "--card") {
+//!         return Ok(process::serve(&mut vpicc::DummySmartCard)?);
+//!     }
+//!     let mut command = Command::new(std::env::current_exe()?);
+//!     command.arg("--card");
+//!     let mut card = ProcessCard::spawn(command)?;
+//!     vpicc::connect()?.run(&mut card)
+//! }
+//! ```
+
+use std::{
+    fmt,
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Result, Write},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+
+use crate::{apdu, frame, PowerState, Request, VSmartCard, DEFAULT_ATR};
+
+/// The time that a child process gets to exit after its input has been closed before it is
+/// killed.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A card running in a child process, see the [module documentation][`self`].
+pub struct ProcessCard {
+    command: Command,
+    child: Option<Worker>,
+    atr: Vec<u8>,
+    crashes: usize,
+}
+
+impl ProcessCard {
+    /// Creates a card that starts a child process using the given command on the first Power On
+    /// or Reset command.
+    ///
+    /// Until the child process has reported its ATR, [`DEFAULT_ATR`][] is used.
+    pub fn new(command: Command) -> Self {
+        Self {
+            command,
+            child: None,
+            atr: DEFAULT_ATR.to_vec(),
+            crashes: 0,
+        }
+    }
+
+    /// Starts a child process using the given command and queries its ATR, so that errors are
+    /// reported immediately.
+    pub fn spawn(command: Command) -> Result<Self> {
+        let mut card = Self::new(command);
+        card.start()?;
+        card.update_atr();
+        if card.child.is_none() {
+            return Err(io::Error::other("card process terminated during startup"));
+        }
+        Ok(card)
+    }
+
+    /// Returns the process ID of the running child process, if any.
+    pub fn id(&self) -> Option<u32> {
+        self.child.as_ref().map(|child| child.process.id())
+    }
+
+    /// Returns the number of times that the child process crashed.
+    pub fn crashes(&self) -> usize {
+        self.crashes
+    }
+
+    fn start(&mut self) -> Result<()> {
+        if self.child.is_some() {
+            return Ok(());
+        }
+        let mut process = self
+            .command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let (Some(stdin), Some(stdout)) = (process.stdin.take(), process.stdout.take()) else {
+            let _ = process.kill();
+            return Err(io::Error::other(
+                "failed to open the pipes of the card process",
+            ));
+        };
+        debug!("Started card process {}", process.id());
+        self.child = Some(Worker {
+            process,
+            stdin: BufWriter::new(stdin),
+            stdout: BufReader::new(stdout),
+        });
+        Ok(())
+    }
+
+    /// Starts the child process if it is not running, logging errors.
+    fn ensure_started(&mut self) {
+        if let Err(err) = self.start() {
+            warn!("Failed to start card process: {}", err);
+        }
+    }
+
+    /// Sends the request to the child process and returns its response if the request has one.
+    ///
+    /// If the child process fails, it is stopped and `None` is returned.
+    fn request(&mut self, request: &Request) -> Option<Vec<u8>> {
+        let child = self.child.as_mut()?;
+        match child.request(request) {
+            Ok(response) => response,
+            Err(err) => {
+                self.crashes += 1;
+                let child = self.child.take()?;
+                let id = child.process.id();
+                match child.stop() {
+                    Ok(status) => warn!("Card process {} failed ({}): {}", id, status, err),
+                    Err(_) => warn!("Card process {} failed: {}", id, err),
+                }
+                None
+            }
+        }
+    }
+
+    fn update_atr(&mut self) {
+        if let Some(atr) = self.request(&Request::GetAtr) {
+            self.atr = atr;
+        }
+    }
+}
+
+impl fmt::Debug for ProcessCard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProcessCard")
+            .field("command", &self.command)
+            .field("id", &self.id())
+            .field("atr", &self.atr)
+            .field("crashes", &self.crashes)
+            .finish()
+    }
+}
+
+impl VSmartCard for ProcessCard {
+    fn atr(&self) -> &[u8] {
+        &self.atr
+    }
+
+    fn power_on(&mut self) {
+        self.ensure_started();
+        self.request(&Request::PowerOn);
+    }
+
+    fn power_off(&mut self) {
+        self.request(&Request::PowerOff);
+    }
+
+    fn reset(&mut self) {
+        self.ensure_started();
+        self.request(&Request::Reset);
+    }
+
+    fn cold_reset(&mut self) {
+        self.update_atr();
+    }
+
+    fn warm_reset(&mut self) {
+        self.update_atr();
+    }
+
+    /// Executes the command in the child process.
+    ///
+    /// Commands shorter than two bytes cannot be distinguished from control commands and are
+    /// rejected with 6700.
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        if msg.len() < 2 {
+            return apdu::response(&[], apdu::SW_WRONG_LENGTH);
+        }
+        self.request(&Request::Apdu(msg.to_vec()))
+            .unwrap_or_else(|| apdu::response(&[], apdu::SW_NO_PRECISE_DIAGNOSIS))
+    }
+}
+
+impl Drop for ProcessCard {
+    fn drop(&mut self) {
+        if let Some(child) = self.child.take() {
+            if let Err(err) = child.stop() {
+                warn!("Failed to stop card process: {}", err);
+            }
+        }
+    }
+}
+
+struct Worker {
+    process: Child,
+    stdin: BufWriter<ChildStdin>,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Worker {
+    fn request(&mut self, request: &Request) -> Result<Option<Vec<u8>>> {
+        let msg = match request {
+            Request::PowerOff => &[0][..],
+            Request::PowerOn => &[1],
+            Request::Reset => &[2],
+            Request::GetAtr => &[4],
+            Request::Apdu(apdu) => apdu,
+        };
+        frame::write(&mut self.stdin, msg)?;
+        self.stdin.flush()?;
+        match request {
+            Request::GetAtr | Request::Apdu(_) => frame::read(&mut self.stdout).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Closes the input of the child process and waits for it to exit, killing it after the
+    /// [`SHUTDOWN_TIMEOUT`][].
+    fn stop(self) -> Result<std::process::ExitStatus> {
+        let Self {
+            mut process, stdin, ..
+        } = self;
+        drop(stdin);
+        let start = Instant::now();
+        while start.elapsed() < SHUTDOWN_TIMEOUT {
+            if let Some(status) = process.try_wait()? {
+                return Ok(status);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        debug!("Killing card process {}", process.id());
+        process.kill()?;
+        process.wait()
+    }
+}
+
+/// Handles requests from the standard input using the given card and writes the responses to
+/// the standard output, see the [module documentation][`self`].
+///
+/// Returns when the standard input is closed.
+pub fn serve<V: VSmartCard>(card: &mut V) -> Result<()> {
+    serve_on(io::stdin().lock(), io::stdout().lock(), card)
+}
+
+/// Handles requests from the given reader using the given card and writes the responses to the
+/// given writer.
+///
+/// Returns when the reader reaches the end of its input.
+///
+/// # Example
+///
+/// ```
+/// let (input, _) = vpicc::vectors::session();
+/// let mut output = Vec::new();
+/// vpicc::process::serve_on(input.as_slice(), &mut output, &mut vpicc::DummySmartCard)?;
+/// // the ATR and the response to the two APDUs
+/// assert_eq!(output.len(), 2 + vpicc::DEFAULT_ATR.len() + 2 * 4);
+/// # Ok::<_, std::io::Error>(())
+/// ```
+pub fn serve_on<R: Read, W: Write, V: VSmartCard>(
+    mut reader: R,
+    mut writer: W,
+    card: &mut V,
+) -> Result<()> {
+    let mut power = PowerState::default();
+    loop {
+        let msg = match frame::read(&mut reader) {
+            Ok(msg) => msg,
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        if let Some(response) = Request::try_from(msg)?.handle_with_state(card, &mut power) {
+            frame::write(&mut writer, &response)?;
+            writer.flush()?;
+        }
+    }
+}
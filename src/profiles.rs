@@ -0,0 +1,14 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Emulations of common card types for integration tests.
+//!
+//! The profiles implement just enough of the respective specifications to exercise host
+//! software with reproducible virtual cards.  They are neither complete nor certified and do not
+//! perform any real cryptography.
+
+pub mod calypso;
+pub mod egk;
+pub mod euicc;
+pub mod mdl;
+pub mod piv;
@@ -0,0 +1,459 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! A Calypso-style transit card.
+//!
+//! [`CalypsoCard`][] stores records in files identified by their short file identifier (SFI) and
+//! offers a stored value purse.  All modifications have to be made in a secure session that is
+//! opened with OPEN SECURE SESSION (8A) and committed with CLOSE SECURE SESSION (8E).  A reset or
+//! power cycle aborts the session and discards its modifications.  Signatures are not verified
+//! and the card signatures are random.
+//!
+//! The committed records and the purse can be persisted with a [`StateBackend`][] using
+//! [`CalypsoCard::set_backend`][].  Closing a session stores all modifications atomically.
+//!
+//! # Example
+//!
+//! ```
+//! use vpicc::{profiles::calypso::{CalypsoCard, AID, SFI_CONTRACTS}, VSmartCard};
+//!
+//! let mut card = CalypsoCard::new();
+//! let select = [&[0x00, 0xa4, 0x04, 0x00, AID.len() as u8][..], AID].concat();
+//! assert!(card.execute(&select).ends_with(&[0x90, 0x00]));
+//!
+//! // open a session, write the first contract and close the session
+//! assert!(card.execute(&[0x00, 0x8a, 0x00, 0x00, 0x04, 1, 2, 3, 4]).ends_with(&[0x90, 0x00]));
+//! let update = [&[0x00, 0xdc, 0x01, (SFI_CONTRACTS << 3) | 0x04, 0x02][..], &[0xca, 0xfe]].concat();
+//! assert_eq!(card.execute(&update), [0x90, 0x00]);
+//! assert!(card.execute(&[0x00, 0x8e, 0x00, 0x00, 0x04, 0, 0, 0, 0]).ends_with(&[0x90, 0x00]));
+//!
+//! assert_eq!(&card.record(SFI_CONTRACTS, 1).unwrap()[..2], [0xca, 0xfe]);
+//! ```
+
+use std::{collections::BTreeMap, io, sync::Arc};
+
+use log::warn;
+
+use crate::{
+    apdu::{self, Command},
+    rng::Rng,
+    state::{Change, StateBackend},
+    Capabilities, VSmartCard,
+};
+
+/// The AID of the transit application, `1TIC.ICA`.
+pub const AID: &[u8] = b"1TIC.ICA";
+/// The length of all records.
+pub const RECORD_LEN: usize = 29;
+/// The SFI of the environment file with one record.
+pub const SFI_ENVIRONMENT: u8 = 0x07;
+/// The SFI of the cyclic event log file with three records.
+pub const SFI_EVENT_LOG: u8 = 0x08;
+/// The SFI of the contracts file with four records.
+pub const SFI_CONTRACTS: u8 = 0x09;
+
+/// The maximum stored value balance, the largest signed 24-bit integer.
+pub const MAX_BALANCE: i32 = 0x7f_ffff;
+
+const INS_SELECT: u8 = 0xa4;
+const INS_READ_RECORD: u8 = 0xb2;
+const INS_UPDATE_RECORD: u8 = 0xdc;
+const INS_APPEND_RECORD: u8 = 0xe2;
+const INS_GET_CHALLENGE: u8 = 0x84;
+const INS_OPEN_SESSION: u8 = 0x8a;
+const INS_CLOSE_SESSION: u8 = 0x8e;
+const INS_SV_GET: u8 = 0x7c;
+const INS_SV_RELOAD: u8 = 0xb8;
+const INS_SV_DEBIT: u8 = 0xba;
+
+const KEY_BALANCE: &str = "calypso/balance";
+const KEY_SV_TRANSACTIONS: &str = "calypso/sv-transactions";
+
+type Record = [u8; RECORD_LEN];
+
+#[derive(Clone, Debug)]
+struct File {
+    records: Vec<Record>,
+    cyclic: bool,
+}
+
+impl File {
+    fn new(records: usize, cyclic: bool) -> Self {
+        Self {
+            records: vec![[0; RECORD_LEN]; records],
+            cyclic,
+        }
+    }
+}
+
+/// The data that can be modified in a session.
+#[derive(Clone, Debug)]
+struct State {
+    files: BTreeMap<u8, File>,
+    balance: i32,
+    sv_transactions: u16,
+}
+
+/// An emulated Calypso-style transit card, see the [module documentation][`self`].
+#[derive(Clone, Debug)]
+pub struct CalypsoCard {
+    serial: [u8; 8],
+    selected: bool,
+    state: State,
+    session: Option<State>,
+    rng: Rng,
+    backend: Option<Arc<dyn StateBackend>>,
+}
+
+impl CalypsoCard {
+    /// Creates a card with empty environment, event log and contracts files and a zero balance.
+    pub fn new() -> Self {
+        let files = [
+            (SFI_ENVIRONMENT, File::new(1, false)),
+            (SFI_EVENT_LOG, File::new(3, true)),
+            (SFI_CONTRACTS, File::new(4, false)),
+        ];
+        Self {
+            serial: [0; 8],
+            selected: false,
+            state: State {
+                files: files.into_iter().collect(),
+                balance: 0,
+                sv_transactions: 0,
+            },
+            session: None,
+            rng: Rng::new(0),
+            backend: None,
+        }
+    }
+
+    /// Sets the serial number returned in the FCI of the application.
+    pub fn set_serial(&mut self, serial: [u8; 8]) {
+        self.serial = serial;
+    }
+
+    /// Sets the seed for the challenges and signatures, defaulting to zero.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    /// Sets the content of a record, padding it with zeros.
+    ///
+    /// Records are numbered starting at one.  Returns false if the file or record does not exist
+    /// or if the data is longer than [`RECORD_LEN`][].  If a backend is set, errors while
+    /// storing the record are logged.
+    pub fn set_record(&mut self, sfi: u8, record: u8, data: &[u8]) -> bool {
+        let written = self
+            .state
+            .record_mut(sfi, record)
+            .map(|r| write_record(r, data))
+            .is_some_and(|result| result.is_ok());
+        if written {
+            self.store_logged();
+        }
+        written
+    }
+
+    /// Sets the backend that the committed records and the purse are stored in.  Clones of this
+    /// card share the backend.
+    ///
+    /// If the backend already contains the state of a card, it replaces the state of this card.
+    /// Otherwise, the current state is written to the backend.
+    pub fn set_backend<B: StateBackend + 'static>(&mut self, backend: B) -> io::Result<()> {
+        let backend = Arc::new(backend);
+        if backend.get(KEY_BALANCE)?.is_some() {
+            self.state.load(&*backend)?;
+            self.backend = Some(backend);
+        } else {
+            self.backend = Some(backend);
+            self.store(&self.state)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the committed content of a record.
+    pub fn record(&self, sfi: u8, record: u8) -> Option<&[u8]> {
+        let index = usize::from(record).checked_sub(1)?;
+        let record = self.state.files.get(&sfi)?.records.get(index)?;
+        Some(record)
+    }
+
+    /// Sets the committed stored value balance, saturating at ±[`MAX_BALANCE`][].
+    pub fn set_balance(&mut self, balance: i32) {
+        self.state.balance = balance.clamp(-MAX_BALANCE - 1, MAX_BALANCE);
+        self.store_logged();
+    }
+
+    /// Returns the committed stored value balance.
+    pub fn balance(&self) -> i32 {
+        self.state.balance
+    }
+
+    /// Returns true if a secure session is open.
+    pub fn in_session(&self) -> bool {
+        self.session.is_some()
+    }
+
+    fn handle(&mut self, command: &Command<'_>) -> Result<Vec<u8>, u16> {
+        if command.ins == INS_SELECT && command.p1 == 0x04 {
+            self.selected = command.data == AID;
+            return if self.selected {
+                Ok(self.fci())
+            } else {
+                Err(apdu::SW_FILE_NOT_FOUND)
+            };
+        }
+        if !self.selected {
+            return Err(apdu::SW_CONDITIONS_NOT_SATISFIED);
+        }
+        match command.ins {
+            INS_GET_CHALLENGE => Ok(self.random(8)),
+            INS_READ_RECORD => {
+                let sfi = record_sfi(command)?;
+                let state = self.session.as_ref().unwrap_or(&self.state);
+                let index = usize::from(command.p1).checked_sub(1);
+                state
+                    .files
+                    .get(&sfi)
+                    .ok_or(apdu::SW_FILE_NOT_FOUND)?
+                    .records
+                    .get(index.ok_or(apdu::SW_WRONG_P1P2)?)
+                    .map(|record| record.to_vec())
+                    .ok_or(apdu::SW_RECORD_NOT_FOUND)
+            }
+            INS_UPDATE_RECORD => {
+                let sfi = record_sfi(command)?;
+                let record = self
+                    .session()?
+                    .record_mut(sfi, command.p1)
+                    .ok_or(apdu::SW_RECORD_NOT_FOUND)?;
+                write_record(record, command.data)?;
+                Ok(Vec::new())
+            }
+            INS_APPEND_RECORD => {
+                let sfi = command.p2 >> 3;
+                let file = self
+                    .session()?
+                    .files
+                    .get_mut(&sfi)
+                    .ok_or(apdu::SW_FILE_NOT_FOUND)?;
+                if !file.cyclic || command.p1 != 0 {
+                    return Err(apdu::SW_CONDITIONS_NOT_SATISFIED);
+                }
+                let mut record = [0; RECORD_LEN];
+                write_record(&mut record, command.data)?;
+                file.records.pop();
+                file.records.insert(0, record);
+                Ok(Vec::new())
+            }
+            INS_OPEN_SESSION => {
+                if self.session.is_some() {
+                    return Err(apdu::SW_CONDITIONS_NOT_SATISFIED);
+                }
+                if command.data.len() < 4 {
+                    return Err(apdu::SW_WRONG_LENGTH);
+                }
+                let (record, sfi) = (command.p1 >> 3, command.p2 >> 3);
+                let mut response = self.random(4);
+                // ratification status: the previous session was ratified
+                response.push(0x00);
+                if record != 0 {
+                    let data = self.record(sfi, record).ok_or(apdu::SW_RECORD_NOT_FOUND)?;
+                    response.extend_from_slice(data);
+                }
+                self.session = Some(self.state.clone());
+                Ok(response)
+            }
+            INS_CLOSE_SESSION => {
+                let state = self
+                    .session
+                    .take()
+                    .ok_or(apdu::SW_CONDITIONS_NOT_SATISFIED)?;
+                // a failed commit aborts the session like a power loss
+                if let Err(err) = self.store(&state) {
+                    warn!("Failed to store the Calypso session: {}", err);
+                    return Err(apdu::SW_MEMORY_FAILURE);
+                }
+                self.state = state;
+                Ok(self.random(4))
+            }
+            INS_SV_GET => {
+                let state = self.session.as_ref().unwrap_or(&self.state);
+                let mut response = state.sv_transactions.to_be_bytes().to_vec();
+                response.extend_from_slice(&state.balance.to_be_bytes()[1..]);
+                Ok(response)
+            }
+            INS_SV_RELOAD | INS_SV_DEBIT => {
+                let [a, b, c, ..] = *command.data else {
+                    return Err(apdu::SW_WRONG_LENGTH);
+                };
+                // sign-extend the 24-bit amount
+                let amount = i32::from_be_bytes([a, b, c, 0]) >> 8;
+                let state = self.session()?;
+                let balance = if command.ins == INS_SV_RELOAD {
+                    state.balance + amount
+                } else if amount > state.balance {
+                    return Err(apdu::SW_EXECUTION_ERROR);
+                } else {
+                    state.balance - amount
+                };
+                if !(-MAX_BALANCE - 1..=MAX_BALANCE).contains(&balance) {
+                    return Err(apdu::SW_WRONG_DATA);
+                }
+                state.balance = balance;
+                state.sv_transactions = state.sv_transactions.wrapping_add(1);
+                Ok(self.random(3))
+            }
+            _ => Err(apdu::SW_INS_NOT_SUPPORTED),
+        }
+    }
+
+    fn session(&mut self) -> Result<&mut State, u16> {
+        self.session
+            .as_mut()
+            .ok_or(apdu::SW_SECURITY_STATUS_NOT_SATISFIED)
+    }
+
+    fn fci(&self) -> Vec<u8> {
+        let mut proprietary = vec![0xc7, self.serial.len() as u8];
+        proprietary.extend_from_slice(&self.serial);
+        let mut fci = vec![0x84, AID.len() as u8];
+        fci.extend_from_slice(AID);
+        fci.push(0xa5);
+        fci.push(proprietary.len() as u8);
+        fci.extend_from_slice(&proprietary);
+        [&[0x6f, fci.len() as u8][..], &fci].concat()
+    }
+
+    fn random(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.rng.next_u64() as u8).collect()
+    }
+
+    fn store(&self, state: &State) -> io::Result<()> {
+        match &self.backend {
+            Some(backend) => backend.apply(&state.changes()),
+            None => Ok(()),
+        }
+    }
+
+    fn store_logged(&self) {
+        if let Err(err) = self.store(&self.state) {
+            warn!("Failed to store the Calypso state: {}", err);
+        }
+    }
+
+    fn abort(&mut self) {
+        self.selected = false;
+        self.session = None;
+    }
+}
+
+impl Default for CalypsoCard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VSmartCard for CalypsoCard {
+    fn power_on(&mut self) {
+        self.abort();
+    }
+
+    fn power_off(&mut self) {
+        self.abort();
+    }
+
+    fn reset(&mut self) {
+        self.abort();
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        let result = Command::parse(msg)
+            .ok_or(apdu::SW_WRONG_LENGTH)
+            .and_then(|command| self.handle(&command));
+        match result {
+            Ok(data) => apdu::response(&data, apdu::SW_SUCCESS),
+            Err(status) => apdu::response(&[], status),
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            aids: vec![AID.to_vec()],
+            ..Default::default()
+        }
+    }
+}
+
+impl State {
+    /// Returns the changes that store this state in a backend.
+    fn changes(&self) -> Vec<Change> {
+        let mut changes = vec![
+            Change::Set(KEY_BALANCE.to_owned(), self.balance.to_be_bytes().to_vec()),
+            Change::Set(
+                KEY_SV_TRANSACTIONS.to_owned(),
+                self.sv_transactions.to_be_bytes().to_vec(),
+            ),
+        ];
+        for (sfi, file) in &self.files {
+            for (i, record) in file.records.iter().enumerate() {
+                changes.push(Change::Set(record_key(*sfi, i + 1), record.to_vec()));
+            }
+        }
+        changes
+    }
+
+    /// Loads the values stored by [`State::changes`][] from a backend.
+    fn load(&mut self, backend: &dyn StateBackend) -> io::Result<()> {
+        if let Some(balance) = backend.get(KEY_BALANCE)? {
+            self.balance = i32::from_be_bytes(fixed(KEY_BALANCE, &balance)?);
+        }
+        if let Some(count) = backend.get(KEY_SV_TRANSACTIONS)? {
+            self.sv_transactions = u16::from_be_bytes(fixed(KEY_SV_TRANSACTIONS, &count)?);
+        }
+        for (sfi, file) in &mut self.files {
+            for (i, record) in file.records.iter_mut().enumerate() {
+                let key = record_key(*sfi, i + 1);
+                if let Some(data) = backend.get(&key)? {
+                    *record = fixed(&key, &data)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn record_mut(&mut self, sfi: u8, record: u8) -> Option<&mut Record> {
+        let index = usize::from(record).checked_sub(1)?;
+        self.files.get_mut(&sfi)?.records.get_mut(index)
+    }
+}
+
+fn record_key(sfi: u8, record: usize) -> String {
+    format!("calypso/record/{:02x}/{}", sfi, record)
+}
+
+fn fixed<const N: usize>(key: &str, value: &[u8]) -> io::Result<[u8; N]> {
+    value.try_into().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid length of stored value {}", key),
+        )
+    })
+}
+
+fn record_sfi(command: &Command<'_>) -> Result<u8, u16> {
+    if command.p2 & 0x07 == 0x04 {
+        Ok(command.p2 >> 3)
+    } else {
+        Err(apdu::SW_WRONG_P1P2)
+    }
+}
+
+fn write_record(record: &mut Record, data: &[u8]) -> Result<(), u16> {
+    if data.len() > RECORD_LEN {
+        return Err(apdu::SW_WRONG_LENGTH);
+    }
+    record.fill(0);
+    record[..data.len()].copy_from_slice(data);
+    Ok(())
+}
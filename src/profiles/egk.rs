@@ -0,0 +1,327 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! A test card modelled after the German electronic health card (eGK).
+//!
+//! [`EgkCard`][] contains the basic file structure of the eGK:  the master file with EF.GDO and
+//! the health care application DF.HCA with the insurance data containers EF.PD, EF.VD and
+//! EF.StatusVD.  The containers hold XML documents for a configurable [`Patient`][], compressed
+//! as gzip like on a real card.  Files can be selected by AID and file identifier and read with
+//! READ BINARY, also using short file identifiers.  The card-to-card authentication that a real
+//! eGK requires before the insurance data can be read is not emulated.
+//!
+//! # Example
+//!
+//! ```
+//! use vpicc::{profiles::egk::{EgkCard, Patient, HCA_AID}, VSmartCard};
+//!
+//! let mut card = EgkCard::new(Patient::default());
+//! let select = [&[0x00, 0xa4, 0x04, 0x0c, HCA_AID.len() as u8][..], HCA_AID].concat();
+//! assert_eq!(card.execute(&select), [0x90, 0x00]);
+//!
+//! // READ BINARY of the first bytes of EF.StatusVD using its short file identifier
+//! let status = card.execute(&[0x00, 0xb0, 0x8c, 0x00, 0x01]);
+//! assert_eq!(status, [b'0', 0x90, 0x00]);
+//! ```
+
+use crate::{
+    apdu::{self, Command},
+    Capabilities, VSmartCard,
+};
+
+/// The AID of the root application (MF).
+pub const ROOT_AID: &[u8] = &[0xd2, 0x76, 0x00, 0x01, 0x44, 0x80, 0x00];
+/// The AID of the health care application (DF.HCA).
+pub const HCA_AID: &[u8] = &[0xd2, 0x76, 0x00, 0x00, 0x01, 0x02];
+
+const INS_SELECT: u8 = 0xa4;
+const INS_READ_BINARY: u8 = 0xb0;
+
+const VSD_NAMESPACE: &str = "http://ws.gematik.de/fa/vsdm/vsd/v5.2";
+
+/// The insured person whose data is stored on an [`EgkCard`][].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Patient {
+    /// The insurant ID, a letter followed by nine digits.
+    pub insurant_id: String,
+    /// The given name.
+    pub given_name: String,
+    /// The surname.
+    pub surname: String,
+    /// The date of birth in the format `YYYYMMDD`.
+    pub birth_date: String,
+    /// The institution code of the health insurance.
+    pub insurance_id: String,
+    /// The name of the health insurance.
+    pub insurance_name: String,
+}
+
+impl Default for Patient {
+    fn default() -> Self {
+        Self {
+            insurant_id: "X110000000".to_owned(),
+            given_name: "Erika".to_owned(),
+            surname: "Mustermann".to_owned(),
+            birth_date: "19640812".to_owned(),
+            insurance_id: "109999999".to_owned(),
+            insurance_name: "vpicc Testkasse".to_owned(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Ef {
+    fid: u16,
+    sfi: u8,
+    data: Vec<u8>,
+}
+
+#[derive(Clone, Debug)]
+struct Df {
+    aid: &'static [u8],
+    files: Vec<Ef>,
+}
+
+/// An emulated eGK test card, see the [module documentation][`self`].
+#[derive(Clone, Debug)]
+pub struct EgkCard {
+    dfs: Vec<Df>,
+    df: usize,
+    ef: Option<usize>,
+}
+
+impl EgkCard {
+    /// Creates a card for the given patient with an ICCSN derived from the insurant ID.
+    pub fn new(patient: Patient) -> Self {
+        let mut iccsn = vec![0x80, 0x27, 0x60, 0x01, 0x01];
+        let digits: Vec<u8> = patient
+            .insurant_id
+            .bytes()
+            .filter(u8::is_ascii_digit)
+            .map(|d| d - b'0')
+            .chain(std::iter::repeat(0))
+            .take(10)
+            .collect();
+        iccsn.extend(digits.chunks(2).map(|pair| (pair[0] << 4) | pair[1]));
+        let gdo = [&[0x5a, iccsn.len() as u8][..], &iccsn].concat();
+
+        let mf = Df {
+            aid: ROOT_AID,
+            files: vec![Ef {
+                fid: 0x2f02,
+                sfi: 0x02,
+                data: gdo,
+            }],
+        };
+        let hca = Df {
+            aid: HCA_AID,
+            files: vec![
+                Ef {
+                    fid: 0xd001,
+                    sfi: 0x01,
+                    data: personal_data(&patient),
+                },
+                Ef {
+                    fid: 0xd002,
+                    sfi: 0x02,
+                    data: insurance_data(&patient),
+                },
+                Ef {
+                    fid: 0xd00c,
+                    sfi: 0x0c,
+                    data: status_vd(),
+                },
+            ],
+        };
+        Self {
+            dfs: vec![mf, hca],
+            df: 0,
+            ef: None,
+        }
+    }
+
+    fn select(&mut self, command: &Command<'_>) -> Result<(), u16> {
+        match command.p1 {
+            0x04 => {
+                let df = self
+                    .dfs
+                    .iter()
+                    .position(|df| df.aid == command.data)
+                    .ok_or(apdu::SW_FILE_NOT_FOUND)?;
+                self.df = df;
+                self.ef = None;
+            }
+            0x00 | 0x02 => {
+                let [hi, lo] = *command.data else {
+                    return Err(apdu::SW_WRONG_LENGTH);
+                };
+                let fid = u16::from_be_bytes([hi, lo]);
+                if fid == 0x3f00 {
+                    self.df = 0;
+                    self.ef = None;
+                    return Ok(());
+                }
+                let ef = self.dfs[self.df]
+                    .files
+                    .iter()
+                    .position(|ef| ef.fid == fid)
+                    .ok_or(apdu::SW_FILE_NOT_FOUND)?;
+                self.ef = Some(ef);
+            }
+            _ => return Err(apdu::SW_WRONG_P1P2),
+        }
+        Ok(())
+    }
+
+    fn read_binary(&mut self, command: &Command<'_>) -> Result<Vec<u8>, u16> {
+        let offset = if command.p1 & 0x80 != 0 {
+            let sfi = command.p1 & 0x1f;
+            let ef = self.dfs[self.df]
+                .files
+                .iter()
+                .position(|ef| ef.sfi == sfi)
+                .ok_or(apdu::SW_FILE_NOT_FOUND)?;
+            self.ef = Some(ef);
+            usize::from(command.p2)
+        } else {
+            usize::from(u16::from_be_bytes([command.p1, command.p2]))
+        };
+        let ef = self.ef.ok_or(apdu::SW_CONDITIONS_NOT_SATISFIED)?;
+        let data = &self.dfs[self.df].files[ef].data;
+        let data = data.get(offset..).ok_or(apdu::SW_WRONG_P1P2)?;
+        let len = command.le.unwrap_or(256).min(data.len());
+        Ok(data[..len].to_vec())
+    }
+
+    fn handle(&mut self, command: &Command<'_>) -> Result<Vec<u8>, u16> {
+        match command.ins {
+            INS_SELECT => self.select(command).map(|()| Vec::new()),
+            INS_READ_BINARY => self.read_binary(command),
+            _ => Err(apdu::SW_INS_NOT_SUPPORTED),
+        }
+    }
+
+    fn deselect(&mut self) {
+        self.df = 0;
+        self.ef = None;
+    }
+}
+
+impl Default for EgkCard {
+    fn default() -> Self {
+        Self::new(Patient::default())
+    }
+}
+
+impl VSmartCard for EgkCard {
+    fn power_on(&mut self) {
+        self.deselect();
+    }
+
+    fn power_off(&mut self) {
+        self.deselect();
+    }
+
+    fn reset(&mut self) {
+        self.deselect();
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        let result = Command::parse(msg)
+            .ok_or(apdu::SW_WRONG_LENGTH)
+            .and_then(|command| self.handle(&command));
+        match result {
+            Ok(data) => apdu::response(&data, apdu::SW_SUCCESS),
+            Err(status) => apdu::response(&[], status),
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            aids: vec![ROOT_AID.to_vec(), HCA_AID.to_vec()],
+            extended_length: true,
+            max_response_len: 65536,
+        }
+    }
+}
+
+/// EF.PD:  the length of the compressed personal data followed by the data.
+fn personal_data(patient: &Patient) -> Vec<u8> {
+    let xml = format!(
+        "<?xml version=\"1.0\" encoding=\"ISO-8859-15\" standalone=\"yes\"?>\
+         <UC_PersoenlicheVersichertendatenXML CDM_VERSION=\"5.2.0\" xmlns=\"{}\">\
+         <Versicherter><Versicherten_ID>{}</Versicherten_ID><Person>\
+         <Geburtsdatum>{}</Geburtsdatum><Vorname>{}</Vorname><Nachname>{}</Nachname>\
+         </Person></Versicherter></UC_PersoenlicheVersichertendatenXML>",
+        VSD_NAMESPACE, patient.insurant_id, patient.birth_date, patient.given_name, patient.surname
+    );
+    let data = gzip(xml.as_bytes());
+    [&(data.len() as u16).to_be_bytes()[..], &data].concat()
+}
+
+/// EF.VD:  the start and end offsets of the insurance data and the protected insurance data,
+/// followed by the data.  The protected insurance data is empty.
+fn insurance_data(patient: &Patient) -> Vec<u8> {
+    let xml = format!(
+        "<?xml version=\"1.0\" encoding=\"ISO-8859-15\" standalone=\"yes\"?>\
+         <UC_AllgemeineVersicherungsdatenXML CDM_VERSION=\"5.2.0\" xmlns=\"{}\">\
+         <Versicherter><Versicherungsschutz><Beginn>20240101</Beginn><Kostentraeger>\
+         <Kostentraegerkennung>{}</Kostentraegerkennung>\
+         <Kostentraegerlaendercode>D</Kostentraegerlaendercode><Name>{}</Name>\
+         </Kostentraeger></Versicherungsschutz></Versicherter>\
+         </UC_AllgemeineVersicherungsdatenXML>",
+        VSD_NAMESPACE, patient.insurance_id, patient.insurance_name
+    );
+    let data = gzip(xml.as_bytes());
+    let start = 8u16;
+    let end = start + data.len() as u16 - 1;
+    let mut vd = Vec::new();
+    for offset in [start, end, end + 1, end] {
+        vd.extend_from_slice(&offset.to_be_bytes());
+    }
+    vd.extend(data);
+    vd
+}
+
+/// EF.StatusVD:  no update in progress, the timestamp of the last update and the version.
+fn status_vd() -> Vec<u8> {
+    let mut status = b"0".to_vec();
+    status.extend_from_slice(b"20240101000000");
+    status.extend_from_slice(&[0x00, 0x52, 0x00, 0x00, 0x00]);
+    status.resize(25, 0);
+    status
+}
+
+/// Wraps data in a gzip container using uncompressed deflate blocks.
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut gzip = vec![0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff];
+    let mut chunks = data.chunks(usize::from(u16::MAX)).peekable();
+    if chunks.peek().is_none() {
+        gzip.extend_from_slice(&[0x01, 0x00, 0x00, 0xff, 0xff]);
+    }
+    while let Some(chunk) = chunks.next() {
+        gzip.push(u8::from(chunks.peek().is_none()));
+        let len = chunk.len() as u16;
+        gzip.extend_from_slice(&len.to_le_bytes());
+        gzip.extend_from_slice(&(!len).to_le_bytes());
+        gzip.extend_from_slice(chunk);
+    }
+    gzip.extend_from_slice(&crc32(data).to_le_bytes());
+    gzip.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    gzip
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
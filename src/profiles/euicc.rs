@@ -0,0 +1,226 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! A minimal eUICC ISD-R as defined in GSMA SGP.22.
+//!
+//! [`Isdr`][] accepts ES10 commands that are sent in STORE DATA (80 E2) commands after the ISD-R
+//! has been selected.  Commands can be split into multiple blocks, and long responses are
+//! returned using GET RESPONSE (00 C0).  The supported ES10 functions are GetEuiccInfo1,
+//! GetEuiccInfo2, GetEID, GetEuiccChallenge, GetProfilesInfo and GetEuiccConfiguredAddresses.
+//! The eUICC does not contain any profiles, so the profile list is always empty.
+//!
+//! # Example
+//!
+//! ```
+//! use vpicc::{profiles::euicc::{Isdr, ISDR_AID}, VSmartCard};
+//!
+//! let mut card = Isdr::new();
+//! card.set_eid([0x89; 16]);
+//! let select = [&[0x00, 0xa4, 0x04, 0x00, ISDR_AID.len() as u8][..], ISDR_AID].concat();
+//! assert_eq!(card.execute(&select), [0x90, 0x00]);
+//!
+//! // GetEID
+//! let response = card.execute(&[0x80, 0xe2, 0x91, 0x00, 0x06, 0xbf, 0x3e, 0x03, 0x5c, 0x01, 0x5a]);
+//! assert_eq!(response[..5], [0xbf, 0x3e, 0x12, 0x5a, 0x10]);
+//! assert_eq!(response[5..21], [0x89; 16]);
+//! ```
+
+use crate::{
+    apdu::{self, Command},
+    rng::Rng,
+    tlv, Capabilities, VSmartCard,
+};
+
+/// The AID of the ISD-R.
+pub const ISDR_AID: &[u8] = &[
+    0xa0, 0x00, 0x00, 0x05, 0x59, 0x10, 0x10, 0xff, 0xff, 0xff, 0xff, 0x89, 0x00, 0x00, 0x01, 0x00,
+];
+/// The key identifier of the GSMA test certificate issuer used in SGP.26.
+pub const TEST_CI_PKID: [u8; 20] = [
+    0xf5, 0x41, 0x72, 0xbd, 0xf9, 0x8a, 0x95, 0xd6, 0x5c, 0xbe, 0xb8, 0x8a, 0x38, 0xa1, 0xc1, 0x1d,
+    0x80, 0x0a, 0x85, 0xc3,
+];
+
+const INS_SELECT: u8 = 0xa4;
+const INS_STORE_DATA: u8 = 0xe2;
+const INS_GET_RESPONSE: u8 = 0xc0;
+
+const TAG_GET_EUICC_INFO_1: u16 = 0xbf20;
+const TAG_GET_EUICC_INFO_2: u16 = 0xbf22;
+const TAG_GET_PROFILES_INFO: u16 = 0xbf2d;
+const TAG_GET_EUICC_CHALLENGE: u16 = 0xbf2e;
+const TAG_GET_CONFIGURED_ADDRESSES: u16 = 0xbf3c;
+const TAG_GET_EID: u16 = 0xbf3e;
+
+/// The supported SGP.22 version, 2.2.0.
+const SVN: [u8; 3] = [0x02, 0x02, 0x00];
+
+/// An emulated eUICC ISD-R, see the [module documentation][`self`].
+#[derive(Clone, Debug)]
+pub struct Isdr {
+    eid: [u8; 16],
+    ci_pkid: [u8; 20],
+    default_smdp_address: String,
+    root_smds_address: String,
+    selected: bool,
+    command: Vec<u8>,
+    pending: Vec<u8>,
+    rng: Rng,
+}
+
+impl Isdr {
+    /// Creates an ISD-R with an all-zero EID that trusts the GSMA test certificate issuer.
+    pub fn new() -> Self {
+        Self {
+            eid: [0; 16],
+            ci_pkid: TEST_CI_PKID,
+            default_smdp_address: String::new(),
+            root_smds_address: "testrootsmds.example.com".to_owned(),
+            selected: false,
+            command: Vec::new(),
+            pending: Vec::new(),
+            rng: Rng::new(0),
+        }
+    }
+
+    /// Sets the EID returned by GetEID.
+    pub fn set_eid(&mut self, eid: [u8; 16]) {
+        self.eid = eid;
+    }
+
+    /// Sets the key identifier of the trusted certificate issuer.
+    pub fn set_ci_pkid(&mut self, pkid: [u8; 20]) {
+        self.ci_pkid = pkid;
+    }
+
+    /// Sets the addresses returned by GetEuiccConfiguredAddresses.
+    pub fn set_addresses(&mut self, default_smdp: impl Into<String>, root_smds: impl Into<String>) {
+        self.default_smdp_address = default_smdp.into();
+        self.root_smds_address = root_smds.into();
+    }
+
+    /// Sets the seed for the eUICC challenges, defaulting to zero.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    fn handle(&mut self, command: &Command<'_>) -> Result<Vec<u8>, u16> {
+        if command.ins == INS_SELECT && command.p1 == 0x04 {
+            self.selected = command.data == ISDR_AID;
+            self.command.clear();
+            self.pending.clear();
+            return if self.selected {
+                Ok(Vec::new())
+            } else {
+                Err(apdu::SW_FILE_NOT_FOUND)
+            };
+        }
+        if !self.selected {
+            return Err(apdu::SW_CONDITIONS_NOT_SATISFIED);
+        }
+        match (command.cla & 0x80, command.ins) {
+            (0x80, INS_STORE_DATA) => {
+                self.command.extend_from_slice(command.data);
+                if command.p1 & 0x80 == 0 {
+                    // more blocks follow
+                    return Ok(Vec::new());
+                }
+                let es10 = std::mem::take(&mut self.command);
+                self.pending = self.es10(&es10)?;
+                Ok(Vec::new())
+            }
+            (0x00, INS_GET_RESPONSE) => Ok(Vec::new()),
+            _ => Err(apdu::SW_INS_NOT_SUPPORTED),
+        }
+    }
+
+    fn es10(&mut self, command: &[u8]) -> Result<Vec<u8>, u16> {
+        let tag = match *command {
+            [first, second, ..] if first & 0x1f == 0x1f => u16::from_be_bytes([first, second]),
+            _ => return Err(apdu::SW_WRONG_DATA),
+        };
+        let content = match tag {
+            TAG_GET_EUICC_INFO_1 => [
+                tlv::encode(0x82, &SVN),
+                tlv::encode(0xa9, &tlv::encode(0x04, &self.ci_pkid)),
+                tlv::encode(0xaa, &tlv::encode(0x04, &self.ci_pkid)),
+            ]
+            .concat(),
+            TAG_GET_EUICC_INFO_2 => [
+                // profile version, SVN, firmware version
+                tlv::encode(0x81, &[0x02, 0x03, 0x00]),
+                tlv::encode(0x82, &SVN),
+                tlv::encode(0x83, &[0x01, 0x00, 0x00]),
+                // extended card resource: installed applications and free memory
+                tlv::encode(0x84, &[0x81, 0x01, 0x00, 0x82, 0x03, 0x01, 0x00, 0x00]),
+                // UICC capabilities as a bit string
+                tlv::encode(0x85, &[0x03, 0x00, 0x00, 0x00]),
+                tlv::encode(0xa9, &tlv::encode(0x04, &self.ci_pkid)),
+                tlv::encode(0xaa, &tlv::encode(0x04, &self.ci_pkid)),
+                // PP version and SAS accreditation number
+                tlv::encode(0x04, &[0x00, 0x00, 0x00]),
+                tlv::encode(0x0c, b"VPICC-TEST"),
+            ]
+            .concat(),
+            TAG_GET_EID => tlv::encode(0x5a, &self.eid),
+            TAG_GET_EUICC_CHALLENGE => {
+                let challenge: Vec<u8> = (0..16).map(|_| self.rng.next_u64() as u8).collect();
+                tlv::encode(0x80, &challenge)
+            }
+            TAG_GET_PROFILES_INFO => tlv::encode(0xa0, &[]),
+            TAG_GET_CONFIGURED_ADDRESSES => {
+                let mut addresses = Vec::new();
+                if !self.default_smdp_address.is_empty() {
+                    addresses.extend(tlv::encode(0x80, self.default_smdp_address.as_bytes()));
+                }
+                addresses.extend(tlv::encode(0x81, self.root_smds_address.as_bytes()));
+                addresses
+            }
+            _ => return Err(apdu::SW_DATA_NOT_FOUND),
+        };
+        Ok(tlv::encode(tag.into(), &content))
+    }
+}
+
+impl Default for Isdr {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VSmartCard for Isdr {
+    fn power_on(&mut self) {
+        self.selected = false;
+    }
+
+    fn power_off(&mut self) {
+        self.selected = false;
+    }
+
+    fn reset(&mut self) {
+        self.selected = false;
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        let Some(command) = Command::parse(msg) else {
+            return apdu::response(&[], apdu::SW_WRONG_LENGTH);
+        };
+        if command.ins != INS_GET_RESPONSE {
+            self.pending.clear();
+        }
+        match self.handle(&command) {
+            Ok(data) => {
+                self.pending.splice(..0, data);
+                apdu::response_chunk(&mut self.pending, command.le)
+            }
+            Err(status) => apdu::response(&[], status),
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            aids: vec![ISDR_AID.to_vec()],
+            ..Default::default()
+        }
+    }
+}
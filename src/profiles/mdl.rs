@@ -0,0 +1,251 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! An ISO 18013-5 mobile driving licence NFC engagement applet.
+//!
+//! [`MdlEngagement`][] emulates the NFC Forum Type 4 Tag that an mDL holder device offers for
+//! static handover.  The NDEF file contains a Handover Select message with the device engagement
+//! structure and a Bluetooth Low Energy carrier, so verifier applications can read the device
+//! engagement without a phone.  The data retrieval itself is not emulated.
+//!
+//! # Example
+//!
+//! ```
+//! use vpicc::{profiles::mdl::{MdlEngagement, NDEF_AID}, VSmartCard};
+//!
+//! let mut card = MdlEngagement::new();
+//! let select = [&[0x00, 0xa4, 0x04, 0x00, NDEF_AID.len() as u8][..], NDEF_AID, &[0x00]].concat();
+//! assert_eq!(card.execute(&select), [0x90, 0x00]);
+//! assert_eq!(card.execute(&[0x00, 0xa4, 0x00, 0x0c, 0x02, 0xe1, 0x04]), [0x90, 0x00]);
+//! let len = card.execute(&[0x00, 0xb0, 0x00, 0x00, 0x02]);
+//! assert_eq!(usize::from(u16::from_be_bytes([len[0], len[1]])), card.ndef_message().len());
+//! ```
+
+use crate::{
+    apdu::{self, Command},
+    Capabilities, VSmartCard,
+};
+
+/// The AID of the NFC Forum Type 4 Tag NDEF application.
+pub const NDEF_AID: &[u8] = &[0xd2, 0x76, 0x00, 0x00, 0x85, 0x01, 0x01];
+/// The AID of the ISO 18013-5 mDL application used for NFC data retrieval.
+pub const MDL_AID: &[u8] = &[0xa0, 0x00, 0x00, 0x02, 0x48, 0x04, 0x00];
+/// The file identifier of the capability container.
+pub const CC_FILE: u16 = 0xe103;
+/// The file identifier of the NDEF file.
+pub const NDEF_FILE: u16 = 0xe104;
+
+const INS_SELECT: u8 = 0xa4;
+const INS_READ_BINARY: u8 = 0xb0;
+
+const DEVICE_ENGAGEMENT_TYPE: &[u8] = b"iso.org:18013:deviceengagement";
+const BLE_TYPE: &[u8] = b"application/vnd.bluetooth.le.oob";
+const MAX_READ_LEN: u16 = 0xff;
+
+const TNF_WELL_KNOWN: u8 = 0x01;
+const TNF_MEDIA: u8 = 0x02;
+const TNF_EXTERNAL: u8 = 0x04;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Selection {
+    None,
+    Ndef,
+    Mdl,
+    File(u16),
+}
+
+/// An emulated mDL NFC engagement applet, see the [module documentation][`self`].
+#[derive(Clone, Debug)]
+pub struct MdlEngagement {
+    device_engagement: Vec<u8>,
+    ble_uuid: [u8; 16],
+    selection: Selection,
+}
+
+impl MdlEngagement {
+    /// Creates an applet with a device engagement structure for version 1.0 containing an
+    /// all-zero P-256 device key.
+    pub fn new() -> Self {
+        Self {
+            device_engagement: default_device_engagement(),
+            ble_uuid: [0; 16],
+            selection: Selection::None,
+        }
+    }
+
+    /// Sets the CBOR-encoded `DeviceEngagement` structure.
+    pub fn set_device_engagement(&mut self, device_engagement: Vec<u8>) {
+        self.device_engagement = device_engagement;
+    }
+
+    /// Sets the UUID of the BLE peripheral server mode service announced in the carrier record.
+    pub fn set_ble_uuid(&mut self, uuid: [u8; 16]) {
+        self.ble_uuid = uuid;
+    }
+
+    /// Returns the NDEF message containing the Handover Select, device engagement and BLE
+    /// carrier records.
+    pub fn ndef_message(&self) -> Vec<u8> {
+        let mut alternative_carrier = vec![0x01, 0x01, b'0', 0x01, 0x04];
+        alternative_carrier.extend_from_slice(b"mdoc");
+        let mut handover_select = vec![0x15];
+        handover_select.extend(record(
+            0xc0 | TNF_WELL_KNOWN,
+            b"ac",
+            b"",
+            &alternative_carrier,
+        ));
+
+        // LE role: peripheral only, followed by the complete list of 128-bit service UUIDs
+        let mut ble = vec![0x02, 0x1c, 0x00, 0x11, 0x07];
+        ble.extend(self.ble_uuid.iter().rev());
+
+        let mut message = record(0x80 | TNF_WELL_KNOWN, b"Hs", b"", &handover_select);
+        message.extend(record(
+            TNF_EXTERNAL,
+            DEVICE_ENGAGEMENT_TYPE,
+            b"mdoc",
+            &self.device_engagement,
+        ));
+        message.extend(record(0x40 | TNF_MEDIA, BLE_TYPE, b"0", &ble));
+        message
+    }
+
+    fn ndef_file(&self) -> Vec<u8> {
+        let message = self.ndef_message();
+        [&(message.len() as u16).to_be_bytes()[..], &message].concat()
+    }
+
+    fn capability_container(&self) -> Vec<u8> {
+        let max_ndef_len = self.ndef_file().len() as u16;
+        let mut cc = vec![0x00, 0x0f, 0x20];
+        cc.extend_from_slice(&MAX_READ_LEN.to_be_bytes());
+        cc.extend_from_slice(&MAX_READ_LEN.to_be_bytes());
+        cc.extend_from_slice(&[0x04, 0x06]);
+        cc.extend_from_slice(&NDEF_FILE.to_be_bytes());
+        cc.extend_from_slice(&max_ndef_len.to_be_bytes());
+        // read access granted, write access denied
+        cc.extend_from_slice(&[0x00, 0xff]);
+        cc
+    }
+
+    fn handle(&mut self, command: &Command<'_>) -> Result<Vec<u8>, u16> {
+        match (command.ins, command.p1) {
+            (INS_SELECT, 0x04) => {
+                self.selection = if command.data == NDEF_AID {
+                    Selection::Ndef
+                } else if command.data == MDL_AID {
+                    Selection::Mdl
+                } else {
+                    Selection::None
+                };
+                if self.selection == Selection::None {
+                    Err(apdu::SW_FILE_NOT_FOUND)
+                } else {
+                    Ok(Vec::new())
+                }
+            }
+            (INS_SELECT, 0x00) => {
+                if self.selection == Selection::None || self.selection == Selection::Mdl {
+                    return Err(apdu::SW_CONDITIONS_NOT_SATISFIED);
+                }
+                let [hi, lo] = *command.data else {
+                    return Err(apdu::SW_WRONG_LENGTH);
+                };
+                match u16::from_be_bytes([hi, lo]) {
+                    fid @ (CC_FILE | NDEF_FILE) => {
+                        self.selection = Selection::File(fid);
+                        Ok(Vec::new())
+                    }
+                    _ => Err(apdu::SW_FILE_NOT_FOUND),
+                }
+            }
+            (INS_READ_BINARY, _) => {
+                let file = match self.selection {
+                    Selection::File(CC_FILE) => self.capability_container(),
+                    Selection::File(_) => self.ndef_file(),
+                    _ => return Err(apdu::SW_CONDITIONS_NOT_SATISFIED),
+                };
+                let offset = usize::from(u16::from_be_bytes([command.p1, command.p2]));
+                let data = file.get(offset..).ok_or(apdu::SW_WRONG_P1P2)?;
+                let len = command.le.unwrap_or(256).min(data.len());
+                Ok(data[..len].to_vec())
+            }
+            _ => Err(apdu::SW_INS_NOT_SUPPORTED),
+        }
+    }
+}
+
+impl Default for MdlEngagement {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VSmartCard for MdlEngagement {
+    fn power_on(&mut self) {
+        self.selection = Selection::None;
+    }
+
+    fn power_off(&mut self) {
+        self.selection = Selection::None;
+    }
+
+    fn reset(&mut self) {
+        self.selection = Selection::None;
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        let result = Command::parse(msg)
+            .ok_or(apdu::SW_WRONG_LENGTH)
+            .and_then(|command| self.handle(&command));
+        match result {
+            Ok(data) => apdu::response(&data, apdu::SW_SUCCESS),
+            Err(status) => apdu::response(&[], status),
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            aids: vec![NDEF_AID.to_vec(), MDL_AID.to_vec()],
+            ..Default::default()
+        }
+    }
+}
+
+/// Encodes an NDEF record with the given flags (MB, ME) and TNF.
+fn record(header: u8, ty: &[u8], id: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mut header = header;
+    if !id.is_empty() {
+        header |= 0x08;
+    }
+    let short = payload.len() < 256;
+    if short {
+        header |= 0x10;
+    }
+    let mut record = vec![header, ty.len() as u8];
+    if short {
+        record.push(payload.len() as u8);
+    } else {
+        record.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    }
+    if !id.is_empty() {
+        record.push(id.len() as u8);
+    }
+    record.extend_from_slice(ty);
+    record.extend_from_slice(id);
+    record.extend_from_slice(payload);
+    record
+}
+
+/// `{0: "1.0", 1: [1, 24(<<{1: 2, -1: 1, -2: x, -3: y}>>)]}` with all-zero coordinates.
+fn default_device_engagement() -> Vec<u8> {
+    let mut key = vec![0xa4, 0x01, 0x02, 0x20, 0x01, 0x21, 0x58, 0x20];
+    key.extend_from_slice(&[0; 32]);
+    key.extend_from_slice(&[0x22, 0x58, 0x20]);
+    key.extend_from_slice(&[0; 32]);
+    let mut engagement = vec![0xa2, 0x00, 0x63, b'1', b'.', b'0', 0x01, 0x82, 0x01];
+    engagement.extend_from_slice(&[0xd8, 0x18, 0x58, key.len() as u8]);
+    engagement.extend_from_slice(&key);
+    engagement
+}
@@ -0,0 +1,502 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! A PIV card with CAC transitional data objects and synthetic identities.
+//!
+//! [`PivCard`][] implements the PIV card application from NIST SP 800-73-4 with a card
+//! capability container as used by CAC transitional cards.  [`PivCard::synthetic`][] derives a
+//! distinct identity from an index, including the CHUID with FASC-N and GUID and the X.509
+//! certificates of all four key slots, so login stacks can be tested against many cards.
+//!
+//! The certificates are structurally valid DER but carry a dummy signature.  Their public key is
+//! the generator of P-256, i. e. the private key is 1.  Cryptographic operations like GENERAL
+//! AUTHENTICATE are not supported.
+//!
+//! The PIN and its retry counter can be persisted with a [`StateBackend`][] using
+//! [`PivCard::set_backend`][].  They are stored with the keys `piv/pin` and `piv/pin-retries`.
+//!
+//! # Example
+//!
+//! ```
+//! use vpicc::{profiles::piv::{PivCard, AID}, VSmartCard};
+//!
+//! let mut card = PivCard::synthetic(42);
+//! assert_eq!(card.identity().name, "Test User 42");
+//!
+//! let select = [&[0x00, 0xa4, 0x04, 0x00, AID.len() as u8][..], AID].concat();
+//! assert!(card.execute(&select).ends_with(&[0x90, 0x00]));
+//!
+//! // GET DATA for the CHUID
+//! let chuid = card.execute(&[0x00, 0xcb, 0x3f, 0xff, 0x05, 0x5c, 0x03, 0x5f, 0xc1, 0x02, 0x00]);
+//! assert_eq!(chuid[0], 0x53);
+//! assert!(chuid.ends_with(&[0x90, 0x00]));
+//! ```
+
+use std::{io, sync::Arc};
+
+use log::warn;
+
+use crate::{
+    apdu::{self, Command},
+    rng::Rng,
+    state::{Change, StateBackend},
+    tlv, Capabilities, VSmartCard,
+};
+
+/// The AID of the PIV card application.
+pub const AID: &[u8] = &[
+    0xa0, 0x00, 0x00, 0x03, 0x08, 0x00, 0x00, 0x10, 0x00, 0x01, 0x00,
+];
+/// The default PIN.
+pub const DEFAULT_PIN: &[u8] = b"123456";
+/// The number of PIN retries.
+pub const PIN_RETRIES: u8 = 3;
+
+/// The tag of the card capability container.
+pub const TAG_CCC: u32 = 0x5fc107;
+/// The tag of the card holder unique identifier.
+pub const TAG_CHUID: u32 = 0x5fc102;
+/// The tag of the certificate for PIV authentication (slot 9A).
+pub const TAG_PIV_AUTHENTICATION: u32 = 0x5fc105;
+/// The tag of the certificate for digital signatures (slot 9C).
+pub const TAG_DIGITAL_SIGNATURE: u32 = 0x5fc10a;
+/// The tag of the certificate for key management (slot 9D).
+pub const TAG_KEY_MANAGEMENT: u32 = 0x5fc10b;
+/// The tag of the certificate for card authentication (slot 9E).
+pub const TAG_CARD_AUTHENTICATION: u32 = 0x5fc101;
+/// The tag of the discovery object.
+pub const TAG_DISCOVERY: u32 = 0x7e;
+
+const INS_SELECT: u8 = 0xa4;
+const INS_GET_DATA: u8 = 0xcb;
+const INS_VERIFY: u8 = 0x20;
+const INS_GET_RESPONSE: u8 = 0xc0;
+const PIV_PIN_REFERENCE: u8 = 0x80;
+
+const KEY_PIN: &str = "piv/pin";
+const KEY_PIN_RETRIES: &str = "piv/pin-retries";
+
+const CERTIFICATES: &[(u32, &str)] = &[
+    (TAG_PIV_AUTHENTICATION, "PIV Authentication"),
+    (TAG_DIGITAL_SIGNATURE, "Digital Signature"),
+    (TAG_KEY_MANAGEMENT, "Key Management"),
+    (TAG_CARD_AUTHENTICATION, "Card Authentication"),
+];
+
+/// The generator of P-256 as an uncompressed point.
+const PUBLIC_KEY: &[u8] = &[
+    0x04, 0x6b, 0x17, 0xd1, 0xf2, 0xe1, 0x2c, 0x42, 0x47, 0xf8, 0xbc, 0xe6, 0xe5, 0x63, 0xa4, 0x40,
+    0xf2, 0x77, 0x03, 0x7d, 0x81, 0x2d, 0xeb, 0x33, 0xa0, 0xf4, 0xa1, 0x39, 0x45, 0xd8, 0x98, 0xc2,
+    0x96, 0x4f, 0xe3, 0x42, 0xe2, 0xfe, 0x1a, 0x7f, 0x9b, 0x8e, 0xe7, 0xeb, 0x4a, 0x7c, 0x0f, 0x9e,
+    0x16, 0x2b, 0xce, 0x33, 0x57, 0x6b, 0x31, 0x5e, 0xce, 0xcb, 0xb6, 0x40, 0x68, 0x37, 0xbf, 0x51,
+    0xf5,
+];
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_PRIME256V1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const OID_ORGANIZATION: &[u8] = &[0x55, 0x04, 0x0a];
+const OID_COUNTRY: &[u8] = &[0x55, 0x04, 0x06];
+
+/// The identity stored on a [`PivCard`][].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    /// The common name of the card holder.
+    pub name: String,
+    /// The agency code, system code and credential number of the FASC-N, as decimal digits.
+    pub fasc_n: [u8; 14],
+    /// The GUID of the card.
+    pub guid: [u8; 16],
+    /// The expiration date in the format `YYYYMMDD`.
+    pub expiration: [u8; 8],
+    /// The serial number used for all certificates.
+    pub serial: u64,
+}
+
+impl Identity {
+    /// Derives a synthetic identity from an index.
+    pub fn synthetic(index: u64) -> Self {
+        let mut rng = Rng::new(index);
+        let mut guid = [0; 16];
+        guid.iter_mut().for_each(|b| *b = rng.next_u64() as u8);
+        // agency code 9999 (test), system code 0001 and the index as credential number
+        let mut fasc_n = *b"99990001000000";
+        let credential = format!("{:06}", index % 1_000_000);
+        fasc_n[8..].copy_from_slice(credential.as_bytes());
+        fasc_n.iter_mut().for_each(|d| *d -= b'0');
+        Self {
+            name: format!("Test User {}", index),
+            fasc_n,
+            guid,
+            expiration: *b"20341231",
+            serial: index + 1,
+        }
+    }
+}
+
+/// An emulated PIV card, see the [module documentation][`self`].
+#[derive(Clone, Debug)]
+pub struct PivCard {
+    identity: Identity,
+    pin: Vec<u8>,
+    retries: u8,
+    verified: bool,
+    selected: bool,
+    pending: Vec<u8>,
+    backend: Option<Arc<dyn StateBackend>>,
+}
+
+impl PivCard {
+    /// Creates a card with the given identity and the [`DEFAULT_PIN`][].
+    pub fn new(identity: Identity) -> Self {
+        Self {
+            identity,
+            pin: DEFAULT_PIN.to_vec(),
+            retries: PIN_RETRIES,
+            verified: false,
+            selected: false,
+            pending: Vec::new(),
+            backend: None,
+        }
+    }
+
+    /// Creates a card with the [synthetic identity][`Identity::synthetic`] for the given index.
+    pub fn synthetic(index: u64) -> Self {
+        Self::new(Identity::synthetic(index))
+    }
+
+    /// Returns the identity stored on this card.
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    /// Sets the PIN and resets the retry counter.  The PIN must have six to eight digits.
+    ///
+    /// If a backend is set, errors while storing the PIN are logged.
+    pub fn set_pin(&mut self, pin: &[u8]) {
+        self.pin = pin.to_vec();
+        self.retries = PIN_RETRIES;
+        if let Err(err) = self.store() {
+            warn!("Failed to store the PIV PIN: {}", err);
+        }
+    }
+
+    /// Sets the backend that the PIN and its retry counter are stored in.  Clones of this card
+    /// share the backend.
+    ///
+    /// If the backend already contains a PIN, it replaces the PIN of this card.  Otherwise, the
+    /// current PIN is written to the backend.
+    pub fn set_backend<B: StateBackend + 'static>(&mut self, backend: B) -> io::Result<()> {
+        let backend = Arc::new(backend);
+        match (backend.get(KEY_PIN)?, backend.get(KEY_PIN_RETRIES)?) {
+            (Some(pin), Some(retries)) => {
+                self.pin = pin;
+                self.retries = retries.first().copied().unwrap_or(0).min(PIN_RETRIES);
+                self.backend = Some(backend);
+            }
+            _ => {
+                self.backend = Some(backend);
+                self.store()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the number of remaining PIN retries.
+    pub fn pin_retries(&self) -> u8 {
+        self.retries
+    }
+
+    fn store(&self) -> io::Result<()> {
+        if let Some(backend) = &self.backend {
+            backend.apply(&[
+                Change::Set(KEY_PIN.to_owned(), self.pin.clone()),
+                Change::Set(KEY_PIN_RETRIES.to_owned(), vec![self.retries]),
+            ])?;
+        }
+        Ok(())
+    }
+
+    /// Returns the content of the data object with the given tag, or `None` if it does not
+    /// exist.
+    pub fn data_object(&self, tag: u32) -> Option<Vec<u8>> {
+        let content = match tag {
+            // the PIV AID and a PIN usage policy that only allows the application PIN
+            TAG_DISCOVERY => [tlv::encode(0x4f, AID), tlv::encode(0x5f2f, &[0x40, 0x00])].concat(),
+            TAG_CCC => [
+                tlv::encode(
+                    0xf0,
+                    &[&[0xa0, 0x00, 0x00, 0x01, 0x16, 0xff], pix(&self.identity)].concat(),
+                ),
+                tlv::encode(0xf1, &[0x21]),
+                tlv::encode(0xf2, &[0x21]),
+                tlv::encode(0xf3, &[]),
+                tlv::encode(0xf4, &[0x00]),
+                tlv::encode(0xf5, &[0x10]),
+                tlv::encode(0xf6, &[]),
+                tlv::encode(0xf7, &[]),
+                tlv::encode(0xfa, &[]),
+                tlv::encode(0xfb, &[]),
+                tlv::encode(0xfc, &[]),
+                tlv::encode(0xfd, &[]),
+                tlv::encode(0xfe, &[]),
+            ]
+            .concat(),
+            TAG_CHUID => [
+                tlv::encode(0x30, &fasc_n(&self.identity.fasc_n)),
+                tlv::encode(0x34, &self.identity.guid),
+                tlv::encode(0x35, &self.identity.expiration),
+                tlv::encode(0x3e, &[]),
+                tlv::encode(0xfe, &[]),
+            ]
+            .concat(),
+            _ => {
+                let (_, purpose) = CERTIFICATES.iter().find(|(t, _)| *t == tag)?;
+                [
+                    tlv::encode(0x70, &certificate(&self.identity, purpose)),
+                    tlv::encode(0x71, &[0x00]),
+                    tlv::encode(0xfe, &[]),
+                ]
+                .concat()
+            }
+        };
+        Some(content)
+    }
+
+    fn handle(&mut self, command: &Command<'_>) -> Result<Vec<u8>, u16> {
+        if command.ins == INS_SELECT && command.p1 == 0x04 {
+            // partial selection with the PIX omitted is allowed
+            self.selected = !command.data.is_empty() && AID.starts_with(command.data);
+            return if self.selected {
+                let template = [
+                    tlv::encode(0x4f, &AID[5..]),
+                    tlv::encode(0x79, &tlv::encode(0x4f, &AID[..5])),
+                    tlv::encode(0x50, b"vpicc PIV"),
+                ]
+                .concat();
+                Ok(tlv::encode(0x61, &template))
+            } else {
+                Err(apdu::SW_FILE_NOT_FOUND)
+            };
+        }
+        if !self.selected {
+            return Err(apdu::SW_CONDITIONS_NOT_SATISFIED);
+        }
+        match command.ins {
+            INS_GET_DATA => {
+                if (command.p1, command.p2) != (0x3f, 0xff) {
+                    return Err(apdu::SW_WRONG_P1P2);
+                }
+                let tag = match *command.data {
+                    [0x5c, len, ref tag @ ..] if usize::from(len) == tag.len() && len <= 3 => {
+                        tag.iter().fold(0, |acc, b| (acc << 8) | u32::from(*b))
+                    }
+                    _ => return Err(apdu::SW_WRONG_DATA),
+                };
+                let content = self.data_object(tag).ok_or(apdu::SW_FILE_NOT_FOUND)?;
+                Ok(if tag == TAG_DISCOVERY {
+                    tlv::encode(0x7e, &content)
+                } else {
+                    tlv::encode(0x53, &content)
+                })
+            }
+            INS_VERIFY => {
+                if command.p1 != 0x00 || command.p2 != PIV_PIN_REFERENCE {
+                    return Err(apdu::SW_DATA_NOT_FOUND);
+                }
+                if command.data.is_empty() {
+                    return if self.verified {
+                        Ok(Vec::new())
+                    } else {
+                        Err(0x63c0 | u16::from(self.retries))
+                    };
+                }
+                if command.data.len() != 8 {
+                    return Err(apdu::SW_WRONG_DATA);
+                }
+                if self.retries == 0 {
+                    return Err(0x6983);
+                }
+                let pin: Vec<u8> = command
+                    .data
+                    .iter()
+                    .copied()
+                    .filter(|b| *b != 0xff)
+                    .collect();
+                let verified = pin == self.pin;
+                let retries = self.retries;
+                self.retries = if verified { PIN_RETRIES } else { retries - 1 };
+                if retries != self.retries {
+                    // the retry counter must be stored before the result is revealed
+                    if let Err(err) = self.store() {
+                        warn!("Failed to store the PIV PIN retry counter: {}", err);
+                        self.retries = retries;
+                        self.verified = false;
+                        return Err(apdu::SW_MEMORY_FAILURE);
+                    }
+                }
+                self.verified = verified;
+                if verified {
+                    Ok(Vec::new())
+                } else {
+                    Err(0x63c0 | u16::from(self.retries))
+                }
+            }
+            INS_GET_RESPONSE if self.pending.is_empty() => Err(apdu::SW_CONDITIONS_NOT_SATISFIED),
+            INS_GET_RESPONSE => Ok(Vec::new()),
+            _ => Err(apdu::SW_INS_NOT_SUPPORTED),
+        }
+    }
+}
+
+impl VSmartCard for PivCard {
+    fn power_on(&mut self) {
+        self.selected = false;
+        self.verified = false;
+    }
+
+    fn power_off(&mut self) {
+        self.selected = false;
+        self.verified = false;
+    }
+
+    fn reset(&mut self) {
+        self.selected = false;
+        self.verified = false;
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        let Some(command) = Command::parse(msg) else {
+            return apdu::response(&[], apdu::SW_WRONG_LENGTH);
+        };
+        if command.ins != INS_GET_RESPONSE {
+            self.pending.clear();
+        }
+        match self.handle(&command) {
+            Ok(data) => {
+                self.pending.splice(..0, data);
+                apdu::response_chunk(&mut self.pending, command.le)
+            }
+            Err(status) => apdu::response(&[], status),
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            aids: vec![AID.to_vec()],
+            ..Default::default()
+        }
+    }
+}
+
+/// Returns the card identifier suffix derived from the GUID.
+fn pix(identity: &Identity) -> &[u8] {
+    &identity.guid[..15]
+}
+
+/// Encodes a FASC-N with 5-bit BCD characters with odd parity.
+fn fasc_n(digits: &[u8; 14]) -> Vec<u8> {
+    const SS: u8 = 0x0b;
+    const FS: u8 = 0x0d;
+    const ES: u8 = 0x0f;
+
+    let (agency, rest) = digits.split_at(4);
+    let (system, credential) = rest.split_at(4);
+    let mut chars = vec![SS];
+    chars.extend_from_slice(agency);
+    chars.push(FS);
+    chars.extend_from_slice(system);
+    chars.push(FS);
+    chars.extend_from_slice(credential);
+    // credential series, individual credential issue and person identifier
+    chars.extend_from_slice(&[FS, 0, FS, 1, FS]);
+    chars.extend_from_slice(&[0; 10]);
+    // organizational category, organizational identifier and association category
+    chars.extend_from_slice(&[1, 9, 9, 9, 9, 1]);
+    chars.push(ES);
+    chars.push(chars.iter().fold(0, |lrc, c| lrc ^ c));
+
+    let mut bits = Vec::with_capacity(200);
+    for c in chars {
+        let data: Vec<bool> = (0..4).map(|i| c & (1 << i) != 0).collect();
+        let parity = data.iter().filter(|b| **b).count() % 2 == 0;
+        bits.extend(data);
+        bits.push(parity);
+    }
+    bits.chunks(8)
+        .map(|byte| byte.iter().fold(0, |acc, bit| (acc << 1) | u8::from(*bit)))
+        .collect()
+}
+
+/// Builds a DER-encoded X.509 certificate for the identity with a dummy signature.
+fn certificate(identity: &Identity, purpose: &str) -> Vec<u8> {
+    let algorithm = tlv::encode(0x30, &tlv::encode(0x06, OID_ECDSA_WITH_SHA256));
+    let issuer = name(&[
+        (OID_COUNTRY, "US"),
+        (OID_ORGANIZATION, "vpicc"),
+        (OID_COMMON_NAME, "vpicc Test CA"),
+    ]);
+    let subject = name(&[
+        (OID_COUNTRY, "US"),
+        (OID_ORGANIZATION, "vpicc"),
+        (OID_COMMON_NAME, &format!("{} ({})", identity.name, purpose)),
+    ]);
+    let validity = [
+        tlv::encode(0x17, b"240101000000Z"),
+        tlv::encode(0x17, &[&identity.expiration[2..], b"235959Z"].concat()),
+    ]
+    .concat();
+    let key_algorithm = [
+        tlv::encode(0x06, OID_EC_PUBLIC_KEY),
+        tlv::encode(0x06, OID_PRIME256V1),
+    ]
+    .concat();
+    let public_key = [
+        tlv::encode(0x30, &key_algorithm),
+        tlv::encode(0x03, &[&[0x00], PUBLIC_KEY].concat()),
+    ]
+    .concat();
+    let tbs = [
+        tlv::encode(0xa0, &tlv::encode(0x02, &[0x02])),
+        tlv::encode(0x02, &integer(identity.serial)),
+        algorithm.clone(),
+        issuer,
+        tlv::encode(0x30, &validity),
+        subject,
+        tlv::encode(0x30, &public_key),
+    ]
+    .concat();
+    let signature = tlv::encode(
+        0x30,
+        &[tlv::encode(0x02, &[0x01]), tlv::encode(0x02, &[0x01])].concat(),
+    );
+    let certificate = [
+        tlv::encode(0x30, &tbs),
+        algorithm,
+        tlv::encode(0x03, &[&[0x00], signature.as_slice()].concat()),
+    ]
+    .concat();
+    tlv::encode(0x30, &certificate)
+}
+
+fn name(attributes: &[(&[u8], &str)]) -> Vec<u8> {
+    let rdns: Vec<u8> = attributes
+        .iter()
+        .flat_map(|(oid, value)| {
+            let attribute = [tlv::encode(0x06, oid), tlv::encode(0x0c, value.as_bytes())].concat();
+            tlv::encode(0x31, &tlv::encode(0x30, &attribute))
+        })
+        .collect();
+    tlv::encode(0x30, &rdns)
+}
+
+/// Encodes a positive DER integer.
+fn integer(value: u64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let start = bytes.iter().position(|b| *b != 0).unwrap_or(7);
+    let mut integer = bytes[start..].to_vec();
+    if integer[0] & 0x80 != 0 {
+        integer.insert(0, 0x00);
+    }
+    integer
+}
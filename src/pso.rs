@@ -0,0 +1,190 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! PERFORM SECURITY OPERATION commands as defined in ISO 7816-8.
+//!
+//! [`perform`][] handles the PSO operations COMPUTE DIGITAL SIGNATURE (P1-P2 9E9A), DECIPHER
+//! (8086) and VERIFY CERTIFICATE (00AE or 00BE) by checking the command and calling the
+//! corresponding method of a [`KeyStore`][].  The key store implements the cryptographic
+//! operations, so applets can use any cryptography library or return fixed test vectors.  The
+//! keys used for the operations are given as [`KeyReferences`][], for example from the current
+//! security environment, see [`mse::Environments`][`crate::mse::Environments`].
+//!
+//! # Example
+//!
+//! ```
+//! use vpicc::{apdu::{self, Command}, pso::{self, KeyReferences, KeyStore}};
+//!
+//! struct Keys;
+//!
+//! impl KeyStore for Keys {
+//!     fn sign(&mut self, key: u8, input: &[u8]) -> Result<Vec<u8>, u16> {
+//!         match key {
+//!             0x84 => Ok(input.iter().rev().copied().collect()),
+//!             _ => Err(apdu::SW_DATA_NOT_FOUND),
+//!         }
+//!     }
+//! }
+//!
+//! let keys = KeyReferences {
+//!     signature: Some(0x84),
+//!     ..Default::default()
+//! };
+//! let msg = [0x00, 0x2a, 0x9e, 0x9a, 0x02, 0x01, 0x02, 0x00];
+//! let command = Command::parse(&msg).unwrap();
+//! assert_eq!(pso::perform(&command, &mut Keys, &keys), Ok(vec![0x02, 0x01]));
+//!
+//! // no key is referenced for DECIPHER
+//! let msg = [0x00, 0x2a, 0x80, 0x86, 0x02, 0x00, 0x01, 0x00];
+//! let command = Command::parse(&msg).unwrap();
+//! let result = pso::perform(&command, &mut Keys, &keys);
+//! assert_eq!(result, Err(apdu::SW_CONDITIONS_NOT_SATISFIED));
+//! ```
+
+use crate::apdu::{self, Command};
+
+/// The instruction byte of the PERFORM SECURITY OPERATION command.
+pub const INS_PSO: u8 = 0x2a;
+
+/// A security operation of a PERFORM SECURITY OPERATION command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    /// COMPUTE DIGITAL SIGNATURE, P1-P2 9E9A.
+    ComputeDigitalSignature,
+    /// DECIPHER, P1-P2 8086.
+    Decipher,
+    /// VERIFY CERTIFICATE, P1-P2 00AE or 00BE.
+    VerifyCertificate,
+}
+
+impl Operation {
+    /// Returns the operation of the given PSO command, or `None` if the command is not a PSO
+    /// command or the operation is not supported.
+    pub fn parse(command: &Command<'_>) -> Option<Self> {
+        if command.ins != INS_PSO {
+            return None;
+        }
+        match (command.p1, command.p2) {
+            (0x9e, 0x9a) => Some(Self::ComputeDigitalSignature),
+            (0x80, 0x86) => Some(Self::Decipher),
+            (0x00, 0xae) | (0x00, 0xbe) => Some(Self::VerifyCertificate),
+            _ => None,
+        }
+    }
+}
+
+/// The references of the keys used by [`perform`][].
+///
+/// If a key reference is `None`, the corresponding operation is rejected with
+/// [`SW_CONDITIONS_NOT_SATISFIED`][`apdu::SW_CONDITIONS_NOT_SATISFIED`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeyReferences {
+    /// The private key for COMPUTE DIGITAL SIGNATURE.
+    pub signature: Option<u8>,
+    /// The private key for DECIPHER.
+    pub decipher: Option<u8>,
+    /// The public key for VERIFY CERTIFICATE.
+    pub verification: Option<u8>,
+}
+
+impl KeyReferences {
+    /// Returns the key reference for the given operation.
+    pub fn get(&self, operation: Operation) -> Option<u8> {
+        match operation {
+            Operation::ComputeDigitalSignature => self.signature,
+            Operation::Decipher => self.decipher,
+            Operation::VerifyCertificate => self.verification,
+        }
+    }
+}
+
+/// The keys of a card that are used for security operations.
+///
+/// All methods return an error status word if the operation fails.  By default, they reject the
+/// operation with [`SW_FUNCTION_NOT_SUPPORTED`][`apdu::SW_FUNCTION_NOT_SUPPORTED`].
+pub trait KeyStore {
+    /// Computes a digital signature of the given input, usually a hash or a DigestInfo
+    /// structure, with the referenced private key.
+    fn sign(&mut self, key: u8, input: &[u8]) -> Result<Vec<u8>, u16> {
+        let _ = (key, input);
+        Err(apdu::SW_FUNCTION_NOT_SUPPORTED)
+    }
+
+    /// Deciphers the given cryptogram with the referenced private key.
+    ///
+    /// The padding indicator is the first byte of the command data as defined in ISO 7816-4,
+    /// for example 00 for no further indication or 81 for RSA.
+    fn decipher(&mut self, key: u8, padding: u8, cryptogram: &[u8]) -> Result<Vec<u8>, u16> {
+        let _ = (key, padding, cryptogram);
+        Err(apdu::SW_FUNCTION_NOT_SUPPORTED)
+    }
+
+    /// Verifies the given certificate with the referenced public key.
+    ///
+    /// If the certificate is valid, the key store can import the public key of the certificate
+    /// so that it can be referenced by a following security environment.
+    fn verify_certificate(&mut self, key: u8, certificate: &[u8]) -> Result<(), u16> {
+        let _ = (key, certificate);
+        Err(apdu::SW_FUNCTION_NOT_SUPPORTED)
+    }
+}
+
+impl<K: KeyStore + ?Sized> KeyStore for &mut K {
+    fn sign(&mut self, key: u8, input: &[u8]) -> Result<Vec<u8>, u16> {
+        (**self).sign(key, input)
+    }
+
+    fn decipher(&mut self, key: u8, padding: u8, cryptogram: &[u8]) -> Result<Vec<u8>, u16> {
+        (**self).decipher(key, padding, cryptogram)
+    }
+
+    fn verify_certificate(&mut self, key: u8, certificate: &[u8]) -> Result<(), u16> {
+        (**self).verify_certificate(key, certificate)
+    }
+}
+
+impl<K: KeyStore + ?Sized> KeyStore for Box<K> {
+    fn sign(&mut self, key: u8, input: &[u8]) -> Result<Vec<u8>, u16> {
+        (**self).sign(key, input)
+    }
+
+    fn decipher(&mut self, key: u8, padding: u8, cryptogram: &[u8]) -> Result<Vec<u8>, u16> {
+        (**self).decipher(key, padding, cryptogram)
+    }
+
+    fn verify_certificate(&mut self, key: u8, certificate: &[u8]) -> Result<(), u16> {
+        (**self).verify_certificate(key, certificate)
+    }
+}
+
+/// Handles a PERFORM SECURITY OPERATION command using the given key store and returns the
+/// response data or an error status word, see the [module documentation][`self`].
+///
+/// Commands with another instruction or an unsupported operation are rejected with
+/// [`SW_INS_NOT_SUPPORTED`][`apdu::SW_INS_NOT_SUPPORTED`] and
+/// [`SW_WRONG_P1P2`][`apdu::SW_WRONG_P1P2`].  Commands without data are rejected with
+/// [`SW_WRONG_LENGTH`][`apdu::SW_WRONG_LENGTH`].  The response is not truncated to Le, so that
+/// the caller can use response chaining for long signatures.
+pub fn perform<K: KeyStore + ?Sized>(
+    command: &Command<'_>,
+    store: &mut K,
+    keys: &KeyReferences,
+) -> Result<Vec<u8>, u16> {
+    if command.ins != INS_PSO {
+        return Err(apdu::SW_INS_NOT_SUPPORTED);
+    }
+    let operation = Operation::parse(command).ok_or(apdu::SW_WRONG_P1P2)?;
+    let key = keys
+        .get(operation)
+        .ok_or(apdu::SW_CONDITIONS_NOT_SATISFIED)?;
+    if command.data.is_empty() {
+        return Err(apdu::SW_WRONG_LENGTH);
+    }
+    match operation {
+        Operation::ComputeDigitalSignature => store.sign(key, command.data),
+        Operation::Decipher => store.decipher(key, command.data[0], &command.data[1..]),
+        Operation::VerifyCertificate => store
+            .verify_certificate(key, command.data)
+            .map(|()| Vec::new()),
+    }
+}
@@ -0,0 +1,169 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+use std::cell::RefCell;
+
+use crate::{Capabilities, DummySmartCard, VSmartCard};
+
+/// A call recorded by [`RecordingCard`][].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Call {
+    /// A call to [`VSmartCard::atr`][].
+    Atr,
+    /// A call to [`VSmartCard::power_on`][].
+    PowerOn,
+    /// A call to [`VSmartCard::power_off`][].
+    PowerOff,
+    /// A call to [`VSmartCard::reset`][].
+    Reset,
+    /// A call to [`VSmartCard::cold_reset`][].
+    ColdReset,
+    /// A call to [`VSmartCard::warm_reset`][].
+    WarmReset,
+    /// A call to [`VSmartCard::execute`][] with the command and the returned response.
+    Execute {
+        /// The executed command APDU.
+        command: Vec<u8>,
+        /// The returned response APDU.
+        response: Vec<u8>,
+    },
+}
+
+/// A [`VSmartCard`][] implementation that records all calls to the wrapped card.
+///
+/// By default, [`DummySmartCard`][] is used as the wrapped card.  The recorded calls can be
+/// inspected using [`calls`][`RecordingCard::calls`] and the assertion helpers, making this card
+/// useful for tests.
+///
+/// # Example
+///
+/// ```
+/// use vpicc::{Call, DummySmartCard, RecordingCard, Request};
+///
+/// let mut card = RecordingCard::new(DummySmartCard);
+/// Request::PowerOn.handle(&mut card);
+/// Request::Apdu(vec![0x00, 0xa4, 0x04, 0x00]).handle(&mut card);
+///
+/// card.assert_commands(&[&[0x00, 0xa4, 0x04, 0x00]]);
+/// assert_eq!(card.calls()[0], Call::PowerOn);
+/// ```
+#[derive(Debug, Default)]
+pub struct RecordingCard<C = DummySmartCard> {
+    card: C,
+    calls: RefCell<Vec<Call>>,
+}
+
+impl<C> RecordingCard<C> {
+    /// Wraps the given card.
+    pub fn new(card: C) -> Self {
+        Self {
+            card,
+            calls: Default::default(),
+        }
+    }
+
+    /// Returns all recorded calls.
+    pub fn calls(&self) -> Vec<Call> {
+        self.calls.borrow().clone()
+    }
+
+    /// Returns the command APDUs of all recorded calls to [`VSmartCard::execute`][].
+    pub fn commands(&self) -> Vec<Vec<u8>> {
+        self.calls
+            .borrow()
+            .iter()
+            .filter_map(|call| match call {
+                Call::Execute { command, .. } => Some(command.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the number of recorded calls that are equal to the given call.
+    pub fn count(&self, call: &Call) -> usize {
+        self.calls.borrow().iter().filter(|c| *c == call).count()
+    }
+
+    /// Removes all recorded calls.
+    pub fn clear(&mut self) {
+        self.calls.get_mut().clear();
+    }
+
+    /// Asserts that the recorded calls are equal to the given calls.
+    #[track_caller]
+    pub fn assert_calls(&self, calls: &[Call]) {
+        assert_eq!(self.calls.borrow().as_slice(), calls, "unexpected calls");
+    }
+
+    /// Asserts that the executed command APDUs are equal to the given commands.
+    #[track_caller]
+    pub fn assert_commands(&self, commands: &[&[u8]]) {
+        let actual = self.commands();
+        let actual: Vec<&[u8]> = actual.iter().map(Vec::as_slice).collect();
+        assert_eq!(actual, commands, "unexpected commands");
+    }
+
+    /// Returns a reference to the wrapped card.
+    pub fn get_ref(&self) -> &C {
+        &self.card
+    }
+
+    /// Returns a mutable reference to the wrapped card.
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.card
+    }
+
+    /// Returns the wrapped card.
+    pub fn into_inner(self) -> C {
+        self.card
+    }
+
+    fn record(&self, call: Call) {
+        self.calls.borrow_mut().push(call);
+    }
+}
+
+impl<C: VSmartCard> VSmartCard for RecordingCard<C> {
+    fn atr(&self) -> &[u8] {
+        self.record(Call::Atr);
+        self.card.atr()
+    }
+
+    fn power_on(&mut self) {
+        self.record(Call::PowerOn);
+        self.card.power_on()
+    }
+
+    fn power_off(&mut self) {
+        self.record(Call::PowerOff);
+        self.card.power_off()
+    }
+
+    fn reset(&mut self) {
+        self.record(Call::Reset);
+        self.card.reset()
+    }
+
+    fn cold_reset(&mut self) {
+        self.record(Call::ColdReset);
+        self.card.cold_reset()
+    }
+
+    fn warm_reset(&mut self) {
+        self.record(Call::WarmReset);
+        self.card.warm_reset()
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        let response = self.card.execute(msg);
+        self.record(Call::Execute {
+            command: msg.to_vec(),
+            response: response.clone(),
+        });
+        response
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.card.capabilities()
+    }
+}
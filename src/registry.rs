@@ -0,0 +1,394 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+use std::{
+    cell::OnceCell,
+    collections::BTreeMap,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    thread::{self, JoinHandle},
+};
+
+use log::{debug, info, warn};
+
+use crate::{
+    apdu, connect_socket, Capabilities, Connection, Error, PowerState, Request, Result,
+    ShutdownHandle, VSmartCard,
+};
+
+/// A card managed by a [`Registry`][].
+pub type BoxedCard = Box<dyn VSmartCard + Send>;
+
+/// The number of requests handled by a card in a [`Registry`][].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CardStats {
+    /// The number of executed APDUs.
+    pub apdus: u64,
+    /// The number of ATR requests.
+    pub atr_requests: u64,
+    /// The number of Power On commands.
+    pub power_ons: u64,
+    /// The number of Power Off commands.
+    pub power_offs: u64,
+    /// The number of Reset commands.
+    pub resets: u64,
+}
+
+impl CardStats {
+    fn record(&mut self, request: &Request) {
+        let counter = match request {
+            Request::Apdu(_) => &mut self.apdus,
+            Request::GetAtr => &mut self.atr_requests,
+            Request::PowerOn => &mut self.power_ons,
+            Request::PowerOff => &mut self.power_offs,
+            Request::Reset => &mut self.resets,
+        };
+        *counter += 1;
+    }
+}
+
+/// Manages named cards and their connections to vpcd.
+///
+/// Every card is served on its own thread.  Cards can be added, removed, restarted and replaced
+/// while the other cards keep running.  Dropping the registry stops all cards.
+///
+/// Stopping a card does not interrupt an exchange:  If the card is currently executing a
+/// command, the response is sent to vpcd before the connection is closed.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::net::SocketAddr;
+///
+/// fn main() -> vpicc::Result<()> {
+///     let addr: SocketAddr = "127.0.0.1:35963".parse().unwrap();
+///     let mut registry = vpicc::Registry::new();
+///     registry.add("first", addr, vpicc::DummySmartCard)?;
+///     registry.add("second", addr, vpicc::DummySmartCard)?;
+///     registry.restart("first")?;
+///     let _card = registry.remove("second")?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct Registry {
+    instances: BTreeMap<String, Instance>,
+}
+
+impl Registry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connects the given card to the vpcd daemon at the given address and adds it with the
+    /// given name.
+    ///
+    /// Returns an error if a card with the same name already exists or if the connection fails.
+    pub fn add<V: VSmartCard + Send + 'static>(
+        &mut self,
+        name: impl Into<String>,
+        addr: SocketAddr,
+        card: V,
+    ) -> Result<()> {
+        self.insert(name.into(), addr, Box::new(card))
+    }
+
+    /// Stops the card with the given name and removes it from the registry.
+    pub fn remove(&mut self, name: &str) -> Result<BoxedCard> {
+        let instance = self.instances.remove(name).ok_or_else(|| not_found(name))?;
+        info!("Removing card {}", name);
+        Ok(instance.stop()?.card)
+    }
+
+    /// Stops the card with the given name and connects it to vpcd again.
+    ///
+    /// If the connection fails, the card is kept in the registry without a connection, so that
+    /// it can be restarted again or removed later.
+    pub fn restart(&mut self, name: &str) -> Result<()> {
+        let instance = self.instances.remove(name).ok_or_else(|| not_found(name))?;
+        info!("Restarting card {}", name);
+        let addr = instance.addr;
+        let mut slot = instance.stop()?;
+        // vpcd powers the card on again on the new connection
+        slot.power = PowerState::default();
+        match Instance::start(name, addr, slot) {
+            Ok(instance) => {
+                self.instances.insert(name.to_owned(), instance);
+                Ok(())
+            }
+            Err((err, slot)) => {
+                warn!("Failed to reconnect card {}: {}", name, err);
+                self.instances
+                    .insert(name.to_owned(), Instance::stopped(addr, slot));
+                Err(err)
+            }
+        }
+    }
+
+    /// Resets the card with the given name without interrupting its connection.
+    ///
+    /// The card is reset like for a Reset command from vpcd, so it is cold reset if vpcd has
+    /// powered it off and considered powered on afterwards.
+    pub fn reset(&self, name: &str) -> Result<()> {
+        let instance = self.instances.get(name).ok_or_else(|| not_found(name))?;
+        info!("Resetting card {}", name);
+        let slot = &mut *instance.lock();
+        Request::Reset.handle_with_state(&mut slot.card, &mut slot.power);
+        Ok(())
+    }
+
+    /// Replaces the card with the given name without interrupting its connection and returns
+    /// the previous card.
+    pub fn replace<V: VSmartCard + Send + 'static>(
+        &self,
+        name: &str,
+        card: V,
+    ) -> Result<BoxedCard> {
+        let instance = self.instances.get(name).ok_or_else(|| not_found(name))?;
+        info!("Replacing card {}", name);
+        let card: BoxedCard = Box::new(card);
+        Ok(std::mem::replace(&mut instance.lock().card, card))
+    }
+
+    /// Returns true if the registry contains a card with the given name.
+    pub fn contains(&self, name: &str) -> bool {
+        self.instances.contains_key(name)
+    }
+
+    /// Returns true if the connection of the card with the given name is still active, or
+    /// `None` if the registry does not contain the card.
+    ///
+    /// Returns false if vpcd has closed the connection or if [`restart`][`Registry::restart`]
+    /// could not reconnect the card.
+    pub fn is_running(&self, name: &str) -> Option<bool> {
+        self.instances.get(name).map(Instance::is_running)
+    }
+
+    /// Returns the statistics of the card with the given name since it was added, or `None` if
+    /// the registry does not contain the card.
+    pub fn stats(&self, name: &str) -> Option<CardStats> {
+        self.instances
+            .get(name)
+            .map(|instance| instance.lock().stats)
+    }
+
+    /// Returns the names of all cards in this registry.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.instances.keys().map(String::as_str)
+    }
+
+    /// Returns the number of cards in this registry.
+    pub fn len(&self) -> usize {
+        self.instances.len()
+    }
+
+    /// Returns true if this registry does not contain any cards.
+    pub fn is_empty(&self) -> bool {
+        self.instances.is_empty()
+    }
+
+    fn insert(&mut self, name: String, addr: SocketAddr, card: BoxedCard) -> Result<()> {
+        if self.instances.contains_key(&name) {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("card {} already exists", name),
+            )
+            .into());
+        }
+        let slot = Slot {
+            card,
+            power: PowerState::default(),
+            stats: Default::default(),
+        };
+        let instance = Instance::start(&name, addr, slot).map_err(|(err, _)| err)?;
+        self.instances.insert(name, instance);
+        Ok(())
+    }
+}
+
+impl Drop for Registry {
+    fn drop(&mut self) {
+        for (name, instance) in std::mem::take(&mut self.instances) {
+            if let Err(err) = instance.stop() {
+                warn!("Failed to stop card {}: {}", name, err);
+            }
+        }
+    }
+}
+
+struct Slot {
+    card: BoxedCard,
+    power: PowerState,
+    stats: CardStats,
+}
+
+impl std::fmt::Debug for Slot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Slot")
+            .field("power", &self.power)
+            .field("stats", &self.stats)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+struct Instance {
+    addr: SocketAddr,
+    slot: Arc<Mutex<Slot>>,
+    /// The thread serving the connection, or `None` if the card is not connected.
+    thread: Option<Running>,
+}
+
+#[derive(Debug)]
+struct Running {
+    shutdown: ShutdownHandle,
+    handle: JoinHandle<()>,
+}
+
+impl Instance {
+    /// Connects the card of the given slot to vpcd and serves the connection on a new thread.
+    ///
+    /// If the connection fails, the slot is returned with the error.
+    fn start(name: &str, addr: SocketAddr, slot: Slot) -> std::result::Result<Self, (Error, Slot)> {
+        let connect = || {
+            let connection = connect_socket(addr)?;
+            let shutdown = connection.shutdown_handle()?;
+            Ok::<_, Error>((connection, shutdown))
+        };
+        let (connection, shutdown) = match connect() {
+            Ok(connection) => connection,
+            Err(err) => return Err((err, slot)),
+        };
+        let name = name.to_owned();
+        let slot = Arc::new(Mutex::new(slot));
+        let shared_slot = slot.clone();
+        let handle = thread::spawn(move || {
+            let result = serve(connection, &shared_slot);
+            debug!("Connection of card {} closed: {:?}", name, result);
+        });
+        Ok(Self {
+            addr,
+            slot,
+            thread: Some(Running { shutdown, handle }),
+        })
+    }
+
+    /// Keeps the card of the given slot without connecting it.
+    fn stopped(addr: SocketAddr, slot: Slot) -> Self {
+        Self {
+            addr,
+            slot: Arc::new(Mutex::new(slot)),
+            thread: None,
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        self.thread
+            .as_ref()
+            .is_some_and(|thread| !thread.handle.is_finished())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Slot> {
+        self.slot.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn stop(self) -> Result<Slot> {
+        if let Some(thread) = self.thread {
+            // A command that is currently executed is finished and its response is sent before
+            // the connection is closed.
+            thread.shutdown.shutdown();
+            thread
+                .handle
+                .join()
+                .map_err(|_| io::Error::other("card thread panicked"))?;
+        }
+        let slot = Arc::try_unwrap(self.slot)
+            .map_err(|_| io::Error::other("card is still in use"))?
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner);
+        Ok(slot)
+    }
+}
+
+fn serve(mut connection: Connection, slot: &Mutex<Slot>) -> Result<()> {
+    while !connection.is_shutdown() {
+        connection
+            .poll(&mut Locked::new(slot))
+            .or_else(|err| connection.check_error(err))?;
+    }
+    Ok(())
+}
+
+/// Passes the requests of a connection to the card of a slot, locking the slot for every call.
+///
+/// A new adapter is used for every request so that the ATR is read from the current card.  The
+/// power state of the slot is used instead of the power state of the connection so that
+/// [`Registry::reset`][] can update it.
+struct Locked<'a> {
+    slot: &'a Mutex<Slot>,
+    atr: OnceCell<Vec<u8>>,
+}
+
+impl<'a> Locked<'a> {
+    fn new(slot: &'a Mutex<Slot>) -> Self {
+        Self {
+            slot,
+            atr: OnceCell::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'a, Slot> {
+        self.slot.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn handle(&self, request: Request) {
+        let slot = &mut *self.lock();
+        slot.stats.record(&request);
+        request.handle_with_state(&mut slot.card, &mut slot.power);
+    }
+}
+
+impl VSmartCard for Locked<'_> {
+    fn atr(&self) -> &[u8] {
+        self.atr.get_or_init(|| {
+            let slot = &mut *self.lock();
+            slot.stats.record(&Request::GetAtr);
+            slot.card.atr().to_vec()
+        })
+    }
+
+    fn power_on(&mut self) {
+        self.handle(Request::PowerOn)
+    }
+
+    fn power_off(&mut self) {
+        self.handle(Request::PowerOff)
+    }
+
+    // the cold and warm resets are performed by handle using the power state of the slot
+    fn reset(&mut self) {
+        self.handle(Request::Reset)
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        let slot = &mut *self.lock();
+        slot.stats.apdus += 1;
+        slot.card.execute(msg)
+    }
+
+    fn execute_small(&mut self, msg: &[u8]) -> apdu::Response {
+        let slot = &mut *self.lock();
+        slot.stats.apdus += 1;
+        slot.card.execute_small(msg)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.lock().card.capabilities()
+    }
+}
+
+fn not_found(name: &str) -> io::Error {
+    io::Error::new(ErrorKind::NotFound, format!("card {} not found", name))
+}
@@ -0,0 +1,336 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Routing of command APDUs to handler functions.
+//!
+//! A [`Router`][] assembles a simple card from closures:  every route consists of a [`Match`][]
+//! on the command header and a handler that returns the response data or an error status word.
+//! Routes are checked in the order in which they were added.  Commands that do not match any
+//! route are passed to the fallback handler, which rejects them with
+//! [`SW_INS_NOT_SUPPORTED`][`apdu::SW_INS_NOT_SUPPORTED`] by default.
+//!
+//! If the [`Capabilities`][] of the card are set, the router enforces them before checking the
+//! routes:  commands with extended length fields are rejected with
+//! [`SW_WRONG_LENGTH`][`apdu::SW_WRONG_LENGTH`] unless extended length is supported, and if AIDs
+//! are declared, selecting any other DF name is rejected with
+//! [`SW_FILE_NOT_FOUND`][`apdu::SW_FILE_NOT_FOUND`].
+//!
+//! Handlers can share a [`SecurityStatus`][] with the router to record verified PINs.  The
+//! router clears it when an application is selected, when a logical channel is closed, see
+//! [`apdu::resets_security`][], and when the card is powered off or reset.
+//!
+//! # Example
+//!
+//! ```
+//! use vpicc::{apdu, router::{Match, Router}, VSmartCard};
+//!
+//! let mut card = Router::new()
+//!     .route(Match::ins(0xa4), |_command| Ok(Vec::new()))
+//!     .route(Match::ins(0xca).p1(0x00).p2(0x6e), |_command| Ok(vec![0x6e, 0x00]))
+//!     .fallback(|_command| Err(apdu::SW_WRONG_P1P2));
+//! assert_eq!(card.execute(&[0x00, 0xa4, 0x04, 0x00]), [0x90, 0x00]);
+//! assert_eq!(card.execute(&[0x00, 0xca, 0x00, 0x6e]), [0x6e, 0x00, 0x90, 0x00]);
+//! assert_eq!(card.execute(&[0x00, 0xca, 0x00, 0x6f]), [0x6a, 0x86]);
+//! ```
+
+use std::{
+    collections::BTreeSet,
+    fmt,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use crate::{
+    apdu::{self, Applet, Command},
+    Capabilities, VSmartCard, DEFAULT_ATR,
+};
+
+/// A handler of a [`Router`][].
+pub type Handler = Box<dyn FnMut(&Command<'_>) -> Result<Vec<u8>, u16> + Send>;
+
+/// A pattern for the header of a command APDU.
+///
+/// Fields that are `None` match any value.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Match {
+    /// The class byte.
+    pub cla: Option<u8>,
+    /// The instruction byte.
+    pub ins: Option<u8>,
+    /// The first parameter byte.
+    pub p1: Option<u8>,
+    /// The second parameter byte.
+    pub p2: Option<u8>,
+}
+
+impl Match {
+    /// Matches all commands.
+    pub fn any() -> Self {
+        Self::default()
+    }
+
+    /// Matches all commands with the given instruction.
+    pub fn ins(ins: u8) -> Self {
+        Self {
+            ins: Some(ins),
+            ..Default::default()
+        }
+    }
+
+    /// Matches all commands with the given class and instruction.
+    pub fn cla_ins(cla: u8, ins: u8) -> Self {
+        Self {
+            cla: Some(cla),
+            ins: Some(ins),
+            ..Default::default()
+        }
+    }
+
+    /// Additionally requires the given first parameter byte.
+    pub fn p1(mut self, p1: u8) -> Self {
+        self.p1 = Some(p1);
+        self
+    }
+
+    /// Additionally requires the given second parameter byte.
+    pub fn p2(mut self, p2: u8) -> Self {
+        self.p2 = Some(p2);
+        self
+    }
+
+    /// Returns true if the given command matches this pattern.
+    pub fn matches(&self, command: &Command<'_>) -> bool {
+        let field = |pattern: Option<u8>, value: u8| pattern.is_none_or(|pattern| pattern == value);
+        field(self.cla, command.cla)
+            && field(self.ins, command.ins)
+            && field(self.p1, command.p1)
+            && field(self.p2, command.p2)
+    }
+}
+
+/// The security status of a [`Router`][], i. e. the references of the PINs and keys that have
+/// been verified.
+///
+/// This is a handle that can be cloned into the handlers of a router.
+///
+/// # Example
+///
+/// ```
+/// use vpicc::{apdu, router::{Match, Router, SecurityStatus}, VSmartCard};
+///
+/// let security = SecurityStatus::new();
+/// let verify = security.clone();
+/// let read = security.clone();
+/// let mut card = Router::new()
+///     .security(security)
+///     .route(Match::ins(0xa4), |_command| Ok(Vec::new()))
+///     .route(Match::ins(0x20), move |command| {
+///         if command.data != b"123456" {
+///             return Err(apdu::SW_SECURITY_STATUS_NOT_SATISFIED);
+///         }
+///         verify.grant(command.p2);
+///         Ok(Vec::new())
+///     })
+///     .route(Match::ins(0xb0), move |_command| {
+///         read.require(0x80)?;
+///         Ok(vec![0x42])
+///     });
+/// card.execute(&[0x00, 0x20, 0x00, 0x80, 0x06, b'1', b'2', b'3', b'4', b'5', b'6']);
+/// assert_eq!(card.execute(&[0x00, 0xb0, 0x00, 0x00]), [0x42, 0x90, 0x00]);
+/// // selecting the application again clears the verified PIN
+/// card.execute(&[0x00, 0xa4, 0x04, 0x00, 0x01, 0xa0]);
+/// assert_eq!(card.execute(&[0x00, 0xb0, 0x00, 0x00]), [0x69, 0x82]);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct SecurityStatus {
+    granted: Arc<Mutex<BTreeSet<u8>>>,
+}
+
+impl SecurityStatus {
+    /// Creates an empty security status.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that the PIN or key with the given reference has been verified.
+    pub fn grant(&self, reference: u8) {
+        self.lock().insert(reference);
+    }
+
+    /// Returns true if the PIN or key with the given reference has been verified.
+    pub fn is_granted(&self, reference: u8) -> bool {
+        self.lock().contains(&reference)
+    }
+
+    /// Returns an error with [`SW_SECURITY_STATUS_NOT_SATISFIED`][`apdu::SW_SECURITY_STATUS_NOT_SATISFIED`]
+    /// unless the PIN or key with the given reference has been verified.
+    pub fn require(&self, reference: u8) -> Result<(), u16> {
+        if self.is_granted(reference) {
+            Ok(())
+        } else {
+            Err(apdu::SW_SECURITY_STATUS_NOT_SATISFIED)
+        }
+    }
+
+    /// Clears all verified PINs and keys.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeSet<u8>> {
+        self.granted.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A card that routes commands to handler functions, see the [module documentation][`self`].
+pub struct Router {
+    atr: Vec<u8>,
+    routes: Vec<(Match, Handler)>,
+    fallback: Handler,
+    capabilities: Option<Capabilities>,
+    security: SecurityStatus,
+}
+
+impl Router {
+    /// Creates a router without routes that uses [`DEFAULT_ATR`][].
+    pub fn new() -> Self {
+        Self {
+            atr: DEFAULT_ATR.to_vec(),
+            routes: Vec::new(),
+            fallback: Box::new(|_| Err(apdu::SW_INS_NOT_SUPPORTED)),
+            capabilities: None,
+            security: SecurityStatus::default(),
+        }
+    }
+
+    /// Adds a route for commands matching the given pattern.
+    pub fn route<F>(mut self, pattern: Match, handler: F) -> Self
+    where
+        F: FnMut(&Command<'_>) -> Result<Vec<u8>, u16> + Send + 'static,
+    {
+        self.routes.push((pattern, Box::new(handler)));
+        self
+    }
+
+    /// Sets the handler for commands that do not match any route.
+    pub fn fallback<F>(mut self, handler: F) -> Self
+    where
+        F: FnMut(&Command<'_>) -> Result<Vec<u8>, u16> + Send + 'static,
+    {
+        self.fallback = Box::new(handler);
+        self
+    }
+
+    /// Sets the ATR of the card.
+    pub fn atr(mut self, atr: &[u8]) -> Self {
+        self.atr = atr.to_vec();
+        self
+    }
+
+    /// Sets the security status that is cleared by the router, see the
+    /// [module documentation][`self`].
+    pub fn security(mut self, security: SecurityStatus) -> Self {
+        self.security = security;
+        self
+    }
+
+    /// Sets the capabilities of the card and enforces them, see the
+    /// [module documentation][`self`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use vpicc::{router::{Match, Router}, Capabilities, VSmartCard};
+    ///
+    /// let mut capabilities = Capabilities::default();
+    /// capabilities.aids.push(vec![0xa0, 0x00, 0x00, 0x06, 0x47]);
+    /// let mut card = Router::new()
+    ///     .route(Match::ins(0xa4), |_command| Ok(Vec::new()))
+    ///     .capabilities(capabilities);
+    /// assert_eq!(card.execute(&[0x00, 0xa4, 0x04, 0x00, 0x02, 0xa0, 0x00]), [0x90, 0x00]);
+    /// assert_eq!(card.execute(&[0x00, 0xa4, 0x04, 0x00, 0x02, 0xd2, 0x76]), [0x6a, 0x82]);
+    /// ```
+    pub fn capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = Some(capabilities);
+        self
+    }
+
+    fn check_capabilities(&self, msg: &[u8], command: &Command<'_>) -> Result<(), u16> {
+        let Some(capabilities) = &self.capabilities else {
+            return Ok(());
+        };
+        if apdu::is_extended(msg) && !capabilities.extended_length {
+            return Err(apdu::SW_WRONG_LENGTH);
+        }
+        let select = command.ins == 0xa4 && command.p1 == 0x04;
+        if select && !capabilities.aids.is_empty() && !capabilities.selects(command.data) {
+            return Err(apdu::SW_FILE_NOT_FOUND);
+        }
+        Ok(())
+    }
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Router {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let routes: Vec<&Match> = self.routes.iter().map(|(pattern, _)| pattern).collect();
+        f.debug_struct("Router")
+            .field("atr", &self.atr)
+            .field("routes", &routes)
+            .field("capabilities", &self.capabilities)
+            .field("security", &self.security)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Applet for Router {
+    fn dispatch(&mut self, command: &Command<'_>) -> Result<Vec<u8>, u16> {
+        let handler = self
+            .routes
+            .iter_mut()
+            .find(|(pattern, _)| pattern.matches(command))
+            .map(|(_, handler)| handler)
+            .unwrap_or(&mut self.fallback);
+        handler(command)
+    }
+
+    fn reset_security(&mut self) {
+        self.security.clear();
+    }
+}
+
+impl VSmartCard for Router {
+    fn atr(&self) -> &[u8] {
+        &self.atr
+    }
+
+    fn power_off(&mut self) {
+        self.reset_security();
+    }
+
+    fn cold_reset(&mut self) {
+        self.reset_security();
+    }
+
+    fn warm_reset(&mut self) {
+        self.reset_security();
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        let checked = Command::parse(msg)
+            .map(|command| self.check_capabilities(msg, &command))
+            .unwrap_or(Ok(()));
+        match checked {
+            Ok(()) => self.execute_command(msg),
+            Err(status) => apdu::response(&[], status),
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.capabilities.clone().unwrap_or_default()
+    }
+}
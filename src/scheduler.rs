@@ -0,0 +1,438 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender, TryRecvError},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use log::{debug, warn};
+
+use crate::{buffer::Buffer, Connection, Result, VSmartCard};
+
+/// The default time [`Scheduler::run`][] sleeps when no connection had any pending data.
+pub const DEFAULT_IDLE_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Serves many connections on a single thread.
+///
+/// The scheduler switches all added connections to non-blocking mode and polls them in turn, so
+/// a large number of virtual smartcards can be served without spawning an OS thread per card.
+/// Connections that fail or are closed by vpcd are removed from the scheduler after the commands
+/// that were received before the connection was closed have been handled.  Connections that
+/// are shut down using a [`ShutdownHandle`][`crate::ShutdownHandle`] are drained like in
+/// [`shutdown`][`Scheduler::shutdown`] and removed.
+///
+/// Cards added with [`add`][`Scheduler::add`] are executed on the scheduler thread, so a slow
+/// command stalls all other connections.  Cards with CPU-heavy commands, for example for
+/// cryptographic operations, can be added with [`add_offloaded`][`Scheduler::add_offloaded`]
+/// instead.
+///
+/// # Example
+///
+/// ```no_run
+/// fn main() -> vpicc::Result<()> {
+///     let mut scheduler = vpicc::Scheduler::new();
+///     for _ in 0..100 {
+///         scheduler.add(vpicc::connect()?, vpicc::DummySmartCard)?;
+///     }
+///     scheduler.run()
+/// }
+/// ```
+pub struct Scheduler {
+    entries: Vec<Entry>,
+    idle_interval: Duration,
+}
+
+impl Scheduler {
+    /// Creates an empty scheduler.
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            idle_interval: DEFAULT_IDLE_INTERVAL,
+        }
+    }
+
+    /// Sets the time [`run`][`Scheduler::run`] sleeps when no connection had any pending data,
+    /// defaulting to [`DEFAULT_IDLE_INTERVAL`][].
+    pub fn set_idle_interval(&mut self, interval: Duration) {
+        self.idle_interval = interval;
+    }
+
+    /// Adds a connection that should be served using the given card.
+    pub fn add<V: VSmartCard + 'static>(&mut self, connection: Connection, card: V) -> Result<()> {
+        let peer = connection.peer_addr()?;
+        let shutdown = connection.shutdown.clone();
+        let (stream, connection) = connection.with_transport(Buffer::default());
+        let executor = Executor::Inline {
+            connection,
+            card: Box::new(card),
+        };
+        self.entries
+            .push(Entry::new(peer, stream, shutdown, executor)?);
+        Ok(())
+    }
+
+    /// Adds a connection that should be served using the given card, executing the card on a
+    /// separate worker thread.
+    ///
+    /// The scheduler thread keeps reading and writing all connections while the worker executes
+    /// a command.  The requests of this connection are still handled one after another and the
+    /// responses are sent in the same order.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// fn main() -> vpicc::Result<()> {
+    ///     let mut scheduler = vpicc::Scheduler::new();
+    ///     scheduler.add(vpicc::connect()?, vpicc::DummySmartCard)?;
+    ///     scheduler.add_offloaded(vpicc::connect()?, vpicc::DummySmartCard)?;
+    ///     scheduler.run()
+    /// }
+    /// ```
+    pub fn add_offloaded<V: VSmartCard + Send + 'static>(
+        &mut self,
+        connection: Connection,
+        card: V,
+    ) -> Result<()> {
+        let peer = connection.peer_addr()?;
+        let shutdown = connection.shutdown.clone();
+        let (requests, request_receiver) = mpsc::channel();
+        let (response_sender, responses) = mpsc::channel();
+        let (stream, connection) = connection.with_transport(Channel {
+            requests: request_receiver,
+            pending: Vec::new(),
+            responses: response_sender,
+        });
+        let worker = Worker::spawn(card, connection, requests, responses, peer)?;
+        self.entries.push(Entry::new(
+            peer,
+            stream,
+            shutdown,
+            Executor::Worker(worker),
+        )?);
+        Ok(())
+    }
+
+    /// Returns the number of connections served by this scheduler.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if this scheduler does not serve any connections.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Handles all commands and responses that are ready on any connection without blocking.
+    ///
+    /// Returns true if any data was received or sent.
+    pub fn poll(&mut self) -> bool {
+        let mut progress = false;
+        self.entries.retain_mut(|entry| {
+            let result = entry.poll();
+            if entry.is_shutdown() {
+                debug!("Connection to {} shut down", entry.peer);
+                if let Err(err) = entry.drain() {
+                    debug!("Failed to drain connection to {}: {}", entry.peer, err);
+                }
+                return false;
+            }
+            match result {
+                Ok(p) => {
+                    progress |= p;
+                    true
+                }
+                Err(err) => {
+                    warn!(
+                        "Removing connection to {} from scheduler: {}",
+                        entry.peer, err
+                    );
+                    false
+                }
+            }
+        });
+        progress
+    }
+
+    /// Closes all connections after handling the commands that have already been received.
+    ///
+    /// Pending responses are sent before the connections are closed, so vpcd never sees a
+    /// half-completed exchange.  Incomplete messages are discarded.
+    pub fn shutdown(self) -> Result<()> {
+        let mut result = Ok(());
+        for mut entry in self.entries {
+            if let Err(err) = entry.drain() {
+                warn!("Failed to drain connection to {}: {}", entry.peer, err);
+                result = Err(err);
+            }
+        }
+        result
+    }
+
+    /// Serves all connections until all of them have been closed.
+    pub fn run(&mut self) -> Result<()> {
+        while !self.entries.is_empty() {
+            if !self.poll() {
+                thread::sleep(self.idle_interval);
+            }
+        }
+        debug!("All scheduled connections have been closed");
+        Ok(())
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Entry {
+    peer: SocketAddr,
+    stream: TcpStream,
+    shutdown: Arc<AtomicBool>,
+    executor: Executor,
+    rx: Vec<u8>,
+    tx: Vec<u8>,
+    closed: bool,
+}
+
+enum Executor {
+    /// Handles the requests on the scheduler thread using a connection that reads the data
+    /// received by the scheduler.
+    Inline {
+        connection: Connection<Buffer>,
+        card: Box<dyn VSmartCard>,
+    },
+    Worker(Worker),
+}
+
+/// The transport of a connection that is run by a [`Worker`][].
+///
+/// The scheduler sends the data it receives to the worker, and the worker sends the framed
+/// responses back to the scheduler.
+struct Channel {
+    requests: Receiver<Vec<u8>>,
+    pending: Vec<u8>,
+    responses: Sender<Vec<u8>>,
+}
+
+impl Read for Channel {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            match self.requests.recv() {
+                Ok(data) => self.pending = data,
+                // the scheduler has closed the connection
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for Channel {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.responses
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::from(ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Executes the requests of a single connection on a separate thread.
+struct Worker {
+    requests: Option<Sender<Vec<u8>>>,
+    responses: Receiver<Vec<u8>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    fn spawn<V: VSmartCard + Send + 'static>(
+        mut card: V,
+        connection: Connection<Channel>,
+        requests: Sender<Vec<u8>>,
+        responses: Receiver<Vec<u8>>,
+        peer: SocketAddr,
+    ) -> Result<Self> {
+        let thread = thread::Builder::new()
+            .name(format!("vpicc-worker-{}", peer))
+            .spawn(move || {
+                let result = connection.run(&mut card);
+                debug!("Worker for {} finished: {:?}", peer, result);
+            })?;
+        Ok(Self {
+            requests: Some(requests),
+            responses,
+            thread: Some(thread),
+        })
+    }
+
+    fn send(&self, data: Vec<u8>) -> Result<()> {
+        self.requests
+            .as_ref()
+            .and_then(|requests| requests.send(data).ok())
+            .ok_or_else(|| terminated().into())
+    }
+
+    /// Appends all responses that are already available to the given buffer.
+    fn collect(&self, tx: &mut Vec<u8>) -> Result<()> {
+        loop {
+            match self.responses.try_recv() {
+                Ok(response) => tx.extend_from_slice(&response),
+                Err(TryRecvError::Empty) => return Ok(()),
+                // the worker only exits after finish has been called
+                Err(TryRecvError::Disconnected) if self.requests.is_none() => return Ok(()),
+                Err(TryRecvError::Disconnected) => return Err(terminated().into()),
+            }
+        }
+    }
+
+    /// Waits until all sent requests have been handled.
+    fn finish(&mut self) -> Result<()> {
+        self.requests = None;
+        match self.thread.take() {
+            Some(thread) => thread.join().map_err(|_| terminated().into()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        // the thread exits once the request channel is closed
+        if let Err(err) = self.finish() {
+            debug!("Failed to join card worker thread: {}", err);
+        }
+    }
+}
+
+fn terminated() -> io::Error {
+    io::Error::other("card worker thread terminated")
+}
+
+impl Entry {
+    fn new(
+        peer: SocketAddr,
+        stream: TcpStream,
+        shutdown: Arc<AtomicBool>,
+        executor: Executor,
+    ) -> Result<Self> {
+        stream.set_nonblocking(true)?;
+        Ok(Self {
+            peer,
+            stream,
+            shutdown,
+            executor,
+            rx: Vec::new(),
+            tx: Vec::new(),
+            closed: false,
+        })
+    }
+
+    fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::Acquire)
+    }
+
+    fn poll(&mut self) -> Result<bool> {
+        let received = self.receive()?;
+        if self.closed {
+            // handle the commands received before vpcd closed the connection
+            if let Err(err) = self.drain() {
+                debug!(
+                    "Failed to drain closed connection to {}: {}",
+                    self.peer, err
+                );
+            }
+            return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+        }
+        self.handle_messages()?;
+        let sent = self.flush()?;
+        Ok(received || sent)
+    }
+
+    fn handle_messages(&mut self) -> Result<()> {
+        match &mut self.executor {
+            Executor::Inline { connection, card } => {
+                connection.get_mut().input.append(&mut self.rx);
+                loop {
+                    match connection.poll(card) {
+                        Ok(()) => {}
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                        Err(err) => return Err(err),
+                    }
+                }
+                self.tx.append(&mut connection.get_mut().output);
+                Ok(())
+            }
+            Executor::Worker(worker) => {
+                if !self.rx.is_empty() {
+                    worker.send(std::mem::take(&mut self.rx))?;
+                }
+                worker.collect(&mut self.tx)
+            }
+        }
+    }
+
+    fn drain(&mut self) -> Result<()> {
+        self.handle_messages()?;
+        if let Executor::Worker(worker) = &mut self.executor {
+            worker.finish()?;
+            worker.collect(&mut self.tx)?;
+        }
+        self.stream.set_nonblocking(false)?;
+        self.stream.write_all(&self.tx)?;
+        self.tx.clear();
+        Ok(self.stream.shutdown(Shutdown::Both)?)
+    }
+
+    fn receive(&mut self) -> Result<bool> {
+        let mut buf = [0; 4096];
+        let mut received = false;
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    self.closed = true;
+                    return Ok(received);
+                }
+                Ok(n) => {
+                    self.rx.extend_from_slice(&buf[..n]);
+                    received = true;
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(received),
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<bool> {
+        let mut sent = false;
+        while !self.tx.is_empty() {
+            match self.stream.write(&self.tx) {
+                Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero).into()),
+                Ok(n) => {
+                    self.tx.drain(..n);
+                    sent = true;
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(sent)
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Simple APDU scripts.
+//!
+//! A script contains one command APDU per line, encoded as hex with optional spaces or colons
+//! between the bytes as used by `opensc-tool` and `gscriptor`.  The command can be followed by
+//! `->` and the expected status word.  The keyword `reset` resets the card.  Everything after a
+//! `#` is a comment.
+//!
+//! ```text
+//! # select the OpenPGP applet
+//! 00 A4 04 00 06 D2 76 00 01 24 01 -> 9000
+//! reset
+//! 00:CA:00:6E:00
+//! ```
+
+use std::{
+    io::{Error, ErrorKind, Result},
+    str::FromStr,
+};
+
+use log::{debug, warn};
+
+use crate::{apdu, hex, VSmartCard};
+
+/// A step of a [`Script`][].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Step {
+    /// Resets the card.
+    Reset,
+    /// Executes a command APDU.
+    Command {
+        /// The line of the command in the script, starting at 1.
+        line: usize,
+        /// The command APDU.
+        apdu: Vec<u8>,
+        /// The expected status word, if any.
+        expected: Option<u16>,
+    },
+}
+
+/// A parsed APDU script.
+///
+/// # Example
+///
+/// ```
+/// use vpicc::script::Script;
+///
+/// let script: Script = "00 A4 04 00 06 D2 76 00 01 24 01 -> 9000".parse()?;
+/// let report = script.run(&mut vpicc::DummySmartCard);
+/// assert!(report.is_success());
+/// # Ok::<_, std::io::Error>(())
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Script {
+    steps: Vec<Step>,
+}
+
+impl Script {
+    /// Returns the steps of this script.
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    /// Executes this script using the given card.
+    ///
+    /// All steps are executed even if a command does not return the expected status word.
+    pub fn run<V: VSmartCard + ?Sized>(&self, card: &mut V) -> Report {
+        let mut report = Report::default();
+        for step in &self.steps {
+            match step {
+                Step::Reset => {
+                    debug!("Resetting card");
+                    card.reset();
+                }
+                Step::Command {
+                    line,
+                    apdu,
+                    expected,
+                } => {
+                    let response = card.execute(apdu);
+                    report.executed += 1;
+                    let status = apdu::status(&response);
+                    if let Some(expected) = *expected {
+                        if status != Some(expected) {
+                            warn!(
+                                "line {}: expected status {:04x}, received response {:x?}",
+                                line, expected, response
+                            );
+                            report.failures.push(Failure {
+                                line: *line,
+                                command: apdu.clone(),
+                                expected,
+                                response,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        report
+    }
+}
+
+impl FromStr for Script {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut steps = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let line_number = i + 1;
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.eq_ignore_ascii_case("reset") {
+                steps.push(Step::Reset);
+                continue;
+            }
+            let line_error = |err: Error| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("line {}: {}", line_number, err),
+                )
+            };
+            let (apdu, expected) = match line.split_once("->") {
+                Some((apdu, expected)) => {
+                    let expected = hex::decode(expected).map_err(line_error)?;
+                    let expected: [u8; 2] = expected.try_into().map_err(|_| {
+                        line_error(Error::new(
+                            ErrorKind::InvalidData,
+                            "expected status word must have two bytes",
+                        ))
+                    })?;
+                    (apdu, Some(u16::from_be_bytes(expected)))
+                }
+                None => (line, None),
+            };
+            let apdu = hex::decode(apdu).map_err(line_error)?;
+            if apdu.len() < 4 {
+                return Err(line_error(Error::new(
+                    ErrorKind::InvalidData,
+                    "command APDU must have at least four bytes",
+                )));
+            }
+            steps.push(Step::Command {
+                line: line_number,
+                apdu,
+                expected,
+            });
+        }
+        Ok(Self { steps })
+    }
+}
+
+/// The result of running a [`Script`][].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// The number of executed commands.
+    pub executed: usize,
+    /// The commands that did not return the expected status word.
+    pub failures: Vec<Failure>,
+}
+
+impl Report {
+    /// Returns true if all commands returned the expected status word.
+    pub fn is_success(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// A command that did not return the expected status word.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Failure {
+    /// The line of the command in the script, starting at 1.
+    pub line: usize,
+    /// The command APDU.
+    pub command: Vec<u8>,
+    /// The expected status word.
+    pub expected: u16,
+    /// The actual response APDU.
+    pub response: Vec<u8>,
+}
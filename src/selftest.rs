@@ -0,0 +1,96 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Self-tests for card implementations.
+//!
+//! A card implementing [`SelfTest`][] provides test vectors that can be checked using [`run`][]
+//! at startup or on demand, before any commands from vpcd are handled.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use vpicc::{selftest::{self, SelfTest, TestVector}, VSmartCard};
+//!
+//! struct Card;
+//!
+//! impl VSmartCard for Card {
+//!     fn execute(&mut self, _msg: &[u8]) -> Vec<u8> {
+//!         vec![0x90, 0x00]
+//!     }
+//! }
+//!
+//! impl SelfTest for Card {
+//!     fn test_vectors(&self) -> Vec<TestVector> {
+//!         vec![TestVector::new(&[0x00, 0xa4, 0x04, 0x00], &[0x90, 0x00])]
+//!     }
+//! }
+//!
+//! fn main() -> vpicc::Result<()> {
+//!     let mut card = Card;
+//!     selftest::run(&mut card)?;
+//!     vpicc::connect()?.run(&mut card)
+//! }
+//! ```
+
+use std::io::{Error, ErrorKind, Result};
+
+use log::{error, info};
+
+use crate::VSmartCard;
+
+/// A command APDU and the response APDU expected from the card.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TestVector {
+    /// The command APDU.
+    pub command: Vec<u8>,
+    /// The expected response APDU.
+    pub response: Vec<u8>,
+}
+
+impl TestVector {
+    /// Creates a test vector from the given command and expected response.
+    pub fn new(command: &[u8], response: &[u8]) -> Self {
+        Self {
+            command: command.to_vec(),
+            response: response.to_vec(),
+        }
+    }
+}
+
+/// A card that provides test vectors for a self-test.
+pub trait SelfTest: VSmartCard {
+    /// Returns the test vectors of this card, executed in the given order.
+    fn test_vectors(&self) -> Vec<TestVector>;
+}
+
+/// Executes the test vectors of the given card.
+///
+/// The card is reset before and after executing the test vectors so that the self-test does not
+/// affect the commands received from vpcd.  All test vectors are executed, and an error listing
+/// all failed test vectors is returned if a response does not match.
+pub fn run<C: SelfTest + ?Sized>(card: &mut C) -> Result<()> {
+    let vectors = card.test_vectors();
+    card.reset();
+    let mut failures = Vec::new();
+    for (i, vector) in vectors.iter().enumerate() {
+        let response = card.execute(&vector.command);
+        if response != vector.response {
+            error!(
+                "Self-test vector {} failed: command {:x?}, expected {:x?}, received {:x?}",
+                i, vector.command, vector.response, response
+            );
+            failures.push(i.to_string());
+        }
+    }
+    card.reset();
+
+    if failures.is_empty() {
+        info!("Self-test passed ({} test vectors)", vectors.len());
+        Ok(())
+    } else {
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("self-test vectors failed: {}", failures.join(", ")),
+        ))
+    }
+}
@@ -0,0 +1,1136 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Persistent state of virtual cards.
+//!
+//! The state of a card, for example its files, data objects and counters, is stored as a set of
+//! binary values with string keys, grouped by the name of the card.  Several changes can be
+//! applied atomically using [`Change`][] batches, so that a card never sees a partially updated
+//! state after a crash.
+//!
+//! The [`StateBackend`][] trait decides where the state of a single card lives.  This module
+//! provides the [`MemoryBackend`][] for tests, the [`FileBackend`][] storing the state in a text
+//! file and, with the `sqlite` feature, the backends of a [`SqliteStore`][].  Applications can
+//! implement the trait for their own storage.  The [card profiles][`crate::profiles`] accept a
+//! backend for their files, PINs and counters.
+//!
+//! # Example
+//!
+//! ```
+//! use vpicc::{profiles::piv::PivCard, state::{MemoryBackend, StateBackend}};
+//!
+//! let backend = MemoryBackend::new();
+//! let mut card = PivCard::synthetic(1);
+//! card.set_backend(backend.clone())?;
+//! card.set_pin(b"654321");
+//! assert_eq!(backend.get("piv/pin")?.as_deref(), Some(&b"654321"[..]));
+//! # Ok::<_, std::io::Error>(())
+//! ```
+//!
+//! Like real cards with EEPROM and RAM, a [`Memory`][] distinguishes persistent data, which is
+//! stored in a backend, from volatile data, which is lost when the card is powered off or reset.
+//! [`MemoryCard`][] clears the volatile data automatically.
+//!
+//! A [`BufferedBackend`][] keeps the changes of a card in memory until they are flushed to a
+//! slower backend.  [`AutoSave`][] flushes them after commands that changed the state, whenever
+//! the card is powered off or reset and when it is dropped, so that read-only commands do not
+//! write to the backend.
+//!
+//! With the `encryption` feature, [`EncryptedBackend`][] encrypts the values of another backend
+//! with AES-256-GCM using a provided key, so that cards holding real certificates or keys can be
+//! stored on shared machines.
+//!
+//! With the `sqlite` feature, [`SqliteStore`][] stores the state of any number of cards in a
+//! single SQLite database.  The database contains a single table `state` with the columns
+//! `card`, `key` and `value`, so it can be inspected with standard tools:
+//!
+//! ```text
+//! sqlite3 cards.db "SELECT card, key, hex(value) FROM state"
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::{self, File},
+    io::{Error, ErrorKind, Result, Write},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex, MutexGuard, PoisonError},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use log::{debug, warn};
+
+use crate::{apdu, hex, Capabilities, VSmartCard};
+
+/// Storage for the state of a single card, see the [module documentation][`self`].
+///
+/// Backends are shared between clones of a card, so all methods take `&self`.
+pub trait StateBackend: fmt::Debug + Send + Sync {
+    /// Returns the value of the given key, or `None` if it is not set.
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Returns all keys in lexicographic order.
+    fn keys(&self) -> Result<Vec<String>>;
+
+    /// Applies the given changes atomically:  either all or none of them are stored.
+    fn apply(&self, changes: &[Change]) -> Result<()>;
+
+    /// Sets the value of the given key.
+    fn set(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.apply(&[Change::Set(key.to_owned(), value.to_vec())])
+    }
+
+    /// Removes the given key.
+    fn remove(&self, key: &str) -> Result<()> {
+        self.apply(&[Change::Remove(key.to_owned())])
+    }
+}
+
+impl<T: StateBackend + ?Sized> StateBackend for Arc<T> {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        (**self).get(key)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        (**self).keys()
+    }
+
+    fn apply(&self, changes: &[Change]) -> Result<()> {
+        (**self).apply(changes)
+    }
+}
+
+/// A change to the state of a card.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// Sets the value of the given key.
+    Set(String, Vec<u8>),
+    /// Removes the given key.
+    Remove(String),
+}
+
+impl Change {
+    fn apply_to(&self, values: &mut BTreeMap<String, Vec<u8>>) {
+        match self {
+            Self::Set(key, value) => {
+                values.insert(key.clone(), value.clone());
+            }
+            Self::Remove(key) => {
+                values.remove(key);
+            }
+        }
+    }
+}
+
+/// A [`StateBackend`][] that keeps the state in memory.
+///
+/// Clones share the same state, so a clone can be used to inspect the state of a card.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryBackend {
+    values: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+}
+
+impl MemoryBackend {
+    /// Creates an empty backend.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Removes all keys.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<String, Vec<u8>>> {
+        self.values.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl StateBackend for MemoryBackend {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.lock().get(key).cloned())
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        Ok(self.lock().keys().cloned().collect())
+    }
+
+    fn apply(&self, changes: &[Change]) -> Result<()> {
+        let mut values = self.lock();
+        changes
+            .iter()
+            .for_each(|change| change.apply_to(&mut values));
+        Ok(())
+    }
+}
+
+/// A [`StateBackend`][] that stores the state in a text file.
+///
+/// The file contains one line per key with the key and the hex-encoded value, separated by a
+/// space.  Keys must not contain whitespace.  The file is rewritten for every update by writing
+/// a temporary file next to it and renaming it, so that the update is atomic.  The temporary file
+/// and, on Unix, the directory are synced to disk, so that a completed update survives a crash
+/// of the system.
+#[derive(Debug)]
+pub struct FileBackend {
+    path: PathBuf,
+    values: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+impl FileBackend {
+    /// Opens the backend for the given file, which is created on the first update if it does not
+    /// exist.
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+        let values = match fs::read_to_string(&path) {
+            Ok(content) => parse(&content)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(Self {
+            path,
+            values: Mutex::new(values),
+        })
+    }
+
+    /// Returns the path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<String, Vec<u8>>> {
+        self.values.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl StateBackend for FileBackend {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.lock().get(key).cloned())
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        Ok(self.lock().keys().cloned().collect())
+    }
+
+    fn apply(&self, changes: &[Change]) -> Result<()> {
+        let mut values = self.lock();
+        let mut updated = values.clone();
+        for change in changes {
+            if let Change::Set(key, _) = change {
+                if key.is_empty() || key.contains(char::is_whitespace) {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("invalid key {:?}", key),
+                    ));
+                }
+            }
+            change.apply_to(&mut updated);
+        }
+        let content: String = updated
+            .iter()
+            .map(|(key, value)| format!("{} {}\n", key, hex::encode(value)))
+            .collect();
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&tmp, &self.path)?;
+        // the file has been replaced even if the rename cannot be synced
+        *values = updated;
+        sync_parent(&self.path)
+    }
+}
+
+/// Syncs the directory containing the given file, so that a rename in it is persisted.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()
+}
+
+/// Directories cannot be opened on other platforms, so a rename cannot be synced.
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+fn parse(content: &str) -> Result<BTreeMap<String, Vec<u8>>> {
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let (key, value) = line.split_once(' ').ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("missing value in state file line {:?}", line),
+                )
+            })?;
+            Ok((key.to_owned(), hex::decode(value)?))
+        })
+        .collect()
+}
+
+/// The kind of storage for a value in a [`Memory`][].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Storage {
+    /// Volatile memory (RAM) that is cleared when the card is powered off or reset.
+    Volatile,
+    /// Persistent memory (EEPROM or flash) that is stored in a [`StateBackend`][].
+    Persistent,
+}
+
+/// The memory of a card with volatile and persistent storage.
+///
+/// Volatile and persistent storage use separate namespaces, so the same key can be used in both.
+/// Clones share the same storage, so a card can keep a clone while a [`MemoryCard`][] clears the
+/// volatile storage.
+#[derive(Clone, Debug)]
+pub struct Memory {
+    volatile: MemoryBackend,
+    persistent: Arc<dyn StateBackend>,
+}
+
+impl Memory {
+    /// Creates a memory with the given backend for the persistent storage.
+    pub fn new<B: StateBackend + 'static>(persistent: B) -> Self {
+        Self {
+            volatile: MemoryBackend::new(),
+            persistent: Arc::new(persistent),
+        }
+    }
+
+    /// Creates a memory that keeps the persistent storage in a [`MemoryBackend`][].
+    pub fn in_memory() -> Self {
+        Self::new(MemoryBackend::new())
+    }
+
+    /// Returns the volatile storage.
+    pub fn volatile(&self) -> &MemoryBackend {
+        &self.volatile
+    }
+
+    /// Returns the backend of the persistent storage.
+    pub fn persistent(&self) -> &dyn StateBackend {
+        &*self.persistent
+    }
+
+    /// Returns the given storage.
+    pub fn storage(&self, storage: Storage) -> &dyn StateBackend {
+        match storage {
+            Storage::Volatile => &self.volatile,
+            Storage::Persistent => self.persistent(),
+        }
+    }
+
+    /// Returns the value of the given key in the given storage, or `None` if it is not set.
+    pub fn get(&self, storage: Storage, key: &str) -> Result<Option<Vec<u8>>> {
+        self.storage(storage).get(key)
+    }
+
+    /// Sets the value of the given key in the given storage.
+    pub fn set(&self, storage: Storage, key: &str, value: &[u8]) -> Result<()> {
+        self.storage(storage).set(key, value)
+    }
+
+    /// Removes the given key from the given storage.
+    pub fn remove(&self, storage: Storage, key: &str) -> Result<()> {
+        self.storage(storage).remove(key)
+    }
+
+    /// Clears the volatile storage, as if the card lost power.
+    pub fn clear_volatile(&self) {
+        self.volatile.clear();
+    }
+}
+
+/// Clears the volatile storage of a [`Memory`][] when the wrapped card is powered on, powered
+/// off or reset.
+///
+/// The volatile storage is cleared before the command is passed to the wrapped card.
+///
+/// # Example
+///
+/// ```
+/// use vpicc::{state::{Memory, MemoryCard, Storage}, VSmartCard};
+///
+/// let memory = Memory::in_memory();
+/// let mut card = MemoryCard::new(vpicc::DummySmartCard, memory.clone());
+/// memory.set(Storage::Volatile, "selected", b"piv")?;
+/// memory.set(Storage::Persistent, "pin", b"123456")?;
+///
+/// card.reset();
+/// assert_eq!(memory.get(Storage::Volatile, "selected")?, None);
+/// assert_eq!(memory.get(Storage::Persistent, "pin")?.as_deref(), Some(&b"123456"[..]));
+/// # Ok::<_, std::io::Error>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct MemoryCard<C> {
+    card: C,
+    memory: Memory,
+}
+
+impl<C> MemoryCard<C> {
+    /// Wraps the given card, clearing the volatile storage of the given memory.
+    pub fn new(card: C, memory: Memory) -> Self {
+        memory.clear_volatile();
+        Self { card, memory }
+    }
+
+    /// Returns the memory of the card.
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    /// Returns a reference to the wrapped card.
+    pub fn get_ref(&self) -> &C {
+        &self.card
+    }
+
+    /// Returns a mutable reference to the wrapped card.
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.card
+    }
+
+    /// Returns the wrapped card.
+    pub fn into_inner(self) -> C {
+        self.card
+    }
+}
+
+impl<C: VSmartCard> VSmartCard for MemoryCard<C> {
+    fn atr(&self) -> &[u8] {
+        self.card.atr()
+    }
+
+    fn power_on(&mut self) {
+        self.memory.clear_volatile();
+        self.card.power_on()
+    }
+
+    fn power_off(&mut self) {
+        self.memory.clear_volatile();
+        self.card.power_off()
+    }
+
+    fn reset(&mut self) {
+        self.memory.clear_volatile();
+        self.card.reset()
+    }
+
+    fn cold_reset(&mut self) {
+        self.card.cold_reset()
+    }
+
+    fn warm_reset(&mut self) {
+        self.card.warm_reset()
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        self.card.execute(msg)
+    }
+
+    fn execute_small(&mut self, msg: &[u8]) -> apdu::Response {
+        self.card.execute_small(msg)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.card.capabilities()
+    }
+}
+
+/// A [`StateBackend`][] that keeps changes in memory until they are flushed to another backend.
+///
+/// Reads see the pending changes.  [`flush`][`BufferedBackend::flush`] applies all pending
+/// changes to the inner backend atomically.  The changes are visible while they are being
+/// flushed and are only discarded once the inner backend has applied them.  Clones share the
+/// pending changes, so a card can use one clone while an [`AutoSave`][] wrapper flushes another
+/// one.
+///
+/// # Example
+///
+/// ```
+/// use vpicc::state::{BufferedBackend, MemoryBackend, StateBackend};
+///
+/// let inner = MemoryBackend::new();
+/// let backend = BufferedBackend::new(inner.clone());
+/// backend.set("piv/pin", b"123456")?;
+/// assert_eq!(backend.get("piv/pin")?.as_deref(), Some(&b"123456"[..]));
+/// assert_eq!(inner.get("piv/pin")?, None);
+///
+/// backend.flush()?;
+/// assert_eq!(inner.get("piv/pin")?.as_deref(), Some(&b"123456"[..]));
+/// # Ok::<_, std::io::Error>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct BufferedBackend {
+    inner: Arc<dyn StateBackend>,
+    pending: Arc<Mutex<BTreeMap<String, Option<Vec<u8>>>>>,
+    flushing: Arc<Mutex<()>>,
+}
+
+impl BufferedBackend {
+    /// Creates a buffer for the given backend without pending changes.
+    pub fn new<B: StateBackend + 'static>(inner: B) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            pending: Default::default(),
+            flushing: Default::default(),
+        }
+    }
+
+    /// Returns the inner backend.
+    pub fn inner(&self) -> &dyn StateBackend {
+        &*self.inner
+    }
+
+    /// Returns true if there are changes that have not been flushed.
+    pub fn is_dirty(&self) -> bool {
+        !self.lock().is_empty()
+    }
+
+    /// Applies all pending changes to the inner backend.
+    ///
+    /// If the inner backend fails, the changes stay pending and the error is returned.  Changes
+    /// made while flushing stay pending until the next flush.  Concurrent flushes are serialized
+    /// so that older changes cannot overwrite newer ones in the inner backend.
+    pub fn flush(&self) -> Result<()> {
+        let _flushing = self.flushing.lock().unwrap_or_else(PoisonError::into_inner);
+        let pending = self.lock().clone();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let changes: Vec<_> = pending
+            .iter()
+            .map(|(key, value)| match value {
+                Some(value) => Change::Set(key.clone(), value.clone()),
+                None => Change::Remove(key.clone()),
+            })
+            .collect();
+        self.inner.apply(&changes)?;
+        // keep changes made during the flush, they are newer
+        let mut current = self.lock();
+        for (key, value) in pending {
+            if current.get(&key) == Some(&value) {
+                current.remove(&key);
+            }
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<String, Option<Vec<u8>>>> {
+        self.pending.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl StateBackend for BufferedBackend {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self.lock().get(key) {
+            Some(value) => Ok(value.clone()),
+            None => self.inner.get(key),
+        }
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        let mut keys: BTreeMap<_, _> = self
+            .inner
+            .keys()?
+            .into_iter()
+            .map(|key| (key, true))
+            .collect();
+        for (key, value) in self.lock().iter() {
+            keys.insert(key.clone(), value.is_some());
+        }
+        Ok(keys
+            .into_iter()
+            .filter_map(|(key, present)| present.then_some(key))
+            .collect())
+    }
+
+    fn apply(&self, changes: &[Change]) -> Result<()> {
+        let mut pending = self.lock();
+        for change in changes {
+            match change {
+                Change::Set(key, value) => pending.insert(key.clone(), Some(value.clone())),
+                Change::Remove(key) => pending.insert(key.clone(), None),
+            };
+        }
+        Ok(())
+    }
+}
+
+/// Saves the state of the wrapped card after commands that changed it, when it is powered off or
+/// reset and when it is dropped.
+///
+/// The card must store its state in the given [`BufferedBackend`][].  Commands that leave
+/// pending changes in the backend and power off and reset requests from vpcd trigger a flush.
+/// The flush is synchronous:  the response is only returned, and sent to vpcd, once the changes
+/// have been flushed, so that at most the changes of the command that is executed when the
+/// process is killed are lost.  When the wrapper is dropped, the remaining changes are flushed.
+/// Errors of the inner backend are logged and the changes are retried with the next flush.
+///
+/// With [`debounced`][`AutoSave::debounced`], the flushes are made on a background thread
+/// instead, so the response to vpcd is not delayed by a slow backend.  A flush then starts once
+/// no further trigger has been received for the given delay.  If the process is killed before
+/// the wrapper is dropped, the changes made during the last debounce delay, and the time the
+/// last flush took, are lost.
+///
+/// # Example
+///
+/// ```
+/// use vpicc::{
+///     profiles::piv::PivCard,
+///     state::{AutoSave, BufferedBackend, MemoryBackend, StateBackend},
+///     VSmartCard,
+/// };
+///
+/// let inner = MemoryBackend::new();
+/// let backend = BufferedBackend::new(inner.clone());
+/// let mut card = PivCard::synthetic(1);
+/// card.set_backend(backend.clone())?;
+/// let mut card = AutoSave::new(card, backend);
+/// card.get_mut().set_pin(b"654321");
+/// assert_eq!(inner.get("piv/pin")?, None);
+///
+/// card.power_off();
+/// assert_eq!(inner.get("piv/pin")?.as_deref(), Some(&b"654321"[..]));
+/// # Ok::<_, std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct AutoSave<C> {
+    card: C,
+    saver: Saver,
+}
+
+impl<C> AutoSave<C> {
+    /// Wraps the given card that stores its state in the given backend, flushing the changes
+    /// before the response to a command is returned.
+    pub fn new(card: C, backend: BufferedBackend) -> Self {
+        Self {
+            card,
+            saver: Saver::new(backend),
+        }
+    }
+
+    /// Wraps the given card that stores its state in the given backend, flushing the changes on
+    /// a background thread after the given debounce delay.
+    pub fn debounced(card: C, backend: BufferedBackend, debounce: Duration) -> Self {
+        Self {
+            card,
+            saver: Saver::debounced(backend, debounce),
+        }
+    }
+
+    /// Returns the backend that is flushed by this wrapper.
+    pub fn backend(&self) -> &BufferedBackend {
+        &self.saver.backend
+    }
+
+    /// Requests a flush, like a power event.
+    pub fn save(&self) {
+        self.saver.request();
+    }
+
+    /// Flushes the pending changes immediately.
+    pub fn flush(&self) -> Result<()> {
+        self.saver.backend.flush()
+    }
+
+    /// Returns a reference to the wrapped card.
+    pub fn get_ref(&self) -> &C {
+        &self.card
+    }
+
+    /// Returns a mutable reference to the wrapped card.
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.card
+    }
+
+    /// Returns the wrapped card after flushing the pending changes.
+    pub fn into_inner(self) -> C {
+        self.card
+    }
+}
+
+impl<C: VSmartCard> VSmartCard for AutoSave<C> {
+    fn atr(&self) -> &[u8] {
+        self.card.atr()
+    }
+
+    fn power_on(&mut self) {
+        self.card.power_on()
+    }
+
+    fn power_off(&mut self) {
+        self.card.power_off();
+        self.saver.request();
+    }
+
+    fn reset(&mut self) {
+        self.card.reset();
+        self.saver.request();
+    }
+
+    fn cold_reset(&mut self) {
+        self.card.cold_reset()
+    }
+
+    fn warm_reset(&mut self) {
+        self.card.warm_reset()
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        let response = self.card.execute(msg);
+        self.saver.request_if_dirty();
+        response
+    }
+
+    fn execute_small(&mut self, msg: &[u8]) -> apdu::Response {
+        let response = self.card.execute_small(msg);
+        self.saver.request_if_dirty();
+        response
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.card.capabilities()
+    }
+}
+
+/// Flushes a backend, either synchronously or, with a debounce delay, on a background thread.
+#[derive(Debug)]
+struct Saver {
+    backend: BufferedBackend,
+    tx: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Saver {
+    fn new(backend: BufferedBackend) -> Self {
+        Self {
+            backend,
+            tx: None,
+            thread: None,
+        }
+    }
+
+    fn debounced(backend: BufferedBackend, debounce: Duration) -> Self {
+        let (tx, rx) = mpsc::channel();
+        let thread = {
+            let backend = backend.clone();
+            thread::spawn(move || {
+                while rx.recv().is_ok() {
+                    while rx.recv_timeout(debounce).is_ok() {}
+                    save(&backend);
+                }
+            })
+        };
+        Self {
+            backend,
+            tx: Some(tx),
+            thread: Some(thread),
+        }
+    }
+
+    fn request(&self) {
+        match &self.tx {
+            Some(tx) => {
+                tx.send(()).ok();
+            }
+            None => save(&self.backend),
+        }
+    }
+
+    fn request_if_dirty(&self) {
+        if self.backend.is_dirty() {
+            self.request();
+        }
+    }
+}
+
+impl Drop for Saver {
+    fn drop(&mut self) {
+        drop(self.tx.take());
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+        save(&self.backend);
+    }
+}
+
+fn save(backend: &BufferedBackend) {
+    if !backend.is_dirty() {
+        return;
+    }
+    match backend.flush() {
+        Ok(()) => debug!("Saved card state"),
+        Err(err) => warn!("Failed to save card state: {}", err),
+    }
+}
+
+#[cfg(feature = "encryption")]
+pub use encryption::EncryptedBackend;
+#[cfg(feature = "sqlite")]
+pub use sqlite::{SqliteBackend, SqliteStore};
+
+#[cfg(feature = "encryption")]
+mod encryption {
+    use std::{
+        fmt,
+        io::{Error, ErrorKind, Result},
+    };
+
+    use aes_gcm::{
+        aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+        Aes256Gcm,
+    };
+
+    use super::{Change, StateBackend};
+
+    /// The header of encrypted values:  a magic value followed by the format version.
+    const MAGIC: &[u8] = b"vpicc-state-aes256gcm\x01";
+    const NONCE_LEN: usize = 12;
+
+    /// A [`StateBackend`][] that encrypts the values of another backend.
+    ///
+    /// Every value is encrypted with AES-256-GCM and a random nonce.  The key of the value is
+    /// authenticated too, so values cannot be swapped between keys.  The keys themselves are
+    /// stored in plain text.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use vpicc::state::{EncryptedBackend, MemoryBackend, StateBackend};
+    ///
+    /// let inner = MemoryBackend::new();
+    /// let backend = EncryptedBackend::new(inner.clone(), &[0x42; 32]);
+    /// backend.set("piv/pin", b"123456")?;
+    /// assert_eq!(backend.get("piv/pin")?.as_deref(), Some(&b"123456"[..]));
+    /// assert_ne!(inner.get("piv/pin")?.as_deref(), Some(&b"123456"[..]));
+    ///
+    /// let wrong_key = EncryptedBackend::new(inner, &[0x00; 32]);
+    /// assert!(wrong_key.get("piv/pin").is_err());
+    /// # Ok::<_, std::io::Error>(())
+    /// ```
+    #[derive(Clone)]
+    pub struct EncryptedBackend<B> {
+        inner: B,
+        cipher: Aes256Gcm,
+    }
+
+    impl<B: StateBackend> EncryptedBackend<B> {
+        /// Wraps the given backend, encrypting its values with the given key.
+        pub fn new(inner: B, key: &[u8; 32]) -> Self {
+            Self {
+                inner,
+                cipher: Aes256Gcm::new(key.into()),
+            }
+        }
+
+        /// Returns a reference to the wrapped backend.
+        pub fn get_ref(&self) -> &B {
+            &self.inner
+        }
+
+        /// Returns a mutable reference to the wrapped backend.
+        pub fn get_mut(&mut self) -> &mut B {
+            &mut self.inner
+        }
+
+        /// Returns the wrapped backend.
+        pub fn into_inner(self) -> B {
+            self.inner
+        }
+
+        fn encrypt(&self, key: &str, value: &[u8]) -> Result<Vec<u8>> {
+            let aad = [MAGIC, key.as_bytes()].concat();
+            let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+            let payload = Payload {
+                msg: value,
+                aad: &aad,
+            };
+            let ciphertext = self
+                .cipher
+                .encrypt(&nonce, payload)
+                .map_err(|_| Error::other(format!("failed to encrypt {}", key)))?;
+            Ok([MAGIC, &nonce, &ciphertext].concat())
+        }
+
+        fn decrypt(&self, key: &str, data: &[u8]) -> Result<Vec<u8>> {
+            let invalid = |reason| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("failed to decrypt {}: {}", key, reason),
+                )
+            };
+            let data = data
+                .strip_prefix(MAGIC)
+                .ok_or_else(|| invalid("not an encrypted value"))?;
+            if data.len() < NONCE_LEN {
+                return Err(invalid("encrypted value too short"));
+            }
+            let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+            let aad = [MAGIC, key.as_bytes()].concat();
+            let payload = Payload {
+                msg: ciphertext,
+                aad: &aad,
+            };
+            self.cipher
+                .decrypt(nonce.into(), payload)
+                .map_err(|_| invalid("wrong key or modified value"))
+        }
+    }
+
+    impl<B: fmt::Debug> fmt::Debug for EncryptedBackend<B> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("EncryptedBackend")
+                .field("inner", &self.inner)
+                .finish_non_exhaustive()
+        }
+    }
+
+    impl<B: StateBackend> StateBackend for EncryptedBackend<B> {
+        fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+            self.inner
+                .get(key)?
+                .map(|data| self.decrypt(key, &data))
+                .transpose()
+        }
+
+        fn keys(&self) -> Result<Vec<String>> {
+            self.inner.keys()
+        }
+
+        fn apply(&self, changes: &[Change]) -> Result<()> {
+            let changes = changes
+                .iter()
+                .map(|change| match change {
+                    Change::Set(key, value) => {
+                        Ok(Change::Set(key.clone(), self.encrypt(key, value)?))
+                    }
+                    Change::Remove(key) => Ok(Change::Remove(key.clone())),
+                })
+                .collect::<Result<Vec<_>>>()?;
+            self.inner.apply(&changes)
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use std::{
+        io::{Error, Result},
+        path::Path,
+        sync::{Arc, Mutex, MutexGuard, PoisonError},
+    };
+
+    use rusqlite::{params, OptionalExtension};
+
+    use super::{Change, StateBackend};
+
+    const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS state (
+        card TEXT NOT NULL,
+        key TEXT NOT NULL,
+        value BLOB NOT NULL,
+        PRIMARY KEY (card, key)
+    ) WITHOUT ROWID";
+
+    /// Stores the state of cards in a SQLite database, see the [module documentation][`super`].
+    ///
+    /// Clones of a store share the same database connection, so a single store can be used by
+    /// all cards of a [`Registry`][`crate::Registry`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use vpicc::state::{Change, SqliteStore};
+    ///
+    /// let store = SqliteStore::open_in_memory()?;
+    /// store.set("card-1", "pin", b"123456")?;
+    /// store.apply("card-1", &[
+    ///     Change::Set("ef/2f00".to_owned(), vec![0x61, 0x00]),
+    ///     Change::Remove("pin".to_owned()),
+    /// ])?;
+    /// assert_eq!(store.increment("card-1", "signatures")?, 1);
+    /// assert_eq!(store.keys("card-1")?, ["ef/2f00", "signatures"]);
+    /// assert_eq!(store.cards()?, ["card-1"]);
+    /// # Ok::<_, std::io::Error>(())
+    /// ```
+    #[derive(Clone, Debug)]
+    pub struct SqliteStore {
+        connection: Arc<Mutex<rusqlite::Connection>>,
+    }
+
+    impl SqliteStore {
+        /// Opens or creates the database at the given path.
+        pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+            Self::new(rusqlite::Connection::open(path).map_err(Error::other)?)
+        }
+
+        /// Creates a temporary database in memory.
+        pub fn open_in_memory() -> Result<Self> {
+            Self::new(rusqlite::Connection::open_in_memory().map_err(Error::other)?)
+        }
+
+        fn new(connection: rusqlite::Connection) -> Result<Self> {
+            connection.execute(SCHEMA, []).map_err(Error::other)?;
+            Ok(Self {
+                connection: Arc::new(Mutex::new(connection)),
+            })
+        }
+
+        /// Returns the value of the given key of the given card, or `None` if it is not set.
+        pub fn get(&self, card: &str, key: &str) -> Result<Option<Vec<u8>>> {
+            get(&self.lock(), card, key)
+        }
+
+        /// Sets the value of the given key of the given card.
+        pub fn set(&self, card: &str, key: &str, value: &[u8]) -> Result<()> {
+            set(&self.lock(), card, key, value)
+        }
+
+        /// Removes the given key of the given card and returns true if it was set.
+        pub fn remove(&self, card: &str, key: &str) -> Result<bool> {
+            remove(&self.lock(), card, key)
+        }
+
+        /// Returns the keys of the given card in lexicographic order.
+        pub fn keys(&self, card: &str) -> Result<Vec<String>> {
+            let connection = self.lock();
+            let mut statement = connection
+                .prepare_cached("SELECT key FROM state WHERE card = ?1 ORDER BY key")
+                .map_err(Error::other)?;
+            let keys = statement
+                .query_map([card], |row| row.get(0))
+                .map_err(Error::other)?;
+            keys.collect::<rusqlite::Result<_>>().map_err(Error::other)
+        }
+
+        /// Returns the names of all cards with stored state in lexicographic order.
+        pub fn cards(&self) -> Result<Vec<String>> {
+            let connection = self.lock();
+            let mut statement = connection
+                .prepare_cached("SELECT DISTINCT card FROM state ORDER BY card")
+                .map_err(Error::other)?;
+            let cards = statement
+                .query_map([], |row| row.get(0))
+                .map_err(Error::other)?;
+            cards.collect::<rusqlite::Result<_>>().map_err(Error::other)
+        }
+
+        /// Applies the given changes to the state of the given card in a single transaction.
+        ///
+        /// Either all or none of the changes are applied.
+        pub fn apply(&self, card: &str, changes: &[Change]) -> Result<()> {
+            let mut connection = self.lock();
+            let transaction = connection.transaction().map_err(Error::other)?;
+            for change in changes {
+                match change {
+                    Change::Set(key, value) => set(&transaction, card, key, value)?,
+                    Change::Remove(key) => {
+                        remove(&transaction, card, key)?;
+                    }
+                }
+            }
+            transaction.commit().map_err(Error::other)
+        }
+
+        /// Increments the counter with the given key of the given card in a single transaction
+        /// and returns the new value.
+        ///
+        /// Counters are stored as big-endian `u64` values and start at zero.  Returns an error
+        /// if the key contains a value that is not a counter or if the counter overflows.
+        pub fn increment(&self, card: &str, key: &str) -> Result<u64> {
+            let mut connection = self.lock();
+            let transaction = connection.transaction().map_err(Error::other)?;
+            let value = match get(&transaction, card, key)? {
+                Some(value) => u64::from_be_bytes(
+                    value
+                        .try_into()
+                        .map_err(|_| Error::other(format!("{} is not a counter", key)))?,
+                ),
+                None => 0,
+            };
+            let value = value
+                .checked_add(1)
+                .ok_or_else(|| Error::other(format!("counter {} overflowed", key)))?;
+            set(&transaction, card, key, &value.to_be_bytes())?;
+            transaction.commit().map_err(Error::other)?;
+            Ok(value)
+        }
+
+        /// Returns a [`StateBackend`][] for the state of the given card in this store.
+        pub fn backend(&self, card: &str) -> SqliteBackend {
+            SqliteBackend {
+                store: self.clone(),
+                card: card.to_owned(),
+            }
+        }
+
+        fn lock(&self) -> MutexGuard<'_, rusqlite::Connection> {
+            self.connection
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+        }
+    }
+
+    /// The [`StateBackend`][] for a single card of a [`SqliteStore`][], see
+    /// [`SqliteStore::backend`][].
+    #[derive(Clone, Debug)]
+    pub struct SqliteBackend {
+        store: SqliteStore,
+        card: String,
+    }
+
+    impl SqliteBackend {
+        /// Returns the name of the card.
+        pub fn card(&self) -> &str {
+            &self.card
+        }
+    }
+
+    impl StateBackend for SqliteBackend {
+        fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+            self.store.get(&self.card, key)
+        }
+
+        fn keys(&self) -> Result<Vec<String>> {
+            self.store.keys(&self.card)
+        }
+
+        fn apply(&self, changes: &[Change]) -> Result<()> {
+            self.store.apply(&self.card, changes)
+        }
+    }
+
+    fn get(connection: &rusqlite::Connection, card: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        connection
+            .prepare_cached("SELECT value FROM state WHERE card = ?1 AND key = ?2")
+            .and_then(|mut statement| {
+                statement
+                    .query_row(params![card, key], |row| row.get(0))
+                    .optional()
+            })
+            .map_err(Error::other)
+    }
+
+    fn set(connection: &rusqlite::Connection, card: &str, key: &str, value: &[u8]) -> Result<()> {
+        connection
+            .prepare_cached("INSERT OR REPLACE INTO state (card, key, value) VALUES (?1, ?2, ?3)")
+            .and_then(|mut statement| statement.execute(params![card, key, value]))
+            .map(|_| ())
+            .map_err(Error::other)
+    }
+
+    fn remove(connection: &rusqlite::Connection, card: &str, key: &str) -> Result<bool> {
+        connection
+            .prepare_cached("DELETE FROM state WHERE card = ?1 AND key = ?2")
+            .and_then(|mut statement| statement.execute(params![card, key]))
+            .map(|removed| removed > 0)
+            .map_err(Error::other)
+    }
+}
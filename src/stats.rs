@@ -0,0 +1,191 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Session statistics.
+//!
+//! [`SessionStats`][] counts the commands handled by a card, records the response times and
+//! the returned error status words, and exports them as JSON, for example to archive the
+//! performance of a card emulation in CI jobs.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Result,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::{apdu, timing::Statistics, Capabilities, VSmartCard};
+
+/// Records statistics about the session of the wrapped card.
+///
+/// # Example
+///
+/// ```no_run
+/// use vpicc::stats::SessionStats;
+///
+/// fn main() -> vpicc::Result<()> {
+///     let mut card = SessionStats::new(vpicc::DummySmartCard);
+///     let result = vpicc::connect()?.run(&mut card);
+///     card.save_json("stats.json")?;
+///     result
+/// }
+/// ```
+#[derive(Debug)]
+pub struct SessionStats<C> {
+    card: C,
+    started_at: Instant,
+    response_times: Vec<Duration>,
+    power_ons: u64,
+    power_offs: u64,
+    resets: u64,
+    errors: BTreeMap<u16, u64>,
+    malformed: u64,
+}
+
+impl<C: VSmartCard> SessionStats<C> {
+    /// Starts recording statistics for the given card.
+    pub fn new(card: C) -> Self {
+        Self {
+            card,
+            started_at: Instant::now(),
+            response_times: Vec::new(),
+            power_ons: 0,
+            power_offs: 0,
+            resets: 0,
+            errors: BTreeMap::new(),
+            malformed: 0,
+        }
+    }
+
+    /// Returns the number of executed APDUs.
+    pub fn apdus(&self) -> usize {
+        self.response_times.len()
+    }
+
+    /// Returns the number of responses with an error status word, i. e. neither 9000 nor 61xx,
+    /// or without a status word.
+    pub fn errors(&self) -> u64 {
+        self.errors.values().sum::<u64>() + self.malformed
+    }
+
+    /// Returns the statistics of the response times, or `None` if no APDU has been executed.
+    pub fn response_times(&self) -> Option<Statistics> {
+        Statistics::from_samples(&self.response_times)
+    }
+
+    /// Returns a JSON report of the statistics.
+    ///
+    /// Durations are given in microseconds.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use vpicc::{stats::SessionStats, VSmartCard};
+    ///
+    /// let mut card = SessionStats::new(vpicc::DummySmartCard);
+    /// card.power_on();
+    /// card.execute(&[0x00, 0xa4, 0x04, 0x00]);
+    /// let json = card.to_json();
+    /// assert!(json.contains(r#""apdus":1"#));
+    /// assert!(json.contains(r#""power_ons":1"#));
+    /// ```
+    pub fn to_json(&self) -> String {
+        let micros = |duration: Duration| duration.as_micros();
+        let latency = match self.response_times() {
+            Some(stats) => format!(
+                r#"{{"mean":{},"std_dev":{},"min":{},"max":{},"median":{},"p90":{},"p99":{}}}"#,
+                micros(stats.mean),
+                micros(stats.std_dev),
+                micros(stats.min),
+                micros(stats.max),
+                micros(stats.median),
+                micros(stats.p90),
+                micros(stats.p99)
+            ),
+            None => "null".to_owned(),
+        };
+        let status_words: Vec<String> = self
+            .errors
+            .iter()
+            .map(|(sw, count)| format!(r#""{:04x}":{}"#, sw, count))
+            .collect();
+        format!(
+            r#"{{"elapsed":{},"counts":{{"apdus":{},"power_ons":{},"power_offs":{},"resets":{}}},"latency":{},"errors":{{"total":{},"malformed":{},"status_words":{{{}}}}}}}"#,
+            micros(self.started_at.elapsed()),
+            self.apdus(),
+            self.power_ons,
+            self.power_offs,
+            self.resets,
+            latency,
+            self.errors(),
+            self.malformed,
+            status_words.join(",")
+        )
+    }
+
+    /// Writes the [JSON report][`SessionStats::to_json`] to the given file.
+    pub fn save_json<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, self.to_json())
+    }
+
+    /// Returns a reference to the wrapped card.
+    pub fn get_ref(&self) -> &C {
+        &self.card
+    }
+
+    /// Returns a mutable reference to the wrapped card.
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.card
+    }
+
+    /// Returns the wrapped card.
+    pub fn into_inner(self) -> C {
+        self.card
+    }
+}
+
+impl<C: VSmartCard> VSmartCard for SessionStats<C> {
+    fn atr(&self) -> &[u8] {
+        self.card.atr()
+    }
+
+    fn power_on(&mut self) {
+        self.power_ons += 1;
+        self.card.power_on()
+    }
+
+    fn power_off(&mut self) {
+        self.power_offs += 1;
+        self.card.power_off()
+    }
+
+    fn reset(&mut self) {
+        self.resets += 1;
+        self.card.reset()
+    }
+
+    fn cold_reset(&mut self) {
+        self.card.cold_reset()
+    }
+
+    fn warm_reset(&mut self) {
+        self.card.warm_reset()
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        let start = Instant::now();
+        let response = self.card.execute(msg);
+        self.response_times.push(start.elapsed());
+        match apdu::status(&response) {
+            Some(status) if apdu::is_success(status) => {}
+            Some(status) => *self.errors.entry(status).or_default() += 1,
+            None => self.malformed += 1,
+        }
+        response
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.card.capabilities()
+    }
+}
@@ -0,0 +1,384 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+use std::{
+    fmt,
+    io::{self, ErrorKind},
+    net::{Shutdown, SocketAddr},
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+    time::Duration,
+};
+
+use log::{info, warn};
+
+use crate::{
+    apdu,
+    observer::{Event, Observer},
+    power, Capabilities, Connection, ConnectionBuilder, Error, PowerState, Request, Result,
+    VSmartCard,
+};
+use crate::{DEFAULT_HOST, DEFAULT_PORT};
+
+/// The default number of consecutive errors after which [`Supervisor`][] power-cycles the card.
+pub const DEFAULT_ERROR_THRESHOLD: usize = 3;
+
+/// The delays between the attempts to reestablish a connection, see [`Supervisor::set_backoff`][].
+///
+/// The first retry is delayed by `initial`, and every following delay is `multiplier` times the
+/// previous one, but at most `max`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    /// The delay after the first failed attempt.
+    pub initial: Duration,
+    /// The maximum delay between two attempts.
+    pub max: Duration,
+    /// The factor by which the delay grows after every failed attempt.
+    pub multiplier: u32,
+    /// The number of attempts after which the supervisor gives up, or `None` to retry forever.
+    pub max_attempts: Option<usize>,
+}
+
+impl Backoff {
+    /// Returns the delay after the given failed attempt, starting at 1.
+    pub fn delay(&self, attempt: usize) -> Duration {
+        let mut delay = self.initial;
+        for _ in 1..attempt {
+            if delay >= self.max {
+                break;
+            }
+            delay = delay.saturating_mul(self.multiplier);
+        }
+        delay.min(self.max)
+    }
+}
+
+impl Default for Backoff {
+    /// Retries forever, starting with a delay of 100 ms and doubling it up to 30 s.
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(30),
+            multiplier: 2,
+            max_attempts: None,
+        }
+    }
+}
+
+/// Counters for the connection events of a [`Supervisor`][].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    /// The number of established connections.
+    pub connects: u64,
+    /// The number of connections closed because of errors.
+    pub disconnects: u64,
+    /// The number of attempts to reestablish a connection.
+    pub reconnect_attempts: u64,
+    /// The number of failed attempts to reestablish a connection.
+    pub reconnect_failures: u64,
+}
+
+/// Runs a card and recovers from repeated errors like a physical reader driver would.
+///
+/// The supervisor counts consecutive errors.  An error is either a protocol anomaly (an I/O
+/// error or a malformed message from vpcd) or a card error (a response without a status word or
+/// with a status word in the 6Fxx range).  The count is only reset by an APDU that the card
+/// executes without an error, not by power events.  Once the error threshold is reached, the
+/// card is powered off and the connection to vpcd is reestablished.  vpcd powers on the card
+/// again using the new connection, which causes a cold reset, see [`PowerState`][].
+///
+/// If vpcd closes the connection, for example because it is restarted, the card is powered off
+/// and the connection is reestablished immediately.  By default, the supervisor makes a single
+/// attempt to reconnect.  Use [`Supervisor::set_backoff`][] to retry with increasing delays.
+///
+/// Connection events like [`Event::Disconnected`][] and [`Event::Connected`][] are counted in
+/// [`LinkStats`][] and reported to the observer set with [`Supervisor::set_observer`][].
+///
+/// The connections are established with the [`ConnectionBuilder`][] set with
+/// [`Supervisor::set_builder`][], so socket options and the fallback ATR apply to every
+/// connection.
+///
+/// # Example
+///
+/// ```no_run
+/// use vpicc::{observer::Event, Backoff};
+///
+/// fn main() -> vpicc::Result<()> {
+///     let mut supervisor = vpicc::Supervisor::default();
+///     supervisor.set_error_threshold(5);
+///     supervisor.set_backoff(Backoff::default());
+///     supervisor.set_observer(|event: &Event<'_>| {
+///         if let Event::Disconnected { reason } = event {
+///             eprintln!("lost connection to vpcd: {}", reason);
+///         }
+///     });
+///     supervisor.run(&mut vpicc::DummySmartCard)
+/// }
+/// ```
+#[derive(Clone)]
+pub struct Supervisor {
+    addr: SocketAddr,
+    builder: ConnectionBuilder,
+    error_threshold: usize,
+    backoff: Option<Backoff>,
+    observer: Option<Arc<Mutex<dyn Observer + Send>>>,
+    stats: LinkStats,
+}
+
+impl Supervisor {
+    /// Creates a supervisor for connections to the vpcd daemon at the given address.
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            builder: ConnectionBuilder::default(),
+            error_threshold: DEFAULT_ERROR_THRESHOLD,
+            backoff: None,
+            observer: None,
+            stats: LinkStats::default(),
+        }
+    }
+
+    /// Sets the builder used to establish the connections to vpcd.
+    pub fn set_builder(&mut self, builder: ConnectionBuilder) {
+        self.builder = builder;
+    }
+
+    /// Sets the number of consecutive errors after which the card is power cycled, defaulting to
+    /// [`DEFAULT_ERROR_THRESHOLD`][].
+    pub fn set_error_threshold(&mut self, threshold: usize) {
+        self.error_threshold = threshold.max(1);
+    }
+
+    /// Retries to reestablish a connection with the delays of the given backoff instead of making
+    /// a single attempt.
+    pub fn set_backoff(&mut self, backoff: Backoff) {
+        self.backoff = Some(backoff);
+    }
+
+    /// Sets the observer that receives the connection events.
+    ///
+    /// Clones of this supervisor share the observer.
+    pub fn set_observer<O: Observer + Send + 'static>(&mut self, observer: O) {
+        self.observer = Some(Arc::new(Mutex::new(observer)));
+    }
+
+    /// Returns the connection event counters.
+    pub fn link_stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    /// Handles all commands using the given card, recovering from errors.
+    ///
+    /// This function only returns if the connection to vpcd cannot be established or
+    /// reestablished.
+    pub fn run<V: VSmartCard>(&mut self, card: &mut V) -> Result<()> {
+        let mut card = StatusCheck { card, status: None };
+        let mut connection = self.connect()?;
+        let mut errors = 0;
+        loop {
+            let result = connection
+                .poll(&mut card)
+                .or_else(|err| connection.check_error(err))
+                .and_then(|()| card.check());
+            let err = match result {
+                Ok(executed) => {
+                    if executed {
+                        errors = 0;
+                    }
+                    continue;
+                }
+                Err(err) => err,
+            };
+            if is_closed(&err) {
+                info!("Connection closed by vpcd, reconnecting");
+                self.stats.disconnects += 1;
+                self.notify(&Event::Disconnected {
+                    reason: &err.to_string(),
+                });
+                power_off(&mut connection, &mut card);
+                close(&connection);
+                connection = self.reconnect()?;
+                errors = 0;
+                continue;
+            }
+            errors += 1;
+            warn!("Error {}/{}: {}", errors, self.error_threshold, err);
+            if errors >= self.error_threshold {
+                info!("Error threshold reached, powering off the card and reconnecting");
+                self.stats.disconnects += 1;
+                self.notify(&Event::Disconnected {
+                    reason: &err.to_string(),
+                });
+                power_off(&mut connection, &mut card);
+                close(&connection);
+                connection = self.reconnect()?;
+                errors = 0;
+            }
+        }
+    }
+
+    fn connect(&mut self) -> Result<Connection> {
+        let connection = self.builder.clone().connect_socket(self.addr)?;
+        self.stats.connects += 1;
+        self.notify(&Event::Connected { addr: self.addr });
+        Ok(connection)
+    }
+
+    fn reconnect(&mut self) -> Result<Connection> {
+        let mut attempt = 1;
+        loop {
+            self.stats.reconnect_attempts += 1;
+            self.notify(&Event::ReconnectAttempt { attempt });
+            let err = match self.connect() {
+                Ok(connection) => return Ok(connection),
+                Err(err) => err,
+            };
+            self.stats.reconnect_failures += 1;
+            self.notify(&Event::ReconnectFailed {
+                attempt,
+                reason: &err.to_string(),
+            });
+            let backoff = self
+                .backoff
+                .filter(|backoff| backoff.max_attempts.is_none_or(|max| attempt < max));
+            let Some(backoff) = backoff else {
+                return Err(err);
+            };
+            let delay = backoff.delay(attempt);
+            warn!(
+                "Reconnect attempt {} failed, retrying in {:?}: {}",
+                attempt, delay, err
+            );
+            thread::sleep(delay);
+            attempt += 1;
+        }
+    }
+
+    fn notify(&self, event: &Event<'_>) {
+        if let Some(observer) = &self.observer {
+            observer
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .on_event(event);
+        }
+    }
+}
+
+impl fmt::Debug for Supervisor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Supervisor")
+            .field("addr", &self.addr)
+            .field("builder", &self.builder)
+            .field("error_threshold", &self.error_threshold)
+            .field("backoff", &self.backoff)
+            .field("observer", &self.observer.is_some())
+            .field("stats", &self.stats)
+            .finish()
+    }
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Self::new(SocketAddr::new(DEFAULT_HOST.into(), DEFAULT_PORT))
+    }
+}
+
+/// Passes all calls to the card and checks the status words of its responses.
+///
+/// The result is kept until [`check`][`StatusCheck::check`] is called after the response has
+/// been sent to vpcd.
+struct StatusCheck<'a, V> {
+    card: &'a mut V,
+    status: Option<Result<()>>,
+}
+
+impl<V> StatusCheck<'_, V> {
+    /// Returns the error of the last executed APDU, or whether an APDU was executed.
+    fn check(&mut self) -> Result<bool> {
+        self.status
+            .take()
+            .transpose()
+            .map(|status| status.is_some())
+    }
+}
+
+impl<V: VSmartCard> VSmartCard for StatusCheck<'_, V> {
+    fn atr(&self) -> &[u8] {
+        self.card.atr()
+    }
+
+    fn power_on(&mut self) {
+        self.card.power_on()
+    }
+
+    fn power_off(&mut self) {
+        self.card.power_off()
+    }
+
+    fn reset(&mut self) {
+        self.card.reset()
+    }
+
+    fn cold_reset(&mut self) {
+        self.card.cold_reset()
+    }
+
+    fn warm_reset(&mut self) {
+        self.card.warm_reset()
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        let response = self.card.execute(msg);
+        self.status = Some(check_status(&response));
+        response
+    }
+
+    fn execute_small(&mut self, msg: &[u8]) -> apdu::Response {
+        let response = self.card.execute_small(msg);
+        self.status = Some(check_status(&response));
+        response
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.card.capabilities()
+    }
+}
+
+/// Powers off the card if vpcd has powered it on using the given connection.
+fn power_off<V: VSmartCard>(connection: &mut Connection, card: &mut V) {
+    if connection.power_state() == PowerState::On {
+        power::handle(&Request::PowerOff, card, &mut connection.power);
+    }
+}
+
+/// Closes the given connection so that vpcd does not see two connections for the card while
+/// reconnecting.  The connection might already be closed, in which case shutdown fails.
+fn close(connection: &Connection) {
+    connection.get_ref().shutdown(Shutdown::Both).ok();
+}
+
+fn is_closed(err: &Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::UnexpectedEof
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+    )
+}
+
+fn check_status(response: &[u8]) -> Result<()> {
+    match response.len().checked_sub(2).map(|i| &response[i..]) {
+        Some([0x6f, sw2]) => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("card responded with status 6f{:02x}", sw2),
+        )
+        .into()),
+        Some(_) => Ok(()),
+        None => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "card response does not contain a status word",
+        )
+        .into()),
+    }
+}
@@ -0,0 +1,657 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Helpers for end-to-end tests against the real smartcard stack.
+//!
+//! This module is only available if the `test-util` feature is enabled.  The helpers require
+//! external programs like `pcscd` with the vpcd driver, GnuPG or OpenSC.  If a required program is not
+//! available, they return `None` so that the test can be skipped instead of failing.
+//!
+//! [`Vpcd`][] starts a private pcscd instance with the vpcd driver to validate the protocol
+//! behavior of a card against the reference implementation.
+//!
+//! With the `pcsc` feature, [`with_pcsc`][] gives direct access to the virtual card through
+//! PC/SC so that scripted exchanges can be checked against the whole chain from the card
+//! emulation through vpcd and pcscd to the application.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use vpicc::test_util;
+//!
+//! # fn card() -> vpicc::DummySmartCard { vpicc::DummySmartCard }
+//! fn main() -> std::io::Result<()> {
+//!     let status = test_util::with_scdaemon(card(), |scdaemon| scdaemon.card_status())?;
+//!     match status {
+//!         Some(status) => assert!(status?.contains("OpenPGP")),
+//!         None => eprintln!("scdaemon or vpcd not available, skipping test"),
+//!     }
+//!     Ok(())
+//! }
+//! ```
+
+use std::{
+    env, fs,
+    io::{Error, ErrorKind, Result},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::{Child, Command, Output, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+
+use crate::{Connection, Registry, Request, VSmartCard, DEFAULT_HOST, DEFAULT_PORT};
+
+/// The time to wait for a started service to become ready.
+pub const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Returns the path of the given program if it can be found in `PATH`.
+pub fn find_program(name: &str) -> Option<PathBuf> {
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// A temporary directory that is removed when it is dropped.
+#[derive(Debug)]
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Creates a new empty directory in the temporary directory of the system.
+    pub fn new(prefix: &str) -> Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            "{}-{}-{}",
+            prefix,
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = env::temp_dir().join(name);
+        fs::create_dir(&path)?;
+        Ok(Self { path })
+    }
+
+    /// Returns the path of this directory.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.path) {
+            warn!("Failed to remove {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+/// A pcscd instance with the vpcd driver.
+///
+/// If vpcd already accepts connections at the default address, for example from a system
+/// service, that instance is used.  Otherwise `pcscd` is started in the foreground and stopped
+/// when this value is dropped.
+#[derive(Debug)]
+pub struct Pcscd {
+    child: Option<Child>,
+    addr: SocketAddr,
+}
+
+impl Pcscd {
+    /// Uses a running vpcd or starts pcscd, returning `None` if vpcd is not available.
+    pub fn start() -> Result<Option<Self>> {
+        let addr = SocketAddr::new(DEFAULT_HOST.into(), DEFAULT_PORT);
+        if is_listening(addr) {
+            debug!("Using running vpcd on {}", addr);
+            return Ok(Some(Self { child: None, addr }));
+        }
+        let Some(pcscd) = find_program("pcscd") else {
+            info!("pcscd not found");
+            return Ok(None);
+        };
+        let child = Command::new(pcscd)
+            .arg("--foreground")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let pcscd = Self {
+            child: Some(child),
+            addr,
+        };
+        let start = Instant::now();
+        while start.elapsed() < STARTUP_TIMEOUT {
+            if is_listening(addr) {
+                return Ok(Some(pcscd));
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        info!("vpcd did not start listening on {}", addr);
+        Ok(None)
+    }
+
+    /// Returns the address of vpcd.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for Pcscd {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            child.kill().ok();
+            child.wait().ok();
+        }
+    }
+}
+
+/// A private pcscd instance with the vpcd driver, using a temporary configuration.
+///
+/// Unlike [`Pcscd`][], this never uses a system service:  pcscd is started with a temporary
+/// reader configuration that makes vpcd listen on a free port, and with a private PC/SC socket
+/// so that it does not conflict with a running pcscd.  PC/SC clients have to use the socket
+/// returned by [`pcsc_socket`][`Vpcd::pcsc_socket`], for example by setting the
+/// `PCSCLITE_CSOCK_NAME` environment variable.
+///
+/// The vpcd driver is searched in the standard driver directories.  A different path can be set
+/// with the `VPCD_DRIVER` environment variable.
+///
+/// # Example
+///
+/// ```no_run
+/// use vpicc::test_util::Vpcd;
+///
+/// fn main() -> std::io::Result<()> {
+///     match Vpcd::spawn()? {
+///         Some(vpcd) => vpcd.verify_handshake(&mut vpicc::DummySmartCard)?,
+///         None => eprintln!("pcscd or vpcd not available, skipping test"),
+///     }
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Vpcd {
+    child: Child,
+    dir: TempDir,
+    addr: SocketAddr,
+}
+
+impl Vpcd {
+    /// Starts pcscd with the vpcd driver, returning `None` if pcscd or the driver is not
+    /// installed or vpcd does not start listening.
+    pub fn spawn() -> Result<Option<Self>> {
+        let Some(pcscd) = find_program("pcscd") else {
+            info!("pcscd not found");
+            return Ok(None);
+        };
+        let Some(driver) = find_vpcd_driver() else {
+            info!("vpcd driver not found");
+            return Ok(None);
+        };
+        let dir = TempDir::new("vpicc-vpcd")?;
+        // let the system choose a free port; there is a small window in which it could be reused
+        let port = TcpListener::bind((DEFAULT_HOST, 0))?.local_addr()?.port();
+        let addr = SocketAddr::new(DEFAULT_HOST.into(), port);
+        let config = dir.path().join("reader.conf");
+        fs::write(
+            &config,
+            format!(
+                "FRIENDLYNAME \"Virtual PCD\"\nDEVICENAME /dev/null:{port}\nLIBPATH {}\nCHANNELID {port}\n",
+                driver.display()
+            ),
+        )?;
+        let child = Command::new(pcscd)
+            .arg("--foreground")
+            .arg("--config")
+            .arg(&config)
+            .env("PCSCLITE_CSOCK_NAME", dir.path().join("pcscd.comm"))
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let mut vpcd = Self { child, dir, addr };
+        let start = Instant::now();
+        while start.elapsed() < STARTUP_TIMEOUT {
+            if is_listening(addr) {
+                debug!("vpcd listening on {}", addr);
+                return Ok(Some(vpcd));
+            }
+            if let Some(status) = vpcd.child.try_wait()? {
+                info!("pcscd exited with {}", status);
+                return Ok(None);
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        info!("vpcd did not start listening on {}", addr);
+        Ok(None)
+    }
+
+    /// Returns the address of vpcd.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the path of the PC/SC socket of this pcscd instance.
+    pub fn pcsc_socket(&self) -> PathBuf {
+        self.dir.path().join("pcscd.comm")
+    }
+
+    /// Connects to vpcd, waiting up to [`STARTUP_TIMEOUT`][].
+    pub fn connect(&self) -> Result<Connection> {
+        Ok(crate::wait_for_vpcd(self.addr, STARTUP_TIMEOUT)?)
+    }
+
+    /// Connects the given card to vpcd and checks that pcscd powers it on and requests the
+    /// ATR within [`STARTUP_TIMEOUT`][].
+    pub fn verify_handshake<V: VSmartCard>(&self, card: &mut V) -> Result<()> {
+        let mut connection = self.connect()?;
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        let mut powered = false;
+        while let Some(request) = connection.poll_deadline(card, deadline)? {
+            match request {
+                Request::PowerOn | Request::Reset => powered = true,
+                Request::GetAtr if powered => return Ok(()),
+                _ => {}
+            }
+        }
+        Err(Error::new(
+            ErrorKind::TimedOut,
+            "vpcd did not power on the card and request the ATR",
+        ))
+    }
+}
+
+impl Drop for Vpcd {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+    }
+}
+
+/// A temporary GnuPG home directory that uses scdaemon with PC/SC, see [`with_scdaemon`][].
+#[derive(Debug)]
+pub struct Scdaemon {
+    home: TempDir,
+}
+
+impl Scdaemon {
+    /// Creates a GnuPG home directory, returning `None` if GnuPG or scdaemon are not available.
+    pub fn new() -> Result<Option<Self>> {
+        if find_program("gpg").is_none() || !has_scdaemon() {
+            info!("gpg or scdaemon not found");
+            return Ok(None);
+        }
+        let home = TempDir::new("vpicc-gnupg")?;
+        fs::write(
+            home.path().join("scdaemon.conf"),
+            "disable-ccid\npcsc-shared\ncard-timeout 1\n",
+        )?;
+        Ok(Some(Self { home }))
+    }
+
+    /// Returns the GnuPG home directory.
+    pub fn home(&self) -> &Path {
+        self.home.path()
+    }
+
+    /// Runs `gpg` non-interactively with the given arguments.
+    pub fn gpg(&self, args: &[&str]) -> Result<Output> {
+        Command::new("gpg")
+            .arg("--homedir")
+            .arg(self.home())
+            .args(["--batch", "--no-tty"])
+            .args(args)
+            .output()
+    }
+
+    /// Runs `gpg --card-status` and returns its output, or an error if it fails.
+    pub fn card_status(&self) -> Result<String> {
+        stdout("gpg --card-status", self.gpg(&["--card-status"])?)
+    }
+}
+
+impl Drop for Scdaemon {
+    fn drop(&mut self) {
+        let result = Command::new("gpgconf")
+            .args(["--kill", "all"])
+            .env("GNUPGHOME", self.home())
+            .status();
+        if let Err(err) = result {
+            warn!("Failed to stop the GnuPG daemons: {}", err);
+        }
+    }
+}
+
+/// Connects the card to vpcd, runs the given function with a GnuPG home that uses the card via
+/// scdaemon and tears everything down afterwards.
+///
+/// Returns `None` if vpcd, GnuPG or scdaemon are not available.
+pub fn with_scdaemon<V, F, T>(card: V, f: F) -> Result<Option<T>>
+where
+    V: VSmartCard + Send + 'static,
+    F: FnOnce(&Scdaemon) -> T,
+{
+    let Some(pcscd) = Pcscd::start()? else {
+        return Ok(None);
+    };
+    let Some(scdaemon) = Scdaemon::new()? else {
+        return Ok(None);
+    };
+    let mut registry = Registry::new();
+    registry.add("test", pcscd.addr(), card)?;
+    let result = f(&scdaemon);
+    drop(scdaemon);
+    drop(registry);
+    Ok(Some(result))
+}
+
+/// The OpenSC command line tools, see [`with_opensc`][].
+#[derive(Debug)]
+pub struct OpenSc {
+    module: PathBuf,
+}
+
+impl OpenSc {
+    /// Locates the OpenSC tools, returning `None` if `opensc-tool`, `pkcs11-tool` or the OpenSC
+    /// PKCS#11 module are not available.
+    pub fn new() -> Result<Option<Self>> {
+        if find_program("opensc-tool").is_none() || find_program("pkcs11-tool").is_none() {
+            info!("opensc-tool or pkcs11-tool not found");
+            return Ok(None);
+        }
+        let Some(module) = find_pkcs11_module() else {
+            info!("opensc-pkcs11.so not found");
+            return Ok(None);
+        };
+        Ok(Some(Self { module }))
+    }
+
+    /// Returns the path of the OpenSC PKCS#11 module.
+    pub fn module(&self) -> &Path {
+        &self.module
+    }
+
+    /// Runs `opensc-tool` with the given arguments.
+    pub fn opensc_tool(&self, args: &[&str]) -> Result<Output> {
+        Command::new("opensc-tool").args(args).output()
+    }
+
+    /// Runs `pkcs11-tool` with the OpenSC module and the given arguments.
+    pub fn pkcs11_tool(&self, args: &[&str]) -> Result<Output> {
+        Command::new("pkcs11-tool")
+            .arg("--module")
+            .arg(&self.module)
+            .args(args)
+            .output()
+    }
+
+    /// Sends the given APDUs with `opensc-tool --send-apdu` and returns its output.
+    ///
+    /// The APDUs are hex strings like `00a4040006d27600012401`.
+    pub fn send_apdus(&self, apdus: &[&str]) -> Result<String> {
+        let args: Vec<&str> = apdus.iter().flat_map(|apdu| ["-s", apdu]).collect();
+        stdout("opensc-tool --send-apdu", self.opensc_tool(&args)?)
+    }
+
+    /// Runs `opensc-tool --atr` and returns the ATR of the card as a hex string.
+    pub fn atr(&self) -> Result<String> {
+        let atr = stdout("opensc-tool --atr", self.opensc_tool(&["--atr"])?)?;
+        Ok(atr.trim().to_owned())
+    }
+
+    /// Runs `pkcs11-tool --list-objects` and returns its output.
+    pub fn list_objects(&self) -> Result<String> {
+        stdout(
+            "pkcs11-tool --list-objects",
+            self.pkcs11_tool(&["--list-objects"])?,
+        )
+    }
+}
+
+/// Connects the card to vpcd, runs the given function with the OpenSC tools and disconnects the
+/// card afterwards.
+///
+/// Returns `None` if vpcd or OpenSC are not available.
+///
+/// # Example
+///
+/// ```no_run
+/// use vpicc::{profiles::piv::PivCard, test_util};
+///
+/// let objects = test_util::with_opensc(PivCard::synthetic(0), |opensc| opensc.list_objects())?;
+/// if let Some(objects) = objects {
+///     assert!(objects?.contains("Certificate Object"));
+/// }
+/// # Ok::<_, std::io::Error>(())
+/// ```
+pub fn with_opensc<V, F, T>(card: V, f: F) -> Result<Option<T>>
+where
+    V: VSmartCard + Send + 'static,
+    F: FnOnce(&OpenSc) -> T,
+{
+    let Some(pcscd) = Pcscd::start()? else {
+        return Ok(None);
+    };
+    let Some(opensc) = OpenSc::new()? else {
+        return Ok(None);
+    };
+    let mut registry = Registry::new();
+    registry.add("test", pcscd.addr(), card)?;
+    let result = f(&opensc);
+    drop(registry);
+    Ok(Some(result))
+}
+
+/// Returns the standard output of a successful command, or an error with its standard error.
+fn stdout(name: &str, output: Output) -> Result<String> {
+    if !output.status.success() {
+        return Err(Error::other(format!(
+            "{} failed: {}",
+            name,
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn find_vpcd_driver() -> Option<PathBuf> {
+    if let Some(driver) = env::var_os("VPCD_DRIVER") {
+        return Some(driver.into());
+    }
+    [
+        "/usr/lib/pcsc/drivers/serial/libifdvpcd.so",
+        "/usr/lib/x86_64-linux-gnu/pcsc/drivers/serial/libifdvpcd.so",
+        "/usr/lib/aarch64-linux-gnu/pcsc/drivers/serial/libifdvpcd.so",
+        "/usr/lib64/pcsc/drivers/serial/libifdvpcd.so",
+        "/usr/local/lib/pcsc/drivers/serial/libifdvpcd.so",
+    ]
+    .iter()
+    .map(PathBuf::from)
+    .find(|path| path.is_file())
+}
+
+fn find_pkcs11_module() -> Option<PathBuf> {
+    if let Some(module) = env::var_os("OPENSC_PKCS11_MODULE") {
+        return Some(module.into());
+    }
+    [
+        "/usr/lib/x86_64-linux-gnu/opensc-pkcs11.so",
+        "/usr/lib/aarch64-linux-gnu/opensc-pkcs11.so",
+        "/usr/lib64/opensc-pkcs11.so",
+        "/usr/lib/opensc-pkcs11.so",
+        "/usr/local/lib/opensc-pkcs11.so",
+        "/opt/homebrew/lib/opensc-pkcs11.so",
+        "/Library/OpenSC/lib/opensc-pkcs11.so",
+    ]
+    .iter()
+    .map(PathBuf::from)
+    .find(|path| path.is_file())
+}
+
+/// The virtual card accessed through PC/SC, see [`with_pcsc`][].
+///
+/// This type implements [`VSmartCard`][] so that it can be used with
+/// [`Script::run`][`crate::script::Script::run`]:  [`execute`][`VSmartCard::execute`] transmits
+/// the command to the card and [`reset`][`VSmartCard::reset`] resets the card.  Errors are
+/// logged and result in an empty response.
+#[cfg(feature = "pcsc")]
+pub struct PcscCard {
+    card: pcsc::Card,
+    reader: std::ffi::CString,
+}
+
+#[cfg(feature = "pcsc")]
+impl PcscCard {
+    /// Connects to the first vpcd reader that contains a card, waiting at most
+    /// [`STARTUP_TIMEOUT`][] for the card to be inserted.
+    ///
+    /// Returns `None` if pcscd is not running or no vpcd reader is available.
+    pub fn connect() -> Result<Option<Self>> {
+        let context = match pcsc::Context::establish(pcsc::Scope::User) {
+            Ok(context) => context,
+            Err(err) => {
+                info!("Failed to connect to pcscd: {}", err);
+                return Ok(None);
+            }
+        };
+        let start = Instant::now();
+        loop {
+            let readers = match context.list_readers_owned() {
+                Ok(readers) => readers,
+                Err(pcsc::Error::NoReadersAvailable) => Vec::new(),
+                Err(err) => return Err(Error::other(err)),
+            };
+            let readers = readers
+                .into_iter()
+                .filter(|reader| reader.to_string_lossy().starts_with("Virtual PCD"));
+            for reader in readers {
+                match context.connect(&reader, pcsc::ShareMode::Shared, pcsc::Protocols::ANY) {
+                    Ok(card) => {
+                        debug!("Connected to {:?}", reader);
+                        return Ok(Some(Self { card, reader }));
+                    }
+                    Err(pcsc::Error::NoSmartcard | pcsc::Error::RemovedCard) => {}
+                    Err(err) => return Err(Error::other(err)),
+                }
+            }
+            if start.elapsed() >= STARTUP_TIMEOUT {
+                info!("No card found in a vpcd reader");
+                return Ok(None);
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// Returns the name of the reader.
+    pub fn reader(&self) -> &std::ffi::CStr {
+        &self.reader
+    }
+
+    /// Returns the ATR of the card.
+    pub fn atr(&self) -> Result<Vec<u8>> {
+        self.card
+            .get_attribute_owned(pcsc::Attribute::AtrString)
+            .map_err(Error::other)
+    }
+
+    /// Sends the given command APDU to the card and returns the response APDU.
+    pub fn transmit(&mut self, command: &[u8]) -> Result<Vec<u8>> {
+        let mut buffer = vec![0; pcsc::MAX_BUFFER_SIZE_EXTENDED];
+        let response = self
+            .card
+            .transmit(command, &mut buffer)
+            .map_err(Error::other)?;
+        Ok(response.to_vec())
+    }
+
+    /// Reconnects to the card, resetting it.
+    pub fn reconnect(&mut self) -> Result<()> {
+        self.card
+            .reconnect(
+                pcsc::ShareMode::Shared,
+                pcsc::Protocols::ANY,
+                pcsc::Disposition::ResetCard,
+            )
+            .map_err(Error::other)
+    }
+}
+
+#[cfg(feature = "pcsc")]
+impl std::fmt::Debug for PcscCard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PcscCard")
+            .field("reader", &self.reader)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "pcsc")]
+impl VSmartCard for PcscCard {
+    fn reset(&mut self) {
+        if let Err(err) = self.reconnect() {
+            warn!("Failed to reset the card: {}", err);
+        }
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        self.transmit(msg).unwrap_or_else(|err| {
+            warn!("Failed to transmit the command: {}", err);
+            Vec::new()
+        })
+    }
+}
+
+/// Connects the card to vpcd, runs the given function with the card accessed through PC/SC and
+/// disconnects the card afterwards.
+///
+/// Returns `None` if vpcd or pcscd are not available.
+///
+/// # Example
+///
+/// ```no_run
+/// use vpicc::{script::Script, test_util};
+///
+/// let script: Script = "00 A4 04 00 06 D2 76 00 01 24 01 -> 9000".parse()?;
+/// let report = test_util::with_pcsc(vpicc::DummySmartCard, |card| script.run(card))?;
+/// if let Some(report) = report {
+///     assert!(report.is_success());
+/// }
+/// # Ok::<_, std::io::Error>(())
+/// ```
+#[cfg(feature = "pcsc")]
+pub fn with_pcsc<V, F, T>(card: V, f: F) -> Result<Option<T>>
+where
+    V: VSmartCard + Send + 'static,
+    F: FnOnce(&mut PcscCard) -> T,
+{
+    let Some(pcscd) = Pcscd::start()? else {
+        return Ok(None);
+    };
+    let mut registry = Registry::new();
+    registry.add("test", pcscd.addr(), card)?;
+    let Some(mut pcsc_card) = PcscCard::connect()? else {
+        return Ok(None);
+    };
+    let result = f(&mut pcsc_card);
+    drop(pcsc_card);
+    drop(registry);
+    Ok(Some(result))
+}
+
+fn has_scdaemon() -> bool {
+    Command::new("gpgconf")
+        .arg("--list-components")
+        .output()
+        .map(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .any(|line| line.starts_with("scdaemon:"))
+        })
+        .unwrap_or_default()
+}
+
+fn is_listening(addr: SocketAddr) -> bool {
+    TcpStream::connect_timeout(&addr, Duration::from_millis(100)).is_ok()
+}
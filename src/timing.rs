@@ -0,0 +1,325 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Tools for timing experiments with card implementations.
+//!
+//! [`Timing`][] injects artificial, possibly data-dependent delays into a card and records the
+//! response time of every command.  This makes it possible to demonstrate and analyze timing
+//! side channels at the APDU level.
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{Capabilities, VSmartCard};
+
+/// A function that computes the artificial delay for a command and its response.
+pub type DelayFn = fn(&[u8], &[u8]) -> Duration;
+
+/// The response time of a single command recorded by [`Timing`][].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TimingRecord {
+    /// The command APDU.
+    pub command: Vec<u8>,
+    /// The time spent in the wrapped card.
+    pub execution: Duration,
+    /// The total response time including the artificial delay.
+    pub total: Duration,
+}
+
+/// Injects artificial delays into a card and records the response time of every command.
+///
+/// The delay function is called with the command and the response of the wrapped card, and the
+/// response is delayed by the returned duration.
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+/// use vpicc::{timing::Timing, VSmartCard};
+///
+/// // leak the number of matching leading bytes of a PIN comparison
+/// let mut card = Timing::with_delay(vpicc::DummySmartCard, |command, _response| {
+///     let matching = command[5..].iter().zip(b"123456").take_while(|(a, b)| a == b).count();
+///     Duration::from_micros(100 * matching as u64)
+/// });
+/// card.execute(b"\x00\x20\x00\x81\x06123000");
+/// assert!(card.records()[0].total >= Duration::from_micros(300));
+/// ```
+pub struct Timing<C, F = DelayFn> {
+    card: C,
+    delay: F,
+    records: Vec<TimingRecord>,
+}
+
+impl<C> Timing<C> {
+    /// Wraps the given card without artificial delays, only recording response times.
+    pub fn new(card: C) -> Self {
+        Self::with_delay(card, |_, _| Duration::ZERO)
+    }
+}
+
+impl<C, F: FnMut(&[u8], &[u8]) -> Duration> Timing<C, F> {
+    /// Wraps the given card, delaying the responses using the given delay function.
+    pub fn with_delay(card: C, delay: F) -> Self {
+        Self {
+            card,
+            delay,
+            records: Vec::new(),
+        }
+    }
+
+    /// Returns the recorded response times.
+    pub fn records(&self) -> &[TimingRecord] {
+        &self.records
+    }
+
+    /// Returns and removes the recorded response times.
+    pub fn take_records(&mut self) -> Vec<TimingRecord> {
+        std::mem::take(&mut self.records)
+    }
+
+    /// Returns a reference to the wrapped card.
+    pub fn get_ref(&self) -> &C {
+        &self.card
+    }
+
+    /// Returns a mutable reference to the wrapped card.
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.card
+    }
+
+    /// Returns the wrapped card.
+    pub fn into_inner(self) -> C {
+        self.card
+    }
+}
+
+impl<C: VSmartCard, F: FnMut(&[u8], &[u8]) -> Duration> VSmartCard for Timing<C, F> {
+    fn atr(&self) -> &[u8] {
+        self.card.atr()
+    }
+
+    fn power_on(&mut self) {
+        self.card.power_on()
+    }
+
+    fn power_off(&mut self) {
+        self.card.power_off()
+    }
+
+    fn reset(&mut self) {
+        self.card.reset()
+    }
+
+    fn cold_reset(&mut self) {
+        self.card.cold_reset()
+    }
+
+    fn warm_reset(&mut self) {
+        self.card.warm_reset()
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        let start = Instant::now();
+        let response = self.card.execute(msg);
+        let execution = start.elapsed();
+        let delay = (self.delay)(msg, &response);
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+        self.records.push(TimingRecord {
+            command: msg.to_vec(),
+            execution,
+            total: start.elapsed(),
+        });
+        response
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.card.capabilities()
+    }
+}
+
+/// Statistics of a set of response times.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Statistics {
+    /// The number of samples.
+    pub samples: usize,
+    /// The mean response time.
+    pub mean: Duration,
+    /// The variance of the response times in square nanoseconds.
+    pub variance: f64,
+    /// The standard deviation of the response times.
+    pub std_dev: Duration,
+    /// The shortest response time.
+    pub min: Duration,
+    /// The longest response time.
+    pub max: Duration,
+    /// The median response time.
+    pub median: Duration,
+    /// The 90th percentile of the response times.
+    pub p90: Duration,
+    /// The 99th percentile of the response times.
+    pub p99: Duration,
+}
+
+impl Statistics {
+    /// Computes the statistics of the given response times, or returns `None` if there are no
+    /// samples.
+    pub fn from_samples(samples: &[Duration]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        let mut sorted = samples.to_vec();
+        sorted.sort_unstable();
+        let nanos: Vec<f64> = sorted.iter().map(|d| d.as_nanos() as f64).collect();
+        let n = nanos.len() as f64;
+        let mean = nanos.iter().sum::<f64>() / n;
+        let variance = nanos.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n;
+        let percentile = |p: f64| sorted[((n - 1.0) * p).round() as usize];
+        Some(Self {
+            samples: sorted.len(),
+            mean: Duration::from_nanos(mean.round() as u64),
+            variance,
+            std_dev: Duration::from_nanos(variance.sqrt().round() as u64),
+            min: sorted[0],
+            max: sorted[sorted.len() - 1],
+            median: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+        })
+    }
+}
+
+/// Executes the command the given number of times and returns the statistics of the response
+/// times, or `None` if `iterations` is zero.
+///
+/// # Example
+///
+/// ```
+/// let stats = vpicc::timing::measure(&mut vpicc::DummySmartCard, &[0x00, 0xca, 0x00, 0x6e], 100);
+/// assert_eq!(stats.unwrap().samples, 100);
+/// ```
+pub fn measure<C: VSmartCard + ?Sized>(
+    card: &mut C,
+    command: &[u8],
+    iterations: usize,
+) -> Option<Statistics> {
+    let samples: Vec<_> = (0..iterations).map(|_| time(card, command)).collect();
+    Statistics::from_samples(&samples)
+}
+
+/// Executes the commands of all input classes the given number of times and returns the
+/// statistics of the response times per class.
+///
+/// The classes are executed in an interleaved order so that drifts of the system load affect all
+/// classes equally.  Classes without commands are omitted from the result.
+///
+/// # Example
+///
+/// ```
+/// let classes = [
+///     ("correct", vec![b"\x00\x20\x00\x81\x06123456".to_vec()]),
+///     ("wrong", vec![b"\x00\x20\x00\x81\x06000000".to_vec()]),
+/// ];
+/// let stats = vpicc::timing::measure_classes(&mut vpicc::DummySmartCard, &classes, 10);
+/// assert_eq!(stats.len(), 2);
+/// ```
+pub fn measure_classes<C, L>(
+    card: &mut C,
+    classes: &[(L, Vec<Vec<u8>>)],
+    iterations: usize,
+) -> Vec<(L, Statistics)>
+where
+    C: VSmartCard + ?Sized,
+    L: Clone,
+{
+    let mut samples = vec![Vec::new(); classes.len()];
+    for _ in 0..iterations {
+        for ((_, commands), samples) in classes.iter().zip(&mut samples) {
+            for command in commands {
+                samples.push(time(card, command));
+            }
+        }
+    }
+    classes
+        .iter()
+        .zip(samples)
+        .filter_map(|((label, _), samples)| {
+            Statistics::from_samples(&samples).map(|stats| (label.clone(), stats))
+        })
+        .collect()
+}
+
+/// The throughput measured by [`batch`][].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Throughput {
+    /// The number of executed commands.
+    pub commands: usize,
+    /// The total number of command and response bytes.
+    pub bytes: usize,
+    /// The total execution time.
+    pub elapsed: Duration,
+}
+
+impl Throughput {
+    /// Returns the number of executed commands per second.
+    pub fn commands_per_second(&self) -> f64 {
+        self.commands as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Returns the number of command and response bytes per second.
+    pub fn bytes_per_second(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// Executes the given commands the given number of rounds as fast as possible and returns the
+/// throughput of the card.
+///
+/// The commands are executed with [`VSmartCard::execute_small`][] like by a
+/// [`Connection`][`crate::Connection`].  The card is not reset between rounds.  To replay a
+/// recorded session, pass the commands of a [`Trace`][`crate::trace::Trace`].
+///
+/// # Example
+///
+/// ```
+/// use vpicc::{timing, trace::Trace};
+///
+/// let trace = Trace::parse_apdu4j("A>> T=1 (4+0000) 00CA006E 00\nA<< (0000+2) 9000\n")?;
+/// let commands: Vec<_> = trace.exchanges().iter().map(|e| &e.command).collect();
+/// let throughput = timing::batch(&mut vpicc::DummySmartCard, &commands, 1000);
+/// assert_eq!(throughput.commands, 1000);
+/// println!("{:.0} commands/s", throughput.commands_per_second());
+/// # Ok::<_, std::io::Error>(())
+/// ```
+pub fn batch<C, A>(card: &mut C, commands: &[A], rounds: usize) -> Throughput
+where
+    C: VSmartCard + ?Sized,
+    A: AsRef<[u8]>,
+{
+    let mut throughput = Throughput {
+        commands: 0,
+        bytes: 0,
+        elapsed: Duration::ZERO,
+    };
+    let start = Instant::now();
+    for _ in 0..rounds {
+        for command in commands {
+            let command = command.as_ref();
+            let response = card.execute_small(command);
+            throughput.commands += 1;
+            throughput.bytes += command.len() + response.len();
+        }
+    }
+    throughput.elapsed = start.elapsed();
+    throughput
+}
+
+fn time<C: VSmartCard + ?Sized>(card: &mut C, command: &[u8]) -> Duration {
+    let start = Instant::now();
+    card.execute(command);
+    start.elapsed()
+}
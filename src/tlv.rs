@@ -0,0 +1,162 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Parsing and encoding of BER-TLV data objects as used in ISO 7816-4.
+//!
+//! # Example
+//!
+//! ```
+//! use vpicc::tlv::Tlv;
+//!
+//! let data = [0x5f, 0x2d, 0x02, 0x64, 0x65, 0x81, 0x81, 0x01, 0xff];
+//! let objects: Vec<Tlv> = vpicc::tlv::iter(&data).collect::<Option<_>>().unwrap();
+//! assert_eq!(objects[0].tag, 0x5f2d);
+//! assert_eq!(objects[0].value, b"de");
+//! assert_eq!(objects[1].tag, 0x81);
+//! assert_eq!(objects[1].value, [0xff]);
+//! ```
+
+/// A BER-TLV data object.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tlv<'a> {
+    /// The tag, including the class and constructed bits, with up to four bytes.
+    pub tag: u32,
+    /// The value.
+    pub value: &'a [u8],
+}
+
+impl<'a> Tlv<'a> {
+    /// Parses the first data object of the given data and returns it together with the
+    /// remaining data.
+    ///
+    /// Returns `None` if the data is empty, truncated or uses a tag with more than four bytes or
+    /// a length with more than four bytes.  The indefinite length form is not supported.
+    pub fn parse(data: &'a [u8]) -> Option<(Self, &'a [u8])> {
+        let (tag, rest) = parse_tag(data)?;
+        let (len, rest) = parse_len(rest)?;
+        if rest.len() < len {
+            return None;
+        }
+        let (value, rest) = rest.split_at(len);
+        Some((Self { tag, value }, rest))
+    }
+
+    /// Returns true if the tag marks a constructed data object, i. e. if the value consists of
+    /// further data objects.
+    pub fn is_constructed(&self) -> bool {
+        let first = self.tag.to_be_bytes().into_iter().find(|b| *b != 0);
+        first.unwrap_or_default() & 0x20 != 0
+    }
+
+    /// Returns an iterator over the data objects in the value of this data object.
+    pub fn children(&self) -> Iter<'a> {
+        iter(self.value)
+    }
+}
+
+/// An iterator over the data objects in a byte string, see [`iter`][].
+///
+/// Yields `None` once if the data is malformed and stops afterwards.
+#[derive(Clone, Debug)]
+pub struct Iter<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Option<Tlv<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // skip padding between data objects, see ISO 7816-4 section 5.2.2
+        while let [0x00 | 0xff, rest @ ..] = self.data {
+            self.data = rest;
+        }
+        if self.data.is_empty() {
+            return None;
+        }
+        match Tlv::parse(self.data) {
+            Some((tlv, rest)) => {
+                self.data = rest;
+                Some(Some(tlv))
+            }
+            None => {
+                self.data = &[];
+                Some(None)
+            }
+        }
+    }
+}
+
+/// Returns an iterator over the data objects in the given data.
+pub fn iter(data: &[u8]) -> Iter<'_> {
+    Iter { data }
+}
+
+/// Encodes a data object with the given tag and value.
+///
+/// The tag is written with as many bytes as it needs, so tags with leading zero bytes cannot be
+/// encoded.  The length is written in the shortest form.
+///
+/// # Example
+///
+/// ```
+/// use vpicc::tlv::{self, Tlv};
+///
+/// let data = tlv::encode(0x5f2d, b"de");
+/// assert_eq!(data, [0x5f, 0x2d, 0x02, 0x64, 0x65]);
+/// assert_eq!(Tlv::parse(&data), Some((Tlv { tag: 0x5f2d, value: b"de" }, &[][..])));
+/// ```
+pub fn encode(tag: u32, value: &[u8]) -> Vec<u8> {
+    let tag_bytes = tag.to_be_bytes();
+    let skip = tag_bytes.iter().take(3).take_while(|b| **b == 0).count();
+    let mut tlv = Vec::with_capacity(4 - skip + 5 + value.len());
+    tlv.extend_from_slice(&tag_bytes[skip..]);
+    match value.len() {
+        len @ 0..=0x7f => tlv.push(len as u8),
+        len @ 0x80..=0xff => tlv.extend_from_slice(&[0x81, len as u8]),
+        len @ 0x100..=0xffff => {
+            tlv.push(0x82);
+            tlv.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            tlv.push(0x84);
+            tlv.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+    }
+    tlv.extend_from_slice(value);
+    tlv
+}
+
+fn parse_tag(data: &[u8]) -> Option<(u32, &[u8])> {
+    let (&first, mut rest) = data.split_first()?;
+    let mut tag = u32::from(first);
+    if first & 0x1f == 0x1f {
+        for i in 1.. {
+            let (&b, next) = rest.split_first()?;
+            rest = next;
+            if i >= 4 {
+                return None;
+            }
+            tag = (tag << 8) | u32::from(b);
+            if b & 0x80 == 0 {
+                break;
+            }
+        }
+    }
+    Some((tag, rest))
+}
+
+fn parse_len(data: &[u8]) -> Option<(usize, &[u8])> {
+    let (&first, rest) = data.split_first()?;
+    if first < 0x80 {
+        return Some((usize::from(first), rest));
+    }
+    let n = usize::from(first & 0x7f);
+    if n == 0 || n > 4 || rest.len() < n {
+        return None;
+    }
+    let (len, rest) = rest.split_at(n);
+    let len = len
+        .iter()
+        .fold(0usize, |len, b| (len << 8) | usize::from(*b));
+    Some((len, rest))
+}
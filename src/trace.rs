@@ -0,0 +1,744 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Recorded APDU exchanges.
+//!
+//! A [`Trace`][] is a sequence of command and response APDUs.  Traces can be imported from the
+//! logs of other tools, see [`Trace::parse_apdu4j`][], recorded using [`Recorder`][] and
+//! replayed using [`ReplayCard`][].  Long-running recordings can be rotated using
+//! [`RotatingWriter`][].
+//!
+//! Traces are stored in the apdu4j log format.  With the `gzip` feature, traces can be written
+//! with gzip compression, and compressed traces are decompressed transparently when reading.
+//! With the `encryption` feature, traces can be encrypted using AES-256-GCM with a provided key,
+//! see `Trace::write_encrypted`, so that traces containing real credentials can be stored
+//! and shared safely.
+
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, BufWriter, Error, ErrorKind, Read, Result, Write},
+    mem,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+
+use crate::{apdu, hex, Capabilities, VSmartCard};
+
+/// A command APDU and the response APDU returned by the card.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Exchange {
+    /// The command APDU.
+    pub command: Vec<u8>,
+    /// The response APDU.
+    pub response: Vec<u8>,
+    /// The time it took the card to respond, if known.
+    pub duration: Option<Duration>,
+}
+
+/// A sequence of recorded APDU exchanges.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Trace {
+    exchanges: Vec<Exchange>,
+}
+
+impl Trace {
+    /// Creates an empty trace.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses a log in the format produced by apdu4j and GlobalPlatformPro.
+    ///
+    /// Commands are logged with the prefix `A>>` and responses with the prefix `A<<`.  All other
+    /// lines are ignored.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let log = "\
+    /// A>> T=1 (4+0005) 00A40400 05 A000000151 00
+    /// A<< (0000+2) (6ms) 9000
+    /// ";
+    /// let trace = vpicc::trace::Trace::parse_apdu4j(log)?;
+    /// let exchange = &trace.exchanges()[0];
+    /// assert_eq!(exchange.command[..5], [0x00, 0xa4, 0x04, 0x00, 0x05]);
+    /// assert_eq!(exchange.response, [0x90, 0x00]);
+    /// assert_eq!(exchange.duration, Some(std::time::Duration::from_millis(6)));
+    /// # Ok::<_, std::io::Error>(())
+    /// ```
+    pub fn parse_apdu4j(log: &str) -> Result<Self> {
+        let mut trace = Self::new();
+        let mut command = None;
+        for (i, line) in log.lines().enumerate() {
+            let line_error =
+                |msg: &str| Error::new(ErrorKind::InvalidData, format!("line {}: {}", i + 1, msg));
+            if let Some(pos) = line.find("A>>") {
+                if command.is_some() {
+                    return Err(line_error("command without response"));
+                }
+                let (data, _) = parse_apdu4j_line(&line[pos + 3..])?;
+                command = Some(data);
+            } else if let Some(pos) = line.find("A<<") {
+                let command = command
+                    .take()
+                    .ok_or_else(|| line_error("response without command"))?;
+                let (response, duration) = parse_apdu4j_line(&line[pos + 3..])?;
+                trace.push(Exchange {
+                    command,
+                    response,
+                    duration,
+                });
+            }
+        }
+        if command.is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "last command without response",
+            ));
+        }
+        Ok(trace)
+    }
+
+    /// Reads a trace in the apdu4j format from the given reader, see
+    /// [`parse_apdu4j`][`Trace::parse_apdu4j`].
+    ///
+    /// gzip-compressed data is detected and decompressed if the `gzip` feature is enabled.
+    pub fn read<R: Read>(mut reader: R) -> Result<Self> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        if data.starts_with(ENCRYPTION_MAGIC) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "trace is encrypted, use Trace::read_encrypted",
+            ));
+        }
+        Self::decode(data)
+    }
+
+    fn decode(mut data: Vec<u8>) -> Result<Self> {
+        if data.starts_with(GZIP_MAGIC) {
+            data = gunzip(&data)?;
+        }
+        let log = String::from_utf8(data).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        Self::parse_apdu4j(&log)
+    }
+
+    /// Reads a trace from the given file, see [`read`][`Trace::read`].
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::read(File::open(path)?)
+    }
+
+    /// Writes this trace in the apdu4j format to the given writer.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use vpicc::trace::Trace;
+    ///
+    /// let trace = Trace::parse_apdu4j("A>> 0084000008\nA<< (3ms) 0102030405060708 9000\n")?;
+    /// let mut log = Vec::new();
+    /// trace.write(&mut log)?;
+    /// assert_eq!(Trace::read(log.as_slice())?, trace);
+    /// # Ok::<_, std::io::Error>(())
+    /// ```
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<()> {
+        for exchange in &self.exchanges {
+            write_exchange(&mut writer, exchange)?;
+        }
+        writer.flush()
+    }
+
+    /// Writes this trace to the given file, see [`write`][`Trace::write`].
+    ///
+    /// If the file name ends with `.gz`, the trace is compressed using gzip.  This requires the
+    /// `gzip` feature.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.write(create(path.as_ref())?)
+    }
+
+    /// Writes this trace in the apdu4j format encrypted with AES-256-GCM using the given key.
+    ///
+    /// The encrypted trace starts with a header that identifies the format, followed by a random
+    /// nonce and the ciphertext including the authentication tag.  This requires the
+    /// `encryption` feature.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use vpicc::trace::Trace;
+    ///
+    /// let key = [0x42; 32];
+    /// let trace = Trace::parse_apdu4j("A>> 0020008106313233343536\nA<< 9000\n")?;
+    /// let mut data = Vec::new();
+    /// trace.write_encrypted(&mut data, &key)?;
+    /// assert_eq!(Trace::read_encrypted(data.as_slice(), &key)?, trace);
+    /// assert!(Trace::read_encrypted(data.as_slice(), &[0x00; 32]).is_err());
+    /// # Ok::<_, std::io::Error>(())
+    /// ```
+    #[cfg(feature = "encryption")]
+    pub fn write_encrypted<W: Write>(&self, mut writer: W, key: &[u8; 32]) -> Result<()> {
+        let mut log = Vec::new();
+        self.write(&mut log)?;
+        writer.write_all(&encrypt(&log, key)?)?;
+        writer.flush()
+    }
+
+    /// Reads a trace encrypted with [`write_encrypted`][`Trace::write_encrypted`] using the
+    /// given key.
+    ///
+    /// Returns an error if the data is not an encrypted trace, the key is wrong or the data has
+    /// been modified.  This requires the `encryption` feature.
+    #[cfg(feature = "encryption")]
+    pub fn read_encrypted<R: Read>(mut reader: R, key: &[u8; 32]) -> Result<Self> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Self::decode(decrypt(&data, key)?)
+    }
+
+    /// Appends an exchange to this trace.
+    pub fn push(&mut self, exchange: Exchange) {
+        self.exchanges.push(exchange);
+    }
+
+    /// Returns the exchanges of this trace.
+    pub fn exchanges(&self) -> &[Exchange] {
+        &self.exchanges
+    }
+
+    /// Returns the number of exchanges in this trace.
+    pub fn len(&self) -> usize {
+        self.exchanges.len()
+    }
+
+    /// Returns true if this trace does not contain any exchanges.
+    pub fn is_empty(&self) -> bool {
+        self.exchanges.is_empty()
+    }
+}
+
+impl From<Vec<Exchange>> for Trace {
+    fn from(exchanges: Vec<Exchange>) -> Self {
+        Self { exchanges }
+    }
+}
+
+impl FromIterator<Exchange> for Trace {
+    fn from_iter<I: IntoIterator<Item = Exchange>>(iter: I) -> Self {
+        Self {
+            exchanges: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl IntoIterator for Trace {
+    type Item = Exchange;
+    type IntoIter = std::vec::IntoIter<Exchange>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.exchanges.into_iter()
+    }
+}
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+/// The header of encrypted traces:  a magic value followed by the format version.
+const ENCRYPTION_MAGIC: &[u8] = b"vpicc-trace-aes256gcm\x01";
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 12;
+
+fn write_exchange<W: Write + ?Sized>(writer: &mut W, exchange: &Exchange) -> Result<()> {
+    writeln!(writer, "A>> {}", hex::encode(&exchange.command))?;
+    match exchange.duration {
+        Some(duration) => writeln!(
+            writer,
+            "A<< ({}ms) {}",
+            duration.as_millis(),
+            hex::encode(&exchange.response)
+        ),
+        None => writeln!(writer, "A<< {}", hex::encode(&exchange.response)),
+    }
+}
+
+/// Creates the given file, compressing the written data if the file name ends with `.gz`.
+fn create(path: &Path) -> Result<Box<dyn Write + Send>> {
+    let file = BufWriter::new(File::create(path)?);
+    if path.extension().is_some_and(|extension| extension == "gz") {
+        gzip(file)
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+#[cfg(feature = "gzip")]
+fn gzip<W: Write + Send + 'static>(writer: W) -> Result<Box<dyn Write + Send>> {
+    Ok(Box::new(flate2::write::GzEncoder::new(
+        writer,
+        flate2::Compression::default(),
+    )))
+}
+
+#[cfg(not(feature = "gzip"))]
+fn gzip<W: Write + Send + 'static>(_writer: W) -> Result<Box<dyn Write + Send>> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "gzip compression requires the gzip feature",
+    ))
+}
+
+#[cfg(feature = "gzip")]
+fn gunzip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    flate2::read::MultiGzDecoder::new(data).read_to_end(&mut decompressed)?;
+    Ok(decompressed)
+}
+
+#[cfg(not(feature = "gzip"))]
+fn gunzip(_data: &[u8]) -> Result<Vec<u8>> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "gzip decompression requires the gzip feature",
+    ))
+}
+
+#[cfg(feature = "encryption")]
+fn encrypt(data: &[u8], key: &[u8; 32]) -> Result<Vec<u8>> {
+    use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+
+    let cipher = aes_gcm::Aes256Gcm::new(key.into());
+    let nonce = aes_gcm::Aes256Gcm::generate_nonce(&mut OsRng);
+    let payload = Payload {
+        msg: data,
+        aad: ENCRYPTION_MAGIC,
+    };
+    let ciphertext = cipher
+        .encrypt(&nonce, payload)
+        .map_err(|_| Error::other("failed to encrypt trace"))?;
+    Ok([ENCRYPTION_MAGIC, &nonce, &ciphertext].concat())
+}
+
+#[cfg(feature = "encryption")]
+fn decrypt(data: &[u8], key: &[u8; 32]) -> Result<Vec<u8>> {
+    use aes_gcm::aead::{Aead, KeyInit, Payload};
+
+    let data = data
+        .strip_prefix(ENCRYPTION_MAGIC)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "not an encrypted trace"))?;
+    if data.len() < NONCE_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "encrypted trace too short",
+        ));
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    let cipher = aes_gcm::Aes256Gcm::new(key.into());
+    let payload = Payload {
+        msg: ciphertext,
+        aad: ENCRYPTION_MAGIC,
+    };
+    cipher.decrypt(nonce.into(), payload).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidData,
+            "failed to decrypt trace: wrong key or modified data",
+        )
+    })
+}
+
+/// Parses the data and the duration, if present, from an apdu4j log line without the prefix.
+///
+/// Groups in parentheses contain lengths and durations, tokens with an equals sign contain the
+/// protocol; everything else is hex data.
+fn parse_apdu4j_line(line: &str) -> Result<(Vec<u8>, Option<Duration>)> {
+    let mut data = String::new();
+    let mut duration = None;
+    let mut rest = line;
+    while let Some(start) = rest.find('(') {
+        data.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find(')')
+            .map(|end| start + end)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "unbalanced parentheses"))?;
+        if let Some(ms) = rest[start + 1..end].strip_suffix("ms") {
+            duration = ms.trim().parse().ok().map(Duration::from_millis);
+        }
+        rest = &rest[end + 1..];
+    }
+    data.push_str(rest);
+    let data: Vec<&str> = data
+        .split_whitespace()
+        .filter(|token| !token.contains('='))
+        .collect();
+    Ok((hex::decode(&data.concat())?, duration))
+}
+
+/// A card that writes all exchanges with the wrapped card to a trace.
+///
+/// Every exchange is written in the apdu4j format as soon as the card has responded, so that
+/// long sessions do not have to be kept in memory.  Write errors are logged and do not affect
+/// the card.  Use [`create`][`Recorder::create`] to write to a file, optionally with gzip
+/// compression.
+///
+/// # Example
+///
+/// ```
+/// use vpicc::{trace::{Recorder, Trace}, VSmartCard};
+///
+/// let mut card = Recorder::new(vpicc::DummySmartCard, Vec::new());
+/// card.execute(&[0x00, 0xa4, 0x04, 0x00]);
+/// let (_, log) = card.into_parts();
+/// assert_eq!(Trace::read(log.as_slice())?.len(), 1);
+/// # Ok::<_, std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct Recorder<C, W = Box<dyn Write + Send>> {
+    card: C,
+    writer: W,
+}
+
+impl<C: VSmartCard> Recorder<C> {
+    /// Records the exchanges with the given card to the given file.
+    ///
+    /// If the file name ends with `.gz`, the trace is compressed using gzip.  This requires the
+    /// `gzip` feature.  The compressed stream is only complete once the recorder is dropped or
+    /// [`into_parts`][`Recorder::into_parts`] is called and the writer is dropped.
+    pub fn create<P: AsRef<Path>>(card: C, path: P) -> Result<Self> {
+        Ok(Self::new(card, create(path.as_ref())?))
+    }
+}
+
+impl<C: VSmartCard, W: Write> Recorder<C, W> {
+    /// Records the exchanges with the given card to the given writer.
+    pub fn new(card: C, writer: W) -> Self {
+        Self { card, writer }
+    }
+
+    /// Flushes the writer.
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()
+    }
+
+    /// Returns a reference to the wrapped card.
+    pub fn get_ref(&self) -> &C {
+        &self.card
+    }
+
+    /// Returns a mutable reference to the wrapped card.
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.card
+    }
+
+    /// Flushes the writer and returns the wrapped card and the writer.
+    pub fn into_parts(mut self) -> (C, W) {
+        if let Err(err) = self.writer.flush() {
+            warn!("Failed to flush trace: {}", err);
+        }
+        (self.card, self.writer)
+    }
+
+    /// Flushes the writer and returns the wrapped card.
+    pub fn into_inner(self) -> C {
+        self.into_parts().0
+    }
+}
+
+impl<C: VSmartCard, W: Write> VSmartCard for Recorder<C, W> {
+    fn atr(&self) -> &[u8] {
+        self.card.atr()
+    }
+
+    fn power_on(&mut self) {
+        self.card.power_on()
+    }
+
+    fn power_off(&mut self) {
+        self.card.power_off()
+    }
+
+    fn reset(&mut self) {
+        self.card.reset()
+    }
+
+    fn cold_reset(&mut self) {
+        self.card.cold_reset()
+    }
+
+    fn warm_reset(&mut self) {
+        self.card.warm_reset()
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        let start = Instant::now();
+        let response = self.card.execute(msg);
+        let exchange = Exchange {
+            command: msg.to_vec(),
+            response,
+            duration: Some(start.elapsed()),
+        };
+        if let Err(err) = write_exchange(&mut self.writer, &exchange) {
+            warn!("Failed to write trace: {}", err);
+        }
+        exchange.response
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.card.capabilities()
+    }
+}
+
+/// Size- and time-based rotation settings for a [`RotatingWriter`][].
+///
+/// By default, the trace is never rotated and all rotated files are kept.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rotation {
+    max_size: Option<u64>,
+    max_age: Option<Duration>,
+    max_files: Option<usize>,
+}
+
+impl Rotation {
+    /// Creates settings that never rotate the trace.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rotates the trace once the given number of bytes has been written to the current file.
+    ///
+    /// The size is measured before compression.
+    pub fn set_max_size(&mut self, bytes: u64) {
+        self.max_size = Some(bytes);
+    }
+
+    /// Rotates the trace once the current file has been open for the given duration.
+    pub fn set_max_age(&mut self, age: Duration) {
+        self.max_age = Some(age);
+    }
+
+    /// Keeps at most the given number of rotated files and deletes older ones.
+    pub fn set_max_files(&mut self, files: usize) {
+        self.max_files = Some(files);
+    }
+}
+
+/// A writer for [`Recorder`][] that rotates the trace file.
+///
+/// When a limit of the [`Rotation`][] is reached, the current file is renamed to `<path>.1`,
+/// existing rotated files are renamed from `<path>.<n>` to `<path>.<n + 1>` and a new file is
+/// created at the original path, as done by logrotate.  Rotation only happens before a command
+/// line, so every exchange is stored completely in one file.  Like [`Recorder::create`][], the
+/// files are compressed if the path ends with `.gz`.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use vpicc::trace::{Recorder, RotatingWriter, Rotation};
+///
+/// let mut rotation = Rotation::new();
+/// rotation.set_max_size(10 * 1024 * 1024);
+/// rotation.set_max_age(Duration::from_secs(24 * 60 * 60));
+/// rotation.set_max_files(7);
+/// let writer = RotatingWriter::create("trace.log", rotation)?;
+/// let card = Recorder::new(vpicc::DummySmartCard, writer);
+/// # Ok::<_, std::io::Error>(())
+/// ```
+pub struct RotatingWriter {
+    path: PathBuf,
+    rotation: Rotation,
+    file: Box<dyn Write + Send>,
+    size: u64,
+    opened_at: Instant,
+    line: Vec<u8>,
+    partial: bool,
+}
+
+impl RotatingWriter {
+    /// Creates the trace file at the given path, truncating an existing file.
+    pub fn create<P: Into<PathBuf>>(path: P, rotation: Rotation) -> Result<Self> {
+        let path = path.into();
+        let file = create(&path)?;
+        Ok(Self {
+            path,
+            rotation,
+            file,
+            size: 0,
+            opened_at: Instant::now(),
+            line: Vec::new(),
+            partial: false,
+        })
+    }
+
+    /// Returns the path of the current trace file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the path of the rotated file with the given index, starting at one for the most
+    /// recent file.
+    pub fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    /// Rotates the trace file immediately.
+    pub fn rotate(&mut self) -> Result<()> {
+        self.file.flush()?;
+        // drop the old writer first to complete the gzip stream
+        drop(mem::replace(&mut self.file, Box::new(io::sink())));
+
+        let last = match self.rotation.max_files {
+            Some(max_files) => max_files,
+            None => (1..)
+                .find(|&index| !self.rotated_path(index).exists())
+                .unwrap_or(1),
+        };
+        if last == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let oldest = self.rotated_path(last);
+            if oldest.exists() {
+                fs::remove_file(oldest)?;
+            }
+            for index in (1..last).rev() {
+                let path = self.rotated_path(index);
+                if path.exists() {
+                    fs::rename(path, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        debug!("Rotated trace {}", self.path.display());
+
+        self.file = create(&self.path)?;
+        self.size = 0;
+        self.opened_at = Instant::now();
+        Ok(())
+    }
+
+    fn is_due(&self) -> bool {
+        self.size > 0
+            && (self.rotation.max_size.is_some_and(|size| self.size >= size)
+                || self
+                    .rotation
+                    .max_age
+                    .is_some_and(|age| self.opened_at.elapsed() >= age))
+    }
+
+    fn write_line(&mut self) -> Result<()> {
+        let partial = mem::replace(&mut self.partial, false);
+        if !partial && self.line.starts_with(b"A>>") && self.is_due() {
+            self.rotate()?;
+        }
+        let result = self.file.write_all(&self.line);
+        self.size += self.line.len() as u64;
+        self.line.clear();
+        result
+    }
+}
+
+impl fmt::Debug for RotatingWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RotatingWriter")
+            .field("path", &self.path)
+            .field("rotation", &self.rotation)
+            .field("size", &self.size)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Write for RotatingWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut rest = buf;
+        while let Some(end) = rest.iter().position(|b| *b == b'\n') {
+            self.line.extend_from_slice(&rest[..=end]);
+            self.write_line()?;
+            rest = &rest[end + 1..];
+        }
+        self.line.extend_from_slice(rest);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if !self.line.is_empty() {
+            // write the incomplete line without rotating in the middle of it
+            self.file.write_all(&self.line)?;
+            self.size += self.line.len() as u64;
+            self.line.clear();
+            self.partial = true;
+        }
+        self.file.flush()
+    }
+}
+
+impl Drop for RotatingWriter {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            warn!("Failed to flush trace: {}", err);
+        }
+    }
+}
+
+/// A card that responds with the responses recorded in a trace.
+///
+/// The commands have to be received in the same order as in the trace.  If a command does not
+/// match the next command in the trace, the card responds with 6F00 (no precise diagnosis).
+///
+/// # Example
+///
+/// ```
+/// use vpicc::{trace::{Exchange, ReplayCard, Trace}, VSmartCard};
+///
+/// let trace = Trace::from(vec![Exchange {
+///     command: vec![0x00, 0x84, 0x00, 0x00, 0x08],
+///     response: vec![0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x90, 0x00],
+///     duration: None,
+/// }]);
+/// let mut card = ReplayCard::new(trace);
+/// assert_eq!(card.execute(&[0x00, 0x84, 0x00, 0x00, 0x08]).len(), 10);
+/// assert!(card.is_finished());
+/// ```
+#[derive(Clone, Debug)]
+pub struct ReplayCard {
+    trace: Trace,
+    position: usize,
+}
+
+impl ReplayCard {
+    /// Creates a card that replays the given trace.
+    pub fn new(trace: Trace) -> Self {
+        Self { trace, position: 0 }
+    }
+
+    /// Returns true if all exchanges of the trace have been replayed.
+    pub fn is_finished(&self) -> bool {
+        self.position >= self.trace.len()
+    }
+
+    /// Restarts the replay from the first exchange.
+    pub fn rewind(&mut self) {
+        self.position = 0;
+    }
+}
+
+impl VSmartCard for ReplayCard {
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        match self.trace.exchanges.get(self.position) {
+            Some(exchange) if exchange.command == msg => {
+                self.position += 1;
+                exchange.response.clone()
+            }
+            Some(exchange) => {
+                warn!(
+                    "Unexpected command {:x?} at position {}, expected {:x?}",
+                    msg, self.position, exchange.command
+                );
+                apdu::SW_NO_PRECISE_DIAGNOSIS.to_be_bytes().to_vec()
+            }
+            None => {
+                warn!("Unexpected command {:x?} after end of trace", msg);
+                apdu::SW_NO_PRECISE_DIAGNOSIS.to_be_bytes().to_vec()
+            }
+        }
+    }
+}
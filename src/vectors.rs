@@ -0,0 +1,248 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Protocol test vectors for the vpcd wire format.
+//!
+//! The vectors follow the [protocol description][] of the virtualsmartcard project:  every
+//! message is prefixed with its length as a big-endian `u16`, messages with a single byte are
+//! control commands and longer messages are command APDUs.  They cover the control commands,
+//! edge-case lengths and incomplete frames, and can also be used to test other implementations
+//! of the protocol.  [`verify`][] runs the codec of this crate against all vectors, so that
+//! changes to the wire format are noticed.
+//!
+//! # Example
+//!
+//! ```
+//! vpicc::vectors::verify().unwrap();
+//! ```
+//!
+//! [protocol description]: https://frankmorgner.github.io/vsmartcard/virtualsmartcard/api.html
+
+use crate::{frame, Request, MAX_MESSAGE_LEN};
+
+/// The expected result of decoding a [`RequestVector`][].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Decoded {
+    /// The frame contains the given request.
+    Request(Request),
+    /// The frame is complete, but the message is not a valid request.
+    Rejected,
+    /// The data does not contain a complete frame yet.
+    Incomplete,
+}
+
+/// A frame sent by vpcd and the expected result of decoding it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestVector {
+    /// A short description of the vector.
+    pub name: &'static str,
+    /// The data received from vpcd.
+    pub wire: Vec<u8>,
+    /// The expected result.
+    pub expected: Decoded,
+}
+
+/// A response sent to vpcd and the expected frame.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResponseVector {
+    /// A short description of the vector.
+    pub name: &'static str,
+    /// The response or ATR.
+    pub response: Vec<u8>,
+    /// The expected frame, or `None` if the response cannot be framed.
+    pub wire: Option<Vec<u8>>,
+}
+
+/// Returns the vectors for frames received from vpcd.
+pub fn requests() -> Vec<RequestVector> {
+    let request = |name, wire: &[u8], expected| RequestVector {
+        name,
+        wire: wire.to_vec(),
+        expected,
+    };
+    let apdu = |data: &[u8]| Decoded::Request(Request::Apdu(data.to_vec()));
+    let max_apdu = vec![0xab; MAX_MESSAGE_LEN];
+    let mut max_frame = vec![0xff, 0xff];
+    max_frame.extend_from_slice(&max_apdu);
+    vec![
+        request(
+            "power off",
+            &[0x00, 0x01, 0x00],
+            Decoded::Request(Request::PowerOff),
+        ),
+        request(
+            "power on",
+            &[0x00, 0x01, 0x01],
+            Decoded::Request(Request::PowerOn),
+        ),
+        request(
+            "reset",
+            &[0x00, 0x01, 0x02],
+            Decoded::Request(Request::Reset),
+        ),
+        request(
+            "get ATR",
+            &[0x00, 0x01, 0x04],
+            Decoded::Request(Request::GetAtr),
+        ),
+        request(
+            "unknown control command 3",
+            &[0x00, 0x01, 0x03],
+            Decoded::Rejected,
+        ),
+        request(
+            "unknown control command ff",
+            &[0x00, 0x01, 0xff],
+            Decoded::Rejected,
+        ),
+        request("empty message", &[0x00, 0x00], Decoded::Rejected),
+        request(
+            "two byte message",
+            &[0x00, 0x02, 0x00, 0xa4],
+            apdu(&[0x00, 0xa4]),
+        ),
+        request(
+            "case 1 APDU",
+            &[0x00, 0x04, 0x00, 0xa4, 0x04, 0x00],
+            apdu(&[0x00, 0xa4, 0x04, 0x00]),
+        ),
+        request(
+            "case 2 APDU",
+            &[0x00, 0x05, 0x00, 0xb0, 0x00, 0x00, 0x00],
+            apdu(&[0x00, 0xb0, 0x00, 0x00, 0x00]),
+        ),
+        request(
+            "case 4 APDU",
+            &[0x00, 0x08, 0x00, 0xa4, 0x04, 0x00, 0x02, 0x3f, 0x00, 0x00],
+            apdu(&[0x00, 0xa4, 0x04, 0x00, 0x02, 0x3f, 0x00, 0x00]),
+        ),
+        request(
+            "extended length case 2 APDU",
+            &[0x00, 0x07, 0x00, 0xb0, 0x00, 0x00, 0x00, 0x00, 0x00],
+            apdu(&[0x00, 0xb0, 0x00, 0x00, 0x00, 0x00, 0x00]),
+        ),
+        RequestVector {
+            name: "maximum length",
+            wire: max_frame,
+            expected: Decoded::Request(Request::Apdu(max_apdu)),
+        },
+        request("no length", &[], Decoded::Incomplete),
+        request("partial length", &[0x00], Decoded::Incomplete),
+        request("missing message", &[0x00, 0x01], Decoded::Incomplete),
+        request(
+            "partial message",
+            &[0x00, 0x04, 0x00, 0xa4],
+            Decoded::Incomplete,
+        ),
+        request(
+            "trailing data",
+            &[0x00, 0x01, 0x01, 0x00],
+            Decoded::Request(Request::PowerOn),
+        ),
+    ]
+}
+
+/// Returns the vectors for responses sent to vpcd.
+pub fn responses() -> Vec<ResponseVector> {
+    let response = |name, response: &[u8], wire: &[u8]| ResponseVector {
+        name,
+        response: response.to_vec(),
+        wire: Some(wire.to_vec()),
+    };
+    let max_response = vec![0xcd; MAX_MESSAGE_LEN];
+    let mut max_frame = vec![0xff, 0xff];
+    max_frame.extend_from_slice(&max_response);
+    vec![
+        response("empty response", &[], &[0x00, 0x00]),
+        response("status word", &[0x90, 0x00], &[0x00, 0x02, 0x90, 0x00]),
+        response(
+            "data and status word",
+            &[0x01, 0x02, 0x61, 0x10],
+            &[0x00, 0x04, 0x01, 0x02, 0x61, 0x10],
+        ),
+        response(
+            "ATR",
+            &[0x3b, 0x80, 0x80, 0x01, 0x01],
+            &[0x00, 0x05, 0x3b, 0x80, 0x80, 0x01, 0x01],
+        ),
+        ResponseVector {
+            name: "maximum length",
+            response: max_response,
+            wire: Some(max_frame),
+        },
+        ResponseVector {
+            name: "too long",
+            response: vec![0xcd; MAX_MESSAGE_LEN + 1],
+            wire: None,
+        },
+    ]
+}
+
+/// Returns a complete session as received from vpcd, consisting of several frames in a single
+/// buffer, and the requests it contains.
+pub fn session() -> (Vec<u8>, Vec<Request>) {
+    let wire = [
+        &[0x00, 0x01, 0x01][..],
+        &[0x00, 0x01, 0x04],
+        &[0x00, 0x04, 0x00, 0xa4, 0x04, 0x00],
+        &[0x00, 0x01, 0x02],
+        &[0x00, 0x05, 0x00, 0xb0, 0x00, 0x00, 0x00],
+        &[0x00, 0x01, 0x00],
+    ]
+    .concat();
+    let requests = vec![
+        Request::PowerOn,
+        Request::GetAtr,
+        Request::Apdu(vec![0x00, 0xa4, 0x04, 0x00]),
+        Request::Reset,
+        Request::Apdu(vec![0x00, 0xb0, 0x00, 0x00, 0x00]),
+        Request::PowerOff,
+    ];
+    (wire, requests)
+}
+
+/// Runs the codec of this crate against all vectors and returns a description of the first
+/// mismatch.
+pub fn verify() -> Result<(), String> {
+    for vector in requests() {
+        let mut buffer = vector.wire.clone();
+        let decoded = match frame::decode(&mut buffer) {
+            Some(msg) => match Request::try_from(msg) {
+                Ok(request) => Decoded::Request(request),
+                Err(_) => Decoded::Rejected,
+            },
+            None => Decoded::Incomplete,
+        };
+        if decoded != vector.expected {
+            return Err(format!(
+                "request vector {:?}: expected {:?}, got {:?}",
+                vector.name, vector.expected, decoded
+            ));
+        }
+    }
+
+    for vector in responses() {
+        let mut wire = Vec::new();
+        let result = frame::write(&mut wire, &vector.response).ok().map(|_| wire);
+        if result != vector.wire {
+            return Err(format!(
+                "response vector {:?}: unexpected frame",
+                vector.name
+            ));
+        }
+    }
+
+    let (mut wire, expected) = session();
+    let mut requests = Vec::new();
+    while let Some(msg) = frame::decode(&mut wire) {
+        let request = Request::try_from(msg).map_err(|err| format!("session: {}", err))?;
+        requests.push(request);
+    }
+    if requests != expected || !wire.is_empty() {
+        return Err(format!(
+            "session: expected {:?}, got {:?}",
+            expected, requests
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,489 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Card implementations loaded as WebAssembly modules.
+//!
+//! [`WasmCard`][] runs a card implemented as a WebAssembly module in a sandbox.  The module can
+//! only access its own memory and the state of the card, and every call can be limited to an
+//! amount of fuel, so a faulty plugin cannot harm the host.  If the module is loaded from a
+//! file, it can be reloaded while the card is running, keeping the state of the card.
+//!
+//! # Host interface
+//!
+//! Pointers and lengths are `i32` values.  Functions returning a buffer return an `i64` with the
+//! pointer in the upper and the length in the lower 32 bits.  The module must export:
+//!
+//! - `memory`:  its linear memory.
+//! - `vpicc_alloc(len) -> ptr`:  returns a buffer for a command with the given length.
+//! - `vpicc_execute(ptr, len) -> buffer`:  executes the command in the given buffer and returns
+//!   the response APDU.
+//!
+//! It may export these functions with the same meaning as the methods of [`VSmartCard`][]:
+//!
+//! - `vpicc_atr() -> buffer`
+//! - `vpicc_power_on()`, `vpicc_power_off()`, `vpicc_reset()`
+//! - `vpicc_cold_reset()`, `vpicc_warm_reset()`
+//!
+//! The host provides these functions in the `vpicc` module for accessing the
+//! [`StateBackend`][] of the card:
+//!
+//! - `state_get(key_ptr, key_len, ptr, len) -> i32`:  copies the value of the key into the
+//!   given buffer, truncating it if the buffer is too short, and returns the length of the
+//!   value, or -1 if the key is not set.
+//! - `state_set(key_ptr, key_len, ptr, len) -> i32`:  sets the value of the key and returns
+//!   0, or -1 if the value could not be stored.
+//! - `state_remove(key_ptr, key_len) -> i32`:  removes the key and returns 0, or -1 if the
+//!   key could not be removed.
+//! - `log(ptr, len)`:  writes a UTF-8 message to the debug log.
+//!
+//! If the module traps, for example because it runs out of fuel, the command is answered with
+//! 6F00 and the module is instantiated again on the next Power On or Reset command.
+//!
+//! # Example
+//!
+//! ```
+//! use vpicc::{wasm::WasmCard, VSmartCard};
+//!
+//! let mut card = WasmCard::new(r#"(module
+//!     (memory (export "memory") 1)
+//!     (data (i32.const 2048) "\90\00")
+//!     (func (export "vpicc_alloc") (param i32) (result i32) i32.const 1024)
+//!     (func (export "vpicc_execute") (param i32 i32) (result i64) i64.const 0x80000000002)
+//! )"#)?;
+//! card.power_on();
+//! card.cold_reset();
+//! assert_eq!(card.execute(&[0x00, 0xa4, 0x04, 0x00]), [0x90, 0x00]);
+//! # Ok::<_, std::io::Error>(())
+//! ```
+
+use std::{
+    fmt, fs,
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use log::{debug, warn};
+use wasmtime::{
+    Caller, Config, Engine, Extern, Instance, Linker, Memory, Module, Store, TypedFunc,
+};
+
+use crate::{
+    apdu,
+    state::{MemoryBackend, StateBackend},
+    VSmartCard, DEFAULT_ATR,
+};
+
+/// The default amount of fuel for every call into the module, see [`WasmCard::set_fuel`][].
+pub const DEFAULT_FUEL: u64 = 100_000_000;
+
+/// A card implemented as a WebAssembly module, see the [module documentation][`self`].
+pub struct WasmCard {
+    engine: Engine,
+    module: Module,
+    source: Option<Source>,
+    store: Store<Host>,
+    guest: Option<Guest>,
+    atr: Vec<u8>,
+    fuel: u64,
+    hot_reload: bool,
+}
+
+impl WasmCard {
+    /// Loads a card from the given module in the binary or text format.
+    ///
+    /// The state of the card is kept in a [`MemoryBackend`][] unless a backend is set with
+    /// [`set_backend`][`WasmCard::set_backend`].
+    pub fn new<B: AsRef<[u8]>>(module: B) -> Result<Self> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).map_err(Error::other)?;
+        let module = compile(&engine, module.as_ref())?;
+        let store = Store::new(
+            &engine,
+            Host {
+                backend: Arc::new(MemoryBackend::new()),
+            },
+        );
+        let mut card = Self {
+            engine,
+            module,
+            source: None,
+            store,
+            guest: None,
+            atr: DEFAULT_ATR.to_vec(),
+            fuel: DEFAULT_FUEL,
+            hot_reload: false,
+        };
+        card.instantiate()?;
+        Ok(card)
+    }
+
+    /// Loads a card from the module in the given file, see [`new`][`WasmCard::new`].
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+        let source = Source::read(&path)?;
+        let mut card = Self::new(&source.data)?;
+        card.source = Some(source);
+        Ok(card)
+    }
+
+    /// Sets the backend that the state of the card is stored in.
+    pub fn set_backend<B: StateBackend + 'static>(&mut self, backend: B) {
+        self.store.data_mut().backend = Arc::new(backend);
+    }
+
+    /// Sets the amount of fuel for every call into the module, defaulting to
+    /// [`DEFAULT_FUEL`][].
+    ///
+    /// Most WebAssembly instructions consume one unit of fuel.  If a call runs out of fuel, the
+    /// module traps.
+    pub fn set_fuel(&mut self, fuel: u64) {
+        self.fuel = fuel;
+    }
+
+    /// Sets whether the module file is reloaded on every cold reset if it has been modified
+    /// since it was loaded, defaulting to false.  This only has an effect for cards loaded with
+    /// [`open`][`WasmCard::open`].
+    ///
+    /// If the modified module cannot be loaded, the error is logged and the previous module is
+    /// used.
+    pub fn set_hot_reload(&mut self, hot_reload: bool) {
+        self.hot_reload = hot_reload;
+    }
+
+    /// Reloads the module from its file and instantiates it, keeping the state of the card.
+    ///
+    /// Returns an error if the card was not loaded from a file or if the module cannot be
+    /// loaded.  In this case, the previous module is kept.
+    pub fn reload(&mut self) -> Result<()> {
+        let path = self
+            .source
+            .as_ref()
+            .map(|source| source.path.clone())
+            .ok_or_else(|| Error::new(ErrorKind::Unsupported, "the card has no module file"))?;
+        let source = Source::read(&path)?;
+        let module = compile(&self.engine, &source.data)?;
+        let previous = std::mem::replace(&mut self.module, module);
+        if let Err(err) = self.instantiate() {
+            self.module = previous;
+            return Err(err);
+        }
+        debug!("Reloaded card module {}", path.display());
+        self.source = Some(source);
+        Ok(())
+    }
+
+    fn instantiate(&mut self) -> Result<()> {
+        // a new store releases the memory of previous instances
+        let host = self.store.data().clone();
+        self.store = Store::new(&self.engine, host);
+        self.guest = None;
+        let mut linker = Linker::new(&self.engine);
+        define_host(&mut linker).map_err(Error::other)?;
+        self.store.set_fuel(self.fuel).map_err(Error::other)?;
+        let instance = linker
+            .instantiate(&mut self.store, &self.module)
+            .map_err(Error::other)?;
+        self.guest = Some(Guest::new(&mut self.store, instance)?);
+        Ok(())
+    }
+
+    fn ensure_instantiated(&mut self) {
+        if self.guest.is_none() {
+            if let Err(err) = self.instantiate() {
+                warn!("Failed to instantiate card module: {}", err);
+            }
+        }
+    }
+
+    fn reload_if_modified(&mut self) {
+        let Some(source) = &self.source else {
+            return;
+        };
+        if !self.hot_reload || Source::modified(&source.path) == source.modified {
+            return;
+        }
+        if let Err(err) = self.reload() {
+            warn!("Failed to reload card module: {}", err);
+        }
+    }
+
+    /// Calls the given function of the module, dropping the instance if it traps.
+    fn call<T>(
+        &mut self,
+        f: impl FnOnce(&Guest, &mut Store<Host>) -> wasmtime::Result<T>,
+    ) -> Option<T> {
+        let guest = self.guest.as_ref()?;
+        if let Err(err) = self.store.set_fuel(self.fuel) {
+            warn!("Failed to set fuel for card module: {}", err);
+        }
+        match f(guest, &mut self.store) {
+            Ok(value) => Some(value),
+            Err(err) => {
+                warn!("Card module trapped: {:?}", err);
+                self.guest = None;
+                None
+            }
+        }
+    }
+
+    fn call_hook(&mut self, name: &str) {
+        self.call(
+            |guest, store| match guest.hooks.iter().find(|(n, _)| *n == name) {
+                Some((_, hook)) => hook.call(store, ()),
+                None => Ok(()),
+            },
+        );
+    }
+
+    fn update_atr(&mut self) {
+        let atr = self.call(|guest, store| match &guest.atr {
+            Some(atr) => {
+                let buffer = atr.call(&mut *store, ())?;
+                guest.read(store, buffer).map(Some)
+            }
+            None => Ok(None),
+        });
+        if let Some(Some(atr)) = atr {
+            self.atr = atr;
+        }
+    }
+}
+
+impl fmt::Debug for WasmCard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WasmCard")
+            .field("path", &self.source.as_ref().map(|source| &source.path))
+            .field("instantiated", &self.guest.is_some())
+            .field("atr", &self.atr)
+            .field("fuel", &self.fuel)
+            .field("hot_reload", &self.hot_reload)
+            .finish_non_exhaustive()
+    }
+}
+
+impl VSmartCard for WasmCard {
+    fn atr(&self) -> &[u8] {
+        &self.atr
+    }
+
+    fn power_on(&mut self) {
+        self.ensure_instantiated();
+        self.call_hook("vpicc_power_on");
+    }
+
+    fn power_off(&mut self) {
+        self.call_hook("vpicc_power_off");
+    }
+
+    fn reset(&mut self) {
+        self.ensure_instantiated();
+        self.call_hook("vpicc_reset");
+    }
+
+    fn cold_reset(&mut self) {
+        self.reload_if_modified();
+        self.call_hook("vpicc_cold_reset");
+        self.update_atr();
+    }
+
+    fn warm_reset(&mut self) {
+        self.call_hook("vpicc_warm_reset");
+        self.update_atr();
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        let Ok(len) = i32::try_from(msg.len()) else {
+            return apdu::response(&[], apdu::SW_WRONG_LENGTH);
+        };
+        self.call(|guest, store| {
+            let ptr = guest.alloc.call(&mut *store, len)?;
+            guest.write(store, ptr, msg)?;
+            let buffer = guest.execute.call(&mut *store, (ptr, len))?;
+            guest.read(store, buffer)
+        })
+        .unwrap_or_else(|| apdu::response(&[], apdu::SW_NO_PRECISE_DIAGNOSIS))
+    }
+}
+
+/// The module file of a card.
+struct Source {
+    path: PathBuf,
+    data: Vec<u8>,
+    modified: Option<SystemTime>,
+}
+
+impl Source {
+    fn read(path: &Path) -> Result<Self> {
+        Ok(Self {
+            path: path.to_owned(),
+            modified: Self::modified(path),
+            data: fs::read(path)?,
+        })
+    }
+
+    fn modified(path: &Path) -> Option<SystemTime> {
+        fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+}
+
+/// The data available to the host functions.
+#[derive(Clone)]
+struct Host {
+    backend: Arc<dyn StateBackend>,
+}
+
+/// The exports of an instantiated module.
+struct Guest {
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    execute: TypedFunc<(i32, i32), i64>,
+    atr: Option<TypedFunc<(), i64>>,
+    hooks: Vec<(&'static str, TypedFunc<(), ()>)>,
+}
+
+impl Guest {
+    fn new(store: &mut Store<Host>, instance: Instance) -> Result<Self> {
+        let invalid = |err: wasmtime::Error| Error::new(ErrorKind::InvalidData, err.to_string());
+        let memory = instance
+            .get_memory(&mut *store, "memory")
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "the module exports no memory"))?;
+        let hooks = [
+            "vpicc_power_on",
+            "vpicc_power_off",
+            "vpicc_reset",
+            "vpicc_cold_reset",
+            "vpicc_warm_reset",
+        ]
+        .into_iter()
+        .filter_map(|name| {
+            let hook = instance.get_typed_func(&mut *store, name).ok()?;
+            Some((name, hook))
+        })
+        .collect();
+        Ok(Self {
+            memory,
+            alloc: instance
+                .get_typed_func(&mut *store, "vpicc_alloc")
+                .map_err(invalid)?,
+            execute: instance
+                .get_typed_func(&mut *store, "vpicc_execute")
+                .map_err(invalid)?,
+            atr: instance.get_typed_func(&mut *store, "vpicc_atr").ok(),
+            hooks,
+        })
+    }
+
+    fn read(&self, store: &Store<Host>, buffer: i64) -> wasmtime::Result<Vec<u8>> {
+        let (ptr, len) = ((buffer >> 32) as i32, buffer as i32);
+        Ok(slice(self.memory.data(store), ptr, len)?.to_vec())
+    }
+
+    fn write(&self, store: &mut Store<Host>, ptr: i32, data: &[u8]) -> wasmtime::Result<()> {
+        let len = data.len() as i32;
+        slice_mut(self.memory.data_mut(store), ptr, len)?.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+fn compile(engine: &Engine, module: &[u8]) -> Result<Module> {
+    Module::new(engine, module).map_err(|err| Error::new(ErrorKind::InvalidData, err.to_string()))
+}
+
+fn range(ptr: i32, len: i32) -> wasmtime::Result<std::ops::Range<usize>> {
+    let start = usize::try_from(ptr as u32)?;
+    let end = start.checked_add(usize::try_from(len as u32)?);
+    end.map(|end| start..end)
+        .ok_or_else(|| wasmtime::Error::msg("buffer out of bounds"))
+}
+
+fn slice(memory: &[u8], ptr: i32, len: i32) -> wasmtime::Result<&[u8]> {
+    memory
+        .get(range(ptr, len)?)
+        .ok_or_else(|| wasmtime::Error::msg("buffer out of bounds"))
+}
+
+fn slice_mut(memory: &mut [u8], ptr: i32, len: i32) -> wasmtime::Result<&mut [u8]> {
+    memory
+        .get_mut(range(ptr, len)?)
+        .ok_or_else(|| wasmtime::Error::msg("buffer out of bounds"))
+}
+
+fn memory(caller: &mut Caller<'_, Host>) -> wasmtime::Result<Memory> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| wasmtime::Error::msg("the module exports no memory"))
+}
+
+fn key(memory: &[u8], ptr: i32, len: i32) -> wasmtime::Result<String> {
+    Ok(std::str::from_utf8(slice(memory, ptr, len)?)?.to_owned())
+}
+
+fn define_host(linker: &mut Linker<Host>) -> wasmtime::Result<()> {
+    linker.func_wrap(
+        "vpicc",
+        "state_get",
+        |mut caller: Caller<'_, Host>, key_ptr: i32, key_len: i32, ptr: i32, len: i32| {
+            let memory = memory(&mut caller)?;
+            let key = key(memory.data(&caller), key_ptr, key_len)?;
+            let value = match caller.data().backend.get(&key) {
+                Ok(Some(value)) => value,
+                Ok(None) => return Ok(-1),
+                Err(err) => {
+                    warn!("Failed to read {} for card module: {}", key, err);
+                    return Ok(-1);
+                }
+            };
+            let buffer = slice_mut(memory.data_mut(&mut caller), ptr, len)?;
+            let n = buffer.len().min(value.len());
+            buffer[..n].copy_from_slice(&value[..n]);
+            Ok(i32::try_from(value.len())?)
+        },
+    )?;
+    linker.func_wrap(
+        "vpicc",
+        "state_set",
+        |mut caller: Caller<'_, Host>, key_ptr: i32, key_len: i32, ptr: i32, len: i32| {
+            let memory = memory(&mut caller)?;
+            let data = memory.data(&caller);
+            let key = key(data, key_ptr, key_len)?;
+            let value = slice(data, ptr, len)?;
+            match caller.data().backend.set(&key, value) {
+                Ok(()) => Ok(0),
+                Err(err) => {
+                    warn!("Failed to store {} for card module: {}", key, err);
+                    Ok(-1)
+                }
+            }
+        },
+    )?;
+    linker.func_wrap(
+        "vpicc",
+        "state_remove",
+        |mut caller: Caller<'_, Host>, key_ptr: i32, key_len: i32| {
+            let memory = memory(&mut caller)?;
+            let key = key(memory.data(&caller), key_ptr, key_len)?;
+            match caller.data().backend.remove(&key) {
+                Ok(()) => Ok(0),
+                Err(err) => {
+                    warn!("Failed to remove {} for card module: {}", key, err);
+                    Ok(-1)
+                }
+            }
+        },
+    )?;
+    linker.func_wrap(
+        "vpicc",
+        "log",
+        |mut caller: Caller<'_, Host>, ptr: i32, len: i32| {
+            let memory = memory(&mut caller)?;
+            let message = slice(memory.data(&caller), ptr, len)?;
+            debug!("Card module: {}", String::from_utf8_lossy(message));
+            Ok(())
+        },
+    )?;
+    Ok(())
+}
@@ -0,0 +1,40 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+#![cfg(feature = "tokio")]
+
+mod common;
+
+use std::{future, net::TcpListener, pin::Pin};
+
+use common::{assert_closed, receive};
+use futures_sink::Sink;
+
+#[tokio::test(flavor = "current_thread")]
+async fn write_half_is_a_sink_for_responses() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let connection = vpicc::aio::connect_socket(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (mut vpcd, _) = listener.accept().unwrap();
+    let (_requests, mut responses) = connection.into_split();
+
+    let mut sink = Pin::new(&mut responses);
+    future::poll_fn(|cx| Sink::<Vec<u8>>::poll_ready(sink.as_mut(), cx))
+        .await
+        .unwrap();
+    sink.as_mut().start_send(vec![0x90, 0x00]).unwrap();
+    sink.as_mut().start_send(vec![0x6a, 0x82]).unwrap();
+    // responses are buffered until the sink is flushed
+    future::poll_fn(|cx| Sink::<Vec<u8>>::poll_flush(sink.as_mut(), cx))
+        .await
+        .unwrap();
+    assert_eq!(receive(&mut vpcd), [0x90, 0x00]);
+    assert_eq!(receive(&mut vpcd), [0x6a, 0x82]);
+
+    assert!(sink.as_mut().start_send(vec![0; 0x10000]).is_err());
+    future::poll_fn(|cx| Sink::<Vec<u8>>::poll_close(sink.as_mut(), cx))
+        .await
+        .unwrap();
+    assert_closed(&mut vpcd);
+}
@@ -0,0 +1,43 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+//! Helpers shared by the integration tests.
+
+// every test crate uses a different subset of the helpers
+#![allow(dead_code)]
+
+use std::{
+    env, fs,
+    io::{Read, Write},
+    net::TcpStream,
+    path::PathBuf,
+    process,
+};
+
+/// Sends a message to the card like vpcd.
+pub fn send(vpcd: &mut TcpStream, msg: &[u8]) {
+    let len = u16::try_from(msg.len()).unwrap().to_be_bytes();
+    vpcd.write_all(&[&len[..], msg].concat()).unwrap();
+}
+
+/// Receives a message from the card like vpcd.
+pub fn receive(vpcd: &mut TcpStream) -> Vec<u8> {
+    let mut len = [0; 2];
+    vpcd.read_exact(&mut len).unwrap();
+    let mut msg = vec![0; u16::from_be_bytes(len).into()];
+    vpcd.read_exact(&mut msg).unwrap();
+    msg
+}
+
+/// Asserts that the card has closed the connection.
+pub fn assert_closed(vpcd: &mut TcpStream) {
+    assert_eq!(vpcd.read(&mut [0; 16]).unwrap(), 0);
+}
+
+/// Creates an empty directory for the given test.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("vpicc-test-{}-{}", name, process::id()));
+    fs::remove_dir_all(&dir).ok();
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
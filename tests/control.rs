@@ -0,0 +1,43 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+use std::{
+    net::TcpListener,
+    sync::{Arc, Mutex},
+};
+
+use vpicc::{control::ControlServer, Registry};
+
+#[test]
+fn names_that_are_not_words_are_rejected() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let registry = Arc::new(Mutex::new(Registry::new()));
+    registry
+        .lock()
+        .unwrap()
+        .add("card", addr, vpicc::DummySmartCard)
+        .unwrap();
+    let mut server = ControlServer::new(registry.clone());
+
+    server
+        .add_profile("dummy", || Box::new(vpicc::DummySmartCard))
+        .unwrap();
+    for name in ["", "two words", "line\nbreak"] {
+        assert!(server
+            .add_profile(name, || Box::new(vpicc::DummySmartCard))
+            .is_err());
+    }
+    assert_eq!(server.handle("profiles"), "ok dummy");
+    assert_eq!(server.handle("list"), "ok card");
+
+    // a card with such a name cannot be listed unambiguously
+    registry
+        .lock()
+        .unwrap()
+        .add("other card", addr, vpicc::DummySmartCard)
+        .unwrap();
+    let response = server.handle("list");
+    assert!(response.starts_with("error "), "{}", response);
+    assert!(!response.contains('\n'));
+}
@@ -0,0 +1,52 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+mod common;
+
+use std::{net::TcpStream, thread};
+
+use common::{receive, send};
+use vpicc::{Shared, VSmartCard};
+
+/// Responds to every command with the number of commands it has executed.
+#[derive(Default)]
+struct CountingCard(u8);
+
+impl VSmartCard for CountingCard {
+    fn execute(&mut self, _msg: &[u8]) -> Vec<u8> {
+        self.0 += 1;
+        vec![self.0, 0x90, 0x00]
+    }
+}
+
+/// Serves the given factory on a local port and returns two connections from "vpcd".
+fn serve<F: vpicc::CardFactory + Send + 'static>(factory: F) -> (TcpStream, TcpStream) {
+    let listener = vpicc::listen("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || listener.serve(factory));
+    let mut first = TcpStream::connect(addr).unwrap();
+    let mut second = TcpStream::connect(addr).unwrap();
+    send(&mut first, &[0x01]);
+    send(&mut second, &[0x01]);
+    (first, second)
+}
+
+#[test]
+fn closure_creates_card_per_connection() {
+    let (mut first, mut second) = serve(CountingCard::default);
+    send(&mut first, &[0x00, 0xca, 0x00, 0x00]);
+    assert_eq!(receive(&mut first), [0x01, 0x90, 0x00]);
+    send(&mut second, &[0x00, 0xca, 0x00, 0x00]);
+    assert_eq!(receive(&mut second), [0x01, 0x90, 0x00]);
+}
+
+#[test]
+fn shared_card_is_used_by_all_connections() {
+    let shared = Shared::new(CountingCard::default());
+    let (mut first, mut second) = serve(shared.clone());
+    send(&mut first, &[0x00, 0xca, 0x00, 0x00]);
+    assert_eq!(receive(&mut first), [0x01, 0x90, 0x00]);
+    send(&mut second, &[0x00, 0xca, 0x00, 0x00]);
+    assert_eq!(receive(&mut second), [0x02, 0x90, 0x00]);
+    assert_eq!(shared.lock().0, 2);
+}
@@ -0,0 +1,95 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+use std::{
+    thread::{self, ThreadId},
+    time::Duration,
+};
+
+use vpicc::{
+    apdu,
+    middleware::{
+        AdjustableAtr, CatchUnwind, ClaRewrite, RandomizedAtr, SlowBoot, Timeout, WithAtr,
+        WithDefault,
+    },
+    VSmartCard,
+};
+
+/// Responds with the index of the thread that executed the command and changes its ATR with
+/// every command.
+#[derive(Default)]
+struct ThreadCard {
+    threads: Vec<ThreadId>,
+    atr: Vec<u8>,
+}
+
+impl VSmartCard for ThreadCard {
+    fn atr(&self) -> &[u8] {
+        &self.atr
+    }
+
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        if msg[1] == 0xff {
+            thread::sleep(Duration::from_millis(50));
+        }
+        let id = thread::current().id();
+        if !self.threads.contains(&id) {
+            self.threads.push(id);
+        }
+        self.atr = vec![0x3b, msg[1]];
+        let index = self
+            .threads
+            .iter()
+            .position(|thread| *thread == id)
+            .unwrap();
+        vec![index as u8, 0x90, 0x00]
+    }
+}
+
+#[test]
+fn timeout_reuses_worker_until_overrun() {
+    let mut card = Timeout::new(ThreadCard::default(), Duration::from_millis(20));
+    assert_eq!(card.execute(&[0x00, 0x01, 0x00, 0x00]), [0x00, 0x90, 0x00]);
+    assert_eq!(card.atr(), [0x3b, 0x01]);
+    assert_eq!(card.execute(&[0x00, 0x02, 0x00, 0x00]), [0x00, 0x90, 0x00]);
+    assert_eq!(card.atr(), [0x3b, 0x02]);
+
+    assert_eq!(card.execute(&[0x00, 0xff, 0x00, 0x00]), [0x6f, 0x00]);
+    thread::sleep(Duration::from_millis(100));
+    // the overrunning worker is replaced
+    assert_eq!(card.execute(&[0x00, 0x03, 0x00, 0x00]), [0x01, 0x90, 0x00]);
+    assert_eq!(card.atr(), [0x3b, 0x03]);
+    assert_eq!(card.execute(&[0x00, 0x04, 0x00, 0x00]), [0x01, 0x90, 0x00]);
+}
+
+/// Responds with 9000 from `execute_small` and with 6F00 from `execute`.
+struct SmallCard;
+
+impl VSmartCard for SmallCard {
+    fn execute(&mut self, _msg: &[u8]) -> Vec<u8> {
+        vec![0x6f, 0x00]
+    }
+
+    fn execute_small(&mut self, _msg: &[u8]) -> apdu::Response {
+        apdu::small_response(&[], apdu::SW_SUCCESS)
+    }
+}
+
+#[test]
+fn wrappers_forward_execute_small() {
+    let cards: Vec<Box<dyn VSmartCard>> = vec![
+        Box::new(CatchUnwind::new(SmallCard)),
+        Box::new(WithDefault::new(SmallCard, apdu::SW_INS_NOT_SUPPORTED)),
+        Box::new(Timeout::new(SmallCard, Duration::from_secs(1))),
+        Box::new(WithAtr::new(SmallCard, [0x3b, 0x00])),
+        Box::new(ClaRewrite::new(SmallCard).channel(0, 1)),
+        Box::new(AdjustableAtr::new(SmallCard)),
+        Box::new(RandomizedAtr::new(SmallCard, 0)),
+        Box::new(SlowBoot::new(SmallCard, Duration::ZERO)),
+    ];
+    for mut card in cards {
+        let command = [0x00, 0xa4, 0x04, 0x00];
+        assert_eq!(card.execute_small(&command).as_slice(), [0x90, 0x00]);
+        assert_eq!(card.execute(&command), [0x6f, 0x00]);
+    }
+}
@@ -0,0 +1,69 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+use std::io::{Cursor, Read, Result, Write};
+
+use vpicc::{pool::BufferPool, Connection, VSmartCard};
+
+/// A transport that accepts a single byte per write.
+struct Trickle {
+    input: Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+impl Read for Trickle {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for Trickle {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.output.extend_from_slice(&buf[..buf.len().min(1)]);
+        Ok(buf.len().min(1))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Responds with 300 bytes from the pool.
+struct Card {
+    pool: BufferPool,
+}
+
+impl VSmartCard for Card {
+    fn execute(&mut self, _msg: &[u8]) -> Vec<u8> {
+        let mut response = self.pool.get();
+        response.resize(298, 0xab);
+        response.extend_from_slice(&[0x90, 0x00]);
+        response
+    }
+}
+
+#[test]
+fn pooled_responses_are_framed_with_partial_writes() {
+    let pool = BufferPool::new(1024, 4);
+    let input = [
+        &[0x00, 0x04, 0x00, 0xa4, 0x04, 0x00][..],
+        &[0x00, 0x01, 0x01],
+    ]
+    .concat();
+    let mut connection = Connection::new(Trickle {
+        input: Cursor::new(input),
+        output: Vec::new(),
+    });
+    connection.set_buffer_pool(pool.clone());
+    let mut card = Card { pool: pool.clone() };
+    connection.poll(&mut card).unwrap();
+    connection.poll(&mut card).unwrap();
+
+    let output = &connection.get_ref().output;
+    assert_eq!(output.len(), 302);
+    assert_eq!(output[..2], [0x01, 0x2c]);
+    assert!(output[2..300].iter().all(|b| *b == 0xab));
+    assert_eq!(output[300..], [0x90, 0x00]);
+    // the command and the response buffers have been returned
+    assert_eq!(pool.len(), 2);
+}
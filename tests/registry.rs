@@ -0,0 +1,39 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+mod common;
+
+use std::net::TcpListener;
+
+use common::{receive, send};
+use vpicc::Registry;
+
+#[test]
+fn restart_keeps_card_if_reconnect_fails() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut registry = Registry::new();
+    registry.add("card", addr, vpicc::DummySmartCard).unwrap();
+    let (mut vpcd, _) = listener.accept().unwrap();
+    send(&mut vpcd, &[0x00, 0xa4, 0x04, 0x00]);
+    receive(&mut vpcd);
+
+    // vpcd is not reachable anymore
+    drop(listener);
+    assert!(registry.restart("card").is_err());
+    assert!(registry.contains("card"));
+    assert_eq!(registry.is_running("card"), Some(false));
+    assert_eq!(registry.stats("card").unwrap().apdus, 1);
+
+    // the card can be restarted once vpcd is available again
+    let listener = TcpListener::bind(addr).unwrap();
+    registry.restart("card").unwrap();
+    let (mut vpcd, _) = listener.accept().unwrap();
+    assert_eq!(registry.is_running("card"), Some(true));
+    send(&mut vpcd, &[0x00, 0xa4, 0x04, 0x00]);
+    receive(&mut vpcd);
+    assert_eq!(registry.stats("card").unwrap().apdus, 2);
+
+    assert!(registry.remove("card").is_ok());
+    assert!(!registry.contains("card"));
+}
@@ -0,0 +1,98 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+use vpicc::{
+    apdu,
+    router::{Match, Router, SecurityStatus},
+    VSmartCard,
+};
+
+const VERIFY: [u8; 6] = [0x00, 0x20, 0x00, 0x80, 0x01, 0x00];
+const READ: [u8; 4] = [0x00, 0xb0, 0x00, 0x00];
+
+/// Returns a router that allows READ BINARY after VERIFY for reference 80.
+fn router() -> Router {
+    let security = SecurityStatus::new();
+    let verify = security.clone();
+    let read = security.clone();
+    Router::new()
+        .security(security)
+        .route(Match::ins(0x20), move |command| {
+            verify.grant(command.p2);
+            Ok(Vec::new())
+        })
+        .route(Match::ins(0x70), |_command| Ok(Vec::new()))
+        .route(Match::ins(0xb0), move |_command| {
+            read.require(0x80)?;
+            Ok(Vec::new())
+        })
+}
+
+fn assert_verified(card: &mut Router, verified: bool) {
+    let status = apdu::status(&card.execute(&READ));
+    let expected = if verified {
+        apdu::SW_SUCCESS
+    } else {
+        apdu::SW_SECURITY_STATUS_NOT_SATISFIED
+    };
+    assert_eq!(status, Some(expected));
+}
+
+#[test]
+fn security_status_is_reset_by_power_events() {
+    let events: [fn(&mut Router); 3] = [Router::power_off, Router::cold_reset, Router::warm_reset];
+    for event in events {
+        let mut card = router();
+        card.execute(&VERIFY);
+        assert_verified(&mut card, true);
+        event(&mut card);
+        assert_verified(&mut card, false);
+    }
+}
+
+#[test]
+fn security_status_is_reset_when_channel_is_closed() {
+    let mut card = router();
+    card.execute(&VERIFY);
+    // opening a channel keeps the security status
+    card.execute(&[0x00, 0x70, 0x00, 0x00, 0x01]);
+    assert_verified(&mut card, true);
+    card.execute(&[0x00, 0x70, 0x80, 0x01]);
+    assert_verified(&mut card, false);
+}
+
+#[cfg(feature = "derive")]
+#[test]
+fn applet_macro_forwards_reset_security() {
+    use vpicc::apdu::{Applet, Command};
+
+    #[derive(Default)]
+    struct Card {
+        verified: bool,
+    }
+
+    #[vpicc::applet]
+    impl Card {
+        #[apdu(ins = 0x20)]
+        fn verify(&mut self, _command: &Command<'_>) -> Result<Vec<u8>, u16> {
+            self.verified = true;
+            Ok(Vec::new())
+        }
+
+        #[apdu(ins = 0xa4)]
+        fn select(&mut self, _command: &Command<'_>) -> Result<Vec<u8>, u16> {
+            Ok(Vec::new())
+        }
+
+        #[reset_security]
+        fn logout(&mut self) {
+            self.verified = false;
+        }
+    }
+
+    let mut card = Card::default();
+    card.execute_command(&VERIFY);
+    assert!(card.verified);
+    card.execute_command(&[0x00, 0xa4, 0x04, 0x00, 0x01, 0xa0]);
+    assert!(!card.verified);
+}
@@ -0,0 +1,144 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+mod common;
+
+use std::{
+    io::Write,
+    net::{Shutdown, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use common::{assert_closed, receive, send};
+use vpicc::{Scheduler, VSmartCard};
+
+/// Responds to every command with its INS byte after a delay.
+struct SlowCard(Duration);
+
+impl VSmartCard for SlowCard {
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        thread::sleep(self.0);
+        vec![msg[1], 0x90, 0x00]
+    }
+}
+
+/// A [`SlowCard`][] that records when it is dropped.
+struct DroppedCard {
+    card: SlowCard,
+    dropped: Arc<AtomicBool>,
+}
+
+impl VSmartCard for DroppedCard {
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        self.card.execute(msg)
+    }
+}
+
+impl Drop for DroppedCard {
+    fn drop(&mut self) {
+        self.dropped.store(true, Ordering::Release);
+    }
+}
+
+/// Returns a connection of a card and the stream of vpcd for it.
+fn connect(listener: &TcpListener) -> (vpicc::Connection, TcpStream) {
+    let connection = vpicc::connect_socket(listener.local_addr().unwrap()).unwrap();
+    let (vpcd, _) = listener.accept().unwrap();
+    (connection, vpcd)
+}
+
+fn poll_until_progress(scheduler: &mut Scheduler) {
+    for _ in 0..1000 {
+        if scheduler.poll() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    panic!("scheduler did not receive or send any data");
+}
+
+#[test]
+fn shutdown_sends_pending_responses_in_order() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (connection, mut vpcd) = connect(&listener);
+    let mut scheduler = Scheduler::new();
+    scheduler
+        .add_offloaded(connection, SlowCard(Duration::from_millis(50)))
+        .unwrap();
+
+    send(&mut vpcd, &[0x01]);
+    send(&mut vpcd, &[0x00, 0x01, 0x00, 0x00]);
+    send(&mut vpcd, &[0x00, 0x02, 0x00, 0x00]);
+    // an incomplete request is discarded
+    vpcd.write_all(&[0x00, 0x04, 0x00]).unwrap();
+    poll_until_progress(&mut scheduler);
+
+    // the worker is still executing the first command
+    scheduler.shutdown().unwrap();
+    assert_eq!(receive(&mut vpcd), [0x01, 0x90, 0x00]);
+    assert_eq!(receive(&mut vpcd), [0x02, 0x90, 0x00]);
+    assert_closed(&mut vpcd);
+}
+
+#[test]
+fn shutdown_handle_drains_and_removes_connection() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (first, mut first_vpcd) = connect(&listener);
+    let (second, mut second_vpcd) = connect(&listener);
+    let handle = first.shutdown_handle().unwrap();
+    let mut scheduler = Scheduler::new();
+    scheduler.add(first, SlowCard(Duration::ZERO)).unwrap();
+    scheduler.add(second, SlowCard(Duration::ZERO)).unwrap();
+
+    send(&mut first_vpcd, &[0x00, 0x03, 0x00, 0x00]);
+    thread::sleep(Duration::from_millis(10));
+    handle.shutdown();
+    scheduler.poll();
+    assert_eq!(receive(&mut first_vpcd), [0x03, 0x90, 0x00]);
+    assert_closed(&mut first_vpcd);
+    assert_eq!(scheduler.len(), 1);
+
+    send(&mut second_vpcd, &[0x00, 0x04, 0x00, 0x00]);
+    poll_until_progress(&mut scheduler);
+    assert_eq!(receive(&mut second_vpcd), [0x04, 0x90, 0x00]);
+}
+
+#[test]
+fn commands_received_before_eof_are_handled() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    for offloaded in [false, true] {
+        let (connection, mut vpcd) = connect(&listener);
+        let dropped = Arc::new(AtomicBool::new(false));
+        let card = DroppedCard {
+            card: SlowCard(Duration::from_millis(20)),
+            dropped: dropped.clone(),
+        };
+        let mut scheduler = Scheduler::new();
+        if offloaded {
+            scheduler.add_offloaded(connection, card).unwrap();
+        } else {
+            scheduler.add(connection, card).unwrap();
+        }
+
+        send(&mut vpcd, &[0x01]);
+        send(&mut vpcd, &[0x00, 0x05, 0x00, 0x00]);
+        vpcd.shutdown(Shutdown::Write).unwrap();
+        for _ in 0..1000 {
+            if scheduler.is_empty() {
+                break;
+            }
+            scheduler.poll();
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(scheduler.is_empty());
+        assert_eq!(receive(&mut vpcd), [0x05, 0x90, 0x00]);
+        assert_closed(&mut vpcd);
+        // the worker thread has been joined when the connection was removed
+        assert!(dropped.load(Ordering::Acquire));
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+mod common;
+
+use std::{
+    fs,
+    io::{Error, Result},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use common::temp_dir;
+use vpicc::{
+    state::{AutoSave, BufferedBackend, Change, FileBackend, MemoryBackend, StateBackend},
+    VSmartCard,
+};
+
+/// A backend that fails to apply changes until it is enabled.
+#[derive(Debug, Default)]
+struct Flaky {
+    enabled: AtomicBool,
+    inner: MemoryBackend,
+}
+
+impl StateBackend for Flaky {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.inner.get(key)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        self.inner.keys()
+    }
+
+    fn apply(&self, changes: &[Change]) -> Result<()> {
+        if !self.enabled.load(Ordering::SeqCst) {
+            return Err(Error::other("backend not available"));
+        }
+        self.inner.apply(changes)
+    }
+}
+
+/// Stores the INS byte of every command in its backend.
+struct StoringCard(BufferedBackend);
+
+impl VSmartCard for StoringCard {
+    fn execute(&mut self, msg: &[u8]) -> Vec<u8> {
+        self.0.set("ins", &msg[1..2]).unwrap();
+        vec![0x90, 0x00]
+    }
+}
+
+#[test]
+fn file_backend_replaces_file_atomically() {
+    let dir = temp_dir("file-backend");
+    let path = dir.join("state");
+    let backend = FileBackend::open(&path).unwrap();
+    backend.set("piv/pin", b"123456").unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "piv/pin 313233343536\n");
+    assert!(!dir.join("state.tmp").exists());
+
+    // if the temporary file cannot be written, the old file and values are kept
+    fs::create_dir(dir.join("state.tmp")).unwrap();
+    assert!(backend.set("piv/pin", b"654321").is_err());
+    assert_eq!(fs::read_to_string(&path).unwrap(), "piv/pin 313233343536\n");
+    assert_eq!(
+        backend.get("piv/pin").unwrap().as_deref(),
+        Some(&b"123456"[..])
+    );
+    fs::remove_dir(dir.join("state.tmp")).unwrap();
+
+    // invalid keys are rejected without changing the file
+    assert!(backend.set("piv pin", b"00").is_err());
+    drop(backend);
+    let backend = FileBackend::open(&path).unwrap();
+    assert_eq!(backend.keys().unwrap(), ["piv/pin"]);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn buffered_backend_retries_failed_flush() {
+    let inner = Arc::new(Flaky::default());
+    let backend = BufferedBackend::new(inner.clone());
+    backend.set("piv/pin", b"123456").unwrap();
+    backend.set("piv/puk", b"12345678").unwrap();
+
+    assert!(backend.flush().is_err());
+    assert!(backend.is_dirty());
+    assert_eq!(
+        backend.get("piv/pin").unwrap().as_deref(),
+        Some(&b"123456"[..])
+    );
+    assert_eq!(inner.get("piv/pin").unwrap(), None);
+
+    // changes made after the failed flush are newer and are kept
+    backend.set("piv/pin", b"654321").unwrap();
+    backend.remove("piv/puk").unwrap();
+    inner.enabled.store(true, Ordering::SeqCst);
+    backend.flush().unwrap();
+    assert!(!backend.is_dirty());
+    assert_eq!(
+        inner.get("piv/pin").unwrap().as_deref(),
+        Some(&b"654321"[..])
+    );
+    assert_eq!(inner.get("piv/puk").unwrap(), None);
+    assert_eq!(backend.keys().unwrap(), ["piv/pin"]);
+}
+
+#[test]
+fn auto_save_flushes_before_response() {
+    let inner = MemoryBackend::new();
+    let backend = BufferedBackend::new(inner.clone());
+    let mut card = AutoSave::new(StoringCard(backend.clone()), backend);
+    card.execute(&[0x00, 0x01, 0x00, 0x00]);
+    assert_eq!(inner.get("ins").unwrap().as_deref(), Some(&[0x01][..]));
+    card.execute_small(&[0x00, 0x02, 0x00, 0x00]);
+    assert_eq!(inner.get("ins").unwrap().as_deref(), Some(&[0x02][..]));
+}
+
+#[test]
+fn debounced_auto_save_flushes_on_drop() {
+    let inner = MemoryBackend::new();
+    let backend = BufferedBackend::new(inner.clone());
+    let mut card = AutoSave::debounced(
+        StoringCard(backend.clone()),
+        backend,
+        Duration::from_secs(60),
+    );
+    card.execute(&[0x00, 0x01, 0x00, 0x00]);
+    assert_eq!(inner.get("ins").unwrap(), None);
+    drop(card);
+    assert_eq!(inner.get("ins").unwrap().as_deref(), Some(&[0x01][..]));
+}
@@ -0,0 +1,117 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+mod common;
+
+use std::{net::TcpListener, thread, time::Duration};
+
+use common::{assert_closed, receive, send};
+use vpicc::{Backoff, Call, RecordingCard, Supervisor, VSmartCard};
+
+/// Responds to every command with 6F00.
+struct FailingCard;
+
+impl VSmartCard for FailingCard {
+    fn execute(&mut self, _msg: &[u8]) -> Vec<u8> {
+        vec![0x6f, 0x00]
+    }
+}
+
+#[test]
+fn error_threshold_power_cycles_and_reconnects() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut supervisor = Supervisor::new(listener.local_addr().unwrap());
+    supervisor.set_error_threshold(2);
+    let supervisor = thread::spawn(move || {
+        let mut card = RecordingCard::new(FailingCard);
+        let result = supervisor.run(&mut card);
+        (supervisor, card, result)
+    });
+
+    let (mut vpcd, _) = listener.accept().unwrap();
+    send(&mut vpcd, &[0x01]);
+    send(&mut vpcd, &[0x00, 0xa4, 0x04, 0x00]);
+    assert_eq!(receive(&mut vpcd), [0x6f, 0x00]);
+    // the first error is tolerated, and power events do not reset the count
+    send(&mut vpcd, &[0x02]);
+    send(&mut vpcd, &[0x00, 0xa4, 0x04, 0x00]);
+    assert_eq!(receive(&mut vpcd), [0x6f, 0x00]);
+    assert_closed(&mut vpcd);
+
+    let (mut vpcd, _) = listener.accept().unwrap();
+    send(&mut vpcd, &[0x01]);
+    // the next reconnect fails and ends the supervisor
+    drop(listener);
+    drop(vpcd);
+
+    let (supervisor, card, result) = supervisor.join().unwrap();
+    assert!(result.is_err());
+    let stats = supervisor.link_stats();
+    assert_eq!(stats.connects, 2);
+    assert_eq!(stats.disconnects, 2);
+    assert_eq!(stats.reconnect_attempts, 2);
+    assert_eq!(stats.reconnect_failures, 1);
+    let power_calls: Vec<_> = card
+        .calls()
+        .iter()
+        .filter(|call| !matches!(call, Call::Atr | Call::Execute { .. }))
+        .cloned()
+        .collect();
+    // the card is only reset once by the new connection
+    assert_eq!(
+        power_calls,
+        [
+            Call::PowerOn,
+            Call::ColdReset,
+            Call::Reset,
+            Call::WarmReset,
+            Call::PowerOff,
+            Call::PowerOn,
+            Call::ColdReset,
+            Call::PowerOff,
+        ]
+    );
+}
+
+#[test]
+fn reconnect_gives_up_after_max_attempts() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut supervisor = Supervisor::new(listener.local_addr().unwrap());
+    supervisor.set_backoff(Backoff {
+        initial: Duration::from_millis(1),
+        max: Duration::from_millis(1),
+        multiplier: 2,
+        max_attempts: Some(3),
+    });
+    let supervisor = thread::spawn(move || {
+        let result = supervisor.run(&mut vpicc::DummySmartCard);
+        (supervisor, result)
+    });
+
+    let (vpcd, _) = listener.accept().unwrap();
+    drop(listener);
+    drop(vpcd);
+
+    let (supervisor, result) = supervisor.join().unwrap();
+    assert!(result.is_err());
+    let stats = supervisor.link_stats();
+    assert_eq!(stats.connects, 1);
+    assert_eq!(stats.reconnect_attempts, 3);
+    assert_eq!(stats.reconnect_failures, 3);
+}
+
+#[test]
+fn backoff_delay_is_limited() {
+    let backoff = Backoff {
+        initial: Duration::from_millis(10),
+        max: Duration::from_millis(50),
+        multiplier: 2,
+        max_attempts: None,
+    };
+    let delays: Vec<_> = (1..=5).map(|attempt| backoff.delay(attempt)).collect();
+    assert_eq!(
+        delays,
+        [10, 20, 40, 50, 50].map(Duration::from_millis).to_vec()
+    );
+    assert_eq!(backoff.delay(usize::MAX), Duration::from_millis(50));
+}
@@ -0,0 +1,69 @@
+// Copyright (C) 2022 Nitrokey GmbH
+// SPDX-License-Identifier: MIT
+
+mod common;
+
+use std::fs;
+
+use common::temp_dir;
+use vpicc::{
+    trace::{Recorder, RotatingWriter, Rotation, Trace},
+    VSmartCard,
+};
+
+fn commands(trace: &Trace) -> Vec<Vec<u8>> {
+    trace
+        .exchanges()
+        .iter()
+        .map(|exchange| exchange.command.clone())
+        .collect()
+}
+
+#[test]
+fn rotating_writer_keeps_max_files() {
+    let dir = temp_dir("rotation");
+    let path = dir.join("trace.log");
+    let mut rotation = Rotation::new();
+    rotation.set_max_size(1);
+    rotation.set_max_files(2);
+    let writer = RotatingWriter::create(&path, rotation).unwrap();
+    let mut card = Recorder::new(vpicc::DummySmartCard, writer);
+    for ins in 1..=4 {
+        card.execute(&[0x00, ins, 0x00, 0x00]);
+    }
+    let writer = card.into_parts().1;
+
+    assert_eq!(writer.rotated_path(1), dir.join("trace.log.1"));
+    drop(writer);
+    // every exchange is stored completely in one file
+    assert_eq!(
+        commands(&Trace::open(&path).unwrap()),
+        [[0x00, 0x04, 0x00, 0x00]]
+    );
+    assert_eq!(
+        commands(&Trace::open(dir.join("trace.log.1")).unwrap()),
+        [[0x00, 0x03, 0x00, 0x00]]
+    );
+    assert_eq!(
+        commands(&Trace::open(dir.join("trace.log.2")).unwrap()),
+        [[0x00, 0x02, 0x00, 0x00]]
+    );
+    assert!(!dir.join("trace.log.3").exists());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn rotating_writer_without_limits_keeps_all_files() {
+    let dir = temp_dir("retention");
+    let path = dir.join("trace.log");
+    let mut writer = RotatingWriter::create(&path, Rotation::new()).unwrap();
+    for _ in 0..3 {
+        writer.rotate().unwrap();
+    }
+    drop(writer);
+    for index in 1..=3 {
+        assert!(dir.join(format!("trace.log.{}", index)).exists());
+    }
+    assert!(path.exists());
+    fs::remove_dir_all(dir).unwrap();
+}